@@ -1,6 +1,8 @@
 fn main() {
     let mut config = prost_build::Config::new();
-    config.bytes(&["."]);
+    config.bytes(["."]);
+    // map 使用 BTreeMap，才能 derive PartialOrd，编码的结果也是确定的
+    config.btree_map(["."]);
     config.type_attribute(".", "#[derive(PartialOrd)]");
     config
         .out_dir("src/pb")
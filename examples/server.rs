@@ -23,7 +23,7 @@ async fn main() -> Result<()> {
             while let Some(Ok(cmd)) = stream.next().await {
                 info!("Got a new command: {:?}", cmd);
                 // 创建一个 404 response 返回客户端
-                let resp = svc.execute(cmd);
+                let resp = svc.execute_async(cmd).await;
                 stream.send(resp).await.unwrap();
             }
             info!("Client {:?} disconnected", addr);
@@ -26,7 +26,7 @@ async fn main() -> Result<()> {
                 AsyncProstStream::<_, CommandRequest, CommandResponse, _>::from(stream).for_async();
             while let Some(Ok(cmd)) = stream.next().await {
                 info!("Got a new command: {:?}", cmd);
-                let res = svc.execute(cmd);
+                let res = svc.execute_async(cmd).await;
                 stream.send(res).await.unwrap();
             }
             info!("Client {:?} disconnected", addr);
@@ -1,5 +1,6 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
 use kv::{CommandRequest, ProstClientStream};
+use std::env;
 use tokio::net::TcpStream;
 use tracing::info;
 
@@ -7,18 +8,47 @@ use tracing::info;
 async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
 
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let cmd = match args[..] {
+        // 导出和恢复使用的是服务器的 dump 目录中的相对路径
+        ["dump", path] => CommandRequest::new_dump(path),
+        ["restore", path] => CommandRequest::new_restore(path),
+        ["listdb"] => CommandRequest::new_listdb(),
+        ["createdb", db] => CommandRequest::new_createdb(db.parse()?),
+        ["flushdb", db] => CommandRequest::new_flushdb(db.parse()?),
+        ["publish", channel, message] => CommandRequest::new_publish(channel, message.into()),
+        // 订阅之后一直打印收到的消息
+        ["subscribe", ref channels @ ..] if !channels.is_empty() => {
+            let channels = channels.iter().map(|c| c.to_string()).collect();
+            CommandRequest::new_subscribe(channels, vec![])
+        }
+        // Watch 之后一直打印收到的修改事件
+        ["watch", table] => CommandRequest::new_watch(table, ""),
+        ["watch", table, prefix] => CommandRequest::new_watch(table, prefix),
+        // 没有参数时发送一个 HSET 命令
+        [] => CommandRequest::new_hset("table1", "hello", "world".to_string().into()),
+        _ => bail!(
+            "Usage: kvc [dump <path> | restore <path> | listdb | createdb <db> | flushdb <db> | publish <channel> <message> | subscribe <channel>... | watch <table> [prefix]]"
+        ),
+    };
+
     let addr = "127.0.0.1:9527";
     // 连接服务器
     let stream = TcpStream::connect(addr).await?;
 
     let mut client = ProstClientStream::new(stream);
 
-    // 生成一个 HSET 命令
-    let cmd = CommandRequest::new_hset("table1", "hello", "world".to_string().into());
-
-    // 发送 HSET 命令
+    // 发送命令
+    let subscribe = matches!(args.first(), Some(&"subscribe") | Some(&"watch"));
     let data = client.execute(cmd).await?;
     info!("Got response {:?}", data);
+    if subscribe {
+        loop {
+            let msg = client.next_message().await?;
+            info!("Got message {:?}", msg);
+        }
+    }
 
     Ok(())
 }
@@ -9,9 +9,19 @@ pub enum KvError {
     FrameError,
     #[error("Cannot parse command: {0}")]
     InvalidCommand(String),
-    #[error("Cannot convert value: {:0} to {1}")]
+    #[error("Cannot convert value: {0:?} to {1}")]
     ConvertError(Value, &'static str),
-    #[error("Cannot process command {0} with table: {1}, key: {2}. Error: {}")]
+    #[error("Compare and swap failed for table: {0}, key: {1}")]
+    CasFailed(String, String),
+    #[error("Transaction aborted at command {0}: {1}")]
+    TransactionAborted(usize, String),
+    #[error("Data corrupted: {0}")]
+    Corrupted(String),
+    #[error("Invalid dump: {0}")]
+    InvalidDump(String),
+    #[error("Out of memory: {0}")]
+    OutOfMemory(String),
+    #[error("Cannot process command {0} with table: {1}, key: {2}. Error: {3}")]
     StorageError(&'static str, String, String, String),
 
     #[error("Failed to encode protobuf message")]
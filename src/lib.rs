@@ -7,6 +7,7 @@ mod storage;
 pub use error::KvError;
 pub use network::*;
 pub use pb::abi::*;
+pub use pb::parse_json_path;
 pub use service::*;
 pub use storage::memory::*;
 pub use storage::*;
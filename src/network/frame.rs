@@ -1,8 +1,8 @@
-use crate::{CommandRequest, CommandResponse, KvError};
+use crate::{CommandRequest, CommandResponse, KvError, Kvpair};
 use bytes::{Buf, BufMut, BytesMut};
 use flate2::{read::GzDecoder, write::GzEncoder, Compression};
 use prost::Message;
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
 use tokio::io::{AsyncRead, AsyncReadExt};
 use tracing::debug;
 
@@ -69,6 +69,7 @@ where
 
 impl FrameCoder for CommandRequest {}
 impl FrameCoder for CommandResponse {}
+impl FrameCoder for Kvpair {}
 
 fn decode_header(header: usize) -> (usize, bool) {
     let len = header & !COMPRESSION_BIT;
@@ -93,6 +94,32 @@ where
     Ok(())
 }
 
+/// 从文件等阻塞的 reader 中读取一个完整的 frame，读到末尾（或者 frame 不完整）时返回 None
+pub(crate) fn read_frame_blocking(reader: &mut impl Read) -> Result<Option<BytesMut>, KvError> {
+    let mut header = [0u8; LEN_LEN];
+    if !read_full(reader, &mut header)? {
+        return Ok(None);
+    }
+    let (len, _) = decode_header(u32::from_be_bytes(header) as usize);
+
+    let mut buf = BytesMut::with_capacity(LEN_LEN + len);
+    buf.extend_from_slice(&header);
+    buf.resize(LEN_LEN + len, 0);
+    if !read_full(reader, &mut buf[LEN_LEN..])? {
+        return Ok(None);
+    }
+
+    Ok(Some(buf))
+}
+
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> Result<bool, KvError> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -107,7 +134,7 @@ mod tests {
         cmd.encode_frame(&mut buf).unwrap();
 
         // 最高位没设置
-        assert_eq!(is_compressed(&buf), false);
+        assert!(!is_compressed(&buf));
 
         let cmd1 = CommandRequest::decode_frame(&mut buf).unwrap();
         assert_eq!(cmd, cmd1);
@@ -122,7 +149,7 @@ mod tests {
         res.encode_frame(&mut buf).unwrap();
 
         // 最高位没设置
-        assert_eq!(is_compressed(&buf), false);
+        assert!(!is_compressed(&buf));
 
         let res1 = CommandResponse::decode_frame(&mut buf).unwrap();
         assert_eq!(res, res1);
@@ -137,7 +164,7 @@ mod tests {
         res.encode_frame(&mut buf).unwrap();
 
         // 最高位设置了
-        assert_eq!(is_compressed(&buf), true);
+        assert!(is_compressed(&buf));
 
         let res1 = CommandResponse::decode_frame(&mut buf).unwrap();
         assert_eq!(res, res1);
@@ -1,56 +1,90 @@
 pub mod frame;
 
-use bytes::{Bytes, BytesMut};
+use bytes::BytesMut;
 pub use frame::FrameCoder;
-use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use std::collections::VecDeque;
+use tokio::io::{self, AsyncRead, AsyncWrite, AsyncWriteExt, ReadHalf};
 use tracing::info;
 
-use crate::{CommandRequest, CommandResponse, KvError, Service};
+use crate::{
+    memory::MemTable, CommandRequest, CommandResponse, KvError, PushMessage, Service, Storage,
+};
 
 use self::frame::read_frame;
 
-pub struct ProstServerStream<S> {
+pub struct ProstServerStream<S, Store = MemTable> {
     inner: S,
-    service: Service,
+    service: Service<Store>,
 }
 
-impl<S> ProstServerStream<S>
+impl<S, Store> ProstServerStream<S, Store>
 where
     S: AsyncRead + AsyncWrite + Unpin + Send,
+    Store: Storage + Send + Sync + 'static,
 {
-    pub fn new(stream: S, service: Service) -> Self {
+    pub fn new(stream: S, service: Service<Store>) -> Self {
         Self {
             inner: stream,
-            service: service,
+            service,
         }
     }
 
-    pub async fn process(mut self) -> Result<(), KvError> {
-        while let Ok(cmd) = self.recv().await {
-            info!("Got a new command: {:?}", cmd);
-            let res = self.service.execute(cmd);
-            self.send(res).await?;
+    /// 依次处理连接上的命令，同时把订阅的消息推送给客户端。
+    /// 推送的消息只会出现在两个回复之间，不会打断一个回复
+    pub async fn process(self) -> Result<(), KvError> {
+        let Self { inner, service } = self;
+        let (reader, mut writer) = io::split(inner);
+        let mut session = service.session();
+
+        // 读取 frame 的 future 不能在 select 中被取消，读完一个命令之后才创建下一个
+        let reading = recv(reader);
+        tokio::pin!(reading);
+        loop {
+            tokio::select! {
+                (reader, cmd) = &mut reading => {
+                    let cmd = match cmd {
+                        Ok(cmd) => cmd,
+                        Err(_) => break,
+                    };
+                    info!("Got a new command: {:?}", cmd);
+                    let res = service.execute_in_session(cmd, &mut session).await;
+                    send(&mut writer, res).await?;
+                    reading.set(recv(reader));
+                }
+                Some(msg) = session.recv() => {
+                    send(&mut writer, msg.into()).await?;
+                }
+            }
         }
 
         Ok(())
     }
+}
 
-    async fn send(&mut self, msg: CommandResponse) -> Result<(), KvError> {
-        let mut buf = BytesMut::new();
-        msg.encode_frame(&mut buf);
-        let encoded = buf.freeze();
-        self.inner.write_all(&encoded[..]).await?;
+async fn send<W>(writer: &mut W, msg: CommandResponse) -> Result<(), KvError>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = BytesMut::new();
+    msg.encode_frame(&mut buf)?;
+    let encoded = buf.freeze();
+    writer.write_all(&encoded[..]).await?;
 
-        Ok(())
-    }
+    Ok(())
+}
 
-    async fn recv(&mut self) -> Result<CommandRequest, KvError> {
-        let mut buf = BytesMut::new();
-        let stream = &mut self.inner;
-        read_frame(stream, &mut buf).await?;
+// 读取一个命令，把 reader 一起返回，方便读取下一个
+async fn recv<S>(mut reader: ReadHalf<S>) -> (ReadHalf<S>, Result<CommandRequest, KvError>)
+where
+    S: AsyncRead + Unpin + Send,
+{
+    let mut buf = BytesMut::new();
+    let result = match read_frame(&mut reader, &mut buf).await {
+        Ok(()) => CommandRequest::decode_frame(&mut buf),
+        Err(e) => Err(e),
+    };
 
-        CommandRequest::decode_frame(&mut buf)
-    }
+    (reader, result)
 }
 
 pub struct ProstClientStream<S>
@@ -58,6 +92,8 @@ where
     S: AsyncRead + AsyncWrite + Unpin + Send,
 {
     inner: S,
+    // 等待回复时收到的推送消息
+    pushed: VecDeque<PushMessage>,
 }
 
 impl<S> ProstClientStream<S>
@@ -65,13 +101,36 @@ where
     S: AsyncRead + AsyncWrite + Unpin + Send,
 {
     pub fn new(stream: S) -> Self {
-        Self { inner: stream }
+        Self {
+            inner: stream,
+            pushed: VecDeque::new(),
+        }
     }
 
+    /// 发送命令并等待它的回复，期间收到的推送消息留给 next_message
     pub async fn execute(&mut self, cmd: CommandRequest) -> Result<CommandResponse, KvError> {
         self.send(cmd).await?;
 
-        Ok(self.recv().await?)
+        loop {
+            let mut res = self.recv().await?;
+            match res.push.take() {
+                Some(msg) => self.pushed.push_back(msg),
+                None => return Ok(res),
+            }
+        }
+    }
+
+    /// 等待下一条订阅的消息
+    pub async fn next_message(&mut self) -> Result<PushMessage, KvError> {
+        if let Some(msg) = self.pushed.pop_front() {
+            return Ok(msg);
+        }
+
+        loop {
+            if let Some(msg) = self.recv().await?.push {
+                return Ok(msg);
+            }
+        }
     }
 
     async fn send(&mut self, cmd: CommandRequest) -> Result<(), KvError> {
@@ -91,3 +150,172 @@ where
         CommandResponse::decode_frame(&mut buf)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assert_res_error, assert_res_ok, ChangeEvent, ServiceInner, Value};
+    use std::{
+        convert::TryFrom,
+        time::{SystemTime, UNIX_EPOCH},
+    };
+    use tokio::io::duplex;
+
+    fn connect(service: &Service) -> ProstClientStream<io::DuplexStream> {
+        let (client, server) = duplex(4096);
+        let stream = ProstServerStream::new(server, service.clone());
+        tokio::spawn(stream.process());
+        ProstClientStream::new(client)
+    }
+
+    #[tokio::test]
+    async fn subscribers_should_receive_pushed_messages() {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let mut sub = connect(&service);
+        let mut publisher = connect(&service);
+
+        let cmd = CommandRequest::new_subscribe(vec!["news".into()], vec!["user.*".into()]);
+        let res = sub.execute(cmd).await.unwrap();
+        assert_res_ok(res, &[2.into()], &[]);
+
+        let cmd = CommandRequest::new_publish("user.1", "hello".into());
+        let res = publisher.execute(cmd).await.unwrap();
+        assert_res_ok(res, &[1.into()], &[]);
+        let msg = sub.next_message().await.unwrap();
+        assert_eq!(msg, PushMessage::new("user.1", "user.*", "hello".into()));
+
+        // 等待回复时收到的推送不会被当成回复
+        let cmd = CommandRequest::new_publish("news", "n1".into());
+        let res = sub.execute(cmd).await.unwrap();
+        assert_res_ok(res, &[1.into()], &[]);
+        let res = sub
+            .execute(CommandRequest::new_hset("t1", "k1", "v1".into()))
+            .await
+            .unwrap();
+        assert_res_ok(res, &[Value::default()], &[]);
+        let msg = sub.next_message().await.unwrap();
+        assert_eq!(msg, PushMessage::new("news", "", "n1".into()));
+
+        let cmd = CommandRequest::new_unsubscribe(vec![], vec![]);
+        let res = sub.execute(cmd).await.unwrap();
+        assert_res_ok(res, &[0.into()], &[]);
+        let cmd = CommandRequest::new_publish("news", "n2".into());
+        let res = publisher.execute(cmd).await.unwrap();
+        assert_res_ok(res, &[0.into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn watchers_should_receive_change_events() {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let mut watcher = connect(&service);
+        let mut writer = connect(&service);
+
+        let res = watcher
+            .execute(CommandRequest::new_watch("t1", "user:"))
+            .await
+            .unwrap();
+        assert_res_ok(res, &[1.into()], &[]);
+
+        let cmd = CommandRequest::new_hset("t1", "item:1", "v0".into());
+        writer.execute(cmd).await.unwrap();
+        let cmd = CommandRequest::new_hset("t1", "user:1", "v1".into());
+        writer.execute(cmd).await.unwrap();
+        let cmd = CommandRequest::new_hdel("t1", "user:1");
+        writer.execute(cmd).await.unwrap();
+
+        let change = watcher.next_message().await.unwrap().change.unwrap();
+        assert_eq!(
+            change,
+            ChangeEvent {
+                table: "t1".into(),
+                key: "user:1".into(),
+                op: "set".into(),
+                old: None,
+                new: Some("v1".into()),
+                db: 0,
+            }
+        );
+        let change = watcher.next_message().await.unwrap().change.unwrap();
+        assert_eq!((change.op.as_str(), change.old), ("del", Some("v1".into())));
+
+        let res = watcher
+            .execute(CommandRequest::new_unwatch())
+            .await
+            .unwrap();
+        assert_res_ok(res, &[0.into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn clients_should_set_expiry_by_deadline() {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let mut client = connect(&service);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        client.execute(cmd).await.unwrap();
+        let cmd = CommandRequest::new_hexpireat("t1", "k1", now + 60_000);
+        let res = client.execute(cmd).await.unwrap();
+        assert_res_ok(res, &[true.into()], &[]);
+        let res = client
+            .execute(CommandRequest::new_httl("t1", "k1"))
+            .await
+            .unwrap();
+        let ttl = i64::try_from(res.values[0].clone()).unwrap();
+        assert!(ttl > 50_000 && ttl <= 60_000, "ttl: {}", ttl);
+
+        let cmd = CommandRequest::new_hexpireat("t1", "k2", now + 60_000);
+        let res = client.execute(cmd).await.unwrap();
+        assert_res_ok(res, &[false.into()], &[]);
+
+        // 已经过去的时刻让 key 立即过期
+        let cmd = CommandRequest::new_hexpireat("t1", "k1", now - 1000);
+        client.execute(cmd).await.unwrap();
+        let res = client
+            .execute(CommandRequest::new_hget("t1", "k1"))
+            .await
+            .unwrap();
+        assert_res_error(res, 404, "Not found");
+    }
+
+    #[tokio::test]
+    async fn select_should_isolate_databases() {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let mut c1 = connect(&service);
+        let mut c2 = connect(&service);
+
+        let res = c1.execute(CommandRequest::new_select(1)).await.unwrap();
+        assert_res_error(res, 400, "does not exist");
+        let res = c1.execute(CommandRequest::new_createdb(1)).await.unwrap();
+        assert_res_ok(res, &[true.into()], &[]);
+        let res = c1.execute(CommandRequest::new_select(1)).await.unwrap();
+        assert_res_ok(res, &[true.into()], &[]);
+
+        // 两个连接在不同的数据库里写入同一个 key
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        c1.execute(cmd).await.unwrap();
+        let cmd = CommandRequest::new_hset("t1", "k1", "v0".into());
+        c2.execute(cmd).await.unwrap();
+        let res = c1
+            .execute(CommandRequest::new_hget("t1", "k1"))
+            .await
+            .unwrap();
+        assert_res_ok(res, &["v1".into()], &[]);
+        let res = c2
+            .execute(CommandRequest::new_hget("t1", "k1"))
+            .await
+            .unwrap();
+        assert_res_ok(res, &["v0".into()], &[]);
+
+        let res = c2.execute(CommandRequest::new_listdb()).await.unwrap();
+        assert_res_ok(res, &[0.into(), 1.into()], &[]);
+        let res = c2.execute(CommandRequest::new_flushdb(1)).await.unwrap();
+        assert_res_ok(res, &[1.into()], &[]);
+        let res = c1.execute(CommandRequest::new_htables()).await.unwrap();
+        assert_res_ok(res, &[], &[]);
+        let res = c2.execute(CommandRequest::new_htables()).await.unwrap();
+        assert_res_ok(res, &["t1".into()], &[]);
+    }
+}
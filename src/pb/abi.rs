@@ -1,7 +1,7 @@
 #[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct CommandRequest {
-    #[prost(oneof="command_request::RequestData", tags="1, 2, 3, 4, 5, 6, 7, 8, 9")]
+    #[prost(oneof="command_request::RequestData", tags="1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47, 48, 49, 50, 51, 52, 53, 54, 55, 56, 57, 58, 59, 60, 61, 62, 63, 64, 65, 66, 67, 68, 69, 70")]
     pub request_data: ::core::option::Option<command_request::RequestData>,
 }
 /// Nested message and enum types in `CommandRequest`.
@@ -27,6 +27,128 @@ pub mod command_request {
         Hexist(super::Hexist),
         #[prost(message, tag="9")]
         Hmexist(super::Hmexist),
+        #[prost(message, tag="10")]
+        Hsetex(super::Hsetex),
+        #[prost(message, tag="11")]
+        Hexpire(super::Hexpire),
+        #[prost(message, tag="12")]
+        Httl(super::Httl),
+        #[prost(message, tag="13")]
+        Hpersist(super::Hpersist),
+        #[prost(message, tag="14")]
+        Transaction(super::Transaction),
+        #[prost(message, tag="15")]
+        Hcas(super::Hcas),
+        #[prost(message, tag="16")]
+        Hincrby(super::Hincrby),
+        #[prost(message, tag="17")]
+        Hincrbyfloat(super::Hincrbyfloat),
+        #[prost(message, tag="18")]
+        Hscan(super::Hscan),
+        #[prost(message, tag="19")]
+        Htables(super::Htables),
+        #[prost(message, tag="20")]
+        Hdrop(super::Hdrop),
+        #[prost(message, tag="21")]
+        Hlen(super::Hlen),
+        #[prost(message, tag="22")]
+        Hexpireat(super::Hexpireat),
+        #[prost(message, tag="23")]
+        Dump(super::Dump),
+        #[prost(message, tag="24")]
+        Restore(super::Restore),
+        #[prost(message, tag="25")]
+        CreateIndex(super::CreateIndex),
+        #[prost(message, tag="26")]
+        Hfind(super::Hfind),
+        #[prost(message, tag="27")]
+        Subscribe(super::Subscribe),
+        #[prost(message, tag="28")]
+        Unsubscribe(super::Unsubscribe),
+        #[prost(message, tag="29")]
+        Publish(super::Publish),
+        #[prost(message, tag="30")]
+        Watch(super::Watch),
+        #[prost(message, tag="31")]
+        Unwatch(super::Unwatch),
+        #[prost(message, tag="32")]
+        Hgetversion(super::Hgetversion),
+        #[prost(message, tag="33")]
+        Hgetat(super::Hgetat),
+        #[prost(message, tag="34")]
+        Hhistory(super::Hhistory),
+        #[prost(message, tag="35")]
+        Hgcversions(super::Hgcversions),
+        #[prost(message, tag="36")]
+        Select(super::Select),
+        #[prost(message, tag="37")]
+        Listdb(super::Listdb),
+        #[prost(message, tag="38")]
+        Createdb(super::Createdb),
+        #[prost(message, tag="39")]
+        Flushdb(super::Flushdb),
+        #[prost(message, tag="40")]
+        Lpush(super::Lpush),
+        #[prost(message, tag="41")]
+        Rpush(super::Rpush),
+        #[prost(message, tag="42")]
+        Lpop(super::Lpop),
+        #[prost(message, tag="43")]
+        Rpop(super::Rpop),
+        #[prost(message, tag="44")]
+        Lrange(super::Lrange),
+        #[prost(message, tag="45")]
+        Llen(super::Llen),
+        #[prost(message, tag="46")]
+        Ltrim(super::Ltrim),
+        #[prost(message, tag="47")]
+        Lindex(super::Lindex),
+        #[prost(message, tag="48")]
+        Sadd(super::Sadd),
+        #[prost(message, tag="49")]
+        Srem(super::Srem),
+        #[prost(message, tag="50")]
+        Smembers(super::Smembers),
+        #[prost(message, tag="51")]
+        Sismember(super::Sismember),
+        #[prost(message, tag="52")]
+        Scard(super::Scard),
+        #[prost(message, tag="53")]
+        Sinter(super::Sinter),
+        #[prost(message, tag="54")]
+        Sunion(super::Sunion),
+        #[prost(message, tag="55")]
+        Sdiff(super::Sdiff),
+        #[prost(message, tag="56")]
+        Zadd(super::Zadd),
+        #[prost(message, tag="57")]
+        Zincrby(super::Zincrby),
+        #[prost(message, tag="58")]
+        Zrem(super::Zrem),
+        #[prost(message, tag="59")]
+        Zscore(super::Zscore),
+        #[prost(message, tag="60")]
+        Zrank(super::Zrank),
+        #[prost(message, tag="61")]
+        Zrange(super::Zrange),
+        #[prost(message, tag="62")]
+        Zrangebyscore(super::Zrangebyscore),
+        #[prost(message, tag="63")]
+        Zcard(super::Zcard),
+        #[prost(message, tag="64")]
+        Hgetpath(super::Hgetpath),
+        #[prost(message, tag="65")]
+        Hsetpath(super::Hsetpath),
+        #[prost(message, tag="66")]
+        Hdelpath(super::Hdelpath),
+        #[prost(message, tag="67")]
+        Jget(super::Jget),
+        #[prost(message, tag="68")]
+        Jset(super::Jset),
+        #[prost(message, tag="69")]
+        Jdel(super::Jdel),
+        #[prost(message, tag="70")]
+        Jarrappend(super::Jarrappend),
     }
 }
 #[derive(PartialOrd)]
@@ -40,11 +162,59 @@ pub struct CommandResponse {
     pub values: ::prost::alloc::vec::Vec<Value>,
     #[prost(message, repeated, tag="4")]
     pub pairs: ::prost::alloc::vec::Vec<Kvpair>,
+    /// 事务中每个子命令的结果
+    #[prost(message, repeated, tag="5")]
+    pub responses: ::prost::alloc::vec::Vec<CommandResponse>,
+    /// 服务器主动推送的订阅消息。只有推送的 frame 设置了这个字段，命令的回复永远不会设置
+    #[prost(message, optional, tag="6")]
+    pub push: ::core::option::Option<PushMessage>,
+    /// Hhistory 返回的 key 的所有版本
+    #[prost(message, repeated, tag="7")]
+    pub versions: ::prost::alloc::vec::Vec<Version>,
+}
+/// 推送给订阅者的消息
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct PushMessage {
+    #[prost(string, tag="1")]
+    pub channel: ::prost::alloc::string::String,
+    /// 通过模式订阅收到时匹配的模式，直接订阅 channel 时为空
+    #[prost(string, tag="2")]
+    pub pattern: ::prost::alloc::string::String,
+    #[prost(message, optional, tag="3")]
+    pub message: ::core::option::Option<Value>,
+    /// Watch 收到的修改事件，这时 channel 和 message 为空
+    #[prost(message, optional, tag="4")]
+    pub change: ::core::option::Option<ChangeEvent>,
+}
+/// 存储中的一次修改。过期之后被清理的 key 不会产生事件
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ChangeEvent {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    /// op 为 drop 时为空
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    /// 修改的类型：set、del、expire、persist，修改 value 中的元素为 setpath、delpath、arrappend，
+    /// list 的修改为 lpush、rpush、lpop、rpop、ltrim，set 的修改为 sadd、srem，
+    /// sorted set 的修改为 zadd、zincrby、zrem，删除整个 table 时为 drop
+    #[prost(string, tag="3")]
+    pub op: ::prost::alloc::string::String,
+    /// 修改之前的 value，key 之前不存在或者无法得知（Hincrby、list 的修改等）时不设置
+    #[prost(message, optional, tag="4")]
+    pub old: ::core::option::Option<Value>,
+    /// 修改之后的 value，只有 set 设置
+    #[prost(message, optional, tag="5")]
+    pub new: ::core::option::Option<Value>,
+    /// table 所在的数据库
+    #[prost(uint32, tag="6")]
+    pub db: u32,
 }
 #[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Value {
-    #[prost(oneof="value::Value", tags="1, 2, 3, 4, 5")]
+    #[prost(oneof="value::Value", tags="1, 2, 3, 4, 5, 6, 7")]
     pub value: ::core::option::Option<value::Value>,
 }
 /// Nested message and enum types in `Value`.
@@ -62,6 +232,43 @@ pub mod value {
         Float(f64),
         #[prost(bool, tag="5")]
         Bool(bool),
+        #[prost(message, tag="6")]
+        Array(super::ValueArray),
+        #[prost(message, tag="7")]
+        Map(super::ValueMap),
+    }
+}
+/// 嵌套的 Value 数组
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ValueArray {
+    #[prost(message, repeated, tag="1")]
+    pub values: ::prost::alloc::vec::Vec<Value>,
+}
+/// 嵌套的 Value map，按 key 排序
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ValueMap {
+    #[prost(btree_map="string, message", tag="1")]
+    pub entries: ::prost::alloc::collections::BTreeMap<::prost::alloc::string::String, Value>,
+}
+/// 嵌套的 value 中路径的一段：ValueMap 中的 key 或者 ValueArray 中的下标，
+/// 下标为负数时从末尾倒数，-1 是最后一个
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct PathSegment {
+    #[prost(oneof="path_segment::Segment", tags="1, 2")]
+    pub segment: ::core::option::Option<path_segment::Segment>,
+}
+/// Nested message and enum types in `PathSegment`.
+pub mod path_segment {
+    #[derive(PartialOrd)]
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
+    pub enum Segment {
+        #[prost(string, tag="1")]
+        Key(::prost::alloc::string::String),
+        #[prost(int64, tag="2")]
+        Index(i64),
     }
 }
 #[derive(PartialOrd)]
@@ -72,6 +279,29 @@ pub struct Kvpair {
     #[prost(message, optional, tag="2")]
     pub value: ::core::option::Option<Value>,
 }
+/// sorted set 中的一个 member 和它的 score
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ScoredMember {
+    #[prost(string, tag="1")]
+    pub member: ::prost::alloc::string::String,
+    #[prost(double, tag="2")]
+    pub score: f64,
+}
+/// key 的一个版本，每次写入和删除都会产生一个新的版本
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Version {
+    /// 同一个 key 的版本号从 1 开始递增
+    #[prost(uint64, tag="1")]
+    pub version: u64,
+    /// 产生这个版本的时刻（unix 时间戳，毫秒）
+    #[prost(uint64, tag="2")]
+    pub timestamp: u64,
+    /// 写入的 value，删除产生的版本不设置
+    #[prost(message, optional, tag="3")]
+    pub value: ::core::option::Option<Value>,
+}
 #[derive(PartialOrd)]
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hget {
@@ -142,3 +372,635 @@ pub struct Hmexist {
     #[prost(string, repeated, tag="2")]
     pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
 }
+/// 设置 key 的 value，同时指定过期时间（毫秒）
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hsetex {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(message, optional, tag="2")]
+    pub pair: ::core::option::Option<Kvpair>,
+    #[prost(uint64, tag="3")]
+    pub ttl: u64,
+}
+/// 为已存在的 key 设置过期时间（毫秒）
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hexpire {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(uint64, tag="3")]
+    pub ttl: u64,
+}
+/// 查询 key 剩余的过期时间（毫秒），没有过期时间返回 -1
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Httl {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+}
+/// 清除 key 的过期时间
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hpersist {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+}
+/// 原子地执行一组命令，任意一个命令失败，所有修改都会回滚
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Transaction {
+    #[prost(message, repeated, tag="1")]
+    pub commands: ::prost::alloc::vec::Vec<CommandRequest>,
+}
+/// key 当前的值等于 expected 时才写入 value，expected 不设置表示期望 key 不存在
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hcas {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(message, optional, tag="3")]
+    pub expected: ::core::option::Option<Value>,
+    #[prost(message, optional, tag="4")]
+    pub value: ::core::option::Option<Value>,
+}
+/// 给整数 value 加上 delta，key 不存在时从 0 开始
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hincrby {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(int64, tag="3")]
+    pub delta: i64,
+}
+/// 给浮点数 value 加上 delta，key 不存在时从 0 开始
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hincrbyfloat {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(double, tag="3")]
+    pub delta: f64,
+}
+/// 按 key 的顺序分页遍历 table，返回一页 kv pair，values 里放下一页的 cursor，
+/// cursor 为空表示遍历结束
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hscan {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    /// 上一页返回的 cursor，为空从头开始
+    #[prost(string, tag="2")]
+    pub cursor: ::prost::alloc::string::String,
+    /// 只返回以 prefix 开头的 key
+    #[prost(string, tag="3")]
+    pub prefix: ::prost::alloc::string::String,
+    /// 只返回 start <= key < end 的 key，为空表示不限制
+    #[prost(string, tag="4")]
+    pub start: ::prost::alloc::string::String,
+    #[prost(string, tag="5")]
+    pub end: ::prost::alloc::string::String,
+    /// 每页的数量，为 0 时使用默认值
+    #[prost(uint32, tag="6")]
+    pub limit: u32,
+}
+/// 列出所有的 table
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Htables {
+}
+/// 删除整个 table
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hdrop {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+}
+/// 获取 table 中 key 的数量
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hlen {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+}
+/// 为已存在的 key 设置过期的时刻（unix 时间戳，毫秒），已经过去的时刻会让 key 立即过期，返回 key 是否存在。
+/// 和 Hexpire 一样是客户端可以使用的命令；AOF 和 dump 也用它记录过期时间，重放时不受时间差的影响
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hexpireat {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(uint64, tag="3")]
+    pub at: u64,
+}
+/// 把所有 table 导出到服务器上的 path。path 是服务器配置的 dump 目录中的相对路径，
+/// 不能包含 ..，服务器没有配置 dump 目录时不能使用
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Dump {
+    #[prost(string, tag="1")]
+    pub path: ::prost::alloc::string::String,
+}
+/// 从服务器上的 path 恢复 dump 导出的数据，dump 中出现的 table 会先被清空。path 的限制和 Dump 一样
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Restore {
+    #[prost(string, tag="1")]
+    pub path: ::prost::alloc::string::String,
+}
+/// 为 table 建立 value 的二级索引，之后 Hfind 可以通过索引查找。返回索引之前是否不存在
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct CreateIndex {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+}
+/// 返回 table 中 value 等于 value 的所有 kv pair，按 key 排序
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hfind {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(message, optional, tag="2")]
+    pub value: ::core::option::Option<Value>,
+}
+/// 订阅 channel 和模式（支持 *、?、[...] 通配符），之后服务器会在这个连接上推送消息。
+/// 返回这个连接订阅的 channel 和模式的总数
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Subscribe {
+    #[prost(string, repeated, tag="1")]
+    pub channels: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    #[prost(string, repeated, tag="2")]
+    pub patterns: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+}
+/// 取消订阅，channels 和 patterns 都为空时取消所有订阅。返回剩下的订阅数量
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Unsubscribe {
+    #[prost(string, repeated, tag="1")]
+    pub channels: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    #[prost(string, repeated, tag="2")]
+    pub patterns: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+}
+/// 向 channel 发布消息，返回收到消息的订阅者数量
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Publish {
+    #[prost(string, tag="1")]
+    pub channel: ::prost::alloc::string::String,
+    #[prost(message, optional, tag="2")]
+    pub message: ::core::option::Option<Value>,
+}
+/// 在这个连接上接收当前数据库的 table 中以 prefix 开头的 key 的修改事件，
+/// table 为空表示所有 table。返回这个连接 Watch 的数量
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Watch {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub prefix: ::prost::alloc::string::String,
+}
+/// 取消这个连接所有的 Watch
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Unwatch {
+}
+/// 读取 key 的某个版本的 value，版本不存在或者是删除产生的版本时返回 404
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hgetversion {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(uint64, tag="3")]
+    pub version: u64,
+}
+/// 读取 key 在 at 时刻（unix 时间戳，毫秒）的 value，那时 key 不存在返回 404
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hgetat {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(uint64, tag="3")]
+    pub at: u64,
+}
+/// 返回 key 保留下来的所有版本，按版本号从旧到新
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hhistory {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+}
+/// 清理 retention（毫秒）之前的旧版本，保留在那个时刻可见的版本，返回清理掉的版本数量
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hgcversions {
+    #[prost(uint64, tag="1")]
+    pub retention: u64,
+}
+/// 把这个连接切换到编号为 db 的数据库，之后的命令都在这个数据库中执行。
+/// 数据库 0 总是存在，其它数据库需要先通过 Createdb 创建
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Select {
+    #[prost(uint32, tag="1")]
+    pub db: u32,
+}
+/// 列出所有的数据库，按编号排序
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Listdb {
+}
+/// 创建数据库，返回之前是否不存在
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Createdb {
+    #[prost(uint32, tag="1")]
+    pub db: u32,
+}
+/// 删除数据库中所有的 table，返回删除的 table 数量。数据库本身保留
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Flushdb {
+    #[prost(uint32, tag="1")]
+    pub db: u32,
+}
+/// 把 values 依次放入 list 的左端，list 不存在时创建，返回放入之后 list 的长度。
+/// list 和 Hset 写入的 key 相互独立
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Lpush {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(message, repeated, tag="3")]
+    pub values: ::prost::alloc::vec::Vec<Value>,
+}
+/// 把 values 依次放入 list 的右端，返回放入之后 list 的长度
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Rpush {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(message, repeated, tag="3")]
+    pub values: ::prost::alloc::vec::Vec<Value>,
+}
+/// 从 list 的左端取出最多 count 个元素，count 为 0 时取出一个。list 取空之后被删除
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Lpop {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(uint32, tag="3")]
+    pub count: u32,
+}
+/// 从 list 的右端取出最多 count 个元素，count 为 0 时取出一个
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Rpop {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(uint32, tag="3")]
+    pub count: u32,
+}
+/// 返回 list 中下标从 start 到 stop（包含 stop）的元素，负数表示从末尾倒数，-1 是最后一个
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Lrange {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(int64, tag="3")]
+    pub start: i64,
+    #[prost(int64, tag="4")]
+    pub stop: i64,
+}
+/// 返回 list 的长度，list 不存在时为 0
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Llen {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+}
+/// 只保留 list 中下标从 start 到 stop 的元素，返回删除的元素数量
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Ltrim {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(int64, tag="3")]
+    pub start: i64,
+    #[prost(int64, tag="4")]
+    pub stop: i64,
+}
+/// 返回 list 中下标为 index 的元素，负数表示从末尾倒数，超出范围时返回 404
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Lindex {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(int64, tag="3")]
+    pub index: i64,
+}
+/// 把 members 加入 set，set 不存在时创建，返回新加入的 member 数量。
+/// set 和 Hset 写入的 key、list 相互独立
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Sadd {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(string, repeated, tag="3")]
+    pub members: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+}
+/// 从 set 中删除 members，返回实际删除的数量。set 删空之后被删除
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Srem {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(string, repeated, tag="3")]
+    pub members: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+}
+/// 返回 set 的所有 member，按字典序排序
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Smembers {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+}
+/// 返回 member 是否在 set 中
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Sismember {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(string, tag="3")]
+    pub member: ::prost::alloc::string::String,
+}
+/// 返回 set 中 member 的数量，set 不存在时为 0
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Scard {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+}
+/// 返回 table 中 keys 对应的 set 的交集，按字典序排序。不存在的 set 当作空集
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Sinter {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, repeated, tag="2")]
+    pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+}
+/// 返回 keys 对应的 set 的并集，按字典序排序
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Sunion {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, repeated, tag="2")]
+    pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+}
+/// 返回第一个 key 对应的 set 去掉其它 set 中的 member 之后的差集，按字典序排序
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Sdiff {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, repeated, tag="2")]
+    pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+}
+/// 把 members 加入 sorted set，已经存在的 member 更新 score，返回新加入的 member 数量。
+/// score 必须是有限的数字。sorted set 和 Hset 写入的 key、list、set 相互独立
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Zadd {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(message, repeated, tag="3")]
+    pub members: ::prost::alloc::vec::Vec<ScoredMember>,
+}
+/// 给 member 的 score 加上 delta，member 不存在时从 0 开始，返回新的 score
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Zincrby {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(string, tag="3")]
+    pub member: ::prost::alloc::string::String,
+    #[prost(double, tag="4")]
+    pub delta: f64,
+}
+/// 从 sorted set 中删除 members，返回实际删除的数量。sorted set 删空之后被删除
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Zrem {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(string, repeated, tag="3")]
+    pub members: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+}
+/// 返回 member 的 score，member 不存在时返回 404
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Zscore {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(string, tag="3")]
+    pub member: ::prost::alloc::string::String,
+}
+/// 返回 member 按 score 从小到大的排名（从 0 开始），member 不存在时返回 404
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Zrank {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(string, tag="3")]
+    pub member: ::prost::alloc::string::String,
+}
+/// 按排名返回从 start 到 stop（包含 stop）的 member，负数表示从末尾倒数。
+/// 结果放在 pairs 里，key 是 member，value 是 score
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Zrange {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(int64, tag="3")]
+    pub start: i64,
+    #[prost(int64, tag="4")]
+    pub stop: i64,
+}
+/// 按排名返回 score 在 min 和 max 之间（包含两端）的 member，跳过前 offset 个，
+/// 最多返回 limit 个，limit 为 0 表示不限制。结果和 Zrange 一样放在 pairs 里
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Zrangebyscore {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(double, tag="3")]
+    pub min: f64,
+    #[prost(double, tag="4")]
+    pub max: f64,
+    #[prost(uint32, tag="5")]
+    pub offset: u32,
+    #[prost(uint32, tag="6")]
+    pub limit: u32,
+}
+/// 返回 sorted set 中 member 的数量，sorted set 不存在时为 0
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Zcard {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+}
+/// 返回 value 中 path 指向的元素，key 或者元素不存在时返回 404
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hgetpath {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(message, repeated, tag="3")]
+    pub path: ::prost::alloc::vec::Vec<PathSegment>,
+}
+/// 把 value 中 path 指向的元素替换成 value，返回原来的元素（不存在时为空）。ValueMap 中不存在的 key
+/// 会被加入，ValueArray 的下标等于长度时追加到末尾。path 为空时替换整个 value。
+/// 过期时间保持不变
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hsetpath {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(message, repeated, tag="3")]
+    pub path: ::prost::alloc::vec::Vec<PathSegment>,
+    #[prost(message, optional, tag="4")]
+    pub value: ::core::option::Option<Value>,
+}
+/// 删除 value 中 path 指向的元素，返回删除的元素，元素不存在时返回 404。path 不能为空
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hdelpath {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(message, repeated, tag="3")]
+    pub path: ::prost::alloc::vec::Vec<PathSegment>,
+}
+// JSON 文档就是由 ValueMap 和 ValueArray 组成的嵌套的 value，JSON 的 null 是空的 Value。
+// 下面的命令中 path 是 JSONPath，只支持以 $ 开头，由 .key、['key'] 和 [index] 组成的指向单个元素的路径
+
+/// 返回 JSON 文档中 path 指向的元素的 JSON 文本，key 或者元素不存在时返回 404
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Jget {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(string, tag="3")]
+    pub path: ::prost::alloc::string::String,
+}
+/// 把 path 指向的元素替换成 json 解析出来的 value，返回原来的元素的 JSON 文本（不存在时为空）。
+/// path 为 $ 时写入整个文档，key 不存在时只能这样创建。过期时间保持不变
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Jset {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(string, tag="3")]
+    pub path: ::prost::alloc::string::String,
+    #[prost(string, tag="4")]
+    pub json: ::prost::alloc::string::String,
+}
+/// 删除 path 指向的元素，返回删除的元素的 JSON 文本，元素不存在时返回 404。path 为 $ 时删除整个 key
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Jdel {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(string, tag="3")]
+    pub path: ::prost::alloc::string::String,
+}
+/// 把 values 中的每个 JSON 依次追加到 path 指向的数组末尾，返回追加之后数组的长度
+#[derive(PartialOrd)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Jarrappend {
+    #[prost(string, tag="1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag="2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(string, tag="3")]
+    pub path: ::prost::alloc::string::String,
+    #[prost(string, repeated, tag="4")]
+    pub values: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+}
@@ -0,0 +1,159 @@
+use serde_json::{Map, Number, Value as Json};
+use std::convert::TryFrom;
+
+use super::abi::{value, PathSegment, Value, ValueArray, ValueMap};
+use crate::KvError;
+
+impl Value {
+    /// 解析 JSON 文本。object 转换成 ValueMap，array 转换成 ValueArray，null 是空的 Value
+    pub fn from_json(text: &str) -> Result<Self, KvError> {
+        let json: Json = serde_json::from_str(text)
+            .map_err(|e| KvError::InvalidCommand(format!("Invalid JSON: {}", e)))?;
+
+        Ok(json.into())
+    }
+
+    /// 转换成 JSON 文本，Binary 和不是有限数字的 Float 无法转换
+    pub fn to_json(&self) -> Result<String, KvError> {
+        Ok(Json::try_from(self)?.to_string())
+    }
+}
+
+impl From<Json> for Value {
+    fn from(json: Json) -> Self {
+        let value = match json {
+            Json::Null => return Value::default(),
+            Json::Bool(b) => value::Value::Bool(b),
+            Json::Number(n) => match n.as_i64() {
+                Some(i) => value::Value::Integer(i),
+                // 超出 i64 范围的整数也只能当作浮点数
+                None => value::Value::Float(n.as_f64().unwrap_or_default()),
+            },
+            Json::String(s) => value::Value::String(s),
+            Json::Array(values) => value::Value::Array(ValueArray {
+                values: values.into_iter().map(Value::from).collect(),
+            }),
+            Json::Object(map) => value::Value::Map(ValueMap {
+                entries: map.into_iter().map(|(k, v)| (k, v.into())).collect(),
+            }),
+        };
+
+        Self { value: Some(value) }
+    }
+}
+
+impl TryFrom<&Value> for Json {
+    type Error = KvError;
+
+    fn try_from(v: &Value) -> Result<Self, Self::Error> {
+        let json = match &v.value {
+            None => Json::Null,
+            Some(value::Value::Bool(b)) => Json::Bool(*b),
+            Some(value::Value::Integer(i)) => Json::Number((*i).into()),
+            Some(value::Value::Float(f)) => match Number::from_f64(*f) {
+                Some(n) => Json::Number(n),
+                None => return Err(KvError::ConvertError(v.clone(), "JSON")),
+            },
+            Some(value::Value::String(s)) => Json::String(s.clone()),
+            Some(value::Value::Array(array)) => Json::Array(
+                array
+                    .values
+                    .iter()
+                    .map(Json::try_from)
+                    .collect::<Result<_, _>>()?,
+            ),
+            Some(value::Value::Map(map)) => Json::Object(
+                map.entries
+                    .iter()
+                    .map(|(k, v)| Ok((k.clone(), Json::try_from(v)?)))
+                    .collect::<Result<Map<_, _>, KvError>>()?,
+            ),
+            Some(value::Value::Binary(_)) => return Err(KvError::ConvertError(v.clone(), "JSON")),
+        };
+
+        Ok(json)
+    }
+}
+
+/// 把 JSONPath 解析成 path。只支持以 $ 开头，由 .key、['key'] 和 [index] 组成的指向单个元素的路径，
+/// 通配符、过滤器和递归查找都不支持
+pub fn parse_json_path(path: &str) -> Result<Vec<PathSegment>, KvError> {
+    let invalid = || KvError::InvalidCommand(format!("Unsupported JSONPath: {}", path));
+    let mut rest = path.trim().strip_prefix('$').ok_or_else(invalid)?;
+    let mut segments = Vec::new();
+    while !rest.is_empty() {
+        if let Some(r) = rest.strip_prefix('.') {
+            let end = r.find(['.', '[']).unwrap_or(r.len());
+            let key = &r[..end];
+            if key.is_empty() || key == "*" {
+                return Err(invalid());
+            }
+            segments.push(key.into());
+            rest = &r[end..];
+        } else if let Some(r) = rest.strip_prefix('[') {
+            match r.chars().next() {
+                Some(quote) if quote == '\'' || quote == '"' => {
+                    let r = &r[1..];
+                    let end = r.find(quote).ok_or_else(invalid)?;
+                    segments.push(r[..end].into());
+                    rest = r[end + 1..].strip_prefix(']').ok_or_else(invalid)?;
+                }
+                _ => {
+                    let end = r.find(']').ok_or_else(invalid)?;
+                    let index: i64 = r[..end].trim().parse().map_err(|_| invalid())?;
+                    segments.push(index.into());
+                    rest = &r[end + 1..];
+                }
+            }
+        } else {
+            return Err(invalid());
+        }
+    }
+
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_should_convert_to_value_and_back() {
+        let text = r#"{"a":[1,2.5,"x",null,true],"b":{"c":{}}}"#;
+        let value = Value::from_json(text).unwrap();
+        let a = value.get_path(&["a".into()]).unwrap();
+        let expected: Value = vec![
+            1.into(),
+            2.5.into(),
+            "x".into(),
+            Value::default(),
+            true.into(),
+        ]
+        .into();
+        assert_eq!(a, &expected);
+        assert_eq!(value.to_json().unwrap(), text);
+
+        assert!(Value::from_json("{").is_err());
+        assert!(Value::from(b"data").to_json().is_err());
+        assert!(Value::from(f64::NAN).to_json().is_err());
+    }
+
+    #[test]
+    fn json_path_should_be_parsed() {
+        let path = parse_json_path("$.store.books[0]['first name'][-1]").unwrap();
+        let expected: Vec<PathSegment> = vec![
+            "store".into(),
+            "books".into(),
+            0.into(),
+            "first name".into(),
+            (-1).into(),
+        ];
+        assert_eq!(path, expected);
+        assert!(parse_json_path("$").unwrap().is_empty());
+        assert_eq!(parse_json_path(r#"$["a.b"]"#).unwrap(), ["a.b".into()]);
+
+        for path in ["", "a.b", "$..a", "$.*", "$[*]", "$[?(@.a)]", "$['a'", "$a"] {
+            assert!(parse_json_path(path).is_err(), "{}", path);
+        }
+    }
+}
@@ -1,13 +1,16 @@
 pub mod abi;
+mod json;
 
-use std::convert::TryFrom;
+use std::{collections::BTreeMap, convert::TryFrom, fmt, mem};
 
 use abi::{command_request::RequestData, *};
 use bytes::Bytes;
 use http::StatusCode;
 use prost::Message;
 
-use crate::KvError;
+use crate::{KvError, PathOp};
+
+pub use json::parse_json_path;
 
 impl CommandRequest {
     pub fn new_hget(table: impl Into<String>, key: impl Into<String>) -> Self {
@@ -27,6 +30,24 @@ impl CommandRequest {
         }
     }
 
+    /// 按 prefix 分页遍历 table，需要更复杂的条件可以直接构造 Hscan
+    pub fn new_hscan(
+        table: impl Into<String>,
+        cursor: impl Into<String>,
+        prefix: impl Into<String>,
+        limit: u32,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hscan(Hscan {
+                table: table.into(),
+                cursor: cursor.into(),
+                prefix: prefix.into(),
+                limit,
+                ..Default::default()
+            })),
+        }
+    }
+
     pub fn new_hmget(table: impl Into<String>, keys: Vec<String>) -> Self {
         Self {
             request_data: Some(RequestData::Hmget(Hmget {
@@ -89,6 +110,724 @@ impl CommandRequest {
             })),
         }
     }
+
+    pub fn new_hsetex(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        value: Value,
+        ttl: u64,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hsetex(Hsetex {
+                table: table.into(),
+                pair: Some(Kvpair::new(key, value)),
+                ttl,
+            })),
+        }
+    }
+
+    pub fn new_hexpire(table: impl Into<String>, key: impl Into<String>, ttl: u64) -> Self {
+        Self {
+            request_data: Some(RequestData::Hexpire(Hexpire {
+                table: table.into(),
+                key: key.into(),
+                ttl,
+            })),
+        }
+    }
+
+    pub fn new_hexpireat(table: impl Into<String>, key: impl Into<String>, at: u64) -> Self {
+        Self {
+            request_data: Some(RequestData::Hexpireat(Hexpireat {
+                table: table.into(),
+                key: key.into(),
+                at,
+            })),
+        }
+    }
+
+    pub fn new_httl(table: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Httl(Httl {
+                table: table.into(),
+                key: key.into(),
+            })),
+        }
+    }
+
+    pub fn new_hpersist(table: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hpersist(Hpersist {
+                table: table.into(),
+                key: key.into(),
+            })),
+        }
+    }
+
+    pub fn new_hcas(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        expected: Option<Value>,
+        value: Value,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hcas(Hcas {
+                table: table.into(),
+                key: key.into(),
+                expected,
+                value: Some(value),
+            })),
+        }
+    }
+
+    pub fn new_hincrby(table: impl Into<String>, key: impl Into<String>, delta: i64) -> Self {
+        Self {
+            request_data: Some(RequestData::Hincrby(Hincrby {
+                table: table.into(),
+                key: key.into(),
+                delta,
+            })),
+        }
+    }
+
+    pub fn new_hincrbyfloat(table: impl Into<String>, key: impl Into<String>, delta: f64) -> Self {
+        Self {
+            request_data: Some(RequestData::Hincrbyfloat(Hincrbyfloat {
+                table: table.into(),
+                key: key.into(),
+                delta,
+            })),
+        }
+    }
+
+    pub fn new_htables() -> Self {
+        Self {
+            request_data: Some(RequestData::Htables(Htables {})),
+        }
+    }
+
+    pub fn new_hdrop(table: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hdrop(Hdrop {
+                table: table.into(),
+            })),
+        }
+    }
+
+    pub fn new_hlen(table: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hlen(Hlen {
+                table: table.into(),
+            })),
+        }
+    }
+
+    pub fn new_transaction(commands: Vec<CommandRequest>) -> Self {
+        Self {
+            request_data: Some(RequestData::Transaction(Transaction { commands })),
+        }
+    }
+
+    pub fn new_dump(path: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Dump(Dump { path: path.into() })),
+        }
+    }
+
+    pub fn new_restore(path: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Restore(Restore { path: path.into() })),
+        }
+    }
+
+    pub fn new_create_index(table: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::CreateIndex(CreateIndex {
+                table: table.into(),
+            })),
+        }
+    }
+
+    pub fn new_hfind(table: impl Into<String>, value: Value) -> Self {
+        Self {
+            request_data: Some(RequestData::Hfind(Hfind {
+                table: table.into(),
+                value: Some(value),
+            })),
+        }
+    }
+
+    pub fn new_subscribe(channels: Vec<String>, patterns: Vec<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Subscribe(Subscribe { channels, patterns })),
+        }
+    }
+
+    pub fn new_unsubscribe(channels: Vec<String>, patterns: Vec<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Unsubscribe(Unsubscribe { channels, patterns })),
+        }
+    }
+
+    pub fn new_publish(channel: impl Into<String>, message: Value) -> Self {
+        Self {
+            request_data: Some(RequestData::Publish(Publish {
+                channel: channel.into(),
+                message: Some(message),
+            })),
+        }
+    }
+
+    pub fn new_watch(table: impl Into<String>, prefix: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Watch(Watch {
+                table: table.into(),
+                prefix: prefix.into(),
+            })),
+        }
+    }
+
+    pub fn new_unwatch() -> Self {
+        Self {
+            request_data: Some(RequestData::Unwatch(Unwatch {})),
+        }
+    }
+
+    pub fn new_hgetversion(table: impl Into<String>, key: impl Into<String>, version: u64) -> Self {
+        Self {
+            request_data: Some(RequestData::Hgetversion(Hgetversion {
+                table: table.into(),
+                key: key.into(),
+                version,
+            })),
+        }
+    }
+
+    pub fn new_hgetat(table: impl Into<String>, key: impl Into<String>, at: u64) -> Self {
+        Self {
+            request_data: Some(RequestData::Hgetat(Hgetat {
+                table: table.into(),
+                key: key.into(),
+                at,
+            })),
+        }
+    }
+
+    pub fn new_hhistory(table: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hhistory(Hhistory {
+                table: table.into(),
+                key: key.into(),
+            })),
+        }
+    }
+
+    pub fn new_hgcversions(retention: u64) -> Self {
+        Self {
+            request_data: Some(RequestData::Hgcversions(Hgcversions { retention })),
+        }
+    }
+
+    pub fn new_select(db: u32) -> Self {
+        Self {
+            request_data: Some(RequestData::Select(Select { db })),
+        }
+    }
+
+    pub fn new_listdb() -> Self {
+        Self {
+            request_data: Some(RequestData::Listdb(Listdb {})),
+        }
+    }
+
+    pub fn new_createdb(db: u32) -> Self {
+        Self {
+            request_data: Some(RequestData::Createdb(Createdb { db })),
+        }
+    }
+
+    pub fn new_flushdb(db: u32) -> Self {
+        Self {
+            request_data: Some(RequestData::Flushdb(Flushdb { db })),
+        }
+    }
+
+    pub fn new_lpush(table: impl Into<String>, key: impl Into<String>, values: Vec<Value>) -> Self {
+        Self {
+            request_data: Some(RequestData::Lpush(Lpush {
+                table: table.into(),
+                key: key.into(),
+                values,
+            })),
+        }
+    }
+
+    pub fn new_rpush(table: impl Into<String>, key: impl Into<String>, values: Vec<Value>) -> Self {
+        Self {
+            request_data: Some(RequestData::Rpush(Rpush {
+                table: table.into(),
+                key: key.into(),
+                values,
+            })),
+        }
+    }
+
+    pub fn new_lpop(table: impl Into<String>, key: impl Into<String>, count: u32) -> Self {
+        Self {
+            request_data: Some(RequestData::Lpop(Lpop {
+                table: table.into(),
+                key: key.into(),
+                count,
+            })),
+        }
+    }
+
+    pub fn new_rpop(table: impl Into<String>, key: impl Into<String>, count: u32) -> Self {
+        Self {
+            request_data: Some(RequestData::Rpop(Rpop {
+                table: table.into(),
+                key: key.into(),
+                count,
+            })),
+        }
+    }
+
+    pub fn new_lrange(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        start: i64,
+        stop: i64,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Lrange(Lrange {
+                table: table.into(),
+                key: key.into(),
+                start,
+                stop,
+            })),
+        }
+    }
+
+    pub fn new_llen(table: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Llen(Llen {
+                table: table.into(),
+                key: key.into(),
+            })),
+        }
+    }
+
+    pub fn new_ltrim(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        start: i64,
+        stop: i64,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Ltrim(Ltrim {
+                table: table.into(),
+                key: key.into(),
+                start,
+                stop,
+            })),
+        }
+    }
+
+    pub fn new_lindex(table: impl Into<String>, key: impl Into<String>, index: i64) -> Self {
+        Self {
+            request_data: Some(RequestData::Lindex(Lindex {
+                table: table.into(),
+                key: key.into(),
+                index,
+            })),
+        }
+    }
+
+    pub fn new_sadd(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        members: Vec<String>,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Sadd(Sadd {
+                table: table.into(),
+                key: key.into(),
+                members,
+            })),
+        }
+    }
+
+    pub fn new_srem(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        members: Vec<String>,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Srem(Srem {
+                table: table.into(),
+                key: key.into(),
+                members,
+            })),
+        }
+    }
+
+    pub fn new_smembers(table: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Smembers(Smembers {
+                table: table.into(),
+                key: key.into(),
+            })),
+        }
+    }
+
+    pub fn new_sismember(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        member: impl Into<String>,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Sismember(Sismember {
+                table: table.into(),
+                key: key.into(),
+                member: member.into(),
+            })),
+        }
+    }
+
+    pub fn new_scard(table: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Scard(Scard {
+                table: table.into(),
+                key: key.into(),
+            })),
+        }
+    }
+
+    pub fn new_sinter(table: impl Into<String>, keys: Vec<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Sinter(Sinter {
+                table: table.into(),
+                keys,
+            })),
+        }
+    }
+
+    pub fn new_sunion(table: impl Into<String>, keys: Vec<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Sunion(Sunion {
+                table: table.into(),
+                keys,
+            })),
+        }
+    }
+
+    pub fn new_sdiff(table: impl Into<String>, keys: Vec<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Sdiff(Sdiff {
+                table: table.into(),
+                keys,
+            })),
+        }
+    }
+
+    pub fn new_zadd(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        members: Vec<ScoredMember>,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Zadd(Zadd {
+                table: table.into(),
+                key: key.into(),
+                members,
+            })),
+        }
+    }
+
+    pub fn new_zincrby(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        member: impl Into<String>,
+        delta: f64,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Zincrby(Zincrby {
+                table: table.into(),
+                key: key.into(),
+                member: member.into(),
+                delta,
+            })),
+        }
+    }
+
+    pub fn new_zrem(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        members: Vec<String>,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Zrem(Zrem {
+                table: table.into(),
+                key: key.into(),
+                members,
+            })),
+        }
+    }
+
+    pub fn new_zscore(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        member: impl Into<String>,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Zscore(Zscore {
+                table: table.into(),
+                key: key.into(),
+                member: member.into(),
+            })),
+        }
+    }
+
+    pub fn new_zrank(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        member: impl Into<String>,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Zrank(Zrank {
+                table: table.into(),
+                key: key.into(),
+                member: member.into(),
+            })),
+        }
+    }
+
+    pub fn new_zrange(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        start: i64,
+        stop: i64,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Zrange(Zrange {
+                table: table.into(),
+                key: key.into(),
+                start,
+                stop,
+            })),
+        }
+    }
+
+    pub fn new_zrangebyscore(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        min: f64,
+        max: f64,
+        offset: u32,
+        limit: u32,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Zrangebyscore(Zrangebyscore {
+                table: table.into(),
+                key: key.into(),
+                min,
+                max,
+                offset,
+                limit,
+            })),
+        }
+    }
+
+    pub fn new_zcard(table: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Zcard(Zcard {
+                table: table.into(),
+                key: key.into(),
+            })),
+        }
+    }
+
+    pub fn new_hgetpath(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        path: Vec<PathSegment>,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hgetpath(Hgetpath {
+                table: table.into(),
+                key: key.into(),
+                path,
+            })),
+        }
+    }
+
+    pub fn new_hsetpath(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        path: Vec<PathSegment>,
+        value: Value,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hsetpath(Hsetpath {
+                table: table.into(),
+                key: key.into(),
+                path,
+                value: Some(value),
+            })),
+        }
+    }
+
+    pub fn new_hdelpath(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        path: Vec<PathSegment>,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Hdelpath(Hdelpath {
+                table: table.into(),
+                key: key.into(),
+                path,
+            })),
+        }
+    }
+
+    pub fn new_jget(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        path: impl Into<String>,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Jget(Jget {
+                table: table.into(),
+                key: key.into(),
+                path: path.into(),
+            })),
+        }
+    }
+
+    pub fn new_jset(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        path: impl Into<String>,
+        json: impl Into<String>,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Jset(Jset {
+                table: table.into(),
+                key: key.into(),
+                path: path.into(),
+                json: json.into(),
+            })),
+        }
+    }
+
+    pub fn new_jdel(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        path: impl Into<String>,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Jdel(Jdel {
+                table: table.into(),
+                key: key.into(),
+                path: path.into(),
+            })),
+        }
+    }
+
+    pub fn new_jarrappend(
+        table: impl Into<String>,
+        key: impl Into<String>,
+        path: impl Into<String>,
+        values: Vec<String>,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::Jarrappend(Jarrappend {
+                table: table.into(),
+                key: key.into(),
+                path: path.into(),
+                values,
+            })),
+        }
+    }
+
+    /// 命令操作的 table，Htables、Transaction 等不针对某个 table 的命令返回 None
+    pub fn table(&self) -> Option<&str> {
+        let table = match self.request_data.as_ref()? {
+            RequestData::Hget(v) => &v.table,
+            RequestData::Hgetall(v) => &v.table,
+            RequestData::Hmget(v) => &v.table,
+            RequestData::Hset(v) => &v.table,
+            RequestData::Hmset(v) => &v.table,
+            RequestData::Hdel(v) => &v.table,
+            RequestData::Hmdel(v) => &v.table,
+            RequestData::Hexist(v) => &v.table,
+            RequestData::Hmexist(v) => &v.table,
+            RequestData::Hsetex(v) => &v.table,
+            RequestData::Hexpire(v) => &v.table,
+            RequestData::Httl(v) => &v.table,
+            RequestData::Hpersist(v) => &v.table,
+            RequestData::Hcas(v) => &v.table,
+            RequestData::Hincrby(v) => &v.table,
+            RequestData::Hincrbyfloat(v) => &v.table,
+            RequestData::Hscan(v) => &v.table,
+            RequestData::Hdrop(v) => &v.table,
+            RequestData::Hlen(v) => &v.table,
+            RequestData::Hexpireat(v) => &v.table,
+            RequestData::CreateIndex(v) => &v.table,
+            RequestData::Hfind(v) => &v.table,
+            RequestData::Hgetversion(v) => &v.table,
+            RequestData::Hgetat(v) => &v.table,
+            RequestData::Hhistory(v) => &v.table,
+            RequestData::Lpush(v) => &v.table,
+            RequestData::Rpush(v) => &v.table,
+            RequestData::Lpop(v) => &v.table,
+            RequestData::Rpop(v) => &v.table,
+            RequestData::Lrange(v) => &v.table,
+            RequestData::Llen(v) => &v.table,
+            RequestData::Ltrim(v) => &v.table,
+            RequestData::Lindex(v) => &v.table,
+            RequestData::Sadd(v) => &v.table,
+            RequestData::Srem(v) => &v.table,
+            RequestData::Smembers(v) => &v.table,
+            RequestData::Sismember(v) => &v.table,
+            RequestData::Scard(v) => &v.table,
+            RequestData::Sinter(v) => &v.table,
+            RequestData::Sunion(v) => &v.table,
+            RequestData::Sdiff(v) => &v.table,
+            RequestData::Zadd(v) => &v.table,
+            RequestData::Zincrby(v) => &v.table,
+            RequestData::Zrem(v) => &v.table,
+            RequestData::Zscore(v) => &v.table,
+            RequestData::Zrank(v) => &v.table,
+            RequestData::Zrange(v) => &v.table,
+            RequestData::Zrangebyscore(v) => &v.table,
+            RequestData::Zcard(v) => &v.table,
+            RequestData::Hgetpath(v) => &v.table,
+            RequestData::Hsetpath(v) => &v.table,
+            RequestData::Hdelpath(v) => &v.table,
+            RequestData::Jget(v) => &v.table,
+            RequestData::Jset(v) => &v.table,
+            RequestData::Jdel(v) => &v.table,
+            RequestData::Jarrappend(v) => &v.table,
+            RequestData::Htables(_)
+            | RequestData::Transaction(_)
+            | RequestData::Dump(_)
+            | RequestData::Restore(_)
+            | RequestData::Subscribe(_)
+            | RequestData::Unsubscribe(_)
+            | RequestData::Publish(_)
+            | RequestData::Watch(_)
+            | RequestData::Unwatch(_)
+            | RequestData::Hgcversions(_)
+            | RequestData::Select(_)
+            | RequestData::Listdb(_)
+            | RequestData::Createdb(_)
+            | RequestData::Flushdb(_) => return None,
+        };
+
+        Some(table)
+    }
 }
 
 impl Kvpair {
@@ -101,6 +840,201 @@ impl Kvpair {
     }
 }
 
+impl ScoredMember {
+    /// 创建 sorted set 中的一个 member
+    pub fn new(member: impl Into<String>, score: f64) -> Self {
+        Self {
+            member: member.into(),
+            score,
+        }
+    }
+}
+
+impl Value {
+    /// 嵌套的 value 中 path 指向的元素，不存在时返回 None。path 为空时就是 value 本身
+    pub fn get_path(&self, path: &[PathSegment]) -> Option<&Value> {
+        path.iter()
+            .try_fold(self, |value, segment| value.child(segment))
+    }
+
+    /// 在 path 指向的位置执行 op，返回原来的元素，Append 返回追加之后数组的长度。
+    /// path 中除了最后一段，其它的元素都必须存在。出错时 value 不会被修改
+    pub fn update_path(
+        &mut self,
+        path: &[PathSegment],
+        op: PathOp,
+    ) -> Result<Option<Value>, KvError> {
+        match op {
+            PathOp::Set(value) => self.set_path(path, value),
+            PathOp::Delete => self.delete_path(path),
+            PathOp::Append(values) => self.append_path(path, values),
+        }
+    }
+
+    fn set_path(&mut self, path: &[PathSegment], value: Value) -> Result<Option<Value>, KvError> {
+        let (last, parents) = match path.split_last() {
+            Some(v) => v,
+            None => return Ok(Some(mem::replace(self, value))),
+        };
+
+        match (self.descend(parents)?.value.as_mut(), last.segment.as_ref()) {
+            (Some(value::Value::Map(map)), Some(path_segment::Segment::Key(k))) => {
+                Ok(map.entries.insert(k.clone(), value))
+            }
+            (Some(value::Value::Array(array)), Some(path_segment::Segment::Index(i))) => {
+                let values = &mut array.values;
+                if *i == values.len() as i64 {
+                    values.push(value);
+                    return Ok(None);
+                }
+                match array_index(values.len(), *i) {
+                    Some(i) => Ok(Some(mem::replace(&mut values[i], value))),
+                    None => Err(KvError::InvalidCommand(format!(
+                        "Index of path {} is out of range",
+                        Path(path)
+                    ))),
+                }
+            }
+            _ => Err(not_container(path)),
+        }
+    }
+
+    fn delete_path(&mut self, path: &[PathSegment]) -> Result<Option<Value>, KvError> {
+        let (last, parents) = path
+            .split_last()
+            .ok_or_else(|| KvError::InvalidCommand("Cannot delete with an empty path".into()))?;
+
+        match (self.descend(parents)?.value.as_mut(), last.segment.as_ref()) {
+            (Some(value::Value::Map(map)), Some(path_segment::Segment::Key(k))) => {
+                Ok(map.entries.remove(k))
+            }
+            (Some(value::Value::Array(array)), Some(path_segment::Segment::Index(i))) => {
+                Ok(array_index(array.values.len(), *i).map(|i| array.values.remove(i)))
+            }
+            _ => Err(not_container(path)),
+        }
+    }
+
+    fn append_path(
+        &mut self,
+        path: &[PathSegment],
+        values: Vec<Value>,
+    ) -> Result<Option<Value>, KvError> {
+        match self.descend(path)?.value.as_mut() {
+            Some(value::Value::Array(array)) => {
+                array.values.extend(values);
+                Ok(Some((array.values.len() as i64).into()))
+            }
+            _ => Err(KvError::InvalidCommand(format!(
+                "Path {} does not point to an array",
+                Path(path)
+            ))),
+        }
+    }
+
+    // path 上的每个元素都必须存在
+    fn descend(&mut self, path: &[PathSegment]) -> Result<&mut Value, KvError> {
+        path.iter()
+            .enumerate()
+            .try_fold(self, |value, (i, segment)| {
+                value.child_mut(segment).ok_or_else(|| {
+                    KvError::InvalidCommand(format!("Path {} does not exist", Path(&path[..=i])))
+                })
+            })
+    }
+
+    fn child(&self, segment: &PathSegment) -> Option<&Value> {
+        match (self.value.as_ref()?, segment.segment.as_ref()?) {
+            (value::Value::Map(map), path_segment::Segment::Key(k)) => map.entries.get(k),
+            (value::Value::Array(array), path_segment::Segment::Index(i)) => {
+                let i = array_index(array.values.len(), *i)?;
+                array.values.get(i)
+            }
+            _ => None,
+        }
+    }
+
+    fn child_mut(&mut self, segment: &PathSegment) -> Option<&mut Value> {
+        match (self.value.as_mut()?, segment.segment.as_ref()?) {
+            (value::Value::Map(map), path_segment::Segment::Key(k)) => map.entries.get_mut(k),
+            (value::Value::Array(array), path_segment::Segment::Index(i)) => {
+                let i = array_index(array.values.len(), *i)?;
+                array.values.get_mut(i)
+            }
+            _ => None,
+        }
+    }
+}
+
+fn not_container(path: &[PathSegment]) -> KvError {
+    KvError::InvalidCommand(format!(
+        "Path {} does not point into an array or map",
+        Path(path)
+    ))
+}
+
+// 把可能为负数的下标转换成长度为 len 的数组中的下标，超出范围时返回 None
+fn array_index(len: usize, index: i64) -> Option<usize> {
+    let i = if index < 0 { len as i64 + index } else { index };
+    if (0..len as i64).contains(&i) {
+        Some(i as usize)
+    } else {
+        None
+    }
+}
+
+// 用于在错误信息中显示 path，比如 .users[0].name
+struct Path<'a>(&'a [PathSegment]);
+
+impl fmt::Display for Path<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "(root)");
+        }
+        for segment in self.0 {
+            write!(f, "{}", segment)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for PathSegment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.segment {
+            Some(path_segment::Segment::Key(k)) => write!(f, ".{}", k),
+            Some(path_segment::Segment::Index(i)) => write!(f, "[{}]", i),
+            None => write!(f, ".?"),
+        }
+    }
+}
+
+/// ValueMap 中的 key
+impl From<&str> for PathSegment {
+    fn from(key: &str) -> Self {
+        Self {
+            segment: Some(path_segment::Segment::Key(key.into())),
+        }
+    }
+}
+
+/// ValueMap 中的 key
+impl From<String> for PathSegment {
+    fn from(key: String) -> Self {
+        Self {
+            segment: Some(path_segment::Segment::Key(key)),
+        }
+    }
+}
+
+/// ValueArray 中的下标
+impl From<i64> for PathSegment {
+    fn from(index: i64) -> Self {
+        Self {
+            segment: Some(path_segment::Segment::Index(index)),
+        }
+    }
+}
+
 /// 从 String 转换成 Value
 impl From<String> for Value {
     fn from(s: String) -> Self {
@@ -150,19 +1084,35 @@ impl From<Vec<Kvpair>> for CommandResponse {
     }
 }
 
+impl From<Vec<Version>> for CommandResponse {
+    fn from(v: Vec<Version>) -> Self {
+        Self {
+            status: StatusCode::OK.as_u16() as _,
+            versions: v,
+            ..Default::default()
+        }
+    }
+}
+
 /// 从 KvError 转换成 CommandResponse
 impl From<KvError> for CommandResponse {
     fn from(e: KvError) -> Self {
         let mut result = Self {
             status: StatusCode::INTERNAL_SERVER_ERROR.as_u16() as _,
             message: e.to_string(),
-            values: vec![],
-            pairs: vec![],
+            ..Default::default()
         };
 
         match e {
             KvError::NotFound(_, _) => result.status = StatusCode::NOT_FOUND.as_u16() as _,
             KvError::InvalidCommand(_) => result.status = StatusCode::BAD_REQUEST.as_u16() as _,
+            KvError::CasFailed(_, _) => {
+                result.status = StatusCode::PRECONDITION_FAILED.as_u16() as _
+            }
+            KvError::TransactionAborted(_, _) => result.status = StatusCode::CONFLICT.as_u16() as _,
+            KvError::OutOfMemory(_) => {
+                result.status = StatusCode::INSUFFICIENT_STORAGE.as_u16() as _
+            }
             _ => {}
         }
 
@@ -170,6 +1120,17 @@ impl From<KvError> for CommandResponse {
     }
 }
 
+/// 从事务中各个命令的结果转换成 CommandResponse
+impl From<Vec<CommandResponse>> for CommandResponse {
+    fn from(v: Vec<CommandResponse>) -> Self {
+        Self {
+            status: StatusCode::OK.as_u16() as _,
+            responses: v,
+            ..Default::default()
+        }
+    }
+}
+
 impl From<Vec<Value>> for CommandResponse {
     fn from(v: Vec<Value>) -> Self {
         Self {
@@ -207,6 +1168,44 @@ impl TryFrom<Value> for i64 {
     }
 }
 
+impl From<Vec<Value>> for Value {
+    fn from(values: Vec<Value>) -> Self {
+        Self {
+            value: Some(value::Value::Array(ValueArray { values })),
+        }
+    }
+}
+
+impl From<BTreeMap<String, Value>> for Value {
+    fn from(entries: BTreeMap<String, Value>) -> Self {
+        Self {
+            value: Some(value::Value::Map(ValueMap { entries })),
+        }
+    }
+}
+
+impl TryFrom<Value> for Vec<Value> {
+    type Error = KvError;
+
+    fn try_from(v: Value) -> Result<Self, Self::Error> {
+        match v.value {
+            Some(value::Value::Array(array)) => Ok(array.values),
+            _ => Err(KvError::ConvertError(v, "Array")),
+        }
+    }
+}
+
+impl TryFrom<Value> for BTreeMap<String, Value> {
+    type Error = KvError;
+
+    fn try_from(v: Value) -> Result<Self, Self::Error> {
+        match v.value {
+            Some(value::Value::Map(map)) => Ok(map.entries),
+            _ => Err(KvError::ConvertError(v, "Map")),
+        }
+    }
+}
+
 impl<const N: usize> From<&[u8; N]> for Value {
     fn from(buf: &[u8; N]) -> Self {
         Bytes::copy_from_slice(&buf[..]).into()
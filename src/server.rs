@@ -1,5 +1,9 @@
 use anyhow::Result;
-use kv::{MemTable, ProstServerStream, Service, ServiceInner};
+use kv::{
+    AofConfig, AofStorage, MemTable, MemTableConfig, ProstServerStream, Service, ServiceInner,
+    Storage, VersionedStorage,
+};
+use std::env;
 use tokio::net::TcpListener;
 use tracing::info;
 
@@ -7,7 +11,49 @@ use tracing::info;
 async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
     let addr = "127.0.0.1:9527";
-    let service: Service = ServiceInner::new(MemTable::new()).into();
+    // KVS_MAX_MEMORY 是内存上限（字节），KVS_EVICTION 是超过上限时的淘汰策略
+    let mut config = MemTableConfig::default();
+    if let Ok(max) = env::var("KVS_MAX_MEMORY") {
+        config.max_memory = max.parse()?;
+    }
+    if let Ok(policy) = env::var("KVS_EVICTION") {
+        config.eviction = policy.parse()?;
+    }
+    let store = MemTable::with_config(config);
+    // 设置了 KVS_AOF 时把修改操作记录到这个文件里，重启后可以恢复数据
+    match env::var("KVS_AOF") {
+        Ok(path) => {
+            info!("AOF enabled: {}", path);
+            let store = AofStorage::with_store(store, path, AofConfig::default())?;
+            serve(addr, store).await
+        }
+        Err(_) => serve(addr, store).await,
+    }
+}
+
+async fn serve<Store>(addr: &str, store: Store) -> Result<()>
+where
+    Store: Storage + Send + Sync + 'static,
+{
+    // 设置了 KVS_VERSIONED 时保留每个 key 的修改历史
+    if env::var("KVS_VERSIONED").is_ok() {
+        info!("Versioning enabled");
+        return listen(addr, VersionedStorage::new(store)).await;
+    }
+    listen(addr, store).await
+}
+
+async fn listen<Store>(addr: &str, store: Store) -> Result<()>
+where
+    Store: Storage + Send + Sync + 'static,
+{
+    let mut inner = ServiceInner::new(store);
+    // 设置了 KVS_DUMP_DIR 时才能使用 Dump 和 Restore，它们只能访问这个目录中的文件
+    if let Ok(dir) = env::var("KVS_DUMP_DIR") {
+        info!("Dump directory: {}", dir);
+        inner = inner.dump_dir(dir);
+    }
+    let service: Service<Store> = inner.into();
     let listener = TcpListener::bind(addr).await?;
     info!("Start listening on {}", addr);
     loop {
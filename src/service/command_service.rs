@@ -1,7 +1,22 @@
-use crate::*;
+use http::StatusCode;
+use std::{
+    collections::BTreeSet,
+    convert::TryFrom,
+    fs::File,
+    io::BufWriter,
+    path::{Component, Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{command_request::RequestData, *};
+
+/// Hscan 没有指定 limit 时每页的数量
+const DEFAULT_SCAN_LIMIT: usize = 100;
+/// Hscan 每页最多的数量，limit 超过它时按它计算
+const MAX_SCAN_LIMIT: usize = 1000;
 
 impl CommandService for Hget {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
         match store.get(&self.table, &self.key) {
             Ok(Some(v)) => v.into(),
             Ok(None) => KvError::NotFound(self.table, self.key).into(),
@@ -11,7 +26,7 @@ impl CommandService for Hget {
 }
 
 impl CommandService for Hmget {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
         self.keys
             .iter()
             .map(|key| match store.get(&self.table, key) {
@@ -24,7 +39,7 @@ impl CommandService for Hmget {
 }
 
 impl CommandService for Hgetall {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
         match store.get_all(&self.table) {
             Ok(v) => v.into(),
             Err(e) => e.into(),
@@ -32,8 +47,53 @@ impl CommandService for Hgetall {
     }
 }
 
+impl CommandService for Hscan {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        let (range, limit) = self.range();
+        // 多取一个，用来判断后面还有没有数据
+        scan_response(store.scan(&self.table, &range, limit + 1), limit)
+    }
+}
+
+impl Hscan {
+    /// 遍历的范围和每页的数量
+    fn range(&self) -> (KeyRange, usize) {
+        let limit = match self.limit {
+            0 => DEFAULT_SCAN_LIMIT,
+            n => (n as usize).min(MAX_SCAN_LIMIT),
+        };
+        let range = KeyRange {
+            prefix: self.prefix.clone(),
+            start: self.start.clone(),
+            end: self.end.clone(),
+            after: self.cursor.clone(),
+        };
+
+        (range, limit)
+    }
+}
+
+/// 把多取了一个的结果变成一页，values 中是下一页的 cursor，没有下一页时为空字符串
+fn scan_response(result: Result<Vec<Kvpair>, KvError>, limit: usize) -> CommandResponse {
+    match result {
+        Ok(mut pairs) => {
+            let cursor = if pairs.len() > limit {
+                pairs.truncate(limit);
+                pairs.last().map(|p| p.key.clone()).unwrap_or_default()
+            } else {
+                String::new()
+            };
+            CommandResponse {
+                values: vec![cursor.into()],
+                ..pairs.into()
+            }
+        }
+        Err(e) => e.into(),
+    }
+}
+
 impl CommandService for Hset {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
         match self.pair {
             Some(v) => match store.set(&self.table, v.key, v.value.unwrap_or_default()) {
                 Ok(Some(v)) => v.into(),
@@ -46,7 +106,7 @@ impl CommandService for Hset {
 }
 
 impl CommandService for Hmset {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
         let pairs = self.pairs;
         let table = self.table;
         pairs
@@ -59,58 +119,763 @@ impl CommandService for Hmset {
                 }
             })
             .collect::<Vec<_>>()
-            .into()
+            .into()
+    }
+}
+
+impl CommandService for Hdel {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        match store.del(&self.table, &self.key) {
+            Ok(Some(v)) => v.into(),
+            Ok(None) => Value::default().into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hmdel {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        self.keys
+            .iter()
+            .map(|key| match store.del(&self.table, key) {
+                Ok(Some(v)) => v,
+                _ => Value::default(),
+            })
+            .collect::<Vec<_>>()
+            .into()
+    }
+}
+
+impl CommandService for Hexist {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        match store.contains(&self.table, &self.key) {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hmexist {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        self.keys
+            .iter()
+            .map(|key| match store.contains(&self.table, key) {
+                Ok(v) => v.into(),
+                _ => Value::default(),
+            })
+            .collect::<Vec<Value>>()
+            .into()
+    }
+}
+
+impl CommandService for Hsetex {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        let ttl = Duration::from_millis(self.ttl);
+        match self.pair {
+            Some(v) => {
+                match store.set_with_ttl(&self.table, v.key, v.value.unwrap_or_default(), ttl) {
+                    Ok(Some(v)) => v.into(),
+                    Ok(None) => Value::default().into(),
+                    Err(e) => e.into(),
+                }
+            }
+            None => KvError::InvalidCommand(format!("{:?}", self)).into(),
+        }
+    }
+}
+
+impl CommandService for Hexpire {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        match store.expire(&self.table, &self.key, Duration::from_millis(self.ttl)) {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hexpireat {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        match store.expire(&self.table, &self.key, self.ttl()) {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl Hexpireat {
+    /// 距离过期时刻的时间，已经过去的时刻为 0
+    fn ttl(&self) -> Duration {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        Duration::from_millis(self.at.saturating_sub(now))
+    }
+}
+
+impl CommandService for Httl {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        ttl_response(store.ttl(&self.table, &self.key))
+    }
+}
+
+/// 剩余的毫秒数，和 redis 一样，没有设置过期时间的 key 返回 -1
+fn ttl_response(result: Result<Option<Duration>, KvError>) -> CommandResponse {
+    match result {
+        Ok(Some(ttl)) => Value::from(i64::try_from(ttl.as_millis()).unwrap_or(i64::MAX)).into(),
+        Ok(None) => Value::from(-1).into(),
+        Err(e) => e.into(),
+    }
+}
+
+impl CommandService for Hpersist {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        match store.persist(&self.table, &self.key) {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hcas {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        let value = self.value.unwrap_or_default();
+        let result = store.compare_and_swap(&self.table, &self.key, self.expected.as_ref(), value);
+        cas_response(result, self.table, self.key)
+    }
+}
+
+fn cas_response(
+    result: Result<Result<(), Option<Value>>, KvError>,
+    table: String,
+    key: String,
+) -> CommandResponse {
+    match result {
+        Ok(Ok(())) => Value::from(true).into(),
+        // 比较失败时带上当前的值，方便客户端重试
+        Ok(Err(current)) => CommandResponse {
+            values: vec![current.unwrap_or_default()],
+            ..KvError::CasFailed(table, key).into()
+        },
+        Err(e) => e.into(),
+    }
+}
+
+impl CommandService for Hincrby {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        match store.incr(&self.table, &self.key, self.delta) {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hincrbyfloat {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        match store.incr_float(&self.table, &self.key, self.delta) {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Htables {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        match store.list_tables() {
+            Ok(tables) => tables
+                .into_iter()
+                .map(Value::from)
+                .collect::<Vec<_>>()
+                .into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hdrop {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        match store.drop_table(&self.table) {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hlen {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        match store.table_len(&self.table) {
+            Ok(v) => Value::from(v as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for CreateIndex {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        match store.create_index(&self.table) {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hfind {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        match store.find(&self.table, &self.value.unwrap_or_default()) {
+            Ok(v) => v.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hgetversion {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        match store.get_version(&self.table, &self.key, self.version) {
+            Ok(Some(v)) => v.into(),
+            Ok(None) => KvError::NotFound(self.table, self.key).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hgetat {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        match store.get_at(&self.table, &self.key, self.at) {
+            Ok(Some(v)) => v.into(),
+            Ok(None) => KvError::NotFound(self.table, self.key).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hhistory {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        match store.history(&self.table, &self.key) {
+            Ok(v) => v.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hgcversions {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        match store.gc_versions(Duration::from_millis(self.retention)) {
+            Ok(n) => Value::from(n as i64).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Lpush {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        len_response(store.list_push(&self.table, &self.key, ListEnd::Left, self.values))
+    }
+}
+
+impl CommandService for Rpush {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        len_response(store.list_push(&self.table, &self.key, ListEnd::Right, self.values))
+    }
+}
+
+impl CommandService for Lpop {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        let count = pop_count(self.count);
+        values_response(store.list_pop(&self.table, &self.key, ListEnd::Left, count))
+    }
+}
+
+impl CommandService for Rpop {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        let count = pop_count(self.count);
+        values_response(store.list_pop(&self.table, &self.key, ListEnd::Right, count))
+    }
+}
+
+impl CommandService for Lrange {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        values_response(store.list_range(&self.table, &self.key, self.start, self.stop))
+    }
+}
+
+impl CommandService for Llen {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        len_response(store.list_len(&self.table, &self.key))
+    }
+}
+
+impl CommandService for Ltrim {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        len_response(store.list_trim(&self.table, &self.key, self.start, self.stop))
+    }
+}
+
+impl CommandService for Lindex {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        match store.list_index(&self.table, &self.key, self.index) {
+            Ok(Some(v)) => v.into(),
+            Ok(None) => KvError::NotFound(self.table, self.key).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Sadd {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        len_response(store.set_add(&self.table, &self.key, self.members))
+    }
+}
+
+impl CommandService for Srem {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        len_response(store.set_remove(&self.table, &self.key, self.members))
+    }
+}
+
+impl CommandService for Smembers {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        members_response(store.set_members(&self.table, &self.key))
+    }
+}
+
+impl CommandService for Sismember {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        match store.set_contains(&self.table, &self.key, &self.member) {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Scard {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        len_response(store.set_len(&self.table, &self.key))
+    }
+}
+
+impl CommandService for Sinter {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        members_response(combine_sets(store, &self.table, &self.keys, SetOp::Inter))
+    }
+}
+
+impl CommandService for Sunion {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        members_response(combine_sets(store, &self.table, &self.keys, SetOp::Union))
+    }
+}
+
+impl CommandService for Sdiff {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        members_response(combine_sets(store, &self.table, &self.keys, SetOp::Diff))
+    }
+}
+
+impl CommandService for Zadd {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        len_response(store.zset_add(&self.table, &self.key, scored_members(self.members)))
+    }
+}
+
+impl CommandService for Zincrby {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        match store.zset_incr(&self.table, &self.key, &self.member, self.delta) {
+            Ok(score) => Value::from(score).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Zrem {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        len_response(store.zset_remove(&self.table, &self.key, self.members))
+    }
+}
+
+impl CommandService for Zscore {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        match store.zset_score(&self.table, &self.key, &self.member) {
+            Ok(Some(score)) => Value::from(score).into(),
+            Ok(None) => KvError::NotFound(self.table, self.key).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Zrank {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        match store.zset_rank(&self.table, &self.key, &self.member) {
+            Ok(Some(rank)) => Value::from(rank as i64).into(),
+            Ok(None) => KvError::NotFound(self.table, self.key).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Zrange {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        scored_response(store.zset_range(&self.table, &self.key, self.start, self.stop))
+    }
+}
+
+impl CommandService for Zrangebyscore {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        let (offset, limit) = (self.offset as usize, self.limit as usize);
+        let result =
+            store.zset_range_by_score(&self.table, &self.key, self.min, self.max, offset, limit);
+        scored_response(result)
+    }
+}
+
+impl CommandService for Zcard {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        len_response(store.zset_len(&self.table, &self.key))
+    }
+}
+
+impl CommandService for Hgetpath {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        match store.get_path(&self.table, &self.key, &self.path) {
+            Ok(Some(v)) => v.into(),
+            Ok(None) => KvError::NotFound(self.table, self.key).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hsetpath {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        let op = PathOp::Set(self.value.unwrap_or_default());
+        match store.update_path(&self.table, &self.key, &self.path, op) {
+            Ok(Some(v)) => v.into(),
+            Ok(None) => Value::default().into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hdelpath {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        match store.update_path(&self.table, &self.key, &self.path, PathOp::Delete) {
+            Ok(Some(v)) => v.into(),
+            Ok(None) => KvError::NotFound(self.table, self.key).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Jget {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        let path = match parse_json_path(&self.path) {
+            Ok(path) => path,
+            Err(e) => return e.into(),
+        };
+        let result = store.get_path(&self.table, &self.key, &path);
+        json_response(result, self.table, self.key)
+    }
+}
+
+impl CommandService for Jset {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        let (path, value) = match json_args(&self.path, &self.json) {
+            Ok(v) => v,
+            Err(e) => return e.into(),
+        };
+        match store.update_path(&self.table, &self.key, &path, PathOp::Set(value)) {
+            Ok(None) => Value::default().into(),
+            result => json_response(result, self.table, self.key),
+        }
+    }
+}
+
+impl CommandService for Jdel {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        let result = match parse_json_path(&self.path) {
+            Ok(path) if path.is_empty() => store.del(&self.table, &self.key),
+            Ok(path) => store.update_path(&self.table, &self.key, &path, PathOp::Delete),
+            Err(e) => return e.into(),
+        };
+        json_response(result, self.table, self.key)
+    }
+}
+
+impl CommandService for Jarrappend {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        let (path, values) = match json_values(&self.path, &self.values) {
+            Ok(v) => v,
+            Err(e) => return e.into(),
+        };
+        match store.update_path(&self.table, &self.key, &path, PathOp::Append(values)) {
+            Ok(len) => len.unwrap_or_default().into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+enum SetOp {
+    Inter,
+    Union,
+    Diff,
+}
+
+// 从第一个 set 开始，依次和之后的每个 set 做运算。不存在的 set 当作空集
+fn combine_sets(
+    store: &(impl Storage + ?Sized),
+    table: &str,
+    keys: &[String],
+    op: SetOp,
+) -> Result<Vec<String>, KvError> {
+    let mut keys = keys.iter();
+    let mut result: BTreeSet<String> = match keys.next() {
+        Some(key) => store.set_members(table, key)?.into_iter().collect(),
+        None => return Ok(vec![]),
+    };
+    for key in keys {
+        let members: BTreeSet<String> = store.set_members(table, key)?.into_iter().collect();
+        match op {
+            SetOp::Inter => result.retain(|m| members.contains(m)),
+            SetOp::Union => result.extend(members),
+            SetOp::Diff => result.retain(|m| !members.contains(m)),
+        }
+    }
+
+    Ok(result.into_iter().collect())
+}
+
+/// Lpop 和 Rpop 取出的元素数量，没有指定时取出一个
+fn pop_count(count: u32) -> usize {
+    count.max(1) as usize
+}
+
+fn len_response(result: Result<usize, KvError>) -> CommandResponse {
+    match result {
+        Ok(n) => Value::from(n as i64).into(),
+        Err(e) => e.into(),
+    }
+}
+
+fn values_response(result: Result<Vec<Value>, KvError>) -> CommandResponse {
+    match result {
+        Ok(values) => values.into(),
+        Err(e) => e.into(),
+    }
+}
+
+fn members_response(result: Result<Vec<String>, KvError>) -> CommandResponse {
+    match result {
+        Ok(members) => members
+            .into_iter()
+            .map(Value::from)
+            .collect::<Vec<_>>()
+            .into(),
+        Err(e) => e.into(),
+    }
+}
+
+/// 解析 JSON 命令中的 JSONPath 和 JSON 文本
+fn json_args(path: &str, json: &str) -> Result<(Vec<PathSegment>, Value), KvError> {
+    Ok((parse_json_path(path)?, Value::from_json(json)?))
+}
+
+/// 解析 JSON 命令中的 JSONPath 和多个 JSON 文本
+fn json_values(path: &str, values: &[String]) -> Result<(Vec<PathSegment>, Vec<Value>), KvError> {
+    let values = values
+        .iter()
+        .map(|v| Value::from_json(v))
+        .collect::<Result<_, _>>()?;
+
+    Ok((parse_json_path(path)?, values))
+}
+
+/// 把元素转换成 JSON 文本返回，元素不存在时返回 404
+fn json_response(
+    result: Result<Option<Value>, KvError>,
+    table: String,
+    key: String,
+) -> CommandResponse {
+    match result.and_then(|v| v.map(|v| v.to_json()).transpose()) {
+        Ok(Some(json)) => Value::from(json).into(),
+        Ok(None) => KvError::NotFound(table, key).into(),
+        Err(e) => e.into(),
+    }
+}
+
+fn scored_members(members: Vec<ScoredMember>) -> Vec<(String, f64)> {
+    members.into_iter().map(|m| (m.member, m.score)).collect()
+}
+
+/// sorted set 的 member 和 score 放在 pairs 里返回
+fn scored_response(result: Result<Vec<(String, f64)>, KvError>) -> CommandResponse {
+    match result {
+        Ok(members) => members
+            .into_iter()
+            .map(|(member, score)| Kvpair::new(member, score.into()))
+            .collect::<Vec<_>>()
+            .into(),
+        Err(e) => e.into(),
     }
 }
 
-impl CommandService for Hdel {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
-        match store.del(&self.table, &self.key) {
-            Ok(Some(v)) => v.into(),
-            Ok(None) => Value::default().into(),
+// 数据库的管理命令需要在整个存储上执行，Service 不会把它们交给某个数据库的 Namespace
+
+impl CommandService for Listdb {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        match list_databases(store) {
+            Ok(dbs) => dbs
+                .into_iter()
+                .map(|db| Value::from(db as i64))
+                .collect::<Vec<_>>()
+                .into(),
             Err(e) => e.into(),
         }
     }
 }
 
-impl CommandService for Hmdel {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
-        self.keys
-            .iter()
-            .map(|key| match store.del(&self.table, key) {
-                Ok(Some(v)) => v,
-                _ => Value::default(),
-            })
-            .collect::<Vec<_>>()
-            .into()
+impl CommandService for Createdb {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        match create_database(store, self.db) {
+            Ok(v) => Value::from(v).into(),
+            Err(e) => e.into(),
+        }
     }
 }
 
-impl CommandService for Hexist {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
-        match store.contains(&self.table, &self.key) {
-            Ok(v) => Value::from(v).into(),
+impl CommandService for Flushdb {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        match flush_database(store, self.db) {
+            Ok(n) => Value::from(n as i64).into(),
             Err(e) => e.into(),
         }
     }
 }
 
-impl CommandService for Hmexist {
-    fn execute(self, store: &impl Storage) -> CommandResponse {
-        self.keys
-            .iter()
-            .map(|key| match store.contains(&self.table, key) {
-                Ok(v) => v.into(),
-                _ => Value::default(),
-            })
-            .collect::<Vec<Value>>()
-            .into()
+/// 把数据导出到 dir 中的 path。dump 访问的是服务器上的文件，path 只能是 dir 中的相对路径
+pub(super) fn dump_in(
+    dir: Option<&Path>,
+    param: Dump,
+    store: &(impl Storage + ?Sized),
+) -> CommandResponse {
+    let result = dump_file(dir, &param.path)
+        .and_then(|path| Ok(File::create(path)?))
+        .and_then(|file| dump(store, BufWriter::new(file)));
+    match result {
+        Ok(n) => Value::from(n as i64).into(),
+        Err(e) => e.into(),
     }
 }
 
+/// 从 dir 中的 path 恢复数据，path 的限制和 dump_in 一样
+pub(super) fn restore_in(
+    dir: Option<&Path>,
+    param: Restore,
+    store: &(impl Storage + ?Sized),
+) -> CommandResponse {
+    let result = dump_file(dir, &param.path)
+        .and_then(|path| Ok(File::open(path)?))
+        .and_then(|file| restore(file, store));
+    match result {
+        Ok(n) => Value::from(n as i64).into(),
+        Err(e) => e.into(),
+    }
+}
+
+// 不允许绝对路径和 ..，避免客户端读写 dump 目录之外的文件
+fn dump_file(dir: Option<&Path>, path: &str) -> Result<PathBuf, KvError> {
+    let dir =
+        dir.ok_or_else(|| KvError::InvalidCommand("Dump directory is not configured".into()))?;
+    let path = Path::new(path);
+    let relative = path.components().all(|c| matches!(c, Component::Normal(_)));
+    if path.as_os_str().is_empty() || !relative {
+        return Err(KvError::InvalidCommand(format!(
+            "Invalid dump path: {}",
+            path.display()
+        )));
+    }
+
+    Ok(dir.join(path))
+}
+
+impl CommandService for Transaction {
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse {
+        let commands = self.commands;
+        // list、set 和 sorted set 的修改在事务中无法回滚，在执行前就拒绝整个事务
+        if let Some(i) = commands.iter().position(is_collection_command) {
+            return KvError::InvalidCommand(format!(
+                "Command {} in transaction operates on a list, set or sorted set, which is not supported in a transaction",
+                i
+            ))
+            .into();
+        }
+        let tables: Vec<_> = commands.iter().filter_map(|cmd| cmd.table()).collect();
+        let mut responses = Vec::with_capacity(commands.len());
+        let result = store.transaction(&tables, &mut |txn| {
+            // 事务可能被重试，每次都要从头执行
+            responses.clear();
+            for (i, cmd) in commands.iter().enumerate() {
+                let res = match cmd.request_data {
+                    Some(RequestData::Transaction(_)) => {
+                        KvError::InvalidCommand("Nested transaction".into()).into()
+                    }
+                    _ => dispatch(cmd.clone(), txn),
+                };
+                // 没有找到（比如 Hget 一个不存在的 key）是正常的结果，不中止事务
+                let failed = res.status != StatusCode::OK.as_u16() as u32
+                    && res.status != StatusCode::NOT_FOUND.as_u16() as u32;
+                let message = res.message.clone();
+                responses.push(res);
+                if failed {
+                    return Err(KvError::TransactionAborted(i, message));
+                }
+            }
+            Ok(())
+        });
+
+        match result {
+            Ok(()) => responses.into(),
+            Err(e) => CommandResponse {
+                responses,
+                ..e.into()
+            },
+        }
+    }
+}
+
+fn is_collection_command(cmd: &CommandRequest) -> bool {
+    matches!(
+        cmd.request_data,
+        Some(
+            RequestData::Lpush(_)
+                | RequestData::Rpush(_)
+                | RequestData::Lpop(_)
+                | RequestData::Rpop(_)
+                | RequestData::Lrange(_)
+                | RequestData::Llen(_)
+                | RequestData::Ltrim(_)
+                | RequestData::Lindex(_)
+                | RequestData::Sadd(_)
+                | RequestData::Srem(_)
+                | RequestData::Smembers(_)
+                | RequestData::Sismember(_)
+                | RequestData::Scard(_)
+                | RequestData::Sinter(_)
+                | RequestData::Sunion(_)
+                | RequestData::Sdiff(_)
+                | RequestData::Zadd(_)
+                | RequestData::Zincrby(_)
+                | RequestData::Zrem(_)
+                | RequestData::Zscore(_)
+                | RequestData::Zrank(_)
+                | RequestData::Zrange(_)
+                | RequestData::Zrangebyscore(_)
+                | RequestData::Zcard(_)
+        )
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use crate::memory::MemTable;
+    use std::{collections::BTreeMap, convert::TryInto, thread};
 
     use super::*;
 
@@ -168,6 +933,32 @@ mod tests {
         assert_res_ok(res, &[], pairs);
     }
 
+    #[test]
+    fn hscan_should_work() {
+        let store = MemTable::new();
+        set_key_pairs(
+            "score",
+            vec![("u1", 10), ("u2", 8), ("u3", 11), ("v1", 6)],
+            &store,
+        );
+
+        let cmd = CommandRequest::new_hscan("score", "", "u", 2);
+        let res = dispatch(cmd, &store);
+        let pairs = &[Kvpair::new("u1", 10.into()), Kvpair::new("u2", 8.into())];
+        assert_res_ok(res, &["u2".into()], pairs);
+
+        let cmd = CommandRequest::new_hscan("score", "u2", "u", 2);
+        let res = dispatch(cmd, &store);
+        assert_res_ok(res, &["".into()], &[Kvpair::new("u3", 11.into())]);
+
+        // 客户端给出的 limit 太大时按 MAX_SCAN_LIMIT 计算
+        let scan = Hscan {
+            limit: u32::MAX,
+            ..Default::default()
+        };
+        assert_eq!(scan.range().1, MAX_SCAN_LIMIT);
+    }
+
     #[test]
     fn hset_should_work() {
         let store = MemTable::new();
@@ -238,6 +1029,519 @@ mod tests {
         assert_res_ok(res, &[true.into(), false.into()], &[]);
     }
 
+    #[test]
+    fn hsetex_should_work() {
+        let store = MemTable::new();
+        let cmd = CommandRequest::new_hsetex("t1", "k1", "v1".into(), 1000);
+        let res = dispatch(cmd, &store);
+        assert_res_ok(res, &[Value::default()], &[]);
+
+        let res = dispatch(CommandRequest::new_httl("t1", "k1"), &store);
+        let ttl: i64 = res.values[0].clone().try_into().unwrap();
+        assert!(ttl > 0 && ttl <= 1000);
+
+        let cmd = CommandRequest::new_hsetex("t1", "k1", "v2".into(), 1);
+        dispatch(cmd, &store);
+        thread::sleep(Duration::from_millis(10));
+        let res = dispatch(CommandRequest::new_hget("t1", "k1"), &store);
+        assert_res_error(res, 404, "Not found");
+    }
+
+    #[test]
+    fn hexpire_and_hpersist_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("k1", "v1")], &store);
+
+        let res = dispatch(CommandRequest::new_httl("t1", "k1"), &store);
+        assert_res_ok(res, &[(-1).into()], &[]);
+
+        let res = dispatch(CommandRequest::new_hexpire("t1", "k2", 1000), &store);
+        assert_res_ok(res, &[false.into()], &[]);
+
+        let res = dispatch(CommandRequest::new_hexpire("t1", "k1", 1000), &store);
+        assert_res_ok(res, &[true.into()], &[]);
+
+        let res = dispatch(CommandRequest::new_hpersist("t1", "k1"), &store);
+        assert_res_ok(res, &[true.into()], &[]);
+
+        let res = dispatch(CommandRequest::new_hpersist("t1", "k1"), &store);
+        assert_res_ok(res, &[false.into()], &[]);
+
+        let res = dispatch(CommandRequest::new_httl("t1", "k2"), &store);
+        assert_res_error(res, 404, "Not found");
+    }
+
+    #[test]
+    fn hexpireat_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("k1", "v1"), ("k2", "v2")], &store);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+
+        let res = dispatch(CommandRequest::new_hexpireat("t1", "k3", now), &store);
+        assert_res_ok(res, &[false.into()], &[]);
+
+        let res = dispatch(
+            CommandRequest::new_hexpireat("t1", "k1", now + 60_000),
+            &store,
+        );
+        assert_res_ok(res, &[true.into()], &[]);
+        let ttl = store.ttl("t1", "k1").unwrap().unwrap();
+        assert!(ttl > Duration::from_secs(50));
+
+        // 已经过去的时刻让 key 立即过期
+        let res = dispatch(
+            CommandRequest::new_hexpireat("t1", "k2", now - 1000),
+            &store,
+        );
+        assert_res_ok(res, &[true.into()], &[]);
+        assert_eq!(store.get("t1", "k2").unwrap(), None);
+    }
+
+    #[test]
+    fn hcas_should_work() {
+        let store = MemTable::new();
+        let cmd = CommandRequest::new_hcas("t1", "k1", None, 1.into());
+        let res = dispatch(cmd.clone(), &store);
+        assert_res_ok(res, &[true.into()], &[]);
+
+        let res = dispatch(cmd, &store);
+        assert_eq!(res.status, 412);
+        assert!(res.message.contains("Compare and swap failed"));
+        assert_eq!(res.values, &[1.into()]);
+
+        let cmd = CommandRequest::new_hcas("t1", "k1", Some(1.into()), 2.into());
+        let res = dispatch(cmd, &store);
+        assert_res_ok(res, &[true.into()], &[]);
+
+        let res = dispatch(CommandRequest::new_hget("t1", "k1"), &store);
+        assert_res_ok(res, &[2.into()], &[]);
+    }
+
+    #[test]
+    fn hincrby_should_work() {
+        let store = MemTable::new();
+        let res = dispatch(CommandRequest::new_hincrby("t1", "k1", 5), &store);
+        assert_res_ok(res, &[5.into()], &[]);
+        let res = dispatch(CommandRequest::new_hincrby("t1", "k1", -2), &store);
+        assert_res_ok(res, &[3.into()], &[]);
+
+        let res = dispatch(CommandRequest::new_hincrbyfloat("t1", "k2", 0.5), &store);
+        assert_res_ok(res, &[0.5.into()], &[]);
+
+        let res = dispatch(CommandRequest::new_hincrbyfloat("t1", "k1", 0.5), &store);
+        assert_res_error(res, 500, "Cannot convert value");
+    }
+
+    #[test]
+    fn table_commands_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("u1", "v1"), ("u2", "v2")], &store);
+        set_key_pairs("t2", vec![("u1", "v1")], &store);
+
+        let res = dispatch(CommandRequest::new_htables(), &store);
+        assert_res_ok(res, &["t1".into(), "t2".into()], &[]);
+
+        let res = dispatch(CommandRequest::new_hlen("t1"), &store);
+        assert_res_ok(res, &[2.into()], &[]);
+
+        let res = dispatch(CommandRequest::new_hdrop("t1"), &store);
+        assert_res_ok(res, &[true.into()], &[]);
+
+        let res = dispatch(CommandRequest::new_htables(), &store);
+        assert_res_ok(res, &["t2".into()], &[]);
+    }
+
+    #[test]
+    fn create_index_and_hfind_should_work() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = SledDb::new(dir);
+        set_key_pairs("t1", vec![("u1", "v1"), ("u2", "v2"), ("u3", "v1")], &store);
+
+        let res = dispatch(CommandRequest::new_create_index("t1"), &store);
+        assert_res_ok(res, &[true.into()], &[]);
+        let res = dispatch(CommandRequest::new_hfind("t1", "v1".into()), &store);
+        let pairs = &[
+            Kvpair::new("u1", "v1".into()),
+            Kvpair::new("u3", "v1".into()),
+        ];
+        assert_res_ok(res, &[], pairs);
+
+        // 没有索引时遍历整个 table
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("u1", "v1"), ("u2", "v2")], &store);
+        let res = dispatch(CommandRequest::new_hfind("t1", "v2".into()), &store);
+        assert_res_ok(res, &[], &[Kvpair::new("u2", "v2".into())]);
+        let res = dispatch(CommandRequest::new_create_index("t1"), &store);
+        assert_res_ok(res, &[true.into()], &[]);
+        let res = dispatch(CommandRequest::new_hfind("t1", "v2".into()), &store);
+        assert_res_ok(res, &[], &[Kvpair::new("u2", "v2".into())]);
+    }
+
+    #[test]
+    fn version_commands_should_work() {
+        let store = VersionedStorage::new(MemTable::new());
+        // 每个版本的时间戳都不一样
+        set_key_pairs("t1", vec![("u1", "v1")], &store);
+        thread::sleep(Duration::from_millis(2));
+        set_key_pairs("t1", vec![("u1", "v2")], &store);
+        thread::sleep(Duration::from_millis(2));
+        dispatch(CommandRequest::new_hdel("t1", "u1"), &store);
+
+        let res = dispatch(CommandRequest::new_hhistory("t1", "u1"), &store);
+        assert_eq!(res.status, 200);
+        let values: Vec<_> = res.versions.iter().map(|v| v.value.clone()).collect();
+        assert_eq!(values, [Some("v1".into()), Some("v2".into()), None]);
+
+        let res = dispatch(CommandRequest::new_hgetversion("t1", "u1", 1), &store);
+        assert_res_ok(res, &["v1".into()], &[]);
+        let res = dispatch(CommandRequest::new_hgetversion("t1", "u1", 3), &store);
+        assert_res_error(res, 404, "Not found");
+        let at = store.history("t1", "u1").unwrap()[1].timestamp;
+        let res = dispatch(CommandRequest::new_hgetat("t1", "u1", at), &store);
+        assert_res_ok(res, &["v2".into()], &[]);
+        let res = dispatch(CommandRequest::new_hgetat("t1", "u1", at - 1000), &store);
+        assert_res_error(res, 404, "Not found");
+
+        let res = dispatch(CommandRequest::new_hgcversions(0), &store);
+        assert_res_ok(res, &[3.into()], &[]);
+        let res = dispatch(CommandRequest::new_hhistory("t1", "u1"), &store);
+        assert!(res.versions.is_empty());
+
+        // 不支持多版本的存储
+        let res = dispatch(CommandRequest::new_hhistory("t1", "u1"), &MemTable::new());
+        assert_res_error(res, 400, "not supported");
+    }
+
+    #[test]
+    fn list_commands_should_work() {
+        let store = MemTable::new();
+        let cmd = CommandRequest::new_rpush("t1", "l1", vec!["b".into(), "c".into()]);
+        assert_res_ok(dispatch(cmd, &store), &[2.into()], &[]);
+        let cmd = CommandRequest::new_lpush("t1", "l1", vec!["a".into()]);
+        assert_res_ok(dispatch(cmd, &store), &[3.into()], &[]);
+        let res = dispatch(CommandRequest::new_lrange("t1", "l1", 0, -1), &store);
+        assert_res_ok(res, &["a".into(), "b".into(), "c".into()], &[]);
+        let res = dispatch(CommandRequest::new_llen("t1", "l1"), &store);
+        assert_res_ok(res, &[3.into()], &[]);
+        let res = dispatch(CommandRequest::new_lindex("t1", "l1", -1), &store);
+        assert_res_ok(res, &["c".into()], &[]);
+        let res = dispatch(CommandRequest::new_lindex("t1", "l1", 3), &store);
+        assert_res_error(res, 404, "Not found");
+
+        // count 为 0 时取出一个
+        let res = dispatch(CommandRequest::new_lpop("t1", "l1", 0), &store);
+        assert_res_ok(res, &["a".into()], &[]);
+        let res = dispatch(CommandRequest::new_rpop("t1", "l1", 5), &store);
+        assert_res_ok(res, &["c".into(), "b".into()], &[]);
+        let res = dispatch(CommandRequest::new_rpop("t1", "l1", 1), &store);
+        assert_res_ok(res, &[], &[]);
+
+        let values = (0..5).map(|i: i64| i.into()).collect();
+        dispatch(CommandRequest::new_rpush("t1", "l2", values), &store);
+        let res = dispatch(CommandRequest::new_ltrim("t1", "l2", 1, -2), &store);
+        assert_res_ok(res, &[2.into()], &[]);
+        let res = dispatch(CommandRequest::new_lrange("t1", "l2", 0, -1), &store);
+        assert_res_ok(res, &[1.into(), 2.into(), 3.into()], &[]);
+
+        // 不支持 list 的存储
+        let dir = tempfile::tempdir().unwrap();
+        let cmd = CommandRequest::new_llen("t1", "l1");
+        let res = dispatch(cmd, &LsmStorage::new(dir.path()));
+        assert_res_error(res, 400, "not supported");
+    }
+
+    #[test]
+    fn set_commands_should_work() {
+        let store = MemTable::new();
+        let strings = |items: &[&str]| -> Vec<String> { items.iter().map(|&m| m.into()).collect() };
+        let values = |items: &[&str]| -> Vec<Value> { items.iter().map(|&m| m.into()).collect() };
+
+        let res = dispatch(
+            CommandRequest::new_sadd("t1", "s1", strings(&["a", "b", "c"])),
+            &store,
+        );
+        assert_res_ok(res, &[3.into()], &[]);
+        let res = dispatch(
+            CommandRequest::new_sadd("t1", "s2", strings(&["b", "c", "d"])),
+            &store,
+        );
+        assert_res_ok(res, &[3.into()], &[]);
+        let res = dispatch(
+            CommandRequest::new_srem("t1", "s2", strings(&["d", "e"])),
+            &store,
+        );
+        assert_res_ok(res, &[1.into()], &[]);
+        let res = dispatch(CommandRequest::new_smembers("t1", "s2"), &store);
+        assert_res_ok(res, &values(&["b", "c"]), &[]);
+        let res = dispatch(CommandRequest::new_sismember("t1", "s1", "a"), &store);
+        assert_res_ok(res, &[true.into()], &[]);
+        let res = dispatch(CommandRequest::new_scard("t1", "s1"), &store);
+        assert_res_ok(res, &[3.into()], &[]);
+
+        store.set_add("t1", "s3", strings(&["c", "e"])).unwrap();
+        let res = dispatch(
+            CommandRequest::new_sinter("t1", strings(&["s1", "s2"])),
+            &store,
+        );
+        assert_res_ok(res, &values(&["b", "c"]), &[]);
+        let res = dispatch(
+            CommandRequest::new_sunion("t1", strings(&["s2", "s3"])),
+            &store,
+        );
+        assert_res_ok(res, &values(&["b", "c", "e"]), &[]);
+        let res = dispatch(
+            CommandRequest::new_sdiff("t1", strings(&["s1", "s3"])),
+            &store,
+        );
+        assert_res_ok(res, &values(&["a", "b"]), &[]);
+        // 不存在的 set 当作空集
+        let res = dispatch(
+            CommandRequest::new_sinter("t1", strings(&["s1", "none"])),
+            &store,
+        );
+        assert_res_ok(res, &[], &[]);
+        let res = dispatch(
+            CommandRequest::new_sdiff("t1", strings(&["s1", "none"])),
+            &store,
+        );
+        assert_res_ok(res, &values(&["a", "b", "c"]), &[]);
+    }
+
+    #[test]
+    fn zset_commands_should_work() {
+        let store = MemTable::new();
+        let members = vec![
+            ScoredMember::new("alice", 30.0),
+            ScoredMember::new("bob", 10.0),
+            ScoredMember::new("carol", 20.0),
+        ];
+        let res = dispatch(CommandRequest::new_zadd("t1", "board", members), &store);
+        assert_res_ok(res, &[3.into()], &[]);
+        let res = dispatch(
+            CommandRequest::new_zincrby("t1", "board", "bob", 25.5),
+            &store,
+        );
+        assert_res_ok(res, &[35.5.into()], &[]);
+        let res = dispatch(CommandRequest::new_zscore("t1", "board", "carol"), &store);
+        assert_res_ok(res, &[20.0.into()], &[]);
+        let res = dispatch(CommandRequest::new_zrank("t1", "board", "bob"), &store);
+        assert_res_ok(res, &[2.into()], &[]);
+        let res = dispatch(CommandRequest::new_zcard("t1", "board"), &store);
+        assert_res_ok(res, &[3.into()], &[]);
+
+        let res = dispatch(CommandRequest::new_zrange("t1", "board", -2, -1), &store);
+        let pairs = [
+            Kvpair::new("alice", 30.0.into()),
+            Kvpair::new("bob", 35.5.into()),
+        ];
+        assert_res_ok(res, &[], &pairs);
+        let cmd = CommandRequest::new_zrangebyscore("t1", "board", 15.0, 100.0, 1, 1);
+        let res = dispatch(cmd, &store);
+        assert_res_ok(res, &[], &[Kvpair::new("alice", 30.0.into())]);
+
+        let res = dispatch(
+            CommandRequest::new_zrem("t1", "board", vec!["bob".into()]),
+            &store,
+        );
+        assert_res_ok(res, &[1.into()], &[]);
+        let res = dispatch(CommandRequest::new_zscore("t1", "board", "bob"), &store);
+        assert_res_error(res, 404, "Not found");
+        let res = dispatch(CommandRequest::new_zrank("t1", "board", "bob"), &store);
+        assert_res_error(res, 404, "Not found");
+
+        let members = vec![ScoredMember::new("dave", f64::NAN)];
+        let res = dispatch(CommandRequest::new_zadd("t1", "board", members), &store);
+        assert_res_error(res, 400, "not a finite number");
+    }
+
+    #[test]
+    fn path_commands_should_work() {
+        let store = MemTable::new();
+        let user: Value = BTreeMap::from([
+            ("name".to_string(), "tyr".into()),
+            ("langs".to_string(), vec!["rust".into()].into()),
+        ])
+        .into();
+        let cmd = CommandRequest::new_hsetpath("t1", "u1", vec![], user.clone());
+        assert_res_ok(dispatch(cmd, &store), &[Value::default()], &[]);
+        let res = dispatch(CommandRequest::new_hgetpath("t1", "u1", vec![]), &store);
+        assert_res_ok(res, &[user], &[]);
+
+        let path = vec!["langs".into(), 1.into()];
+        let cmd = CommandRequest::new_hsetpath("t1", "u1", path.clone(), "go".into());
+        assert_res_ok(dispatch(cmd, &store), &[Value::default()], &[]);
+        let cmd = CommandRequest::new_hgetpath("t1", "u1", path.clone());
+        assert_res_ok(dispatch(cmd, &store), &["go".into()], &[]);
+        let cmd = CommandRequest::new_hsetpath("t1", "u1", vec!["name".into()], "kv".into());
+        assert_res_ok(dispatch(cmd, &store), &["tyr".into()], &[]);
+
+        let cmd = CommandRequest::new_hdelpath("t1", "u1", path.clone());
+        assert_res_ok(dispatch(cmd, &store), &["go".into()], &[]);
+        let cmd = CommandRequest::new_hdelpath("t1", "u1", path.clone());
+        assert_res_error(dispatch(cmd, &store), 404, "Not found");
+        let cmd = CommandRequest::new_hgetpath("t1", "u1", path);
+        assert_res_error(dispatch(cmd, &store), 404, "Not found");
+
+        let cmd = CommandRequest::new_hsetpath("t1", "u1", vec!["x".into(), "y".into()], 1.into());
+        assert_res_error(dispatch(cmd, &store), 400, "does not exist");
+        let cmd = CommandRequest::new_hsetpath("t1", "u2", vec!["name".into()], "kv".into());
+        assert_res_error(dispatch(cmd, &store), 404, "Not found");
+    }
+
+    #[test]
+    fn memtable_json_commands_should_work() {
+        test_json_commands(MemTable::new());
+    }
+
+    #[test]
+    fn sleddb_json_commands_should_work() {
+        let dir = tempfile::tempdir().unwrap();
+        test_json_commands(SledDb::new(dir));
+    }
+
+    fn test_json_commands(store: impl Storage + Sync) {
+        let doc = r#"{"name":"kv","tags":["a"],"limits":{"conns":10}}"#;
+        let cmd = CommandRequest::new_jset("t1", "cfg", "$.name", "\"x\"");
+        assert_res_error(dispatch(cmd, &store), 404, "Not found");
+        let cmd = CommandRequest::new_jset("t1", "cfg", "$", doc);
+        assert_res_ok(dispatch(cmd, &store), &[Value::default()], &[]);
+        let res = dispatch(CommandRequest::new_jget("t1", "cfg", "$"), &store);
+        let expected = r#"{"limits":{"conns":10},"name":"kv","tags":["a"]}"#;
+        assert_res_ok(res, &[expected.into()], &[]);
+
+        let cmd = CommandRequest::new_jset("t1", "cfg", "$.limits.conns", "100");
+        assert_res_ok(dispatch(cmd, &store), &["10".into()], &[]);
+        let cmd = CommandRequest::new_jset("t1", "cfg", "$['limits'].rate", "1.5");
+        assert_res_ok(dispatch(cmd, &store), &[Value::default()], &[]);
+        let res = dispatch(CommandRequest::new_jget("t1", "cfg", "$.limits"), &store);
+        assert_res_ok(res, &[r#"{"conns":100,"rate":1.5}"#.into()], &[]);
+
+        // 并发追加的元素一个都不会丢
+        thread::scope(|s| {
+            for i in 0..4 {
+                let store = &store;
+                s.spawn(move || {
+                    let values = vec![i.to_string(), "null".into()];
+                    let cmd = CommandRequest::new_jarrappend("t1", "cfg", "$.tags", values);
+                    assert_eq!(dispatch(cmd, store).status, 200);
+                });
+            }
+        });
+        let res = dispatch(CommandRequest::new_jget("t1", "cfg", "$.tags[8]"), &store);
+        assert_res_ok(res, &["null".into()], &[]);
+        let cmd = CommandRequest::new_jarrappend("t1", "cfg", "$.tags", vec!["true".into()]);
+        assert_res_ok(dispatch(cmd, &store), &[10.into()], &[]);
+        let cmd = CommandRequest::new_jarrappend("t1", "cfg", "$.name", vec!["1".into()]);
+        assert_res_error(dispatch(cmd, &store), 400, "does not point to an array");
+
+        let res = dispatch(CommandRequest::new_jdel("t1", "cfg", "$.tags[0]"), &store);
+        assert_res_ok(res, &["\"a\"".into()], &[]);
+        let res = dispatch(CommandRequest::new_jdel("t1", "cfg", "$.missing"), &store);
+        assert_res_error(res, 404, "Not found");
+        let res = dispatch(CommandRequest::new_jget("t1", "cfg", "$.tags[99]"), &store);
+        assert_res_error(res, 404, "Not found");
+
+        let res = dispatch(CommandRequest::new_jget("t1", "cfg", "$..name"), &store);
+        assert_res_error(res, 400, "Unsupported JSONPath");
+        let cmd = CommandRequest::new_jset("t1", "cfg", "$.name", "{");
+        assert_res_error(dispatch(cmd, &store), 400, "Invalid JSON");
+
+        // $ 删除整个 key
+        let res = dispatch(CommandRequest::new_jdel("t1", "cfg", "$"), &store);
+        assert_eq!(res.status, 200);
+        let res = dispatch(CommandRequest::new_jget("t1", "cfg", "$"), &store);
+        assert_res_error(res, 404, "Not found");
+    }
+
+    #[test]
+    fn transaction_should_work() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("u1", "v1")], &store);
+        let cmd = CommandRequest::new_transaction(vec![
+            CommandRequest::new_hmset(
+                "t1",
+                vec![Kvpair::new("u1", 1.into()), Kvpair::new("u2", 2.into())],
+            ),
+            CommandRequest::new_hdel("t1", "u1"),
+            CommandRequest::new_hget("t1", "u2"),
+        ]);
+        let res = dispatch(cmd, &store);
+        assert_res_ok(res.clone(), &[], &[]);
+        assert_eq!(res.responses.len(), 3);
+        assert_res_ok(
+            res.responses[0].clone(),
+            &["v1".into(), Value::default()],
+            &[],
+        );
+        assert_res_ok(res.responses[1].clone(), &[1.into()], &[]);
+        assert_res_ok(res.responses[2].clone(), &[2.into()], &[]);
+    }
+
+    #[test]
+    fn failed_transaction_should_rollback() {
+        let store = MemTable::new();
+        set_key_pairs("t1", vec![("u1", "v1")], &store);
+        let cmd = CommandRequest::new_transaction(vec![
+            CommandRequest::new_hset("t1", "u1", "v2".into()),
+            CommandRequest::new_hset("t1", "u2", "v2".into()),
+            CommandRequest::new_hcas("t1", "u3", Some("v3".into()), "v4".into()),
+            CommandRequest::new_hset("t1", "u4", "v4".into()),
+        ]);
+        let res = dispatch(cmd, &store);
+        assert_eq!(res.status, 409);
+        assert!(res.message.contains("Transaction aborted at command 2"));
+        assert_eq!(res.responses.len(), 3);
+
+        let cmd = CommandRequest::new_hgetall("t1");
+        let res = dispatch(cmd, &store);
+        assert_res_ok(res, &[], &[Kvpair::new("u1", "v1".into())]);
+    }
+
+    #[test]
+    fn transaction_should_not_abort_on_not_found() {
+        let store = MemTable::new();
+        let cmd = CommandRequest::new_transaction(vec![
+            CommandRequest::new_hget("t1", "u1"),
+            CommandRequest::new_hset("t1", "u1", "v1".into()),
+        ]);
+        let res = dispatch(cmd, &store);
+        assert_eq!(res.status, 200);
+        assert_res_error(res.responses[0].clone(), 404, "Not found");
+        let res = dispatch(CommandRequest::new_hget("t1", "u1"), &store);
+        assert_res_ok(res, &["v1".into()], &[]);
+    }
+
+    #[test]
+    fn transaction_with_collections_should_be_rejected() {
+        let store = MemTable::new();
+        let cmd = CommandRequest::new_transaction(vec![
+            CommandRequest::new_hset("t1", "u1", "v1".into()),
+            CommandRequest::new_rpush("t1", "l1", vec!["a".into()]),
+        ]);
+        let res = dispatch(cmd, &store);
+        assert_res_error(res, 400, "Command 1 in transaction operates on a list");
+        let res = dispatch(CommandRequest::new_hget("t1", "u1"), &store);
+        assert_res_error(res, 404, "Not found");
+    }
+
+    #[test]
+    fn nested_transaction_should_fail() {
+        let store = MemTable::new();
+        let inner = CommandRequest::new_transaction(vec![]);
+        let cmd = CommandRequest::new_transaction(vec![
+            CommandRequest::new_hset("t1", "u1", "v1".into()),
+            inner,
+        ]);
+        let res = dispatch(cmd, &store);
+        assert_eq!(res.status, 409);
+
+        let res = dispatch(CommandRequest::new_hget("t1", "u1"), &store);
+        assert_res_error(res, 404, "Not found");
+    }
+
     fn set_key_pairs<T: Into<Value>>(table: &str, pairs: Vec<(&str, T)>, store: &impl Storage) {
         pairs
             .into_iter()
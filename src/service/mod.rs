@@ -1,17 +1,27 @@
-use std::sync::Arc;
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 use tracing::debug;
 
 use crate::{
-    command_request::RequestData, memory::MemTable, storage::Storage, CommandRequest,
-    CommandResponse, KvError,
+    command_request::RequestData, database_exists, memory::MemTable, storage::Storage,
+    AsyncStorage, CommandRequest, CommandResponse, KvError, Namespace, Publish, PushMessage, Value,
+    DEFAULT_DB,
 };
 
 mod command_service;
+mod pubsub;
+mod watch;
+
+use command_service::{dump_in, restore_in};
+pub use pubsub::{glob_match, Broker, Subscriber};
+use watch::WatchedStorage;
 
 /// 对 Command 的处理的抽象
 pub trait CommandService {
     /// 处理 Command，返回 Response
-    fn execute(self, store: &impl Storage) -> CommandResponse;
+    fn execute(self, store: &(impl Storage + ?Sized)) -> CommandResponse;
 }
 
 pub struct Service<Store = MemTable> {
@@ -52,7 +62,12 @@ impl<Arg> NotifyMut<Arg> for Vec<fn(&mut Arg)> {
 }
 
 pub struct ServiceInner<Store> {
-    store: Store,
+    // 放在 Arc 里，异步执行时可以交给 spawn_blocking 的线程。
+    // 修改操作产生的事件通过 broker 发给 Watch 的连接
+    store: Arc<WatchedStorage<Store>>,
+    broker: Arc<Broker>,
+    // Dump 和 Restore 只能读写这个目录中的文件，没有设置时不能使用
+    dump_dir: Option<PathBuf>,
     on_received: Vec<fn(&CommandRequest)>,
     on_executed: Vec<fn(&CommandResponse)>,
     on_before_send: Vec<fn(&mut CommandResponse)>,
@@ -61,8 +76,11 @@ pub struct ServiceInner<Store> {
 
 impl<Store: Storage> ServiceInner<Store> {
     pub fn new(store: Store) -> Self {
+        let broker = Arc::new(Broker::new());
         Self {
-            store: store,
+            store: Arc::new(WatchedStorage::new(store, broker.clone())),
+            broker,
+            dump_dir: None,
             on_received: Vec::new(),
             on_executed: Vec::new(),
             on_before_send: Vec::new(),
@@ -70,6 +88,12 @@ impl<Store: Storage> ServiceInner<Store> {
         }
     }
 
+    /// 设置 Dump 和 Restore 使用的目录
+    pub fn dump_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.dump_dir = Some(dir.into());
+        self
+    }
+
     pub fn fn_received(mut self, f: fn(&CommandRequest)) -> Self {
         self.on_received.push(f);
         self
@@ -89,13 +113,67 @@ impl<Store: Storage> ServiceInner<Store> {
     }
 }
 
+/// 一个连接的状态：选择的数据库和订阅
+pub struct Session {
+    db: u32,
+    subscriber: Subscriber,
+}
+
+impl Session {
+    /// 连接当前选择的数据库
+    pub fn db(&self) -> u32 {
+        self.db
+    }
+
+    /// 等待下一条推送给这个连接的消息
+    pub async fn recv(&mut self) -> Option<PushMessage> {
+        self.subscriber.recv().await
+    }
+}
+
 impl<Store: Storage> Service<Store> {
+    /// 在当前线程执行，存储的操作可能会阻塞。命令在默认的数据库中执行
     pub fn execute(&self, cmd: CommandRequest) -> CommandResponse {
         debug!("Got request: {:?}", cmd);
         self.inner.on_received.notify(&cmd);
 
-        let mut res = dispatch(cmd, &self.inner.store);
+        let store = self.inner.store.as_ref();
+        let res = match cmd.request_data {
+            Some(RequestData::Publish(param)) => self.publish(param),
+            Some(RequestData::Listdb(param)) => param.execute(store),
+            Some(RequestData::Createdb(param)) => param.execute(store),
+            Some(RequestData::Flushdb(param)) => param.execute(store),
+            Some(RequestData::Dump(param)) => {
+                dump_in(self.dump_dir(), param, &Namespace::new(store, DEFAULT_DB))
+            }
+            Some(RequestData::Restore(param)) => {
+                restore_in(self.dump_dir(), param, &Namespace::new(store, DEFAULT_DB))
+            }
+            _ => dispatch(cmd, &Namespace::new(store, DEFAULT_DB)),
+        };
+
+        self.after_executed(res)
+    }
+
+    /// 为一个连接创建 Session，通过 execute_in_session 执行的命令会修改它
+    pub fn session(&self) -> Session {
+        Session {
+            db: DEFAULT_DB,
+            subscriber: self.inner.broker.subscriber(),
+        }
+    }
 
+    fn publish(&self, param: Publish) -> CommandResponse {
+        let message = param.message.unwrap_or_default();
+        let count = self.inner.broker.publish(&param.channel, message);
+        Value::from(count as i64).into()
+    }
+
+    fn dump_dir(&self) -> Option<&Path> {
+        self.inner.dump_dir.as_deref()
+    }
+
+    fn after_executed(&self, mut res: CommandResponse) -> CommandResponse {
         self.inner.on_executed.notify(&res);
         self.inner.on_before_send.notify(&mut res);
         if !self.inner.on_before_send.is_empty() {
@@ -106,6 +184,96 @@ impl<Store: Storage> Service<Store> {
     }
 }
 
+impl<Store: Storage + Send + Sync + 'static> Service<Store> {
+    /// 存储的操作在 spawn_blocking 的线程池里执行，不会阻塞 tokio 的 worker 线程。
+    /// 命令在默认的数据库中执行
+    pub async fn execute_async(&self, cmd: CommandRequest) -> CommandResponse {
+        self.execute_in(cmd, DEFAULT_DB).await
+    }
+
+    /// 执行一个连接上收到的命令，命令在 session 选择的数据库中执行。
+    /// Select、Subscribe、Unsubscribe、Watch 和 Unwatch 修改这个连接的 session
+    pub async fn execute_in_session(
+        &self,
+        cmd: CommandRequest,
+        session: &mut Session,
+    ) -> CommandResponse {
+        let count = |n: usize| CommandResponse::from(Value::from(n as i64));
+        let subscriber = &mut session.subscriber;
+        let res = match &cmd.request_data {
+            Some(RequestData::Subscribe(param)) => {
+                count(subscriber.subscribe(&param.channels, &param.patterns))
+            }
+            Some(RequestData::Unsubscribe(param)) => {
+                count(subscriber.unsubscribe(&param.channels, &param.patterns))
+            }
+            Some(RequestData::Watch(param)) => {
+                count(subscriber.watch(session.db, &param.table, &param.prefix))
+            }
+            Some(RequestData::Unwatch(_)) => count(subscriber.unwatch()),
+            Some(RequestData::Select(param)) => self.select(param.db, session).await,
+            _ => return self.execute_in(cmd, session.db).await,
+        };
+
+        debug!("Got request: {:?}", cmd);
+        self.inner.on_received.notify(&cmd);
+        self.after_executed(res)
+    }
+
+    async fn execute_in(&self, cmd: CommandRequest, db: u32) -> CommandResponse {
+        debug!("Got request: {:?}", cmd);
+        self.inner.on_received.notify(&cmd);
+
+        let store = &self.inner.store;
+        let res = match cmd.request_data {
+            Some(RequestData::Publish(param)) => self.publish(param),
+            Some(RequestData::Listdb(param)) => run(store, move |s| param.execute(s)).await,
+            Some(RequestData::Createdb(param)) => run(store, move |s| param.execute(s)).await,
+            Some(RequestData::Flushdb(param)) => run(store, move |s| param.execute(s)).await,
+            Some(RequestData::Dump(param)) => {
+                self.run_dump(db, move |dir, s| dump_in(dir, param, s))
+                    .await
+            }
+            Some(RequestData::Restore(param)) => {
+                self.run_dump(db, move |dir, s| restore_in(dir, param, s))
+                    .await
+            }
+            _ => dispatch_async(cmd, &Arc::new(Namespace::new(store.clone(), db))).await,
+        };
+
+        self.after_executed(res)
+    }
+
+    // 导出和恢复要读写整个数据库，放到阻塞线程中执行
+    async fn run_dump<F>(&self, db: u32, f: F) -> CommandResponse
+    where
+        F: FnOnce(Option<&Path>, &dyn Storage) -> CommandResponse + Send + 'static,
+    {
+        let dir = self.inner.dump_dir.clone();
+        let store = Arc::new(Namespace::new(self.inner.store.clone(), db));
+        run(&store, move |s| f(dir.as_deref(), s)).await
+    }
+
+    // 只能切换到已经存在的数据库
+    async fn select(&self, db: u32, session: &mut Session) -> CommandResponse {
+        let exists = self
+            .inner
+            .store
+            .run_blocking(move |s| database_exists(s, db))
+            .await;
+        match exists {
+            Ok(Ok(true)) => {
+                session.db = db;
+                Value::from(true).into()
+            }
+            Ok(Ok(false)) => {
+                KvError::InvalidCommand(format!("Database {} does not exist", db)).into()
+            }
+            Ok(Err(e)) | Err(e) => e.into(),
+        }
+    }
+}
+
 impl<Store: Storage> From<ServiceInner<Store>> for Service<Store> {
     fn from(inner: ServiceInner<Store>) -> Self {
         Self {
@@ -114,21 +282,132 @@ impl<Store: Storage> From<ServiceInner<Store>> for Service<Store> {
     }
 }
 
-pub fn dispatch(cmd: CommandRequest, store: &impl Storage) -> CommandResponse {
+pub fn dispatch(cmd: CommandRequest, store: &(impl Storage + ?Sized)) -> CommandResponse {
     match cmd.request_data {
         Some(RequestData::Hget(param)) => param.execute(store),
         Some(RequestData::Hgetall(param)) => param.execute(store),
         Some(RequestData::Hset(param)) => param.execute(store),
+        Some(RequestData::Hmset(param)) => param.execute(store),
         Some(RequestData::Hmget(param)) => param.execute(store),
         Some(RequestData::Hdel(param)) => param.execute(store),
         Some(RequestData::Hmdel(param)) => param.execute(store),
         Some(RequestData::Hexist(param)) => param.execute(store),
         Some(RequestData::Hmexist(param)) => param.execute(store),
+        Some(RequestData::Hsetex(param)) => param.execute(store),
+        Some(RequestData::Hexpire(param)) => param.execute(store),
+        Some(RequestData::Httl(param)) => param.execute(store),
+        Some(RequestData::Hpersist(param)) => param.execute(store),
+        Some(RequestData::Transaction(param)) => param.execute(store),
+        Some(RequestData::Hcas(param)) => param.execute(store),
+        Some(RequestData::Hincrby(param)) => param.execute(store),
+        Some(RequestData::Hincrbyfloat(param)) => param.execute(store),
+        Some(RequestData::Hscan(param)) => param.execute(store),
+        Some(RequestData::Htables(param)) => param.execute(store),
+        Some(RequestData::Hdrop(param)) => param.execute(store),
+        Some(RequestData::Hlen(param)) => param.execute(store),
+        Some(RequestData::Hexpireat(param)) => param.execute(store),
+        Some(RequestData::CreateIndex(param)) => param.execute(store),
+        Some(RequestData::Hfind(param)) => param.execute(store),
+        Some(RequestData::Hgetversion(param)) => param.execute(store),
+        Some(RequestData::Hgetat(param)) => param.execute(store),
+        Some(RequestData::Hhistory(param)) => param.execute(store),
+        Some(RequestData::Hgcversions(param)) => param.execute(store),
+        Some(RequestData::Lpush(param)) => param.execute(store),
+        Some(RequestData::Rpush(param)) => param.execute(store),
+        Some(RequestData::Lpop(param)) => param.execute(store),
+        Some(RequestData::Rpop(param)) => param.execute(store),
+        Some(RequestData::Lrange(param)) => param.execute(store),
+        Some(RequestData::Llen(param)) => param.execute(store),
+        Some(RequestData::Ltrim(param)) => param.execute(store),
+        Some(RequestData::Lindex(param)) => param.execute(store),
+        Some(RequestData::Sadd(param)) => param.execute(store),
+        Some(RequestData::Srem(param)) => param.execute(store),
+        Some(RequestData::Smembers(param)) => param.execute(store),
+        Some(RequestData::Sismember(param)) => param.execute(store),
+        Some(RequestData::Scard(param)) => param.execute(store),
+        Some(RequestData::Sinter(param)) => param.execute(store),
+        Some(RequestData::Sunion(param)) => param.execute(store),
+        Some(RequestData::Sdiff(param)) => param.execute(store),
+        Some(RequestData::Zadd(param)) => param.execute(store),
+        Some(RequestData::Zincrby(param)) => param.execute(store),
+        Some(RequestData::Zrem(param)) => param.execute(store),
+        Some(RequestData::Zscore(param)) => param.execute(store),
+        Some(RequestData::Zrank(param)) => param.execute(store),
+        Some(RequestData::Zrange(param)) => param.execute(store),
+        Some(RequestData::Zrangebyscore(param)) => param.execute(store),
+        Some(RequestData::Zcard(param)) => param.execute(store),
+        Some(RequestData::Hgetpath(param)) => param.execute(store),
+        Some(RequestData::Hsetpath(param)) => param.execute(store),
+        Some(RequestData::Hdelpath(param)) => param.execute(store),
+        Some(RequestData::Jget(param)) => param.execute(store),
+        Some(RequestData::Jset(param)) => param.execute(store),
+        Some(RequestData::Jdel(param)) => param.execute(store),
+        Some(RequestData::Jarrappend(param)) => param.execute(store),
+        Some(RequestData::Subscribe(_))
+        | Some(RequestData::Unsubscribe(_))
+        | Some(RequestData::Publish(_))
+        | Some(RequestData::Watch(_))
+        | Some(RequestData::Unwatch(_))
+        | Some(RequestData::Select(_))
+        | Some(RequestData::Listdb(_))
+        | Some(RequestData::Createdb(_))
+        | Some(RequestData::Flushdb(_))
+        | Some(RequestData::Dump(_))
+        | Some(RequestData::Restore(_)) => service_only(),
         None => KvError::InvalidCommand("Request has no data".into()).into(),
-        _ => KvError::Internal("Not Implemented".into()).into(),
     }
 }
 
+// 订阅、发布、数据库、导出和恢复的命令由 Service 处理，不能直接在存储上执行，比如放在事务里
+fn service_only() -> CommandResponse {
+    KvError::InvalidCommand(
+        "Pub/Sub, database and dump commands cannot be executed on a storage".into(),
+    )
+    .into()
+}
+
+/// 在异步存储上处理 Command。整个命令在一个阻塞线程里通过 dispatch 执行，
+/// 访问多个 key 的命令和同步执行时一样
+pub async fn dispatch_async(
+    cmd: CommandRequest,
+    store: &(impl AsyncStorage + ?Sized),
+) -> CommandResponse {
+    run(store, move |s| dispatch(cmd, s)).await
+}
+
+// 在阻塞线程里生成 Response，阻塞线程失败时返回错误
+async fn run<F>(store: &(impl AsyncStorage + ?Sized), f: F) -> CommandResponse
+where
+    F: FnOnce(&dyn Storage) -> CommandResponse + Send + 'static,
+{
+    match store.run_blocking(f).await {
+        Ok(res) => res,
+        Err(e) => e.into(),
+    }
+}
+
+#[cfg(test)]
+use crate::Kvpair;
+
+// 测试成功返回的结果
+#[cfg(test)]
+pub fn assert_res_ok(mut res: CommandResponse, values: &[Value], pairs: &[Kvpair]) {
+    res.pairs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    assert_eq!(res.status, 200);
+    assert_eq!(res.message, "");
+    assert_eq!(res.values, values);
+    assert_eq!(res.pairs, pairs);
+}
+
+// 测试失败返回的结果
+#[cfg(test)]
+pub fn assert_res_error(res: CommandResponse, code: u32, msg: &str) {
+    assert_eq!(res.status, code);
+    assert!(res.message.contains(msg));
+    assert_eq!(res.values, &[]);
+    assert_eq!(res.pairs, &[]);
+}
+
 #[cfg(test)]
 mod tests {
     use http::StatusCode;
@@ -181,30 +460,97 @@ mod tests {
             .fn_after_send(e)
             .into();
         let res = service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
-        assert_eq!(res.status, StatusCode::CREATED.as_u16() as _);
+        assert_eq!(res.status, StatusCode::CREATED.as_u16() as u32);
         assert_eq!(res.message, "");
         assert_eq!(res.values, vec![Value::default()]);
     }
-}
 
-#[cfg(test)]
-use crate::{Kvpair, Value};
+    #[test]
+    fn dump_and_restore_should_work() {
+        let dir = tempfile::tempdir().unwrap();
+        let service: Service = ServiceInner::new(MemTable::new())
+            .dump_dir(dir.path())
+            .into();
+        service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
+        service.execute(CommandRequest::new_hset("t1", "k2", "v2".into()));
+        let res = service.execute(CommandRequest::new_dump("kv.dump"));
+        assert_res_ok(res, &[2.into()], &[]);
 
-// 测试成功返回的结果
-#[cfg(test)]
-pub fn assert_res_ok(mut res: CommandResponse, values: &[Value], pairs: &[Kvpair]) {
-    res.pairs.sort_by(|a, b| a.partial_cmp(b).unwrap());
-    assert_eq!(res.status, 200);
-    assert_eq!(res.message, "");
-    assert_eq!(res.values, values);
-    assert_eq!(res.pairs, pairs);
-}
+        let target: Service = ServiceInner::new(MemTable::new())
+            .dump_dir(dir.path())
+            .into();
+        let res = target.execute(CommandRequest::new_restore("kv.dump"));
+        assert_res_ok(res, &[2.into()], &[]);
+        let res = target.execute(CommandRequest::new_hget("t1", "k2"));
+        assert_res_ok(res, &["v2".into()], &[]);
 
-// 测试失败返回的结果
-#[cfg(test)]
-pub fn assert_res_error(res: CommandResponse, code: u32, msg: &str) {
-    assert_eq!(res.status, code);
-    assert!(res.message.contains(msg));
-    assert_eq!(res.values, &[]);
-    assert_eq!(res.pairs, &[]);
+        let res = target.execute(CommandRequest::new_restore("missing.dump"));
+        assert_res_error(res, 500, "I/O error");
+    }
+
+    #[tokio::test]
+    async fn dispatch_async_should_work() {
+        let store = Arc::new(MemTable::new());
+        let res = dispatch_async(CommandRequest::new_hset("t1", "k1", "v1".into()), &store).await;
+        assert_res_ok(res, &[Value::default()], &[]);
+        let res = dispatch_async(CommandRequest::new_hget("t1", "k1"), &store).await;
+        assert_res_ok(res, &["v1".into()], &[]);
+        let res = dispatch_async(CommandRequest::new_hget("t1", "k2"), &store).await;
+        assert_res_error(res, 404, "Not found");
+
+        let cmd = CommandRequest::new_hmget("t1", vec!["k1".into(), "k2".into()]);
+        let res = dispatch_async(cmd, &store).await;
+        assert_res_ok(res, &["v1".into(), Value::default()], &[]);
+    }
+
+    #[tokio::test]
+    async fn dispatch_async_should_run_transaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(crate::SledDb::new(dir));
+        let cmd = CommandRequest::new_transaction(vec![
+            CommandRequest::new_hset("t1", "k1", "v1".into()),
+            CommandRequest::new_hincrby("t1", "k2", 2),
+        ]);
+        let res = dispatch_async(cmd, &store).await;
+        assert_eq!(res.status, 200);
+        assert_eq!(res.responses.len(), 2);
+
+        let res = dispatch_async(CommandRequest::new_hgetall("t1"), &store).await;
+        let pairs = &[Kvpair::new("k1", "v1".into()), Kvpair::new("k2", 2.into())];
+        assert_res_ok(res, &[], pairs);
+    }
+
+    #[tokio::test]
+    async fn dump_should_only_access_dump_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let service: Service = ServiceInner::new(MemTable::new())
+            .dump_dir(dir.path().join("dumps"))
+            .into();
+        let outside = dir.path().join("kv.dump");
+        let paths = [
+            outside.to_str().unwrap(),
+            "../kv.dump",
+            "a/../../kv.dump",
+            "",
+        ];
+        for path in paths {
+            let res = service.execute_async(CommandRequest::new_dump(path)).await;
+            assert_res_error(res, 400, "Invalid dump path");
+            let res = service
+                .execute_async(CommandRequest::new_restore(path))
+                .await;
+            assert_res_error(res, 400, "Invalid dump path");
+        }
+        assert!(!outside.exists());
+
+        // 不能放在事务中绕过 Service 的检查
+        let cmd = CommandRequest::new_transaction(vec![CommandRequest::new_dump("kv.dump")]);
+        let res = service.execute_async(cmd).await;
+        assert_res_error(res, 409, "cannot be executed on a storage");
+
+        // 没有设置目录时不能导出和恢复
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let res = service.execute(CommandRequest::new_dump("kv.dump"));
+        assert_res_error(res, 400, "not configured");
+    }
 }
@@ -0,0 +1,400 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+};
+use tokio::sync::mpsc::{self, error::TrySendError, Receiver, Sender};
+use tracing::warn;
+
+use crate::{ChangeEvent, CommandResponse, PushMessage, Value};
+
+/// 每个订阅者最多缓存的消息数量，连接处理不过来时新的消息会被丢弃
+const PUSH_BUFFER: usize = 1024;
+
+type Subscribers = HashMap<String, HashMap<u64, Sender<PushMessage>>>;
+// 每个订阅者 Watch 的 (db, table, prefix)
+type Watchers = HashMap<u64, (Sender<PushMessage>, Vec<(u32, String, String)>)>;
+
+/// 在所有连接之间转发发布的消息
+#[derive(Default)]
+pub struct Broker {
+    next_id: AtomicU64,
+    channels: RwLock<Subscribers>,
+    patterns: RwLock<Subscribers>,
+    watchers: RwLock<Watchers>,
+}
+
+impl Broker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 创建一个订阅者，它被释放时会取消所有订阅
+    pub fn subscriber(self: &Arc<Self>) -> Subscriber {
+        let (tx, rx) = mpsc::channel(PUSH_BUFFER);
+        Subscriber {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            broker: self.clone(),
+            tx,
+            rx,
+            channels: HashSet::new(),
+            patterns: HashSet::new(),
+            watches: 0,
+        }
+    }
+
+    /// 把消息发给订阅了 channel 或者匹配的模式的订阅者，返回收到消息的数量
+    pub fn publish(&self, channel: &str, message: Value) -> usize {
+        let mut count = 0;
+        if let Some(subscribers) = self.channels.read().unwrap().get(channel) {
+            for tx in subscribers.values() {
+                let msg = PushMessage::new(channel, "", message.clone());
+                count += deliver(tx, msg) as usize;
+            }
+        }
+        for (pattern, subscribers) in self.patterns.read().unwrap().iter() {
+            if !glob_match(pattern, channel) {
+                continue;
+            }
+            for tx in subscribers.values() {
+                let msg = PushMessage::new(channel, pattern, message.clone());
+                count += deliver(tx, msg) as usize;
+            }
+        }
+
+        count
+    }
+
+    /// 是否有订阅者 Watch，没有时不需要生成修改事件
+    pub fn watching(&self) -> bool {
+        !self.watchers.read().unwrap().is_empty()
+    }
+
+    /// 把修改事件发给 Watch 了对应 table 和 prefix 的订阅者，每个订阅者最多收到一次
+    pub fn notify(&self, event: ChangeEvent) {
+        for (tx, watches) in self.watchers.read().unwrap().values() {
+            let matched = watches.iter().any(|(db, table, prefix)| {
+                *db == event.db
+                    && (table.is_empty() || *table == event.table)
+                    && (event.op == "drop" || event.key.starts_with(prefix.as_str()))
+            });
+            if matched {
+                deliver(tx, PushMessage::change(event.clone()));
+            }
+        }
+    }
+
+    fn add(&self, map: &RwLock<Subscribers>, name: &str, id: u64, tx: &Sender<PushMessage>) {
+        let mut map = map.write().unwrap();
+        map.entry(name.into()).or_default().insert(id, tx.clone());
+    }
+
+    fn remove(&self, map: &RwLock<Subscribers>, name: &str, id: u64) {
+        let mut map = map.write().unwrap();
+        if let Some(subscribers) = map.get_mut(name) {
+            subscribers.remove(&id);
+            if subscribers.is_empty() {
+                map.remove(name);
+            }
+        }
+    }
+}
+
+// 订阅者的缓冲区满了就丢弃消息，不能让一个慢的连接拖住发布者
+fn deliver(tx: &Sender<PushMessage>, msg: PushMessage) -> bool {
+    match tx.try_send(msg) {
+        Ok(()) => true,
+        Err(TrySendError::Full(msg)) => {
+            warn!("Subscriber is too slow, dropped message on {}", msg.channel);
+            false
+        }
+        Err(TrySendError::Closed(_)) => false,
+    }
+}
+
+/// 一个连接的订阅。订阅之后通过 recv 收到发布的消息
+pub struct Subscriber {
+    id: u64,
+    broker: Arc<Broker>,
+    tx: Sender<PushMessage>,
+    rx: Receiver<PushMessage>,
+    channels: HashSet<String>,
+    patterns: HashSet<String>,
+    watches: usize,
+}
+
+impl Subscriber {
+    /// 订阅 channel 和模式，返回订阅的总数
+    pub fn subscribe(&mut self, channels: &[String], patterns: &[String]) -> usize {
+        let broker = &self.broker;
+        for channel in channels {
+            if self.channels.insert(channel.clone()) {
+                broker.add(&broker.channels, channel, self.id, &self.tx);
+            }
+        }
+        for pattern in patterns {
+            if self.patterns.insert(pattern.clone()) {
+                broker.add(&broker.patterns, pattern, self.id, &self.tx);
+            }
+        }
+
+        self.count()
+    }
+
+    /// 取消订阅，channels 和 patterns 都为空时取消所有订阅，返回剩下的订阅数量
+    pub fn unsubscribe(&mut self, channels: &[String], patterns: &[String]) -> usize {
+        let (channels, patterns) = if channels.is_empty() && patterns.is_empty() {
+            (
+                self.channels.iter().cloned().collect(),
+                self.patterns.iter().cloned().collect(),
+            )
+        } else {
+            (channels.to_vec(), patterns.to_vec())
+        };
+
+        let broker = &self.broker;
+        for channel in channels {
+            if self.channels.remove(&channel) {
+                broker.remove(&broker.channels, &channel, self.id);
+            }
+        }
+        for pattern in patterns {
+            if self.patterns.remove(&pattern) {
+                broker.remove(&broker.patterns, &pattern, self.id);
+            }
+        }
+
+        self.count()
+    }
+
+    pub fn count(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+
+    /// Watch 数据库 db 的 table 中以 prefix 开头的 key 的修改，table 为空表示所有 table。
+    /// 返回 Watch 的数量
+    pub fn watch(&mut self, db: u32, table: &str, prefix: &str) -> usize {
+        let mut watchers = self.broker.watchers.write().unwrap();
+        let (_, watches) = watchers
+            .entry(self.id)
+            .or_insert_with(|| (self.tx.clone(), Vec::new()));
+        let watch = (db, table.to_string(), prefix.to_string());
+        if !watches.contains(&watch) {
+            watches.push(watch);
+        }
+        self.watches = watches.len();
+
+        self.watches
+    }
+
+    /// 取消所有的 Watch，返回剩下的数量
+    pub fn unwatch(&mut self) -> usize {
+        if self.watches > 0 {
+            self.broker.watchers.write().unwrap().remove(&self.id);
+            self.watches = 0;
+        }
+
+        self.watches
+    }
+
+    /// 等待下一条消息。Subscriber 自己持有发送端，所以不会返回 None
+    pub async fn recv(&mut self) -> Option<PushMessage> {
+        self.rx.recv().await
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        self.unsubscribe(&[], &[]);
+        self.unwatch();
+    }
+}
+
+impl PushMessage {
+    pub fn new(channel: impl Into<String>, pattern: impl Into<String>, message: Value) -> Self {
+        Self {
+            channel: channel.into(),
+            pattern: pattern.into(),
+            message: Some(message),
+            change: None,
+        }
+    }
+
+    pub fn change(event: ChangeEvent) -> Self {
+        Self {
+            change: Some(event),
+            ..Default::default()
+        }
+    }
+}
+
+impl From<PushMessage> for CommandResponse {
+    fn from(msg: PushMessage) -> Self {
+        Self {
+            status: http::StatusCode::OK.as_u16() as _,
+            push: Some(msg),
+            ..Default::default()
+        }
+    }
+}
+
+/// glob 风格的模式匹配：* 匹配任意字符串，? 匹配一个字符，[abc]、[a-z]、[^a] 匹配字符集合，
+/// \ 转义下一个字符
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    // 回溯到最近一个 * 的位置：(* 之后的 pattern 位置, 这个 * 匹配到的 text 位置)
+    let mut star: Option<(usize, usize)> = None;
+    let (mut p, mut t) = (0, 0);
+    while t < text.len() {
+        let step = match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, t));
+                p += 1;
+                continue;
+            }
+            Some('?') => Some(1),
+            Some('[') => match_class(&pattern[p..], text[t]),
+            Some('\\') if p + 1 < pattern.len() => (pattern[p + 1] == text[t]).then_some(2),
+            Some(c) => (*c == text[t]).then_some(1),
+            None => None,
+        };
+        match (step, star) {
+            (Some(n), _) => {
+                p += n;
+                t += 1;
+            }
+            // 不匹配时让上一个 * 多匹配一个字符
+            (None, Some((sp, st))) => {
+                p = sp;
+                t = st + 1;
+                star = Some((sp, st + 1));
+            }
+            (None, None) => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+// 匹配 [...]，成功时返回这个字符集合在 pattern 中的长度。没有 ] 结尾时 [ 当作普通字符
+fn match_class(pattern: &[char], c: char) -> Option<usize> {
+    let end = match pattern.iter().skip(2).position(|c| *c == ']') {
+        Some(pos) => pos + 2,
+        None => return (c == '[').then_some(1),
+    };
+    let (negate, class) = match pattern[1] {
+        '^' => (true, &pattern[2..end]),
+        _ => (false, &pattern[1..end]),
+    };
+
+    let mut matched = false;
+    let mut i = 0;
+    while i < class.len() {
+        if i + 2 < class.len() && class[i + 1] == '-' {
+            matched |= class[i] <= c && c <= class[i + 2];
+            i += 3;
+        } else {
+            matched |= class[i] == c;
+            i += 1;
+        }
+    }
+
+    (matched != negate).then_some(end + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_match_should_work() {
+        assert!(glob_match("news.*", "news.tech"));
+        assert!(glob_match("news.*", "news."));
+        assert!(!glob_match("news.*", "new"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("h?llo", "hallo"));
+        assert!(!glob_match("h?llo", "hllo"));
+        assert!(glob_match("h[ae]llo", "hello"));
+        assert!(!glob_match("h[^e]llo", "hello"));
+        assert!(glob_match("user:[0-9]*", "user:42"));
+        assert!(!glob_match("user:[0-9]*", "user:x"));
+        assert!(glob_match("a*b*c", "axxbyyc"));
+        assert!(!glob_match("a*b*c", "axxbyy"));
+        assert!(glob_match("a\\*", "a*"));
+        assert!(!glob_match("a\\*", "ab"));
+    }
+
+    #[tokio::test]
+    async fn publish_should_reach_subscribers() {
+        let broker = Arc::new(Broker::new());
+        let mut s1 = broker.subscriber();
+        let mut s2 = broker.subscriber();
+        assert_eq!(s1.subscribe(&["news".into()], &[]), 1);
+        assert_eq!(s2.subscribe(&[], &["n*".into(), "x*".into()]), 2);
+
+        assert_eq!(broker.publish("news", "hello".into()), 2);
+        assert_eq!(broker.publish("other", "hello".into()), 0);
+        assert_eq!(
+            s1.recv().await.unwrap(),
+            PushMessage::new("news", "", "hello".into())
+        );
+        assert_eq!(
+            s2.recv().await.unwrap(),
+            PushMessage::new("news", "n*", "hello".into())
+        );
+
+        assert_eq!(s2.unsubscribe(&[], &["n*".into()]), 1);
+        assert_eq!(broker.publish("news", "again".into()), 1);
+
+        // 释放的订阅者会自动取消订阅
+        drop(s1);
+        assert_eq!(broker.publish("news", "bye".into()), 0);
+        assert_eq!(s2.unsubscribe(&[], &[]), 0);
+        assert!(broker.channels.read().unwrap().is_empty());
+        assert!(broker.patterns.read().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn watchers_should_receive_matched_changes() {
+        let broker = Arc::new(Broker::new());
+        let mut s1 = broker.subscriber();
+        let mut s2 = broker.subscriber();
+        assert!(!broker.watching());
+        assert_eq!(s1.watch(0, "t1", "user:"), 1);
+        assert_eq!(s1.watch(0, "", "user:1"), 2);
+        assert_eq!(s1.watch(0, "t1", "user:"), 2);
+        assert_eq!(s2.watch(0, "t2", ""), 1);
+        assert!(broker.watching());
+
+        let event = |table: &str, key: &str, op: &str| ChangeEvent {
+            table: table.into(),
+            key: key.into(),
+            op: op.into(),
+            ..Default::default()
+        };
+        // 同时匹配两个 Watch 也只收到一次
+        broker.notify(event("t1", "user:1", "del"));
+        broker.notify(event("t1", "item:1", "del"));
+        // 其它数据库里的修改
+        broker.notify(ChangeEvent {
+            db: 1,
+            ..event("t1", "user:1", "del")
+        });
+        broker.notify(event("t2", "item:1", "del"));
+        broker.notify(event("t1", "", "drop"));
+
+        let msg = s1.recv().await.unwrap();
+        assert_eq!(msg, PushMessage::change(event("t1", "user:1", "del")));
+        let msg = s1.recv().await.unwrap();
+        assert_eq!(msg, PushMessage::change(event("t1", "", "drop")));
+        let msg = s2.recv().await.unwrap();
+        assert_eq!(msg, PushMessage::change(event("t2", "item:1", "del")));
+
+        assert_eq!(s1.unwatch(), 0);
+        drop(s2);
+        assert!(!broker.watching());
+    }
+}
@@ -0,0 +1,926 @@
+use std::{
+    cell::RefCell,
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use super::Broker;
+use crate::{
+    storage::namespace, ChangeEvent, KeyRange, KvError, Kvpair, ListEnd, PathOp, PathSegment,
+    Storage, Value, Version,
+};
+
+/// 接收修改事件
+trait Emitter {
+    fn emit(&self, event: ChangeEvent);
+}
+
+impl Emitter for Broker {
+    fn emit(&self, event: ChangeEvent) {
+        self.notify(event);
+    }
+}
+
+// 事务中的事件先记下来，提交之后再发出去
+impl Emitter for RefCell<Vec<ChangeEvent>> {
+    fn emit(&self, event: ChangeEvent) {
+        self.borrow_mut().push(event);
+    }
+}
+
+/// 同一个 key 的修改和事件的发出在这么多把锁中的一把里完成
+const ORDER_LOCKS: usize = 64;
+
+/// 锁和记录修改事件的视图，没有连接 Watch 时为 None
+type Ordered<'a, G, S> = Option<(G, Watched<'a, S, Broker>)>;
+
+/// 把修改操作转换成 ChangeEvent 交给 Broker，发给 Watch 了这个 table 的连接。
+/// 没有连接 Watch 的时候直接访问内部的存储，不会复制 value
+pub(super) struct WatchedStorage<S> {
+    inner: S,
+    broker: Arc<Broker>,
+    // 有连接 Watch 时，修改 key 和发出事件都持有 key 对应的锁，
+    // 同一个 key 的事件按修改的顺序发出。drop_table 和事务持有所有的锁
+    order: Vec<Mutex<()>>,
+}
+
+impl<S: Storage> WatchedStorage<S> {
+    pub fn new(inner: S, broker: Arc<Broker>) -> Self {
+        Self {
+            inner,
+            broker,
+            order: (0..ORDER_LOCKS).map(|_| Mutex::new(())).collect(),
+        }
+    }
+
+    // 有连接 Watch 时锁住 key，返回记录修改事件的视图
+    fn watched(&self, table: &str, key: &str) -> Ordered<'_, MutexGuard<'_, ()>, S> {
+        if !self.broker.watching() {
+            return None;
+        }
+        let mut hasher = DefaultHasher::new();
+        (table, key).hash(&mut hasher);
+        let guard = self.order[hasher.finish() as usize % ORDER_LOCKS]
+            .lock()
+            .unwrap();
+
+        Some((guard, self.view()))
+    }
+
+    // 修改整个 table 或者事务中的多个 key 时按顺序锁住所有的 key
+    fn watched_all(&self) -> Ordered<'_, Vec<MutexGuard<'_, ()>>, S> {
+        if !self.broker.watching() {
+            return None;
+        }
+        let guards = self.order.iter().map(|l| l.lock().unwrap()).collect();
+
+        Some((guards, self.view()))
+    }
+
+    fn view(&self) -> Watched<'_, S, Broker> {
+        Watched {
+            inner: &self.inner,
+            emitter: self.broker.as_ref(),
+        }
+    }
+}
+
+struct Watched<'a, S: ?Sized, E> {
+    inner: &'a S,
+    emitter: &'a E,
+}
+
+impl<S: Storage + ?Sized, E: Emitter> Watched<'_, S, E> {
+    // table 是内部存储中的名字，拆出它所在的数据库
+    fn emit(&self, table: &str, key: &str, op: &str, old: Option<Value>, new: Option<Value>) {
+        let (db, table) = namespace::split(table);
+        self.emitter.emit(ChangeEvent {
+            table: table.into(),
+            key: key.into(),
+            op: op.into(),
+            old,
+            new,
+            db,
+        });
+    }
+}
+
+impl<S: Storage + ?Sized, E: Emitter> Storage for Watched<'_, S, E> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.inner.get(table, key)
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        let old = self.inner.set(table, key.clone(), value.clone())?;
+        self.emit(table, &key, "set", old.clone(), Some(value));
+
+        Ok(old)
+    }
+
+    fn set_with_ttl(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> Result<Option<Value>, KvError> {
+        let old = self
+            .inner
+            .set_with_ttl(table, key.clone(), value.clone(), ttl)?;
+        self.emit(table, &key, "set", old.clone(), Some(value));
+
+        Ok(old)
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.inner.contains(table, key)
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let old = self.inner.del(table, key)?;
+        if old.is_some() {
+            self.emit(table, key, "del", old.clone(), None);
+        }
+
+        Ok(old)
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.inner.get_all(table)
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        self.inner.get_iter(table)
+    }
+
+    fn scan(&self, table: &str, range: &KeyRange, limit: usize) -> Result<Vec<Kvpair>, KvError> {
+        self.inner.scan(table, range, limit)
+    }
+
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        self.inner.list_tables()
+    }
+
+    fn drop_table(&self, table: &str) -> Result<bool, KvError> {
+        let existed = self.inner.drop_table(table)?;
+        if existed {
+            self.emit(table, "", "drop", None, None);
+        }
+
+        Ok(existed)
+    }
+
+    fn table_len(&self, table: &str) -> Result<usize, KvError> {
+        self.inner.table_len(table)
+    }
+
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        let found = self.inner.expire(table, key, ttl)?;
+        if found {
+            self.emit(table, key, "expire", None, None);
+        }
+
+        Ok(found)
+    }
+
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Duration>, KvError> {
+        self.inner.ttl(table, key)
+    }
+
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        let persisted = self.inner.persist(table, key)?;
+        if persisted {
+            self.emit(table, key, "persist", None, None);
+        }
+
+        Ok(persisted)
+    }
+
+    fn compare_and_swap(
+        &self,
+        table: &str,
+        key: &str,
+        expected: Option<&Value>,
+        value: Value,
+    ) -> Result<Result<(), Option<Value>>, KvError> {
+        let result = self
+            .inner
+            .compare_and_swap(table, key, expected, value.clone())?;
+        if result.is_ok() {
+            self.emit(table, key, "set", expected.cloned(), Some(value));
+        }
+
+        Ok(result)
+    }
+
+    // 原子地加上 delta 的存储引擎不会返回旧的 value，先读出来。
+    // 同一个 key 的修改持有同一把锁，读到的就是 incr 之前的 value
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        let old = self.inner.get(table, key)?;
+        let result = self.inner.incr(table, key, delta)?;
+        self.emit(table, key, "set", old, Some(result.into()));
+
+        Ok(result)
+    }
+
+    fn incr_float(&self, table: &str, key: &str, delta: f64) -> Result<f64, KvError> {
+        let old = self.inner.get(table, key)?;
+        let result = self.inner.incr_float(table, key, delta)?;
+        self.emit(table, key, "set", old, Some(result.into()));
+
+        Ok(result)
+    }
+
+    fn update_path(
+        &self,
+        table: &str,
+        key: &str,
+        path: &[PathSegment],
+        op: PathOp,
+    ) -> Result<Option<Value>, KvError> {
+        let old = self.inner.update_path(table, key, path, op.clone())?;
+        match op {
+            PathOp::Set(_) => self.emit(table, key, "setpath", None, None),
+            PathOp::Delete if old.is_some() => self.emit(table, key, "delpath", None, None),
+            PathOp::Delete => {}
+            PathOp::Append(_) => self.emit(table, key, "arrappend", None, None),
+        }
+
+        Ok(old)
+    }
+
+    fn create_index(&self, table: &str) -> Result<bool, KvError> {
+        self.inner.create_index(table)
+    }
+
+    fn find(&self, table: &str, value: &Value) -> Result<Vec<Kvpair>, KvError> {
+        self.inner.find(table, value)
+    }
+
+    fn history(&self, table: &str, key: &str) -> Result<Vec<Version>, KvError> {
+        self.inner.history(table, key)
+    }
+
+    fn get_version(&self, table: &str, key: &str, version: u64) -> Result<Option<Value>, KvError> {
+        self.inner.get_version(table, key, version)
+    }
+
+    fn get_at(&self, table: &str, key: &str, at: u64) -> Result<Option<Value>, KvError> {
+        self.inner.get_at(table, key, at)
+    }
+
+    fn gc_versions_in(
+        &self,
+        retention: Duration,
+        tables: &dyn Fn(&str) -> bool,
+    ) -> Result<usize, KvError> {
+        self.inner.gc_versions_in(retention, tables)
+    }
+
+    fn list_push(
+        &self,
+        table: &str,
+        key: &str,
+        end: ListEnd,
+        values: Vec<Value>,
+    ) -> Result<usize, KvError> {
+        let pushed = !values.is_empty();
+        let len = self.inner.list_push(table, key, end, values)?;
+        if pushed {
+            let op = match end {
+                ListEnd::Left => "lpush",
+                ListEnd::Right => "rpush",
+            };
+            self.emit(table, key, op, None, None);
+        }
+
+        Ok(len)
+    }
+
+    fn list_pop(
+        &self,
+        table: &str,
+        key: &str,
+        end: ListEnd,
+        count: usize,
+    ) -> Result<Vec<Value>, KvError> {
+        let popped = self.inner.list_pop(table, key, end, count)?;
+        if !popped.is_empty() {
+            let op = match end {
+                ListEnd::Left => "lpop",
+                ListEnd::Right => "rpop",
+            };
+            self.emit(table, key, op, None, None);
+        }
+
+        Ok(popped)
+    }
+
+    fn list_range(
+        &self,
+        table: &str,
+        key: &str,
+        start: i64,
+        stop: i64,
+    ) -> Result<Vec<Value>, KvError> {
+        self.inner.list_range(table, key, start, stop)
+    }
+
+    fn list_trim(&self, table: &str, key: &str, start: i64, stop: i64) -> Result<usize, KvError> {
+        let removed = self.inner.list_trim(table, key, start, stop)?;
+        if removed > 0 {
+            self.emit(table, key, "ltrim", None, None);
+        }
+
+        Ok(removed)
+    }
+
+    fn list_len(&self, table: &str, key: &str) -> Result<usize, KvError> {
+        self.inner.list_len(table, key)
+    }
+
+    fn list_index(&self, table: &str, key: &str, index: i64) -> Result<Option<Value>, KvError> {
+        self.inner.list_index(table, key, index)
+    }
+
+    fn list_keys(&self, table: &str) -> Result<Vec<String>, KvError> {
+        self.inner.list_keys(table)
+    }
+
+    fn set_add(&self, table: &str, key: &str, members: Vec<String>) -> Result<usize, KvError> {
+        let added = self.inner.set_add(table, key, members)?;
+        if added > 0 {
+            self.emit(table, key, "sadd", None, None);
+        }
+
+        Ok(added)
+    }
+
+    fn set_remove(&self, table: &str, key: &str, members: Vec<String>) -> Result<usize, KvError> {
+        let removed = self.inner.set_remove(table, key, members)?;
+        if removed > 0 {
+            self.emit(table, key, "srem", None, None);
+        }
+
+        Ok(removed)
+    }
+
+    fn set_members(&self, table: &str, key: &str) -> Result<Vec<String>, KvError> {
+        self.inner.set_members(table, key)
+    }
+
+    fn set_contains(&self, table: &str, key: &str, member: &str) -> Result<bool, KvError> {
+        self.inner.set_contains(table, key, member)
+    }
+
+    fn set_len(&self, table: &str, key: &str) -> Result<usize, KvError> {
+        self.inner.set_len(table, key)
+    }
+
+    fn set_keys(&self, table: &str) -> Result<Vec<String>, KvError> {
+        self.inner.set_keys(table)
+    }
+
+    fn zset_add(
+        &self,
+        table: &str,
+        key: &str,
+        members: Vec<(String, f64)>,
+    ) -> Result<usize, KvError> {
+        let added = !members.is_empty();
+        let n = self.inner.zset_add(table, key, members)?;
+        if added {
+            self.emit(table, key, "zadd", None, None);
+        }
+
+        Ok(n)
+    }
+
+    fn zset_incr(&self, table: &str, key: &str, member: &str, delta: f64) -> Result<f64, KvError> {
+        let score = self.inner.zset_incr(table, key, member, delta)?;
+        self.emit(table, key, "zincrby", None, None);
+
+        Ok(score)
+    }
+
+    fn zset_remove(&self, table: &str, key: &str, members: Vec<String>) -> Result<usize, KvError> {
+        let removed = self.inner.zset_remove(table, key, members)?;
+        if removed > 0 {
+            self.emit(table, key, "zrem", None, None);
+        }
+
+        Ok(removed)
+    }
+
+    fn zset_range(
+        &self,
+        table: &str,
+        key: &str,
+        start: i64,
+        stop: i64,
+    ) -> Result<Vec<(String, f64)>, KvError> {
+        self.inner.zset_range(table, key, start, stop)
+    }
+
+    fn zset_range_by_score(
+        &self,
+        table: &str,
+        key: &str,
+        min: f64,
+        max: f64,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<(String, f64)>, KvError> {
+        self.inner
+            .zset_range_by_score(table, key, min, max, offset, limit)
+    }
+
+    fn zset_score(&self, table: &str, key: &str, member: &str) -> Result<Option<f64>, KvError> {
+        self.inner.zset_score(table, key, member)
+    }
+
+    fn zset_rank(&self, table: &str, key: &str, member: &str) -> Result<Option<usize>, KvError> {
+        self.inner.zset_rank(table, key, member)
+    }
+
+    fn zset_len(&self, table: &str, key: &str) -> Result<usize, KvError> {
+        self.inner.zset_len(table, key)
+    }
+
+    fn zset_keys(&self, table: &str) -> Result<Vec<String>, KvError> {
+        self.inner.zset_keys(table)
+    }
+
+    fn transaction(
+        &self,
+        tables: &[&str],
+        f: &mut dyn FnMut(&dyn Storage) -> Result<(), KvError>,
+    ) -> Result<(), KvError> {
+        let events = RefCell::new(Vec::new());
+        self.inner.transaction(tables, &mut |txn| {
+            // 事务可能被重试，只保留最后一次执行的事件
+            events.borrow_mut().clear();
+            f(&Watched {
+                inner: txn,
+                emitter: &events,
+            })
+        })?;
+        for event in events.into_inner() {
+            self.emitter.emit(event);
+        }
+
+        Ok(())
+    }
+}
+
+impl<S: Storage> Storage for WatchedStorage<S> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.inner.get(table, key)
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        match self.watched(table, &key) {
+            Some((_order, w)) => w.set(table, key, value),
+            None => self.inner.set(table, key, value),
+        }
+    }
+
+    fn set_with_ttl(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> Result<Option<Value>, KvError> {
+        match self.watched(table, &key) {
+            Some((_order, w)) => w.set_with_ttl(table, key, value, ttl),
+            None => self.inner.set_with_ttl(table, key, value, ttl),
+        }
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.inner.contains(table, key)
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        match self.watched(table, key) {
+            Some((_order, w)) => w.del(table, key),
+            None => self.inner.del(table, key),
+        }
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.inner.get_all(table)
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        self.inner.get_iter(table)
+    }
+
+    fn scan(&self, table: &str, range: &KeyRange, limit: usize) -> Result<Vec<Kvpair>, KvError> {
+        self.inner.scan(table, range, limit)
+    }
+
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        self.inner.list_tables()
+    }
+
+    fn drop_table(&self, table: &str) -> Result<bool, KvError> {
+        match self.watched_all() {
+            Some((_order, w)) => w.drop_table(table),
+            None => self.inner.drop_table(table),
+        }
+    }
+
+    fn table_len(&self, table: &str) -> Result<usize, KvError> {
+        self.inner.table_len(table)
+    }
+
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        match self.watched(table, key) {
+            Some((_order, w)) => w.expire(table, key, ttl),
+            None => self.inner.expire(table, key, ttl),
+        }
+    }
+
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Duration>, KvError> {
+        self.inner.ttl(table, key)
+    }
+
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        match self.watched(table, key) {
+            Some((_order, w)) => w.persist(table, key),
+            None => self.inner.persist(table, key),
+        }
+    }
+
+    fn compare_and_swap(
+        &self,
+        table: &str,
+        key: &str,
+        expected: Option<&Value>,
+        value: Value,
+    ) -> Result<Result<(), Option<Value>>, KvError> {
+        match self.watched(table, key) {
+            Some((_order, w)) => w.compare_and_swap(table, key, expected, value),
+            None => self.inner.compare_and_swap(table, key, expected, value),
+        }
+    }
+
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        match self.watched(table, key) {
+            Some((_order, w)) => w.incr(table, key, delta),
+            None => self.inner.incr(table, key, delta),
+        }
+    }
+
+    fn incr_float(&self, table: &str, key: &str, delta: f64) -> Result<f64, KvError> {
+        match self.watched(table, key) {
+            Some((_order, w)) => w.incr_float(table, key, delta),
+            None => self.inner.incr_float(table, key, delta),
+        }
+    }
+
+    fn update_path(
+        &self,
+        table: &str,
+        key: &str,
+        path: &[PathSegment],
+        op: PathOp,
+    ) -> Result<Option<Value>, KvError> {
+        match self.watched(table, key) {
+            Some((_order, w)) => w.update_path(table, key, path, op),
+            None => self.inner.update_path(table, key, path, op),
+        }
+    }
+
+    fn create_index(&self, table: &str) -> Result<bool, KvError> {
+        self.inner.create_index(table)
+    }
+
+    fn find(&self, table: &str, value: &Value) -> Result<Vec<Kvpair>, KvError> {
+        self.inner.find(table, value)
+    }
+
+    fn history(&self, table: &str, key: &str) -> Result<Vec<Version>, KvError> {
+        self.inner.history(table, key)
+    }
+
+    fn get_version(&self, table: &str, key: &str, version: u64) -> Result<Option<Value>, KvError> {
+        self.inner.get_version(table, key, version)
+    }
+
+    fn get_at(&self, table: &str, key: &str, at: u64) -> Result<Option<Value>, KvError> {
+        self.inner.get_at(table, key, at)
+    }
+
+    fn gc_versions_in(
+        &self,
+        retention: Duration,
+        tables: &dyn Fn(&str) -> bool,
+    ) -> Result<usize, KvError> {
+        self.inner.gc_versions_in(retention, tables)
+    }
+
+    fn list_push(
+        &self,
+        table: &str,
+        key: &str,
+        end: ListEnd,
+        values: Vec<Value>,
+    ) -> Result<usize, KvError> {
+        match self.watched(table, key) {
+            Some((_order, w)) => w.list_push(table, key, end, values),
+            None => self.inner.list_push(table, key, end, values),
+        }
+    }
+
+    fn list_pop(
+        &self,
+        table: &str,
+        key: &str,
+        end: ListEnd,
+        count: usize,
+    ) -> Result<Vec<Value>, KvError> {
+        match self.watched(table, key) {
+            Some((_order, w)) => w.list_pop(table, key, end, count),
+            None => self.inner.list_pop(table, key, end, count),
+        }
+    }
+
+    fn list_range(
+        &self,
+        table: &str,
+        key: &str,
+        start: i64,
+        stop: i64,
+    ) -> Result<Vec<Value>, KvError> {
+        self.inner.list_range(table, key, start, stop)
+    }
+
+    fn list_trim(&self, table: &str, key: &str, start: i64, stop: i64) -> Result<usize, KvError> {
+        match self.watched(table, key) {
+            Some((_order, w)) => w.list_trim(table, key, start, stop),
+            None => self.inner.list_trim(table, key, start, stop),
+        }
+    }
+
+    fn list_len(&self, table: &str, key: &str) -> Result<usize, KvError> {
+        self.inner.list_len(table, key)
+    }
+
+    fn list_index(&self, table: &str, key: &str, index: i64) -> Result<Option<Value>, KvError> {
+        self.inner.list_index(table, key, index)
+    }
+
+    fn list_keys(&self, table: &str) -> Result<Vec<String>, KvError> {
+        self.inner.list_keys(table)
+    }
+
+    fn set_add(&self, table: &str, key: &str, members: Vec<String>) -> Result<usize, KvError> {
+        match self.watched(table, key) {
+            Some((_order, w)) => w.set_add(table, key, members),
+            None => self.inner.set_add(table, key, members),
+        }
+    }
+
+    fn set_remove(&self, table: &str, key: &str, members: Vec<String>) -> Result<usize, KvError> {
+        match self.watched(table, key) {
+            Some((_order, w)) => w.set_remove(table, key, members),
+            None => self.inner.set_remove(table, key, members),
+        }
+    }
+
+    fn set_members(&self, table: &str, key: &str) -> Result<Vec<String>, KvError> {
+        self.inner.set_members(table, key)
+    }
+
+    fn set_contains(&self, table: &str, key: &str, member: &str) -> Result<bool, KvError> {
+        self.inner.set_contains(table, key, member)
+    }
+
+    fn set_len(&self, table: &str, key: &str) -> Result<usize, KvError> {
+        self.inner.set_len(table, key)
+    }
+
+    fn set_keys(&self, table: &str) -> Result<Vec<String>, KvError> {
+        self.inner.set_keys(table)
+    }
+
+    fn zset_add(
+        &self,
+        table: &str,
+        key: &str,
+        members: Vec<(String, f64)>,
+    ) -> Result<usize, KvError> {
+        match self.watched(table, key) {
+            Some((_order, w)) => w.zset_add(table, key, members),
+            None => self.inner.zset_add(table, key, members),
+        }
+    }
+
+    fn zset_incr(&self, table: &str, key: &str, member: &str, delta: f64) -> Result<f64, KvError> {
+        match self.watched(table, key) {
+            Some((_order, w)) => w.zset_incr(table, key, member, delta),
+            None => self.inner.zset_incr(table, key, member, delta),
+        }
+    }
+
+    fn zset_remove(&self, table: &str, key: &str, members: Vec<String>) -> Result<usize, KvError> {
+        match self.watched(table, key) {
+            Some((_order, w)) => w.zset_remove(table, key, members),
+            None => self.inner.zset_remove(table, key, members),
+        }
+    }
+
+    fn zset_range(
+        &self,
+        table: &str,
+        key: &str,
+        start: i64,
+        stop: i64,
+    ) -> Result<Vec<(String, f64)>, KvError> {
+        self.inner.zset_range(table, key, start, stop)
+    }
+
+    fn zset_range_by_score(
+        &self,
+        table: &str,
+        key: &str,
+        min: f64,
+        max: f64,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<(String, f64)>, KvError> {
+        self.inner
+            .zset_range_by_score(table, key, min, max, offset, limit)
+    }
+
+    fn zset_score(&self, table: &str, key: &str, member: &str) -> Result<Option<f64>, KvError> {
+        self.inner.zset_score(table, key, member)
+    }
+
+    fn zset_rank(&self, table: &str, key: &str, member: &str) -> Result<Option<usize>, KvError> {
+        self.inner.zset_rank(table, key, member)
+    }
+
+    fn zset_len(&self, table: &str, key: &str) -> Result<usize, KvError> {
+        self.inner.zset_len(table, key)
+    }
+
+    fn zset_keys(&self, table: &str) -> Result<Vec<String>, KvError> {
+        self.inner.zset_keys(table)
+    }
+
+    fn transaction(
+        &self,
+        tables: &[&str],
+        f: &mut dyn FnMut(&dyn Storage) -> Result<(), KvError>,
+    ) -> Result<(), KvError> {
+        match self.watched_all() {
+            Some((_order, w)) => w.transaction(tables, f),
+            None => self.inner.transaction(tables, f),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemTable, PushMessage, Subscriber};
+
+    fn change(msg: PushMessage) -> ChangeEvent {
+        msg.change.unwrap()
+    }
+
+    fn event(
+        table: &str,
+        key: &str,
+        op: &str,
+        old: Option<Value>,
+        new: Option<Value>,
+    ) -> ChangeEvent {
+        ChangeEvent {
+            table: table.into(),
+            key: key.into(),
+            op: op.into(),
+            old,
+            new,
+            db: 0,
+        }
+    }
+
+    fn watched() -> (WatchedStorage<MemTable>, Subscriber) {
+        let broker = Arc::new(Broker::new());
+        let subscriber = broker.subscriber();
+        (WatchedStorage::new(MemTable::new(), broker), subscriber)
+    }
+
+    #[tokio::test]
+    async fn mutations_should_emit_change_events() {
+        let (store, mut sub) = watched();
+        // 没有 Watch 时不产生事件
+        store.set("t1", "k0".into(), "v0".into()).unwrap();
+
+        assert_eq!(sub.watch(0, "t1", "k"), 1);
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t1", "k1".into(), "v2".into()).unwrap();
+        store.set("t1", "x1".into(), "v1".into()).unwrap();
+        store.set("t2", "k1".into(), "v1".into()).unwrap();
+        store.incr("t1", "k2", 2).unwrap();
+        store.del("t1", "k1").unwrap();
+        store.del("t1", "k3").unwrap();
+        store.drop_table("t1").unwrap();
+
+        let expected = [
+            event("t1", "k1", "set", None, Some("v1".into())),
+            event("t1", "k1", "set", Some("v1".into()), Some("v2".into())),
+            event("t1", "k2", "set", None, Some(2.into())),
+            event("t1", "k1", "del", Some("v2".into()), None),
+            event("t1", "", "drop", None, None),
+        ];
+        for e in expected {
+            assert_eq!(change(sub.recv().await.unwrap()), e);
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_writes_should_emit_events_in_order() {
+        let (store, mut sub) = watched();
+        assert_eq!(sub.watch(0, "t1", ""), 1);
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..25 {
+                        store.incr("t1", "k1", 1).unwrap();
+                    }
+                });
+            }
+        });
+
+        // 每个事件的旧 value 都是上一个事件的新 value
+        let e = change(sub.recv().await.unwrap());
+        assert_eq!(e, event("t1", "k1", "set", None, Some(1.into())));
+        for i in 1..100i64 {
+            let e = change(sub.recv().await.unwrap());
+            assert_eq!(
+                e,
+                event("t1", "k1", "set", Some(i.into()), Some((i + 1).into()))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn incr_should_emit_real_old_value() {
+        let (store, mut sub) = watched();
+        assert_eq!(sub.watch(0, "t1", ""), 1);
+        store.incr_float("t1", "f1", 0.1).unwrap();
+        store.incr_float("t1", "f1", 0.2).unwrap();
+        store.incr("t1", "i1", 5).unwrap();
+
+        let e = change(sub.recv().await.unwrap());
+        assert_eq!(e, event("t1", "f1", "set", None, Some(0.1.into())));
+        // 旧的 value 是存储的值，不是 0.1 + 0.2 - 0.2 算出来的近似值
+        let e = change(sub.recv().await.unwrap());
+        assert_eq!(
+            e,
+            event(
+                "t1",
+                "f1",
+                "set",
+                Some(0.1.into()),
+                Some((0.1 + 0.2).into())
+            )
+        );
+        let e = change(sub.recv().await.unwrap());
+        assert_eq!(e, event("t1", "i1", "set", None, Some(5.into())));
+    }
+
+    #[tokio::test]
+    async fn transaction_should_emit_events_after_commit() {
+        let (store, mut sub) = watched();
+        assert_eq!(sub.watch(0, "", ""), 1);
+        let result = store.transaction(&["t1"], &mut |txn| {
+            txn.set("t1", "k1".into(), "v1".into())?;
+            Err(KvError::Internal("abort".into()))
+        });
+        assert!(result.is_err());
+        store
+            .transaction(&["t1", "t2"], &mut |txn| {
+                txn.set("t1", "k1".into(), "v1".into())?;
+                txn.set("t2", "k2".into(), "v2".into())?;
+                Ok(())
+            })
+            .unwrap();
+
+        let e = change(sub.recv().await.unwrap());
+        assert_eq!(e, event("t1", "k1", "set", None, Some("v1".into())));
+        let e = change(sub.recv().await.unwrap());
+        assert_eq!(e, event("t2", "k2", "set", None, Some("v2".into())));
+
+        assert_eq!(sub.unwatch(), 0);
+        assert!(!store.broker.watching());
+    }
+}
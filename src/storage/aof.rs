@@ -0,0 +1,1691 @@
+use bytes::BytesMut;
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+    convert::TryFrom,
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, Weak},
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::{error, info, warn};
+
+use crate::{
+    command_request::RequestData, dispatch, memory::MemTable, network::frame::read_frame_blocking,
+    CommandRequest, FrameCoder, Hcas, Hdel, Hdelpath, Hexpireat, Hincrby, Hincrbyfloat, Hpersist,
+    Hsetpath, KeyRange, KvError, Kvpair, ListEnd, Lpop, Lpush, Ltrim, PathOp, PathSegment, Rpop,
+    Rpush, Sadd, ScoredMember, Srem, Storage, Value, Version, Zadd, Zrem,
+};
+
+/// EverySecond 策略下后台 fsync 的间隔
+const FSYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 什么时候把 AOF 的内容刷到磁盘上
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// 每次写入后都 fsync，最安全也最慢
+    Always,
+    /// 后台线程每秒 fsync 一次，崩溃时最多丢失一秒的数据
+    EverySecond,
+    /// 从不主动 fsync，交给操作系统决定
+    Never,
+}
+
+#[derive(Clone, Debug)]
+pub struct AofConfig {
+    pub fsync: FsyncPolicy,
+    /// AOF 至少达到这个大小（字节）才会自动重写
+    pub rewrite_min_size: u64,
+    /// AOF 比上次重写后增长了这个百分比时自动重写，为 0 表示不自动重写
+    pub rewrite_percentage: u64,
+}
+
+impl Default for AofConfig {
+    fn default() -> Self {
+        Self {
+            fsync: FsyncPolicy::EverySecond,
+            rewrite_min_size: 64 * 1024 * 1024,
+            rewrite_percentage: 100,
+        }
+    }
+}
+
+/// 把所有修改操作以 CommandRequest frame 的格式追加写入文件的 Storage，启动时重放文件恢复数据。
+/// 所有修改操作串行执行，保证文件里命令的顺序和实际执行的顺序一致。
+/// 写入 AOF 失败之后拒绝所有的修改，需要重启恢复
+pub struct AofStorage<S = MemTable> {
+    inner: Arc<S>,
+    log: Arc<AofLog>,
+}
+
+struct AofLog {
+    path: PathBuf,
+    config: AofConfig,
+    writer: Mutex<AofWriter>,
+}
+
+struct AofWriter {
+    file: File,
+    // 当前文件的大小
+    size: u64,
+    // 上次重写（或启动）后文件的大小，用来判断是否需要自动重写
+    base_size: u64,
+    // 有没有还没 fsync 的数据
+    dirty: bool,
+    // 正在重写时记录被修改过的 key，重写完成前在新文件末尾补上它们最新的数据
+    rewrite: Option<Touched>,
+    // 写入失败之后内存里的数据和 AOF 不再一致
+    failed: bool,
+    // 建立了索引的 table，重写时在新文件末尾重新建立它们的索引
+    indexes: BTreeSet<String>,
+}
+
+/// 重写期间被修改过的 key 和 table，快照中它们的数据可能已经过时
+#[derive(Default)]
+struct Touched {
+    keys: BTreeSet<(String, String)>,
+    tables: BTreeSet<String>,
+}
+
+/// 快照中一个 key 的数据，同一个 key 的普通 value、list、set 和 sorted set 分别是一条记录
+type Snapshot = BTreeMap<(String, String), Vec<Record>>;
+
+enum Record {
+    Value(Value, Option<u64>),
+    List(Vec<Value>),
+    Set(Vec<String>),
+    Zset(Vec<ScoredMember>),
+}
+
+impl AofStorage<MemTable> {
+    /// 打开（或创建）path 处的 AOF，重放其中的命令恢复 MemTable
+    pub fn open(path: impl AsRef<Path>, config: AofConfig) -> Result<Self, KvError> {
+        Self::with_store(MemTable::new(), path, config)
+    }
+}
+
+impl<S: Storage + Send + Sync + 'static> AofStorage<S> {
+    /// 用 store 作为内部存储打开 AOF，AOF 中的命令会重放到 store 里
+    pub fn with_store(
+        store: S,
+        path: impl AsRef<Path>,
+        config: AofConfig,
+    ) -> Result<Self, KvError> {
+        let path = path.as_ref().to_path_buf();
+        store.track_evictions();
+        let mut indexes = BTreeSet::new();
+        let size = replay(&path, &store, &mut indexes)?;
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+
+        let log = Arc::new(AofLog {
+            path,
+            writer: Mutex::new(AofWriter {
+                file,
+                size,
+                base_size: size,
+                dirty: false,
+                rewrite: None,
+                failed: false,
+                indexes,
+            }),
+            config,
+        });
+        if log.config.fsync == FsyncPolicy::EverySecond {
+            spawn_fsync(Arc::downgrade(&log));
+        }
+
+        Ok(Self {
+            inner: Arc::new(store),
+            log,
+        })
+    }
+
+    /// 在后台线程里重写 AOF：用当前数据的快照替换掉历史命令。已经在重写时返回 None
+    pub fn rewrite(&self) -> Result<Option<JoinHandle<Result<(), KvError>>>, KvError> {
+        let mut writer = self.log.writer.lock().unwrap();
+        Ok(self.start_rewrite(&mut writer))
+    }
+
+    // 在锁里开始记录被修改的 key，快照在后台线程里生成，不阻塞写入
+    fn start_rewrite(&self, writer: &mut AofWriter) -> Option<JoinHandle<Result<(), KvError>>> {
+        if writer.rewrite.is_some() {
+            return None;
+        }
+        writer.rewrite = Some(Touched::default());
+
+        let log = self.log.clone();
+        let store = self.inner.clone();
+        Some(thread::spawn(move || log.rewrite(&*store)))
+    }
+
+    fn logged(&self) -> Logged<'_, S, Self> {
+        Logged {
+            inner: &self.inner,
+            recorder: self,
+        }
+    }
+}
+
+impl AofLog {
+    fn rewrite(&self, store: &impl Storage) -> Result<(), KvError> {
+        let mut tmp = OsString::from(self.path.as_os_str());
+        tmp.push(".rewrite");
+        let tmp = PathBuf::from(tmp);
+
+        let result = self.write_rewrite(&tmp, store);
+        match &result {
+            Ok(size) => info!("AOF rewritten: {} bytes", size),
+            Err(e) => {
+                warn!("Failed to rewrite AOF: {}", e);
+                self.writer.lock().unwrap().rewrite = None;
+                let _ = fs::remove_file(&tmp);
+            }
+        }
+
+        result.map(|_| ())
+    }
+
+    fn write_rewrite(&self, tmp: &Path, store: &impl Storage) -> Result<u64, KvError> {
+        // 生成和写入快照时不持有锁，这期间被修改的 key 在快照中可能已经过时
+        let snapshot = snapshot(store)?;
+        let mut file = BufWriter::new(File::create(tmp)?);
+        let mut size = 0;
+        for ((table, key), records) in &snapshot {
+            for record in records {
+                size += write_commands(&mut file, record.commands(table, key))?;
+            }
+        }
+        file.flush()?;
+        file.get_ref().sync_data()?;
+
+        // 最后在锁里撤销被修改过的 key 在快照中的数据，写入它们最新的数据并替换旧文件，
+        // 这期间不能有新的写入。被删除的 table 整个重新写入
+        let mut writer = self.writer.lock().unwrap();
+        let touched = writer.rewrite.take().unwrap_or_default();
+        let mut cmds = Vec::new();
+        for table in &touched.tables {
+            cmds.push(CommandRequest::new_hdrop(table));
+            let mut current = Snapshot::new();
+            snapshot_table(store, table, &mut current)?;
+            for ((table, key), records) in &current {
+                records
+                    .iter()
+                    .for_each(|r| cmds.extend(r.commands(table, key)));
+            }
+        }
+        for (table, key) in &touched.keys {
+            if touched.tables.contains(table) {
+                continue;
+            }
+            if let Some(records) = snapshot.get(&(table.clone(), key.clone())) {
+                cmds.extend(records.iter().map(|r| r.undo(table, key)));
+            }
+            for record in snapshot_key(store, table, key)? {
+                cmds.extend(record.commands(table, key));
+            }
+        }
+        // 索引在数据之后建立，重放时会包含已有的数据
+        cmds.extend(writer.indexes.iter().map(CommandRequest::new_create_index));
+        size += write_commands(&mut file, cmds)?;
+        let file = file.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        fs::rename(tmp, &self.path)?;
+
+        writer.file = OpenOptions::new().append(true).open(&self.path)?;
+        writer.size = size;
+        writer.base_size = size;
+        writer.dirty = false;
+
+        Ok(size)
+    }
+}
+
+impl AofWriter {
+    // 每个命令写成一个 frame，一起写入文件
+    fn append(&mut self, frames: Vec<CommandRequest>, fsync: FsyncPolicy) -> Result<(), KvError> {
+        if frames.is_empty() {
+            return Ok(());
+        }
+        let mut buf = BytesMut::new();
+        for cmd in frames {
+            cmd.encode_frame(&mut buf)?;
+        }
+
+        self.file.write_all(&buf)?;
+        self.size += buf.len() as u64;
+        match fsync {
+            FsyncPolicy::Always => self.file.sync_data()?,
+            _ => self.dirty = true,
+        }
+
+        Ok(())
+    }
+
+    fn should_rewrite(&self, config: &AofConfig) -> bool {
+        config.rewrite_percentage > 0
+            && self.rewrite.is_none()
+            && self.size >= config.rewrite_min_size
+            && self.size >= self.base_size + self.base_size * config.rewrite_percentage / 100
+    }
+}
+
+impl Drop for AofLog {
+    fn drop(&mut self) {
+        if let Ok(writer) = self.writer.get_mut() {
+            if writer.dirty {
+                let _ = writer.file.sync_data();
+            }
+        }
+    }
+}
+
+// 一个操作的多个命令包在一个 Transaction 里，重放时要么都执行，要么都不执行。
+// Transaction 不能嵌套，其中的 Transaction 展开成它包含的命令
+fn merge_commands(mut cmds: Vec<CommandRequest>) -> Option<CommandRequest> {
+    match cmds.len() {
+        0 => None,
+        1 => cmds.pop(),
+        _ => Some(CommandRequest::new_transaction(
+            cmds.into_iter()
+                .flat_map(|cmd| match cmd.request_data {
+                    Some(RequestData::Transaction(txn)) => txn.commands,
+                    _ => vec![cmd],
+                })
+                .collect(),
+        )),
+    }
+}
+
+/// 执行一个修改操作，成功后记录它对应的命令
+trait Recorder {
+    fn record<T>(
+        &self,
+        f: impl FnOnce() -> Result<T, KvError>,
+        cmds: impl FnOnce(&T) -> Vec<CommandRequest>,
+    ) -> Result<T, KvError>;
+}
+
+impl<S: Storage + Send + Sync + 'static> Recorder for AofStorage<S> {
+    // 修改数据和写入 AOF 在同一把锁里完成，文件里命令的顺序就是执行的顺序
+    fn record<T>(
+        &self,
+        f: impl FnOnce() -> Result<T, KvError>,
+        cmds: impl FnOnce(&T) -> Vec<CommandRequest>,
+    ) -> Result<T, KvError> {
+        let mut writer = self.log.writer.lock().unwrap();
+        if writer.failed {
+            return Err(KvError::Internal(
+                "AOF is unavailable after a failed write, restart the server to recover".into(),
+            ));
+        }
+        let result = f();
+        // 执行过程中因为内存不足被淘汰的 key 记录成在命令之前删除，命令失败时也要记录。
+        // 每个删除单独写成一个 frame，不和命令放进同一个 Transaction，
+        // 否则 list、set 和 sorted set 的命令重放时会被 Transaction 拒绝
+        let mut frames: Vec<_> = self
+            .inner
+            .take_evicted()
+            .into_iter()
+            .map(|(table, key)| CommandRequest::new_hdel(table, key))
+            .collect();
+        if let Ok(result) = &result {
+            frames.extend(merge_commands(cmds(result)));
+        }
+        if let Some(touched) = writer.rewrite.as_mut() {
+            frames.iter().for_each(|cmd| touched.add(cmd));
+        }
+        frames
+            .iter()
+            .for_each(|cmd| add_index(cmd, &mut writer.indexes));
+        if let Err(e) = writer.append(frames, self.log.config.fsync) {
+            // 修改已经生效但没有写入 AOF，之后的命令再写入的话重启后的数据就和现在不一致了
+            error!("Failed to append to AOF, rejecting all writes: {}", e);
+            writer.failed = true;
+            return Err(e);
+        }
+
+        if writer.should_rewrite(&self.log.config) {
+            info!("AOF grows to {} bytes, start rewriting", writer.size);
+            self.start_rewrite(&mut writer);
+        }
+
+        result
+    }
+}
+
+// 事务里的命令先记在这里，事务提交后再作为一个 Transaction 写入 AOF
+impl Recorder for RefCell<Vec<CommandRequest>> {
+    fn record<T>(
+        &self,
+        f: impl FnOnce() -> Result<T, KvError>,
+        cmds: impl FnOnce(&T) -> Vec<CommandRequest>,
+    ) -> Result<T, KvError> {
+        let result = f()?;
+        self.borrow_mut().extend(cmds(&result));
+
+        Ok(result)
+    }
+}
+
+/// 把修改操作转换成等价的命令交给 Recorder 记录的 Storage。
+/// 过期时间记录成绝对的时刻，重放时不会因为重启而延长
+struct Logged<'a, S: ?Sized, R> {
+    inner: &'a S,
+    recorder: &'a R,
+}
+
+impl<S: Storage + ?Sized, R: Recorder> Storage for Logged<'_, S, R> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.inner.get(table, key)
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        let cmd = CommandRequest::new_hset(table, key.clone(), value.clone());
+        self.recorder
+            .record(|| self.inner.set(table, key, value), |_| vec![cmd])
+    }
+
+    fn set_with_ttl(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> Result<Option<Value>, KvError> {
+        let cmds = vec![
+            CommandRequest::new_hset(table, key.clone(), value.clone()),
+            CommandRequest::new_hexpireat(table, key.clone(), deadline(ttl)),
+        ];
+        self.recorder
+            .record(|| self.inner.set_with_ttl(table, key, value, ttl), |_| cmds)
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.inner.contains(table, key)
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.recorder.record(
+            || self.inner.del(table, key),
+            |old| match old {
+                Some(_) => vec![CommandRequest::new_hdel(table, key)],
+                None => vec![],
+            },
+        )
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.inner.get_all(table)
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        self.inner.get_iter(table)
+    }
+
+    fn scan(&self, table: &str, range: &KeyRange, limit: usize) -> Result<Vec<Kvpair>, KvError> {
+        self.inner.scan(table, range, limit)
+    }
+
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        self.inner.list_tables()
+    }
+
+    fn drop_table(&self, table: &str) -> Result<bool, KvError> {
+        self.recorder.record(
+            || self.inner.drop_table(table),
+            |existed| {
+                if *existed {
+                    vec![CommandRequest::new_hdrop(table)]
+                } else {
+                    vec![]
+                }
+            },
+        )
+    }
+
+    fn table_len(&self, table: &str) -> Result<usize, KvError> {
+        self.inner.table_len(table)
+    }
+
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        let cmd = CommandRequest::new_hexpireat(table, key, deadline(ttl));
+        self.recorder.record(
+            || self.inner.expire(table, key, ttl),
+            |found| if *found { vec![cmd] } else { vec![] },
+        )
+    }
+
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Duration>, KvError> {
+        self.inner.ttl(table, key)
+    }
+
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.recorder.record(
+            || self.inner.persist(table, key),
+            |persisted| {
+                if *persisted {
+                    vec![CommandRequest::new_hpersist(table, key)]
+                } else {
+                    vec![]
+                }
+            },
+        )
+    }
+
+    fn compare_and_swap(
+        &self,
+        table: &str,
+        key: &str,
+        expected: Option<&Value>,
+        value: Value,
+    ) -> Result<Result<(), Option<Value>>, KvError> {
+        let cmd = CommandRequest::new_hcas(table, key, expected.cloned(), value.clone());
+        self.recorder.record(
+            || self.inner.compare_and_swap(table, key, expected, value),
+            |result| match result {
+                Ok(()) => vec![cmd],
+                Err(_) => vec![],
+            },
+        )
+    }
+
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        self.recorder.record(
+            || self.inner.incr(table, key, delta),
+            |_| vec![CommandRequest::new_hincrby(table, key, delta)],
+        )
+    }
+
+    fn incr_float(&self, table: &str, key: &str, delta: f64) -> Result<f64, KvError> {
+        self.recorder.record(
+            || self.inner.incr_float(table, key, delta),
+            |_| vec![CommandRequest::new_hincrbyfloat(table, key, delta)],
+        )
+    }
+
+    // 删除不存在的元素时没有修改，不需要记录。追加记录成依次在数组末尾写入每个元素
+    fn update_path(
+        &self,
+        table: &str,
+        key: &str,
+        path: &[PathSegment],
+        op: PathOp,
+    ) -> Result<Option<Value>, KvError> {
+        self.recorder.record(
+            || self.inner.update_path(table, key, path, op.clone()),
+            |old| match &op {
+                PathOp::Set(value) => vec![CommandRequest::new_hsetpath(
+                    table,
+                    key,
+                    path.to_vec(),
+                    value.clone(),
+                )],
+                PathOp::Delete if old.is_some() => {
+                    vec![CommandRequest::new_hdelpath(table, key, path.to_vec())]
+                }
+                PathOp::Delete => vec![],
+                PathOp::Append(values) => {
+                    let len = old.clone().map_or(Ok(0), i64::try_from).unwrap_or_default();
+                    let start = len - values.len() as i64;
+                    values
+                        .iter()
+                        .enumerate()
+                        .map(|(i, value)| {
+                            let mut path = path.to_vec();
+                            path.push((start + i as i64).into());
+                            CommandRequest::new_hsetpath(table, key, path, value.clone())
+                        })
+                        .collect()
+                }
+            },
+        )
+    }
+
+    fn create_index(&self, table: &str) -> Result<bool, KvError> {
+        self.recorder.record(
+            || self.inner.create_index(table),
+            |created| {
+                if *created {
+                    vec![CommandRequest::new_create_index(table)]
+                } else {
+                    vec![]
+                }
+            },
+        )
+    }
+
+    fn find(&self, table: &str, value: &Value) -> Result<Vec<Kvpair>, KvError> {
+        self.inner.find(table, value)
+    }
+
+    fn history(&self, table: &str, key: &str) -> Result<Vec<Version>, KvError> {
+        self.inner.history(table, key)
+    }
+
+    fn get_version(&self, table: &str, key: &str, version: u64) -> Result<Option<Value>, KvError> {
+        self.inner.get_version(table, key, version)
+    }
+
+    fn get_at(&self, table: &str, key: &str, at: u64) -> Result<Option<Value>, KvError> {
+        self.inner.get_at(table, key, at)
+    }
+
+    fn gc_versions_in(
+        &self,
+        retention: Duration,
+        tables: &dyn Fn(&str) -> bool,
+    ) -> Result<usize, KvError> {
+        self.inner.gc_versions_in(retention, tables)
+    }
+
+    fn list_push(
+        &self,
+        table: &str,
+        key: &str,
+        end: ListEnd,
+        values: Vec<Value>,
+    ) -> Result<usize, KvError> {
+        let cmd = match end {
+            ListEnd::Left => CommandRequest::new_lpush(table, key, values.clone()),
+            ListEnd::Right => CommandRequest::new_rpush(table, key, values.clone()),
+        };
+        let pushed = !values.is_empty();
+        self.recorder.record(
+            || self.inner.list_push(table, key, end, values),
+            |_| if pushed { vec![cmd] } else { vec![] },
+        )
+    }
+
+    // 记录实际取出的元素数量，重放时取出同样多的元素
+    fn list_pop(
+        &self,
+        table: &str,
+        key: &str,
+        end: ListEnd,
+        count: usize,
+    ) -> Result<Vec<Value>, KvError> {
+        self.recorder.record(
+            || self.inner.list_pop(table, key, end, count),
+            |popped| {
+                let n = popped.len() as u32;
+                match (n, end) {
+                    (0, _) => vec![],
+                    (_, ListEnd::Left) => vec![CommandRequest::new_lpop(table, key, n)],
+                    (_, ListEnd::Right) => vec![CommandRequest::new_rpop(table, key, n)],
+                }
+            },
+        )
+    }
+
+    fn list_range(
+        &self,
+        table: &str,
+        key: &str,
+        start: i64,
+        stop: i64,
+    ) -> Result<Vec<Value>, KvError> {
+        self.inner.list_range(table, key, start, stop)
+    }
+
+    fn list_trim(&self, table: &str, key: &str, start: i64, stop: i64) -> Result<usize, KvError> {
+        self.recorder.record(
+            || self.inner.list_trim(table, key, start, stop),
+            |removed| match removed {
+                0 => vec![],
+                _ => vec![CommandRequest::new_ltrim(table, key, start, stop)],
+            },
+        )
+    }
+
+    fn list_len(&self, table: &str, key: &str) -> Result<usize, KvError> {
+        self.inner.list_len(table, key)
+    }
+
+    fn list_index(&self, table: &str, key: &str, index: i64) -> Result<Option<Value>, KvError> {
+        self.inner.list_index(table, key, index)
+    }
+
+    fn list_keys(&self, table: &str) -> Result<Vec<String>, KvError> {
+        self.inner.list_keys(table)
+    }
+
+    fn set_add(&self, table: &str, key: &str, members: Vec<String>) -> Result<usize, KvError> {
+        let cmd = CommandRequest::new_sadd(table, key, members.clone());
+        self.recorder.record(
+            || self.inner.set_add(table, key, members),
+            |added| if *added > 0 { vec![cmd] } else { vec![] },
+        )
+    }
+
+    fn set_remove(&self, table: &str, key: &str, members: Vec<String>) -> Result<usize, KvError> {
+        let cmd = CommandRequest::new_srem(table, key, members.clone());
+        self.recorder.record(
+            || self.inner.set_remove(table, key, members),
+            |removed| if *removed > 0 { vec![cmd] } else { vec![] },
+        )
+    }
+
+    fn set_members(&self, table: &str, key: &str) -> Result<Vec<String>, KvError> {
+        self.inner.set_members(table, key)
+    }
+
+    fn set_contains(&self, table: &str, key: &str, member: &str) -> Result<bool, KvError> {
+        self.inner.set_contains(table, key, member)
+    }
+
+    fn set_len(&self, table: &str, key: &str) -> Result<usize, KvError> {
+        self.inner.set_len(table, key)
+    }
+
+    fn set_keys(&self, table: &str) -> Result<Vec<String>, KvError> {
+        self.inner.set_keys(table)
+    }
+
+    fn zset_add(
+        &self,
+        table: &str,
+        key: &str,
+        members: Vec<(String, f64)>,
+    ) -> Result<usize, KvError> {
+        let scored = members
+            .iter()
+            .map(|(m, s)| ScoredMember::new(m, *s))
+            .collect();
+        let cmd = CommandRequest::new_zadd(table, key, scored);
+        let added = !members.is_empty();
+        self.recorder.record(
+            || self.inner.zset_add(table, key, members),
+            |_| if added { vec![cmd] } else { vec![] },
+        )
+    }
+
+    // 记录加上之后的 score，重放时直接写入
+    fn zset_incr(&self, table: &str, key: &str, member: &str, delta: f64) -> Result<f64, KvError> {
+        self.recorder.record(
+            || self.inner.zset_incr(table, key, member, delta),
+            |score| {
+                let members = vec![ScoredMember::new(member, *score)];
+                vec![CommandRequest::new_zadd(table, key, members)]
+            },
+        )
+    }
+
+    fn zset_remove(&self, table: &str, key: &str, members: Vec<String>) -> Result<usize, KvError> {
+        let cmd = CommandRequest::new_zrem(table, key, members.clone());
+        self.recorder.record(
+            || self.inner.zset_remove(table, key, members),
+            |removed| if *removed > 0 { vec![cmd] } else { vec![] },
+        )
+    }
+
+    fn zset_range(
+        &self,
+        table: &str,
+        key: &str,
+        start: i64,
+        stop: i64,
+    ) -> Result<Vec<(String, f64)>, KvError> {
+        self.inner.zset_range(table, key, start, stop)
+    }
+
+    fn zset_range_by_score(
+        &self,
+        table: &str,
+        key: &str,
+        min: f64,
+        max: f64,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<(String, f64)>, KvError> {
+        self.inner
+            .zset_range_by_score(table, key, min, max, offset, limit)
+    }
+
+    fn zset_score(&self, table: &str, key: &str, member: &str) -> Result<Option<f64>, KvError> {
+        self.inner.zset_score(table, key, member)
+    }
+
+    fn zset_rank(&self, table: &str, key: &str, member: &str) -> Result<Option<usize>, KvError> {
+        self.inner.zset_rank(table, key, member)
+    }
+
+    fn zset_len(&self, table: &str, key: &str) -> Result<usize, KvError> {
+        self.inner.zset_len(table, key)
+    }
+
+    fn zset_keys(&self, table: &str) -> Result<Vec<String>, KvError> {
+        self.inner.zset_keys(table)
+    }
+
+    fn transaction(
+        &self,
+        tables: &[&str],
+        f: &mut dyn FnMut(&dyn Storage) -> Result<(), KvError>,
+    ) -> Result<(), KvError> {
+        self.recorder.record(
+            || {
+                let cmds = RefCell::new(Vec::new());
+                self.inner.transaction(tables, &mut |txn| {
+                    // 事务可能被重试，只保留最后一次执行的命令
+                    cmds.borrow_mut().clear();
+                    f(&Logged {
+                        inner: txn,
+                        recorder: &cmds,
+                    })
+                })?;
+                Ok(cmds.into_inner())
+            },
+            |cmds| {
+                if cmds.is_empty() {
+                    vec![]
+                } else {
+                    vec![CommandRequest::new_transaction(cmds.clone())]
+                }
+            },
+        )?;
+
+        Ok(())
+    }
+}
+
+impl<S: Storage + Send + Sync + 'static> Storage for AofStorage<S> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.inner.get(table, key)
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        self.logged().set(table, key, value)
+    }
+
+    fn set_with_ttl(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> Result<Option<Value>, KvError> {
+        self.logged().set_with_ttl(table, key, value, ttl)
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.inner.contains(table, key)
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.logged().del(table, key)
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.inner.get_all(table)
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        self.inner.get_iter(table)
+    }
+
+    fn scan(&self, table: &str, range: &KeyRange, limit: usize) -> Result<Vec<Kvpair>, KvError> {
+        self.inner.scan(table, range, limit)
+    }
+
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        self.inner.list_tables()
+    }
+
+    fn drop_table(&self, table: &str) -> Result<bool, KvError> {
+        self.logged().drop_table(table)
+    }
+
+    fn table_len(&self, table: &str) -> Result<usize, KvError> {
+        self.inner.table_len(table)
+    }
+
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        self.logged().expire(table, key, ttl)
+    }
+
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Duration>, KvError> {
+        self.inner.ttl(table, key)
+    }
+
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.logged().persist(table, key)
+    }
+
+    fn compare_and_swap(
+        &self,
+        table: &str,
+        key: &str,
+        expected: Option<&Value>,
+        value: Value,
+    ) -> Result<Result<(), Option<Value>>, KvError> {
+        self.logged().compare_and_swap(table, key, expected, value)
+    }
+
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        self.logged().incr(table, key, delta)
+    }
+
+    fn incr_float(&self, table: &str, key: &str, delta: f64) -> Result<f64, KvError> {
+        self.logged().incr_float(table, key, delta)
+    }
+
+    fn update_path(
+        &self,
+        table: &str,
+        key: &str,
+        path: &[PathSegment],
+        op: PathOp,
+    ) -> Result<Option<Value>, KvError> {
+        self.logged().update_path(table, key, path, op)
+    }
+
+    fn create_index(&self, table: &str) -> Result<bool, KvError> {
+        self.logged().create_index(table)
+    }
+
+    fn find(&self, table: &str, value: &Value) -> Result<Vec<Kvpair>, KvError> {
+        self.inner.find(table, value)
+    }
+
+    fn history(&self, table: &str, key: &str) -> Result<Vec<Version>, KvError> {
+        self.inner.history(table, key)
+    }
+
+    fn get_version(&self, table: &str, key: &str, version: u64) -> Result<Option<Value>, KvError> {
+        self.inner.get_version(table, key, version)
+    }
+
+    fn get_at(&self, table: &str, key: &str, at: u64) -> Result<Option<Value>, KvError> {
+        self.inner.get_at(table, key, at)
+    }
+
+    // 清理旧版本不影响读到的数据，不需要写入日志
+    fn gc_versions_in(
+        &self,
+        retention: Duration,
+        tables: &dyn Fn(&str) -> bool,
+    ) -> Result<usize, KvError> {
+        self.inner.gc_versions_in(retention, tables)
+    }
+
+    fn list_push(
+        &self,
+        table: &str,
+        key: &str,
+        end: ListEnd,
+        values: Vec<Value>,
+    ) -> Result<usize, KvError> {
+        self.logged().list_push(table, key, end, values)
+    }
+
+    fn list_pop(
+        &self,
+        table: &str,
+        key: &str,
+        end: ListEnd,
+        count: usize,
+    ) -> Result<Vec<Value>, KvError> {
+        self.logged().list_pop(table, key, end, count)
+    }
+
+    fn list_range(
+        &self,
+        table: &str,
+        key: &str,
+        start: i64,
+        stop: i64,
+    ) -> Result<Vec<Value>, KvError> {
+        self.inner.list_range(table, key, start, stop)
+    }
+
+    fn list_trim(&self, table: &str, key: &str, start: i64, stop: i64) -> Result<usize, KvError> {
+        self.logged().list_trim(table, key, start, stop)
+    }
+
+    fn list_len(&self, table: &str, key: &str) -> Result<usize, KvError> {
+        self.inner.list_len(table, key)
+    }
+
+    fn list_index(&self, table: &str, key: &str, index: i64) -> Result<Option<Value>, KvError> {
+        self.inner.list_index(table, key, index)
+    }
+
+    fn list_keys(&self, table: &str) -> Result<Vec<String>, KvError> {
+        self.inner.list_keys(table)
+    }
+
+    fn set_add(&self, table: &str, key: &str, members: Vec<String>) -> Result<usize, KvError> {
+        self.logged().set_add(table, key, members)
+    }
+
+    fn set_remove(&self, table: &str, key: &str, members: Vec<String>) -> Result<usize, KvError> {
+        self.logged().set_remove(table, key, members)
+    }
+
+    fn set_members(&self, table: &str, key: &str) -> Result<Vec<String>, KvError> {
+        self.inner.set_members(table, key)
+    }
+
+    fn set_contains(&self, table: &str, key: &str, member: &str) -> Result<bool, KvError> {
+        self.inner.set_contains(table, key, member)
+    }
+
+    fn set_len(&self, table: &str, key: &str) -> Result<usize, KvError> {
+        self.inner.set_len(table, key)
+    }
+
+    fn set_keys(&self, table: &str) -> Result<Vec<String>, KvError> {
+        self.inner.set_keys(table)
+    }
+
+    fn zset_add(
+        &self,
+        table: &str,
+        key: &str,
+        members: Vec<(String, f64)>,
+    ) -> Result<usize, KvError> {
+        self.logged().zset_add(table, key, members)
+    }
+
+    fn zset_incr(&self, table: &str, key: &str, member: &str, delta: f64) -> Result<f64, KvError> {
+        self.logged().zset_incr(table, key, member, delta)
+    }
+
+    fn zset_remove(&self, table: &str, key: &str, members: Vec<String>) -> Result<usize, KvError> {
+        self.logged().zset_remove(table, key, members)
+    }
+
+    fn zset_range(
+        &self,
+        table: &str,
+        key: &str,
+        start: i64,
+        stop: i64,
+    ) -> Result<Vec<(String, f64)>, KvError> {
+        self.inner.zset_range(table, key, start, stop)
+    }
+
+    fn zset_range_by_score(
+        &self,
+        table: &str,
+        key: &str,
+        min: f64,
+        max: f64,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<(String, f64)>, KvError> {
+        self.inner
+            .zset_range_by_score(table, key, min, max, offset, limit)
+    }
+
+    fn zset_score(&self, table: &str, key: &str, member: &str) -> Result<Option<f64>, KvError> {
+        self.inner.zset_score(table, key, member)
+    }
+
+    fn zset_rank(&self, table: &str, key: &str, member: &str) -> Result<Option<usize>, KvError> {
+        self.inner.zset_rank(table, key, member)
+    }
+
+    fn zset_len(&self, table: &str, key: &str) -> Result<usize, KvError> {
+        self.inner.zset_len(table, key)
+    }
+
+    fn zset_keys(&self, table: &str) -> Result<Vec<String>, KvError> {
+        self.inner.zset_keys(table)
+    }
+
+    fn transaction(
+        &self,
+        tables: &[&str],
+        f: &mut dyn FnMut(&dyn Storage) -> Result<(), KvError>,
+    ) -> Result<(), KvError> {
+        self.logged().transaction(tables, f)
+    }
+}
+
+// 重放 AOF 中的所有命令，返回有效内容的长度。文件末尾不完整的 frame（写入时崩溃）会被截掉
+fn replay(
+    path: &Path,
+    store: &impl Storage,
+    indexes: &mut BTreeSet<String>,
+) -> Result<u64, KvError> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+    let len = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+
+    let mut offset = 0;
+    let mut count = 0;
+    while let Some(mut buf) = read_frame_blocking(&mut reader)? {
+        offset += buf.len() as u64;
+        let cmd = CommandRequest::decode_frame(&mut buf)?;
+        add_index(&cmd, indexes);
+        let res = dispatch(cmd, store);
+        if res.status != 200 {
+            warn!("Failed to replay command {}: {}", count, res.message);
+        }
+        count += 1;
+    }
+    info!("Replayed {} commands from {:?}", count, path);
+
+    if offset < len {
+        warn!(
+            "AOF {:?} has {} bytes of incomplete data, truncated",
+            path,
+            len - offset
+        );
+        OpenOptions::new().write(true).open(path)?.set_len(offset)?;
+    }
+
+    Ok(offset)
+}
+
+// 把 store 当前的数据按 key 整理成快照
+fn snapshot(store: &impl Storage) -> Result<Snapshot, KvError> {
+    let mut snapshot = Snapshot::new();
+    for table in store.list_tables()? {
+        snapshot_table(store, &table, &mut snapshot)?;
+    }
+
+    Ok(snapshot)
+}
+
+fn snapshot_table(
+    store: &impl Storage,
+    table: &str,
+    snapshot: &mut Snapshot,
+) -> Result<(), KvError> {
+    let mut keys = BTreeSet::new();
+    keys.extend(store.list_keys(table)?);
+    keys.extend(store.set_keys(table)?);
+    keys.extend(store.zset_keys(table)?);
+    keys.extend(store.get_iter(table)?.map(|pair| pair.key));
+    for key in keys {
+        let records = snapshot_key(store, table, &key)?;
+        if !records.is_empty() {
+            snapshot.insert((table.to_string(), key), records);
+        }
+    }
+
+    Ok(())
+}
+
+fn snapshot_key(store: &impl Storage, table: &str, key: &str) -> Result<Vec<Record>, KvError> {
+    let mut records = Vec::new();
+    let values = store.list_range(table, key, 0, -1)?;
+    if !values.is_empty() {
+        records.push(Record::List(values));
+    }
+    let members = store.set_members(table, key)?;
+    if !members.is_empty() {
+        records.push(Record::Set(members));
+    }
+    let members: Vec<_> = store
+        .zset_range(table, key, 0, -1)?
+        .into_iter()
+        .map(|(member, score)| ScoredMember::new(member, score))
+        .collect();
+    if !members.is_empty() {
+        records.push(Record::Zset(members));
+    }
+    if let Some(value) = store.get(table, key)? {
+        match store.ttl(table, key) {
+            Ok(ttl) => records.push(Record::Value(value, ttl.map(deadline))),
+            // 刚刚过期了
+            Err(KvError::NotFound(_, _)) => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(records)
+}
+
+impl Record {
+    // 重放时恢复这条记录的命令：普通的 value 是 Hset 和 Hexpireat，
+    // list、set 和 sorted set 分别是一个 Rpush、Sadd 和 Zadd
+    fn commands(&self, table: &str, key: &str) -> Vec<CommandRequest> {
+        match self {
+            Record::Value(value, at) => {
+                let mut cmds = vec![CommandRequest::new_hset(table, key, value.clone())];
+                if let Some(at) = at {
+                    cmds.push(CommandRequest::new_hexpireat(table, key, *at));
+                }
+                cmds
+            }
+            Record::List(values) => vec![CommandRequest::new_rpush(table, key, values.clone())],
+            Record::Set(members) => vec![CommandRequest::new_sadd(table, key, members.clone())],
+            Record::Zset(members) => vec![CommandRequest::new_zadd(table, key, members.clone())],
+        }
+    }
+
+    // 重放时撤销这条记录的命令
+    fn undo(&self, table: &str, key: &str) -> CommandRequest {
+        match self {
+            Record::Value(..) => CommandRequest::new_hdel(table, key),
+            Record::List(values) => CommandRequest::new_lpop(table, key, values.len() as u32),
+            Record::Set(members) => CommandRequest::new_srem(table, key, members.clone()),
+            Record::Zset(members) => {
+                let members = members.iter().map(|m| m.member.clone()).collect();
+                CommandRequest::new_zrem(table, key, members)
+            }
+        }
+    }
+}
+
+impl Touched {
+    fn add(&mut self, cmd: &CommandRequest) {
+        let key = match &cmd.request_data {
+            Some(RequestData::Transaction(txn)) => {
+                txn.commands.iter().for_each(|cmd| self.add(cmd));
+                return;
+            }
+            Some(RequestData::Hset(v)) => v.pair.as_ref().map(|pair| &pair.key),
+            Some(
+                RequestData::Hexpireat(Hexpireat { key, .. })
+                | RequestData::Hdel(Hdel { key, .. })
+                | RequestData::Hpersist(Hpersist { key, .. })
+                | RequestData::Hcas(Hcas { key, .. })
+                | RequestData::Hincrby(Hincrby { key, .. })
+                | RequestData::Hincrbyfloat(Hincrbyfloat { key, .. })
+                | RequestData::Hsetpath(Hsetpath { key, .. })
+                | RequestData::Hdelpath(Hdelpath { key, .. })
+                | RequestData::Lpush(Lpush { key, .. })
+                | RequestData::Rpush(Rpush { key, .. })
+                | RequestData::Lpop(Lpop { key, .. })
+                | RequestData::Rpop(Rpop { key, .. })
+                | RequestData::Ltrim(Ltrim { key, .. })
+                | RequestData::Sadd(Sadd { key, .. })
+                | RequestData::Srem(Srem { key, .. })
+                | RequestData::Zadd(Zadd { key, .. })
+                | RequestData::Zrem(Zrem { key, .. }),
+            ) => Some(key),
+            // 建立索引不修改数据，重写时会单独记录
+            Some(RequestData::CreateIndex(_)) => return,
+            // Hdrop 等命令影响整个 table
+            _ => None,
+        };
+        if let Some(table) = cmd.table() {
+            match key {
+                Some(key) => self.keys.insert((table.to_string(), key.clone())),
+                None => self.tables.insert(table.to_string()),
+            };
+        }
+    }
+}
+
+// 记录命令中建立了索引的 table
+fn add_index(cmd: &CommandRequest, indexes: &mut BTreeSet<String>) {
+    match &cmd.request_data {
+        Some(RequestData::Transaction(txn)) => {
+            txn.commands.iter().for_each(|cmd| add_index(cmd, indexes));
+        }
+        Some(RequestData::CreateIndex(v)) => {
+            indexes.insert(v.table.clone());
+        }
+        _ => {}
+    }
+}
+
+// 把命令依次写入 writer，返回写入的字节数
+fn write_commands(
+    writer: &mut impl Write,
+    cmds: impl IntoIterator<Item = CommandRequest>,
+) -> Result<u64, KvError> {
+    let mut size = 0;
+    for cmd in cmds {
+        let mut buf = BytesMut::new();
+        cmd.encode_frame(&mut buf)?;
+        writer.write_all(&buf)?;
+        size += buf.len() as u64;
+    }
+
+    Ok(size)
+}
+
+// ttl 之后的 unix 时间戳（毫秒）
+fn deadline(ttl: Duration) -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    u64::try_from(now.saturating_add(ttl).as_millis()).unwrap_or(u64::MAX)
+}
+
+// 后台线程定期 fsync。fsync 时不持有锁，不会阻塞写入。AofLog 释放后线程退出
+fn spawn_fsync(log: Weak<AofLog>) {
+    thread::spawn(move || loop {
+        thread::sleep(FSYNC_INTERVAL);
+        let log = match log.upgrade() {
+            Some(log) => log,
+            None => break,
+        };
+        let file = {
+            let mut writer = log.writer.lock().unwrap();
+            if !writer.dirty {
+                continue;
+            }
+            writer.dirty = false;
+            writer.file.try_clone()
+        };
+        if let Err(e) = file.and_then(|f| f.sync_data()) {
+            warn!("Failed to fsync AOF: {}", e);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{EvictionPolicy, MemTableConfig};
+    use tempfile::tempdir;
+
+    fn config(fsync: FsyncPolicy) -> AofConfig {
+        AofConfig {
+            fsync,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn aof_should_replay_after_restart() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("kv.aof");
+
+        let store = AofStorage::open(&path, config(FsyncPolicy::Always)).unwrap();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        store.del("t1", "k2").unwrap();
+        store.incr("t1", "counter", 10).unwrap();
+        store.incr("t1", "counter", -3).unwrap();
+        store
+            .set_with_ttl("t2", "k1".into(), "v1".into(), Duration::from_secs(60))
+            .unwrap();
+        store
+            .set_with_ttl("t2", "k2".into(), "v2".into(), Duration::from_millis(10))
+            .unwrap();
+        store
+            .transaction(&["t3"], &mut |txn| {
+                txn.set("t3", "k1".into(), "v1".into())?;
+                txn.set("t3", "k2".into(), "v2".into())?;
+                Ok(())
+            })
+            .unwrap();
+        // 回滚的事务不会写入 AOF
+        store
+            .transaction(&["t3"], &mut |txn| {
+                txn.del("t3", "k1")?;
+                Err(KvError::Internal("abort".into()))
+            })
+            .unwrap_err();
+        drop(store);
+        thread::sleep(Duration::from_millis(20));
+
+        let store = AofStorage::open(&path, config(FsyncPolicy::Never)).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.get("t1", "k2").unwrap(), None);
+        assert_eq!(store.get("t1", "counter").unwrap(), Some(7.into()));
+        assert!(store.ttl("t2", "k1").unwrap().unwrap() > Duration::from_secs(50));
+        assert_eq!(store.get("t2", "k2").unwrap(), None);
+        assert_eq!(store.table_len("t3").unwrap(), 2);
+    }
+
+    #[test]
+    fn aof_should_ignore_incomplete_tail() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("kv.aof");
+
+        let store = AofStorage::open(&path, config(FsyncPolicy::Always)).unwrap();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        drop(store);
+
+        // 模拟写入一半时崩溃
+        let mut buf = BytesMut::new();
+        CommandRequest::new_hset("t1", "k2", "v2".into())
+            .encode_frame(&mut buf)
+            .unwrap();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&buf[..buf.len() - 1]).unwrap();
+        drop(file);
+
+        let store = AofStorage::open(&path, config(FsyncPolicy::Always)).unwrap();
+        assert_eq!(store.get("t1", "k2").unwrap(), None);
+        store.set("t1", "k3".into(), "v3".into()).unwrap();
+        drop(store);
+
+        let store = AofStorage::open(&path, config(FsyncPolicy::Always)).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.get("t1", "k3").unwrap(), Some("v3".into()));
+    }
+
+    #[test]
+    fn aof_rewrite_should_compact_log() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("kv.aof");
+
+        let store = AofStorage::open(&path, config(FsyncPolicy::EverySecond)).unwrap();
+        for i in 0..100 {
+            store.set("t1", "k1".into(), i.into()).unwrap();
+        }
+        store
+            .set_with_ttl("t1", "k2".into(), "v2".into(), Duration::from_secs(60))
+            .unwrap();
+        let size = fs::metadata(&path).unwrap().len();
+
+        store.rewrite().unwrap().unwrap().join().unwrap().unwrap();
+        assert!(fs::metadata(&path).unwrap().len() < size);
+
+        // 重写之后的写入依然会记录
+        store.set("t1", "k3".into(), "v3".into()).unwrap();
+        drop(store);
+
+        let store = AofStorage::open(&path, config(FsyncPolicy::Always)).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some(99.into()));
+        assert!(store.ttl("t1", "k2").unwrap().is_some());
+        assert_eq!(store.get("t1", "k3").unwrap(), Some("v3".into()));
+    }
+
+    #[test]
+    fn aof_should_replay_and_rewrite_lists() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("kv.aof");
+
+        let store = AofStorage::open(&path, config(FsyncPolicy::Always)).unwrap();
+        let values: Vec<Value> = (0..10).map(Value::from).collect();
+        store.list_push("t1", "l1", ListEnd::Right, values).unwrap();
+        store
+            .list_push("t1", "l1", ListEnd::Left, vec!["a".into()])
+            .unwrap();
+        store.list_pop("t1", "l1", ListEnd::Right, 2).unwrap();
+        store.list_trim("t1", "l1", 0, 4).unwrap();
+        store
+            .list_push("t2", "l1", ListEnd::Right, vec!["b".into()])
+            .unwrap();
+        store.list_pop("t2", "l1", ListEnd::Left, 5).unwrap();
+        drop(store);
+
+        let expected: Vec<Value> = vec!["a".into(), 0.into(), 1.into(), 2.into(), 3.into()];
+        let store = AofStorage::open(&path, config(FsyncPolicy::Always)).unwrap();
+        assert_eq!(store.list_range("t1", "l1", 0, -1).unwrap(), expected);
+        assert_eq!(store.list_len("t2", "l1").unwrap(), 0);
+
+        store.rewrite().unwrap().unwrap().join().unwrap().unwrap();
+        drop(store);
+
+        let store = AofStorage::open(&path, config(FsyncPolicy::Always)).unwrap();
+        assert_eq!(store.list_range("t1", "l1", 0, -1).unwrap(), expected);
+        assert_eq!(store.list_tables().unwrap(), ["t1"]);
+    }
+
+    #[test]
+    fn aof_should_replay_and_rewrite_zsets() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("kv.aof");
+
+        let store = AofStorage::open(&path, config(FsyncPolicy::Always)).unwrap();
+        let members = vec![("a".to_string(), 1.0), ("b".to_string(), 2.0)];
+        store.zset_add("t1", "z1", members).unwrap();
+        store.zset_incr("t1", "z1", "a", 5.0).unwrap();
+        store.zset_incr("t1", "z1", "c", -1.0).unwrap();
+        store.zset_remove("t1", "z1", vec!["b".into()]).unwrap();
+        drop(store);
+
+        let expected = vec![("c".to_string(), -1.0), ("a".to_string(), 6.0)];
+        let store = AofStorage::open(&path, config(FsyncPolicy::Always)).unwrap();
+        assert_eq!(store.zset_range("t1", "z1", 0, -1).unwrap(), expected);
+
+        store.rewrite().unwrap().unwrap().join().unwrap().unwrap();
+        drop(store);
+
+        let store = AofStorage::open(&path, config(FsyncPolicy::Always)).unwrap();
+        assert_eq!(store.zset_range("t1", "z1", 0, -1).unwrap(), expected);
+    }
+
+    #[test]
+    fn aof_should_replay_paths() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("kv.aof");
+
+        let store = AofStorage::open(&path, config(FsyncPolicy::Always)).unwrap();
+        let doc: Value = vec!["a".into(), "b".into()].into();
+        store
+            .update_path("t1", "k1", &[], PathOp::Set(doc))
+            .unwrap();
+        let set = PathOp::Set("c".into());
+        store.update_path("t1", "k1", &[2.into()], set).unwrap();
+        store
+            .update_path("t1", "k1", &[0.into()], PathOp::Delete)
+            .unwrap();
+        let append = PathOp::Append(vec!["d".into(), "e".into()]);
+        store.update_path("t1", "k1", &[], append).unwrap();
+        drop(store);
+
+        let store = AofStorage::open(&path, config(FsyncPolicy::Always)).unwrap();
+        let expected: Value = vec!["b".into(), "c".into(), "d".into(), "e".into()].into();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some(expected));
+    }
+
+    #[test]
+    fn aof_should_replay_and_rewrite_sets() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("kv.aof");
+        let members = |items: &[&str]| -> Vec<String> { items.iter().map(|&m| m.into()).collect() };
+
+        let store = AofStorage::open(&path, config(FsyncPolicy::Always)).unwrap();
+        store
+            .set_add("t1", "s1", members(&["a", "b", "c"]))
+            .unwrap();
+        store.set_remove("t1", "s1", members(&["b"])).unwrap();
+        store.set_add("t2", "s1", members(&["x"])).unwrap();
+        store.set_remove("t2", "s1", members(&["x"])).unwrap();
+        drop(store);
+
+        let store = AofStorage::open(&path, config(FsyncPolicy::Always)).unwrap();
+        assert_eq!(store.set_members("t1", "s1").unwrap(), ["a", "c"]);
+        assert_eq!(store.set_len("t2", "s1").unwrap(), 0);
+
+        store.rewrite().unwrap().unwrap().join().unwrap().unwrap();
+        drop(store);
+
+        let store = AofStorage::open(&path, config(FsyncPolicy::Always)).unwrap();
+        assert_eq!(store.set_members("t1", "s1").unwrap(), ["a", "c"]);
+        assert_eq!(store.list_tables().unwrap(), ["t1"]);
+    }
+
+    #[test]
+    fn aof_rewrite_should_keep_concurrent_writes() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("kv.aof");
+
+        let store = AofStorage::open(&path, config(FsyncPolicy::Never)).unwrap();
+        for i in 0..1000 {
+            store.set("t1", format!("k{}", i), i.into()).unwrap();
+            store.set("t2", format!("k{}", i), i.into()).unwrap();
+        }
+        store
+            .list_push("t1", "l1", ListEnd::Right, vec![1.into(), 2.into()])
+            .unwrap();
+        store.set_add("t1", "s1", vec!["a".into()]).unwrap();
+
+        // 重写的同时修改数据，快照中过时的 key 会在新文件末尾被修正
+        let handle = store.rewrite().unwrap().unwrap();
+        for i in 0..100 {
+            store.set("t1", format!("k{}", i), "new".into()).unwrap();
+            store.del("t1", &format!("k{}", 999 - i)).unwrap();
+        }
+        store
+            .list_push("t1", "l1", ListEnd::Right, vec![3.into()])
+            .unwrap();
+        store.set_add("t1", "s1", vec!["b".into()]).unwrap();
+        store.set_remove("t1", "s1", vec!["a".into()]).unwrap();
+        store.drop_table("t2").unwrap();
+        store.set("t2", "k1".into(), "v1".into()).unwrap();
+        handle.join().unwrap().unwrap();
+        store.set("t1", "k500".into(), "after".into()).unwrap();
+
+        let mut expected = store.get_all("t1").unwrap();
+        expected.sort_by(|a, b| a.key.cmp(&b.key));
+        drop(store);
+
+        let store = AofStorage::open(&path, config(FsyncPolicy::Never)).unwrap();
+        let mut pairs = store.get_all("t1").unwrap();
+        pairs.sort_by(|a, b| a.key.cmp(&b.key));
+        assert_eq!(pairs, expected);
+        assert_eq!(pairs.len(), 900);
+        assert_eq!(store.get("t1", "k500").unwrap(), Some("after".into()));
+        let values: Vec<Value> = vec![1.into(), 2.into(), 3.into()];
+        assert_eq!(store.list_range("t1", "l1", 0, -1).unwrap(), values);
+        assert_eq!(store.set_members("t1", "s1").unwrap(), ["b"]);
+        assert_eq!(
+            store.get_all("t2").unwrap(),
+            [Kvpair::new("k1", "v1".into())]
+        );
+    }
+
+    #[test]
+    fn aof_should_reject_writes_after_append_failure() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("kv.aof");
+
+        let store = AofStorage::open(&path, config(FsyncPolicy::Always)).unwrap();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        // 换成只读的文件，模拟磁盘写满等写入错误
+        store.log.writer.lock().unwrap().file = File::open(&path).unwrap();
+        assert!(store.set("t1", "k2".into(), "v2".into()).is_err());
+
+        // 文件恢复正常也不再接受修改，读取不受影响
+        store.log.writer.lock().unwrap().file =
+            OpenOptions::new().append(true).open(&path).unwrap();
+        let err = store.set("t1", "k3".into(), "v3".into()).unwrap_err();
+        assert!(err.to_string().contains("AOF is unavailable"));
+        assert_eq!(store.get("t1", "k3").unwrap(), None);
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        drop(store);
+
+        let store = AofStorage::open(&path, config(FsyncPolicy::Always)).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.get("t1", "k2").unwrap(), None);
+    }
+
+    #[test]
+    fn aof_should_replay_evictions() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("kv.aof");
+        let memtable = || {
+            MemTable::with_config(MemTableConfig {
+                max_memory: 4096,
+                eviction: EvictionPolicy::Lru,
+            })
+        };
+
+        let store = AofStorage::with_store(memtable(), &path, config(FsyncPolicy::Never)).unwrap();
+        for i in 0..100 {
+            store
+                .set("t1", format!("k{}", i), "x".repeat(64).into())
+                .unwrap();
+        }
+        store
+            .transaction(&["t1"], &mut |txn| {
+                txn.set("t1", "k100".into(), "v".into())?;
+                Ok(())
+            })
+            .unwrap();
+        assert!(store.inner.evicted_keys() > 0);
+        let mut expected = store.get_all("t1").unwrap();
+        expected.sort_by(|a, b| a.key.cmp(&b.key));
+        drop(store);
+
+        // 没有内存上限时重放，被淘汰的 key 也不会回来
+        let store = AofStorage::open(&path, config(FsyncPolicy::Never)).unwrap();
+        let mut pairs = store.get_all("t1").unwrap();
+        pairs.sort_by(|a, b| a.key.cmp(&b.key));
+        assert_eq!(pairs, expected);
+    }
+
+    #[test]
+    fn aof_should_replay_evictions_during_collection_writes() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("kv.aof");
+        let memtable = MemTable::with_config(MemTableConfig {
+            max_memory: 4096,
+            eviction: EvictionPolicy::Lru,
+        });
+
+        let store = AofStorage::with_store(memtable, &path, config(FsyncPolicy::Never)).unwrap();
+        for i in 0..40 {
+            store
+                .set("t1", format!("k{}", i), "x".repeat(64).into())
+                .unwrap();
+        }
+        let evicted = store.inner.evicted_keys();
+        store
+            .list_push("t1", "l1", ListEnd::Right, vec!["y".repeat(1024).into()])
+            .unwrap();
+        assert!(store.inner.evicted_keys() > evicted);
+        let mut expected = store.get_all("t1").unwrap();
+        expected.sort_by(|a, b| a.key.cmp(&b.key));
+        drop(store);
+
+        // Rpush 和它淘汰的 key 都会被重放
+        let store = AofStorage::open(&path, config(FsyncPolicy::Never)).unwrap();
+        let mut pairs = store.get_all("t1").unwrap();
+        pairs.sort_by(|a, b| a.key.cmp(&b.key));
+        assert_eq!(pairs, expected);
+        assert_eq!(store.list_len("t1", "l1").unwrap(), 1);
+    }
+
+    #[test]
+    fn aof_should_keep_indexes_across_replay_and_rewrite() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("kv.aof");
+
+        let store = AofStorage::open(&path, config(FsyncPolicy::Never)).unwrap();
+        store.set("t1", "u1".into(), "a".into()).unwrap();
+        assert!(store.create_index("t1").unwrap());
+        store.set("t1", "u2".into(), "a".into()).unwrap();
+        drop(store);
+
+        let store = AofStorage::open(&path, config(FsyncPolicy::Never)).unwrap();
+        assert!(!store.create_index("t1").unwrap());
+        store.rewrite().unwrap().unwrap().join().unwrap().unwrap();
+        drop(store);
+
+        let store = AofStorage::open(&path, config(FsyncPolicy::Never)).unwrap();
+        assert!(!store.create_index("t1").unwrap());
+        let keys: Vec<_> = store
+            .find("t1", &"a".into())
+            .unwrap()
+            .into_iter()
+            .map(|p| p.key)
+            .collect();
+        assert_eq!(keys, ["u1", "u2"]);
+    }
+
+    #[test]
+    fn aof_should_rewrite_automatically() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("kv.aof");
+        let config = AofConfig {
+            fsync: FsyncPolicy::Never,
+            rewrite_min_size: 1024,
+            rewrite_percentage: 100,
+        };
+
+        let store = AofStorage::open(&path, config).unwrap();
+        // 每条命令 20 字节左右，写到 1024 字节时触发一次重写
+        for i in 0..60 {
+            store.set("t1", "k1".into(), i.into()).unwrap();
+        }
+        // 等待后台重写完成
+        thread::sleep(Duration::from_millis(100));
+        assert!(fs::metadata(&path).unwrap().len() < 1024);
+        drop(store);
+
+        let store = AofStorage::open(&path, AofConfig::default()).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some(59.into()));
+    }
+}
@@ -0,0 +1,67 @@
+use async_trait::async_trait;
+use std::sync::Arc;
+
+use crate::{KvError, Storage};
+
+/// 异步访问存储的接口，不会阻塞 tokio 的 worker 线程。
+/// 同步的 Storage 放进 Arc 之后就实现了 AsyncStorage，操作在 spawn_blocking 的线程池里执行
+#[async_trait]
+pub trait AsyncStorage: Send + Sync {
+    /// 在一个同步的 Storage 上执行 f。f 中对存储的多次访问在同一个阻塞线程里完成，
+    /// 不会在 tokio 的 worker 线程上运行
+    async fn run_blocking<T, F>(&self, f: F) -> Result<T, KvError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&dyn Storage) -> T + Send + 'static;
+}
+
+#[async_trait]
+impl<S> AsyncStorage for Arc<S>
+where
+    S: Storage + Send + Sync + 'static,
+{
+    async fn run_blocking<T, F>(&self, f: F) -> Result<T, KvError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&dyn Storage) -> T + Send + 'static,
+    {
+        let store = self.clone();
+        tokio::task::spawn_blocking(move || f(store.as_ref()))
+            .await
+            .map_err(|e| KvError::Internal(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemTable;
+    use std::{
+        sync::atomic::{AtomicBool, Ordering},
+        thread,
+        time::Duration,
+    };
+
+    #[tokio::test]
+    async fn run_blocking_should_not_block_runtime() {
+        let store = Arc::new(MemTable::new());
+        let done = Arc::new(AtomicBool::new(false));
+        let flag = done.clone();
+
+        // 单线程的 runtime 上，阻塞的操作在其它线程执行，不影响这里的计时器
+        let slow = store.run_blocking(move |s| {
+            thread::sleep(Duration::from_millis(200));
+            flag.store(true, Ordering::SeqCst);
+            s.set("t1", "k1".into(), "v1".into())
+        });
+        tokio::pin!(slow);
+        tokio::select! {
+            _ = &mut slow => panic!("blocking operation finished first"),
+            _ = tokio::time::sleep(Duration::from_millis(20)) => {}
+        }
+        assert!(!done.load(Ordering::SeqCst));
+
+        slow.await.unwrap().unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+    }
+}
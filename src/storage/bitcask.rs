@@ -0,0 +1,1369 @@
+use bytes::{Buf, BufMut};
+use std::{
+    cell::RefCell,
+    collections::{BTreeSet, HashMap},
+    convert::{TryFrom, TryInto},
+    fs::{self, File, OpenOptions},
+    io::{BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::{info, warn};
+
+use crate::{
+    storage::{serial_compare_and_swap, serial_incr, serial_incr_float, serial_update_path},
+    KvError, Kvpair, PathOp, PathSegment, Storage, Value,
+};
+
+/// 每条记录的 header：CRC32、flags、过期时刻、table / key / value 的长度
+const HEADER_LEN: usize = 4 + 1 + 8 + 4 + 4 + 4;
+/// 记录是一个删除标记
+const FLAG_TOMBSTONE: u8 = 1;
+/// 同一批的记录还没有结束，恢复时只有整批都完整才会生效
+const FLAG_BATCH: u8 = 2;
+
+#[derive(Clone, Debug)]
+pub struct BitcaskConfig {
+    /// 当前数据文件超过这个大小（字节）后，新的写入会使用一个新的文件
+    pub max_file_size: u64,
+    /// 每次写入后是否 fsync
+    pub sync: bool,
+    /// 数据文件总大小不低于 merge_min_size，并且失效的数据占比达到 merge_percentage% 时在后台自动 merge
+    pub merge_min_size: u64,
+    pub merge_percentage: u64,
+}
+
+impl Default for BitcaskConfig {
+    fn default() -> Self {
+        Self {
+            max_file_size: 64 * 1024 * 1024,
+            sync: false,
+            merge_min_size: 64 * 1024 * 1024,
+            merge_percentage: 50,
+        }
+    }
+}
+
+/// Bitcask 风格的存储引擎：所有修改都追加到数据文件末尾，内存中的 keydir 记录每个 key
+/// 最新的记录在哪个文件的什么位置，读取时只需要一次磁盘访问。
+/// merge 会把仍然有效的数据写到新的文件里，同时生成 hint 文件加快启动
+#[derive(Debug)]
+pub struct BitcaskStorage {
+    dir: PathBuf,
+    config: BitcaskConfig,
+    // 读操作持有读锁；写操作持有写锁，merge 只在开始和结束时短暂地持有写锁
+    state: Arc<RwLock<State>>,
+    // 后台正在执行的自动 merge，同一时间最多只有一个 merge
+    merger: Mutex<Option<JoinHandle<()>>>,
+}
+
+#[derive(Debug)]
+struct State {
+    keydir: HashMap<String, HashMap<String, KeydirEntry>>,
+    files: HashMap<u64, DataFile>,
+    active: ActiveFile,
+    // 所有数据文件的大小和其中仍然有效的记录的大小，两者之差就是 merge 可以回收的空间
+    total_size: u64,
+    live_size: u64,
+}
+
+/// key 最新的记录所在的位置
+#[derive(Clone, Debug, PartialEq)]
+struct KeydirEntry {
+    file_id: u64,
+    offset: u64,
+    size: u32,
+    expire_at: Option<u64>,
+}
+
+/// 只读打开的数据文件
+#[derive(Debug)]
+struct DataFile {
+    path: PathBuf,
+    reader: Mutex<File>,
+}
+
+/// 当前追加写入的数据文件
+#[derive(Debug)]
+struct ActiveFile {
+    id: u64,
+    writer: File,
+    size: u64,
+}
+
+/// 从数据文件中解析出的一条记录，value 为 None 表示删除标记
+#[derive(Debug)]
+struct DiskRecord {
+    flags: u8,
+    expire_at: Option<u64>,
+    table: String,
+    key: String,
+    value: Option<Vec<u8>>,
+}
+
+/// 从数据文件或 hint 文件中读出的 keydir 修改，None 表示删除
+type KeydirChanges = Vec<(String, String, Option<KeydirEntry>)>;
+
+/// 一个 key 在事务中的修改，None 表示删除
+type Change = Option<(Value, Option<u64>)>;
+
+impl BitcaskStorage {
+    /// 使用默认配置打开，失败时 panic，和 SledDb::new 一样
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self::open(path, BitcaskConfig::default()).unwrap()
+    }
+
+    /// 打开（或创建）目录 path 下的数据。有 hint 文件的数据文件直接从 hint 文件加载 keydir，
+    /// 其它的数据文件需要从头到尾扫描一遍
+    pub fn open(path: impl AsRef<Path>, config: BitcaskConfig) -> Result<Self, KvError> {
+        let dir = path.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut ids = Vec::new();
+        for item in fs::read_dir(&dir)? {
+            let path = item?.path();
+            match path.extension().and_then(|ext| ext.to_str()) {
+                Some("data") => ids.extend(file_id(&path)),
+                // 写了一半的 hint 文件和没有完成的 merge 的输出
+                Some("tmp") | Some("merge") => fs::remove_file(&path)?,
+                _ => {}
+            }
+        }
+        ids.sort_unstable();
+        if ids.is_empty() {
+            File::create(data_path(&dir, 0))?;
+            ids.push(0);
+        }
+
+        let mut keydir = HashMap::new();
+        let mut files = HashMap::new();
+        let mut total_size = 0;
+        let last = *ids.last().unwrap();
+        for &id in &ids {
+            let path = data_path(&dir, id);
+            let loaded = match load_hint(&dir, id)? {
+                Some(entries) => entries,
+                None => scan_data_file(&path, id, id == last)?,
+            };
+            for (table, key, entry) in loaded {
+                apply_entry(&mut keydir, table, key, entry);
+            }
+            let file = DataFile::open(path)?;
+            total_size += file.size()?;
+            files.insert(id, file);
+        }
+
+        let live_size = keydir
+            .values()
+            .flat_map(|t: &HashMap<String, KeydirEntry>| t.values())
+            .map(|e| e.size as u64)
+            .sum();
+        let active = ActiveFile::open(&dir, last)?;
+        info!(
+            "Opened bitcask storage {:?} with {} data files",
+            dir,
+            ids.len()
+        );
+
+        Ok(Self {
+            dir,
+            config,
+            state: Arc::new(RwLock::new(State {
+                keydir,
+                files,
+                active,
+                total_size,
+                live_size,
+            })),
+            merger: Mutex::new(None),
+        })
+    }
+
+    /// 把所有数据文件中仍然有效的数据写到新的文件里，然后删除旧的文件。
+    /// 被覆盖、删除或者已经过期的记录都会被丢弃。后台正在 merge 时先等它完成
+    pub fn merge(&self) -> Result<(), KvError> {
+        let mut merger = self.merger.lock().unwrap();
+        if let Some(handle) = merger.take() {
+            let _ = handle.join();
+        }
+        merge_files(&self.dir, self.config.max_file_size, &self.state)
+    }
+
+    // 在后台线程里 merge，已经有 merge 在执行时什么都不做
+    fn start_merge(&self) {
+        // 调用者持有写锁，merge 正在等写锁时不能阻塞在这里
+        let mut merger = match self.merger.try_lock() {
+            Ok(merger) => merger,
+            Err(_) => return,
+        };
+        if matches!(merger.as_ref(), Some(handle) if !handle.is_finished()) {
+            return;
+        }
+
+        let dir = self.dir.clone();
+        let max_file_size = self.config.max_file_size;
+        let state = self.state.clone();
+        *merger = Some(thread::spawn(move || {
+            if let Err(e) = merge_files(&dir, max_file_size, &state) {
+                warn!("Failed to merge bitcask data files: {}", e);
+            }
+        }));
+    }
+
+    // 在读锁的保护下读取数据
+    fn read<T>(&self, f: impl FnOnce(&BitcaskTxn) -> Result<T, KvError>) -> Result<T, KvError> {
+        let state = self.state.read().unwrap();
+        f(&BitcaskTxn::new(&state))
+    }
+
+    // 在写锁的保护下执行 f，f 成功后把它的修改作为一批写入
+    fn write<T>(&self, f: impl FnOnce(&BitcaskTxn) -> Result<T, KvError>) -> Result<T, KvError> {
+        let mut state = self.state.write().unwrap();
+        let txn = BitcaskTxn::new(&state);
+        let result = f(&txn)?;
+        let batch = txn.into_batch();
+        self.commit(&mut state, batch)?;
+
+        Ok(result)
+    }
+
+    fn commit(
+        &self,
+        state: &mut State,
+        batch: Vec<(String, String, Change)>,
+    ) -> Result<(), KvError> {
+        // 删除原本就不存在的 key 不需要写入
+        let batch: Vec<_> = batch
+            .into_iter()
+            .filter(|(table, key, write)| write.is_some() || state.entry(table, key).is_some())
+            .collect();
+        if batch.is_empty() {
+            return Ok(());
+        }
+        if state.active.size >= self.config.max_file_size {
+            let id = state.active.id + 1;
+            rotate(&self.dir, state, id)?;
+        }
+
+        // 除了最后一条，一批中的每条记录都带上 FLAG_BATCH
+        let mut buf = Vec::new();
+        let mut records = Vec::with_capacity(batch.len());
+        let last = batch.len() - 1;
+        for (i, (table, key, write)) in batch.into_iter().enumerate() {
+            let flags = if i < last { FLAG_BATCH } else { 0 };
+            let start = buf.len();
+            let expire_at = match write {
+                Some((value, expire_at)) => {
+                    let data: Vec<u8> = value.try_into()?;
+                    encode_record(&mut buf, flags, expire_at, &table, &key, Some(&data));
+                    Some(expire_at)
+                }
+                None => {
+                    encode_record(&mut buf, flags | FLAG_TOMBSTONE, None, &table, &key, None);
+                    None
+                }
+            };
+            records.push((table, key, start, buf.len() - start, expire_at));
+        }
+
+        let base = state.active.append(&buf, self.config.sync)?;
+        state.total_size += buf.len() as u64;
+        for (table, key, start, size, expire_at) in records {
+            let entry = expire_at.map(|expire_at| KeydirEntry {
+                file_id: state.active.id,
+                offset: base + start as u64,
+                size: size as u32,
+                expire_at,
+            });
+            state.update(table, key, entry);
+        }
+
+        let dead = state.total_size - state.live_size;
+        if state.total_size >= self.config.merge_min_size
+            && dead * 100 >= state.total_size * self.config.merge_percentage
+        {
+            self.start_merge();
+        }
+
+        Ok(())
+    }
+
+    // 等待后台的 merge 完成
+    fn join_merge(&self) {
+        if let Some(handle) = self.merger.lock().unwrap().take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for BitcaskStorage {
+    fn drop(&mut self) {
+        self.join_merge();
+    }
+}
+
+// 之后的写入使用数据文件 id
+fn rotate(dir: &Path, state: &mut State, id: u64) -> Result<(), KvError> {
+    state.active = ActiveFile::open(dir, id)?;
+    state.files.insert(id, DataFile::open(data_path(dir, id))?);
+
+    Ok(())
+}
+
+/// 把 old 中的数据文件合并成新的文件。写锁里切换到新的活跃文件，并在旧文件和它之间给输出预留 id，
+/// 之后不持有锁复制仍然有效的记录，最后在写锁里把期间没有被修改过的 key 指向新的文件。
+/// 输出先写成 .merge 文件，全部完成后才改名成数据文件，出错时删除，重启时也会删除
+fn merge_files(dir: &Path, max_file_size: u64, state: &RwLock<State>) -> Result<(), KvError> {
+    let (old, first, last, snapshot) = {
+        let mut state = state.write().unwrap();
+        let mut old: Vec<_> = state.files.keys().copied().collect();
+        old.sort_unstable();
+        // 输出的文件数不超过旧文件数，最后一个输出不会再切换文件
+        let first = state.active.id + 1;
+        let last = first + old.len() as u64 - 1;
+        rotate(dir, &mut state, last + 1)?;
+        let snapshot: Vec<_> = state
+            .keydir
+            .iter()
+            .flat_map(|(table, entries)| {
+                entries
+                    .iter()
+                    .map(move |(key, entry)| (table.clone(), key.clone(), entry.clone()))
+            })
+            .collect();
+        (old, first, last, snapshot)
+    };
+
+    let result = copy_live_records(dir, max_file_size, &old, first, last, snapshot);
+    let (outputs, moved) = match result {
+        Ok(result) => result,
+        Err(e) => {
+            for id in first..=last {
+                let _ = remove_if_exists(&merge_path(dir, id));
+                let _ = remove_if_exists(&hint_path(dir, id));
+            }
+            return Err(e);
+        }
+    };
+    let mut files = Vec::with_capacity(outputs.len());
+    for &id in &outputs {
+        fs::rename(merge_path(dir, id), data_path(dir, id))?;
+        files.push((id, DataFile::open(data_path(dir, id))?));
+    }
+
+    let mut state = state.write().unwrap();
+    for (table, key, old, new) in moved {
+        if state.entry(&table, &key) == Some(&old) {
+            state.update(table, key, new);
+        }
+    }
+    for id in &old {
+        if let Some(file) = state.files.remove(id) {
+            state.total_size -= file.size()?;
+        }
+    }
+    for (id, file) in files {
+        state.total_size += file.size()?;
+        state.files.insert(id, file);
+    }
+    drop(state);
+
+    // 按从旧到新的顺序删除，中途退出时剩下的旧文件依然可以正确重放
+    for id in &old {
+        remove_if_exists(&hint_path(dir, *id))?;
+        fs::remove_file(data_path(dir, *id))?;
+    }
+    info!(
+        "Merged {} data files into {} files",
+        old.len(),
+        outputs.len()
+    );
+
+    Ok(())
+}
+
+/// 一个 key 在 merge 前后的位置，新的位置为 None 表示已经过期
+type Moved = Vec<(String, String, KeydirEntry, Option<KeydirEntry>)>;
+
+// 把 snapshot 中没有过期的记录写到 id 从 first 到 last 的输出文件里，返回输出的 id 和每个 key 的新位置
+fn copy_live_records(
+    dir: &Path,
+    max_file_size: u64,
+    old: &[u64],
+    first: u64,
+    last: u64,
+    snapshot: Vec<(String, String, KeydirEntry)>,
+) -> Result<(Vec<u64>, Moved), KvError> {
+    // 旧文件不会再被写入，单独打开它们，不和读操作争用
+    let mut readers = HashMap::new();
+    for &id in old {
+        readers.insert(id, File::open(data_path(dir, id))?);
+    }
+
+    let now = now();
+    let mut next_id = first;
+    let mut outputs = Vec::new();
+    let mut output: Option<MergeOutput> = None;
+    let mut moved = Vec::with_capacity(snapshot.len());
+    for (table, key, entry) in snapshot {
+        if matches!(entry.expire_at, Some(at) if at <= now) {
+            moved.push((table, key, entry, None));
+            continue;
+        }
+        let reader = readers
+            .get_mut(&entry.file_id)
+            .ok_or_else(|| KvError::Internal(format!("data file {} is missing", entry.file_id)))?;
+        let record = read_record_at(reader, &data_path(dir, entry.file_id), &entry)?;
+        let out = match output.as_mut() {
+            Some(out) => out,
+            None => {
+                next_id += 1;
+                output.insert(MergeOutput::create(dir, next_id - 1)?)
+            }
+        };
+        let new = out.add(&table, &key, entry.expire_at, record.value.as_deref())?;
+        if out.size >= max_file_size && out.id < last {
+            outputs.push(output.take().unwrap().finish()?);
+        }
+        moved.push((table, key, entry, Some(new)));
+    }
+    if let Some(out) = output {
+        outputs.push(out.finish()?);
+    }
+
+    Ok((outputs, moved))
+}
+
+impl State {
+    fn entry(&self, table: &str, key: &str) -> Option<&KeydirEntry> {
+        self.keydir.get(table).and_then(|t| t.get(key))
+    }
+
+    // 更新 keydir 中的一个 key，entry 为 None 时删除
+    fn update(&mut self, table: String, key: String, entry: Option<KeydirEntry>) {
+        if let Some(entry) = &entry {
+            self.live_size += entry.size as u64;
+        }
+        if let Some(old) = apply_entry(&mut self.keydir, table, key, entry) {
+            self.live_size -= old.size as u64;
+        }
+    }
+
+    // 读出 entry 指向的记录并校验 CRC
+    fn read_record(&self, entry: &KeydirEntry) -> Result<DiskRecord, KvError> {
+        let file = self
+            .files
+            .get(&entry.file_id)
+            .ok_or_else(|| KvError::Internal(format!("data file {} is missing", entry.file_id)))?;
+        let mut reader = file.reader.lock().unwrap();
+        read_record_at(&mut reader, &file.path, entry)
+    }
+
+    fn read_value(&self, entry: &KeydirEntry) -> Result<Value, KvError> {
+        match self.read_record(entry)?.value {
+            Some(data) => Value::try_from(data.as_slice()),
+            None => Err(KvError::Internal("keydir points to a tombstone".into())),
+        }
+    }
+}
+
+impl DataFile {
+    fn open(path: PathBuf) -> Result<Self, KvError> {
+        Ok(Self {
+            reader: Mutex::new(File::open(&path)?),
+            path,
+        })
+    }
+
+    fn size(&self) -> Result<u64, KvError> {
+        Ok(self.reader.lock().unwrap().metadata()?.len())
+    }
+}
+
+impl ActiveFile {
+    fn open(dir: &Path, id: u64) -> Result<Self, KvError> {
+        let writer = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(data_path(dir, id))?;
+        let size = writer.metadata()?.len();
+
+        Ok(Self { id, writer, size })
+    }
+
+    // 追加写入，返回写入的位置
+    fn append(&mut self, buf: &[u8], sync: bool) -> Result<u64, KvError> {
+        let offset = self.size;
+        self.writer.write_all(buf)?;
+        if sync {
+            self.writer.sync_data()?;
+        }
+        self.size += buf.len() as u64;
+
+        Ok(offset)
+    }
+}
+
+/// merge 时正在写入的新数据文件，以及对应的 hint 文件的内容
+struct MergeOutput {
+    id: u64,
+    dir: PathBuf,
+    writer: BufWriter<File>,
+    size: u64,
+    hint: Vec<u8>,
+}
+
+impl MergeOutput {
+    fn create(dir: &Path, id: u64) -> Result<Self, KvError> {
+        Ok(Self {
+            id,
+            dir: dir.to_path_buf(),
+            writer: BufWriter::new(File::create(merge_path(dir, id))?),
+            size: 0,
+            hint: Vec::new(),
+        })
+    }
+
+    fn add(
+        &mut self,
+        table: &str,
+        key: &str,
+        expire_at: Option<u64>,
+        value: Option<&[u8]>,
+    ) -> Result<KeydirEntry, KvError> {
+        let mut buf = Vec::new();
+        encode_record(&mut buf, 0, expire_at, table, key, value);
+        self.writer.write_all(&buf)?;
+        let entry = KeydirEntry {
+            file_id: self.id,
+            offset: self.size,
+            size: buf.len() as u32,
+            expire_at,
+        };
+        self.size += buf.len() as u64;
+        encode_hint(&mut self.hint, table, key, &entry);
+
+        Ok(entry)
+    }
+
+    // 数据文件落盘之后才写 hint 文件，hint 文件先写到临时文件再改名
+    fn finish(mut self) -> Result<u64, KvError> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+
+        let crc = crc32fast::hash(&self.hint);
+        self.hint.put_u32(crc);
+        let tmp = self.dir.join(format!("{:06}.tmp", self.id));
+        let mut file = File::create(&tmp)?;
+        file.write_all(&self.hint)?;
+        file.sync_all()?;
+        fs::rename(&tmp, hint_path(&self.dir, self.id))?;
+
+        Ok(self.id)
+    }
+}
+
+// 从数据文件 path 中读出 entry 指向的记录并校验 CRC
+fn read_record_at(
+    reader: &mut File,
+    path: &Path,
+    entry: &KeydirEntry,
+) -> Result<DiskRecord, KvError> {
+    let mut buf = vec![0u8; entry.size as usize];
+    reader.seek(SeekFrom::Start(entry.offset))?;
+    reader.read_exact(&mut buf)?;
+
+    match decode_record(&buf) {
+        Some(Ok((record, _))) => Ok(record),
+        _ => Err(corrupted(path, entry.offset, "bad record")),
+    }
+}
+
+// 更新 keydir，返回被替换掉的 entry
+fn apply_entry(
+    keydir: &mut HashMap<String, HashMap<String, KeydirEntry>>,
+    table: String,
+    key: String,
+    entry: Option<KeydirEntry>,
+) -> Option<KeydirEntry> {
+    match entry {
+        Some(entry) => keydir.entry(table).or_default().insert(key, entry),
+        None => {
+            let entries = keydir.get_mut(&table)?;
+            let old = entries.remove(&key);
+            if entries.is_empty() {
+                keydir.remove(&table);
+            }
+            old
+        }
+    }
+}
+
+/// 记录的格式：CRC32 | flags | 过期时刻（0 表示没有）| table 长度 | key 长度 | value 长度 | table | key | value。
+/// CRC32 覆盖 flags 之后的所有内容
+fn encode_record(
+    buf: &mut Vec<u8>,
+    flags: u8,
+    expire_at: Option<u64>,
+    table: &str,
+    key: &str,
+    value: Option<&[u8]>,
+) {
+    let start = buf.len();
+    let value = value.unwrap_or_default();
+    // 先占位，最后填上 CRC32
+    buf.put_u32(0);
+    buf.put_u8(flags);
+    buf.put_u64(expire_at.unwrap_or(0));
+    buf.put_u32(table.len() as _);
+    buf.put_u32(key.len() as _);
+    buf.put_u32(value.len() as _);
+    buf.extend_from_slice(table.as_bytes());
+    buf.extend_from_slice(key.as_bytes());
+    buf.extend_from_slice(value);
+    let crc = crc32fast::hash(&buf[start + 4..]);
+    buf[start..start + 4].copy_from_slice(&crc.to_be_bytes());
+}
+
+/// 解析 buf 开头的一条记录，返回记录和它的长度。数据不完整时返回 None，CRC 不匹配时返回 Err
+fn decode_record(buf: &[u8]) -> Option<Result<(DiskRecord, usize), ()>> {
+    if buf.len() < HEADER_LEN {
+        return None;
+    }
+    let mut header = &buf[..HEADER_LEN];
+    let crc = header.get_u32();
+    let flags = header.get_u8();
+    let expire_at = header.get_u64();
+    let table_len = header.get_u32() as usize;
+    let key_len = header.get_u32() as usize;
+    let value_len = header.get_u32() as usize;
+    let len = HEADER_LEN + table_len + key_len + value_len;
+    if buf.len() < len {
+        return None;
+    }
+    if crc32fast::hash(&buf[4..len]) != crc {
+        return Some(Err(()));
+    }
+
+    let mut body = &buf[HEADER_LEN..len];
+    let table = String::from_utf8_lossy(&body[..table_len]).into_owned();
+    body.advance(table_len);
+    let key = String::from_utf8_lossy(&body[..key_len]).into_owned();
+    body.advance(key_len);
+    let value = if flags & FLAG_TOMBSTONE == 0 {
+        Some(body.to_vec())
+    } else {
+        None
+    };
+    let record = DiskRecord {
+        flags,
+        expire_at: if expire_at == 0 {
+            None
+        } else {
+            Some(expire_at)
+        },
+        table,
+        key,
+        value,
+    };
+
+    Some(Ok((record, len)))
+}
+
+// 扫描整个数据文件得到 keydir 的修改。最后一个文件末尾不完整的记录（包括不完整的一批）会被截掉，
+// 其它位置的损坏返回错误
+fn scan_data_file(path: &Path, id: u64, is_last: bool) -> Result<KeydirChanges, KvError> {
+    let data = fs::read(path)?;
+    let mut result = Vec::new();
+    let mut batch = Vec::new();
+    let mut committed = 0;
+    let mut offset = 0;
+    while offset < data.len() {
+        let (record, len) = match decode_record(&data[offset..]) {
+            Some(Ok(item)) => item,
+            _ if is_last => break,
+            _ => return Err(corrupted(path, offset as u64, "bad record")),
+        };
+        let entry = record.value.as_ref().map(|_| KeydirEntry {
+            file_id: id,
+            offset: offset as u64,
+            size: len as u32,
+            expire_at: record.expire_at,
+        });
+        batch.push((record.table, record.key, entry));
+        offset += len;
+        if record.flags & FLAG_BATCH == 0 {
+            result.append(&mut batch);
+            committed = offset;
+        }
+    }
+
+    if committed < data.len() {
+        if !is_last {
+            return Err(corrupted(path, committed as u64, "incomplete batch"));
+        }
+        warn!(
+            "Data file {:?} has {} bytes of broken data, truncated",
+            path,
+            data.len() - committed
+        );
+        OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .set_len(committed as u64)?;
+    }
+
+    Ok(result)
+}
+
+/// hint 文件中每一项的格式：table 长度 | key 长度 | 位置 | 记录长度 | 过期时刻 | table | key，
+/// 文件末尾是所有内容的 CRC32
+fn encode_hint(buf: &mut Vec<u8>, table: &str, key: &str, entry: &KeydirEntry) {
+    buf.put_u32(table.len() as _);
+    buf.put_u32(key.len() as _);
+    buf.put_u64(entry.offset);
+    buf.put_u32(entry.size);
+    buf.put_u64(entry.expire_at.unwrap_or(0));
+    buf.extend_from_slice(table.as_bytes());
+    buf.extend_from_slice(key.as_bytes());
+}
+
+// 读取 hint 文件，不存在或者损坏时返回 None，这时需要扫描数据文件
+fn load_hint(dir: &Path, id: u64) -> Result<Option<KeydirChanges>, KvError> {
+    let path = hint_path(dir, id);
+    let data = match fs::read(&path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let entries = decode_hint(&data, id);
+    if entries.is_none() {
+        warn!("Hint file {:?} is broken, scan the data file instead", path);
+    }
+
+    Ok(entries)
+}
+
+fn decode_hint(data: &[u8], id: u64) -> Option<KeydirChanges> {
+    let (mut buf, mut crc) = data.split_at(data.len().checked_sub(4)?);
+    if crc32fast::hash(buf) != crc.get_u32() {
+        return None;
+    }
+
+    let mut entries = Vec::new();
+    while buf.has_remaining() {
+        if buf.remaining() < 28 {
+            return None;
+        }
+        let table_len = buf.get_u32() as usize;
+        let key_len = buf.get_u32() as usize;
+        let offset = buf.get_u64();
+        let size = buf.get_u32();
+        let expire_at = buf.get_u64();
+        if buf.remaining() < table_len + key_len {
+            return None;
+        }
+        let table = String::from_utf8_lossy(&buf[..table_len]).into_owned();
+        buf.advance(table_len);
+        let key = String::from_utf8_lossy(&buf[..key_len]).into_owned();
+        buf.advance(key_len);
+        let entry = KeydirEntry {
+            file_id: id,
+            offset,
+            size,
+            expire_at: if expire_at == 0 {
+                None
+            } else {
+                Some(expire_at)
+            },
+        };
+        entries.push((table, key, Some(entry)));
+    }
+
+    Some(entries)
+}
+
+fn data_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:06}.data", id))
+}
+
+fn hint_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:06}.hint", id))
+}
+
+fn merge_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:06}.merge", id))
+}
+
+fn file_id(path: &Path) -> Option<u64> {
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+fn remove_if_exists(path: &Path) -> Result<(), KvError> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+fn corrupted(path: &Path, offset: u64, reason: &str) -> KvError {
+    KvError::Corrupted(format!("{:?} at {}: {}", path, offset, reason))
+}
+
+// 当前时间戳（毫秒），过期时间需要在重启后依然有效
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+// ttl 之后的时间戳（毫秒），ttl 太大时取 u64::MAX，相当于不过期
+fn deadline(ttl: Duration) -> u64 {
+    now().saturating_add(ttl.as_millis().try_into().unwrap_or(u64::MAX))
+}
+
+fn is_expired(expire_at: Option<u64>) -> bool {
+    matches!(expire_at, Some(at) if at <= now())
+}
+
+/// BitcaskStorage 上的一组读写操作。修改先记在 writes 里，之后的读取能看到这些修改，
+/// 提交时作为一批追加到数据文件
+struct BitcaskTxn<'a> {
+    state: &'a State,
+    writes: RefCell<HashMap<String, HashMap<String, Change>>>,
+}
+
+impl<'a> BitcaskTxn<'a> {
+    fn new(state: &'a State) -> Self {
+        Self {
+            state,
+            writes: RefCell::new(HashMap::new()),
+        }
+    }
+
+    fn into_batch(self) -> Vec<(String, String, Change)> {
+        self.writes
+            .into_inner()
+            .into_iter()
+            .flat_map(|(table, writes)| {
+                writes
+                    .into_iter()
+                    .map(move |(key, write)| (table.clone(), key, write))
+            })
+            .collect()
+    }
+
+    // 没有被删除也没有过期的 value 和过期时刻
+    fn live(&self, table: &str, key: &str) -> Result<Option<(Value, Option<u64>)>, KvError> {
+        if let Some(write) = self.writes.borrow().get(table).and_then(|t| t.get(key)) {
+            return Ok(match write {
+                Some((value, at)) if !is_expired(*at) => Some((value.clone(), *at)),
+                _ => None,
+            });
+        }
+        match self.state.entry(table, key) {
+            Some(entry) if !is_expired(entry.expire_at) => {
+                Ok(Some((self.state.read_value(entry)?, entry.expire_at)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    // table 中没有被删除也没有过期的 key
+    fn keys(&self, table: &str) -> Vec<String> {
+        let writes = self.writes.borrow();
+        let writes = writes.get(table);
+        let mut keys: Vec<_> = self
+            .state
+            .keydir
+            .get(table)
+            .into_iter()
+            .flatten()
+            .filter(|(k, e)| {
+                !is_expired(e.expire_at) && !writes.is_some_and(|w| w.contains_key(*k))
+            })
+            .map(|(k, _)| k.clone())
+            .collect();
+        keys.extend(writes.into_iter().flatten().filter_map(|(k, w)| match w {
+            Some((_, at)) if !is_expired(*at) => Some(k.clone()),
+            _ => None,
+        }));
+
+        keys
+    }
+
+    fn put(&self, table: &str, key: String, write: Change) {
+        self.writes
+            .borrow_mut()
+            .entry(table.into())
+            .or_default()
+            .insert(key, write);
+    }
+}
+
+impl Storage for BitcaskTxn<'_> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        Ok(self.live(table, key)?.map(|(v, _)| v))
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        let old = self.get(table, &key)?;
+        self.put(table, key, Some((value, None)));
+
+        Ok(old)
+    }
+
+    fn set_with_ttl(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> Result<Option<Value>, KvError> {
+        let old = self.get(table, &key)?;
+        let at = deadline(ttl);
+        self.put(table, key, Some((value, Some(at))));
+
+        Ok(old)
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        Ok(self.live(table, key)?.is_some())
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let old = self.get(table, key)?;
+        if old.is_some() {
+            self.put(table, key.into(), None);
+        }
+
+        Ok(old)
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        let mut pairs = Vec::new();
+        for key in self.keys(table) {
+            if let Some(value) = self.get(table, &key)? {
+                pairs.push(Kvpair::new(key, value));
+            }
+        }
+
+        Ok(pairs)
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        Ok(Box::new(self.get_all(table)?.into_iter()))
+    }
+
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        let writes = self.writes.borrow();
+        let tables: BTreeSet<_> = self.state.keydir.keys().chain(writes.keys()).collect();
+
+        Ok(tables
+            .into_iter()
+            .filter(|table| !self.keys(table).is_empty())
+            .cloned()
+            .collect())
+    }
+
+    fn drop_table(&self, table: &str) -> Result<bool, KvError> {
+        let keys = self.keys(table);
+        let existed = !keys.is_empty();
+        for key in keys {
+            self.put(table, key, None);
+        }
+
+        Ok(existed)
+    }
+
+    fn table_len(&self, table: &str) -> Result<usize, KvError> {
+        Ok(self.keys(table).len())
+    }
+
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        match self.live(table, key)? {
+            Some((value, _)) => {
+                let at = deadline(ttl);
+                self.put(table, key.into(), Some((value, Some(at))));
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Duration>, KvError> {
+        match self.live(table, key)? {
+            Some((_, at)) => Ok(at.map(|at| Duration::from_millis(at.saturating_sub(now())))),
+            None => Err(KvError::NotFound(table.into(), key.into())),
+        }
+    }
+
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        match self.live(table, key)? {
+            Some((value, Some(_))) => {
+                self.put(table, key.into(), Some((value, None)));
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    // 在写锁里执行，先读再写不会被其它修改打断
+    fn compare_and_swap(
+        &self,
+        table: &str,
+        key: &str,
+        expected: Option<&Value>,
+        value: Value,
+    ) -> Result<Result<(), Option<Value>>, KvError> {
+        serial_compare_and_swap(self, table, key, expected, value)
+    }
+
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        serial_incr(self, table, key, delta)
+    }
+
+    fn incr_float(&self, table: &str, key: &str, delta: f64) -> Result<f64, KvError> {
+        serial_incr_float(self, table, key, delta)
+    }
+
+    fn update_path(
+        &self,
+        table: &str,
+        key: &str,
+        path: &[PathSegment],
+        op: PathOp,
+    ) -> Result<Option<Value>, KvError> {
+        serial_update_path(self, table, key, path, op)
+    }
+
+    fn transaction(
+        &self,
+        _tables: &[&str],
+        _f: &mut dyn FnMut(&dyn Storage) -> Result<(), KvError>,
+    ) -> Result<(), KvError> {
+        Err(KvError::InvalidCommand("Nested transaction".into()))
+    }
+}
+
+impl Storage for BitcaskStorage {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.read(|t| t.get(table, key))
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        self.write(|t| t.set(table, key, value))
+    }
+
+    fn set_with_ttl(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> Result<Option<Value>, KvError> {
+        self.write(|t| t.set_with_ttl(table, key, value, ttl))
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.read(|t| t.contains(table, key))
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.write(|t| t.del(table, key))
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.read(|t| t.get_all(table))
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        self.read(|t| t.get_iter(table))
+    }
+
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        self.read(|t| t.list_tables())
+    }
+
+    fn drop_table(&self, table: &str) -> Result<bool, KvError> {
+        self.write(|t| t.drop_table(table))
+    }
+
+    fn table_len(&self, table: &str) -> Result<usize, KvError> {
+        self.read(|t| t.table_len(table))
+    }
+
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        self.write(|t| t.expire(table, key, ttl))
+    }
+
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Duration>, KvError> {
+        self.read(|t| t.ttl(table, key))
+    }
+
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.write(|t| t.persist(table, key))
+    }
+
+    // 下面这些在写锁里执行，读和写之间不会有其它修改
+    fn compare_and_swap(
+        &self,
+        table: &str,
+        key: &str,
+        expected: Option<&Value>,
+        value: Value,
+    ) -> Result<Result<(), Option<Value>>, KvError> {
+        self.write(|t| t.compare_and_swap(table, key, expected, value))
+    }
+
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        self.write(|t| t.incr(table, key, delta))
+    }
+
+    fn incr_float(&self, table: &str, key: &str, delta: f64) -> Result<f64, KvError> {
+        self.write(|t| t.incr_float(table, key, delta))
+    }
+
+    fn update_path(
+        &self,
+        table: &str,
+        key: &str,
+        path: &[PathSegment],
+        op: PathOp,
+    ) -> Result<Option<Value>, KvError> {
+        self.write(|t| t.update_path(table, key, path, op))
+    }
+
+    fn transaction(
+        &self,
+        _tables: &[&str],
+        f: &mut dyn FnMut(&dyn Storage) -> Result<(), KvError>,
+    ) -> Result<(), KvError> {
+        self.write(|t| f(t))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    // 很小的数据文件，写入少量数据就会切换文件
+    fn small_config() -> BitcaskConfig {
+        BitcaskConfig {
+            max_file_size: 256,
+            sync: false,
+            merge_min_size: u64::MAX,
+            merge_percentage: 50,
+        }
+    }
+
+    fn files(dir: &Path, ext: &str) -> Vec<u64> {
+        let mut ids: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|item| item.unwrap().path())
+            .filter(|p| p.extension().unwrap() == ext)
+            .filter_map(|p| file_id(&p))
+            .collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    #[test]
+    fn bitcask_should_recover_after_restart() {
+        let dir = tempdir().unwrap();
+        let store = BitcaskStorage::open(dir.path(), small_config()).unwrap();
+        for i in 0..50 {
+            store.set("t1", format!("key{}", i % 10), i.into()).unwrap();
+        }
+        store.del("t1", "key0").unwrap();
+        store
+            .set_with_ttl("t2", "k1".into(), "v1".into(), Duration::from_secs(60))
+            .unwrap();
+        assert!(files(dir.path(), "data").len() > 1);
+        drop(store);
+
+        let store = BitcaskStorage::open(dir.path(), small_config()).unwrap();
+        assert_eq!(store.get("t1", "key0").unwrap(), None);
+        assert_eq!(store.get("t1", "key9").unwrap(), Some(49.into()));
+        assert_eq!(store.table_len("t1").unwrap(), 9);
+        assert!(store.ttl("t2", "k1").unwrap().is_some());
+    }
+
+    #[test]
+    fn bitcask_merge_should_drop_dead_records_and_write_hints() {
+        let dir = tempdir().unwrap();
+        let store = BitcaskStorage::open(dir.path(), small_config()).unwrap();
+        for i in 0..50 {
+            store.set("t1", format!("key{}", i % 10), i.into()).unwrap();
+        }
+        store.drop_table("t1").unwrap();
+        store.set("t1", "key1".into(), "v1".into()).unwrap();
+        store
+            .set_with_ttl("t2", "k1".into(), "v1".into(), Duration::from_millis(10))
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+
+        let before = store.state.read().unwrap().total_size;
+        store.merge().unwrap();
+        let after = store.state.read().unwrap().total_size;
+        assert!(after < before / 10, "{} -> {}", before, after);
+        assert_eq!(files(dir.path(), "hint").len(), 1);
+        assert_eq!(store.get("t1", "key1").unwrap(), Some("v1".into()));
+        store.set("t1", "key2".into(), "v2".into()).unwrap();
+        drop(store);
+
+        // 合并后的文件从 hint 文件加载，之后的写入依然需要扫描数据文件
+        let store = BitcaskStorage::open(dir.path(), small_config()).unwrap();
+        assert_eq!(store.list_tables().unwrap(), ["t1"]);
+        assert_eq!(store.get("t1", "key1").unwrap(), Some("v1".into()));
+        assert_eq!(store.get("t1", "key2").unwrap(), Some("v2".into()));
+        drop(store);
+
+        // hint 文件损坏时退回到扫描数据文件
+        let hint = hint_path(dir.path(), files(dir.path(), "hint")[0]);
+        fs::write(&hint, b"garbage").unwrap();
+        let store = BitcaskStorage::open(dir.path(), small_config()).unwrap();
+        assert_eq!(store.get("t1", "key1").unwrap(), Some("v1".into()));
+    }
+
+    #[test]
+    fn bitcask_should_merge_automatically() {
+        let dir = tempdir().unwrap();
+        let config = BitcaskConfig {
+            merge_min_size: 1024,
+            ..small_config()
+        };
+        let store = BitcaskStorage::open(dir.path(), config).unwrap();
+        for i in 0..200 {
+            store.set("t1", "k1".into(), i.into()).unwrap();
+        }
+        // merge 在后台执行，等它完成后再写入一次，期间写入的数据也会被 merge 掉
+        store.join_merge();
+        store.set("t1", "k1".into(), 200.into()).unwrap();
+        store.join_merge();
+        assert!(store.state.read().unwrap().total_size < 1024 * 2);
+        assert_eq!(store.get("t1", "k1").unwrap(), Some(200.into()));
+    }
+
+    #[test]
+    fn bitcask_merge_should_keep_concurrent_writes() {
+        let dir = tempdir().unwrap();
+        let store = BitcaskStorage::open(dir.path(), small_config()).unwrap();
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for _ in 0..5 {
+                    store.merge().unwrap();
+                }
+            });
+            for i in 0..200 {
+                store.set("t1", format!("key{}", i % 10), i.into()).unwrap();
+            }
+        });
+        store.del("t1", "key0").unwrap();
+        let check = |store: &BitcaskStorage| {
+            assert_eq!(store.get("t1", "key0").unwrap(), None);
+            for i in 1..10 {
+                let key = format!("key{}", i);
+                assert_eq!(store.get("t1", &key).unwrap(), Some((190 + i).into()));
+            }
+        };
+        check(&store);
+        drop(store);
+
+        check(&BitcaskStorage::open(dir.path(), small_config()).unwrap());
+    }
+
+    #[test]
+    fn bitcask_failed_merge_should_not_leave_outputs() {
+        let dir = tempdir().unwrap();
+        let store = BitcaskStorage::open(dir.path(), small_config()).unwrap();
+        for i in 0..20 {
+            store.set("t1", format!("key{}", i), i.into()).unwrap();
+        }
+        // 损坏第一条记录，merge 读到它时失败
+        let path = data_path(dir.path(), 0);
+        let data = fs::read(&path).unwrap();
+        let mut corrupted = data.clone();
+        corrupted[HEADER_LEN + 3] ^= 1;
+        fs::write(&path, &corrupted).unwrap();
+
+        assert!(matches!(store.merge(), Err(KvError::Corrupted(_))));
+        assert!(files(dir.path(), "merge").is_empty());
+        assert!(files(dir.path(), "hint").is_empty());
+
+        // 之后的写入在重启后依然是最新的
+        fs::write(&path, &data).unwrap();
+        store.set("t1", "key1".into(), "v1".into()).unwrap();
+        drop(store);
+        let store = BitcaskStorage::open(dir.path(), small_config()).unwrap();
+        assert_eq!(store.get("t1", "key1").unwrap(), Some("v1".into()));
+        assert_eq!(store.get("t1", "key19").unwrap(), Some(19.into()));
+        assert_eq!(store.table_len("t1").unwrap(), 20);
+    }
+
+    #[test]
+    fn bitcask_should_detect_corruption() {
+        let dir = tempdir().unwrap();
+        let store = BitcaskStorage::open(dir.path(), small_config()).unwrap();
+        for i in 0..20 {
+            store.set("t1", format!("key{}", i), i.into()).unwrap();
+        }
+        // 第一条记录的 key 中的一个字节
+        let path = data_path(dir.path(), 0);
+        let mut data = fs::read(&path).unwrap();
+        data[HEADER_LEN + 3] ^= 1;
+        fs::write(&path, &data).unwrap();
+
+        assert!(matches!(
+            store.get("t1", "key0"),
+            Err(KvError::Corrupted(_))
+        ));
+        assert_eq!(store.get("t1", "key19").unwrap(), Some(19.into()));
+        drop(store);
+
+        // 不是最后一个数据文件中的损坏在启动时就会被发现
+        assert!(matches!(
+            BitcaskStorage::open(dir.path(), small_config()),
+            Err(KvError::Corrupted(_))
+        ));
+    }
+
+    #[test]
+    fn bitcask_should_drop_incomplete_batch() {
+        let dir = tempdir().unwrap();
+        let store = BitcaskStorage::new(dir.path());
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store
+            .transaction(&["t1"], &mut |txn| {
+                txn.set("t1", "k2".into(), "v2".into())?;
+                txn.set("t1", "k3".into(), "v3".into())?;
+                Ok(())
+            })
+            .unwrap();
+        drop(store);
+
+        // 模拟写入一批记录的中途崩溃：去掉最后一条记录的最后一个字节
+        let path = data_path(dir.path(), 0);
+        let size = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(size - 1)
+            .unwrap();
+
+        let store = BitcaskStorage::new(dir.path());
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.table_len("t1").unwrap(), 1);
+        store.set("t1", "k4".into(), "v4".into()).unwrap();
+        drop(store);
+
+        let store = BitcaskStorage::new(dir.path());
+        assert_eq!(store.get("t1", "k4").unwrap(), Some("v4".into()));
+        assert_eq!(store.table_len("t1").unwrap(), 2);
+    }
+}
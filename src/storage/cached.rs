@@ -0,0 +1,1050 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Mutex, RwLock},
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+use crate::{
+    storage::{serial_compare_and_swap, serial_incr, serial_incr_float, serial_update_path},
+    KvError, Kvpair, ListEnd, MemTable, PathOp, PathSegment, SledDb, Storage, Value, Version,
+};
+
+/// 修改如何写入底层存储
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CacheMode {
+    /// 先写底层存储再更新缓存，写入返回时数据已经持久化
+    WriteThrough,
+    /// 只写缓存并记下被修改的 key 和修改后的数据，调用 flush、key 被淘汰或者 CachedStorage 被释放时才写入底层存储
+    WriteBack,
+}
+
+#[derive(Clone, Debug)]
+pub struct CacheConfig {
+    pub mode: CacheMode,
+    /// 缓存中最多保存的 key 的数量，超过之后淘汰最久没有访问的 key
+    pub capacity: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            mode: CacheMode::WriteThrough,
+            capacity: 10_000,
+        }
+    }
+}
+
+/// 在 Backing 前面加一层 Cache：读取时先查缓存，没有命中再读 Backing 并放入缓存。
+/// 遍历 table 之类的操作直接访问 Backing（write-back 模式下会先 flush）
+pub struct CachedStorage<Cache: Storage = MemTable, Backing: Storage = SledDb> {
+    cache: Cache,
+    backing: Backing,
+    config: CacheConfig,
+    // 命中缓存的读取和不改变缓存的读取持有读锁，其它操作持有写锁，
+    // 避免读到旧数据之后覆盖掉缓存中新的数据
+    lock: RwLock<()>,
+    lru: Mutex<Lru>,
+    // write-back 模式下还没有写入 Backing 的 key 和它们修改后的数据。
+    // 内存有上限的 Cache 可能自己淘汰掉这些 key，所以修改记在这里，不依赖缓存中的数据
+    dirty: Mutex<HashMap<(String, String), Pending>>,
+}
+
+impl<Cache: Storage, Backing: Storage> CachedStorage<Cache, Backing> {
+    pub fn new(cache: Cache, backing: Backing, config: CacheConfig) -> Self {
+        assert!(config.capacity > 0, "cache capacity must be positive");
+        Self {
+            cache,
+            backing,
+            config,
+            lock: RwLock::new(()),
+            lru: Mutex::new(Lru::default()),
+            dirty: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// 把 write-back 模式下所有修改过的 key 作为一个事务写入 Backing
+    pub fn flush(&self) -> Result<(), KvError> {
+        let _guard = self.lock.write().unwrap();
+        Unlocked(self).flush()
+    }
+
+    // 在写锁的保护下执行 f
+    fn write<T>(
+        &self,
+        f: impl FnOnce(&Unlocked<Cache, Backing>) -> Result<T, KvError>,
+    ) -> Result<T, KvError> {
+        let _guard = self.lock.write().unwrap();
+        f(&Unlocked(self))
+    }
+
+    // 在读锁的保护下执行只访问 Backing、不改变缓存的读取
+    fn read<T>(
+        &self,
+        f: impl FnOnce(&Unlocked<Cache, Backing>) -> Result<T, KvError>,
+    ) -> Result<T, KvError> {
+        let _guard = self.lock.read().unwrap();
+        f(&Unlocked(self))
+    }
+
+    // 遍历之类需要先 flush 的读取：没有待写入的修改时只需要读锁，否则在写锁里先 flush
+    fn read_flushed<T>(
+        &self,
+        f: impl FnOnce(&Unlocked<Cache, Backing>) -> Result<T, KvError>,
+    ) -> Result<T, KvError> {
+        {
+            let _guard = self.lock.read().unwrap();
+            if self.dirty.lock().unwrap().is_empty() {
+                return f(&Unlocked(self));
+            }
+        }
+        self.write(f)
+    }
+}
+
+impl<Cache: Storage, Backing: Storage> Drop for CachedStorage<Cache, Backing> {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            warn!("Failed to flush cached storage: {:?}", e);
+        }
+    }
+}
+
+/// 按最近访问的顺序记录缓存中的 key
+#[derive(Debug, Default)]
+struct Lru {
+    tick: u64,
+    keys: HashMap<(String, String), u64>,
+    order: BTreeMap<u64, (String, String)>,
+}
+
+impl Lru {
+    fn touch(&mut self, table: &str, key: &str) {
+        let id = (table.to_owned(), key.to_owned());
+        if let Some(tick) = self.keys.remove(&id) {
+            self.order.remove(&tick);
+        }
+        self.tick += 1;
+        self.keys.insert(id.clone(), self.tick);
+        self.order.insert(self.tick, id);
+    }
+
+    fn remove(&mut self, table: &str, key: &str) {
+        if let Some(tick) = self.keys.remove(&(table.to_owned(), key.to_owned())) {
+            self.order.remove(&tick);
+        }
+    }
+
+    fn remove_table(&mut self, table: &str) {
+        let keys = &mut self.keys;
+        self.order.retain(|_, (t, k)| {
+            if t == table {
+                keys.remove(&(t.clone(), k.clone()));
+                return false;
+            }
+            true
+        });
+    }
+
+    // 超过 capacity 时取出最久没有访问的 key
+    fn pop_over(&mut self, capacity: usize) -> Vec<(String, String)> {
+        let mut evicted = Vec::new();
+        while self.keys.len() > capacity {
+            let (_, id) = self.order.pop_first().unwrap();
+            self.keys.remove(&id);
+            evicted.push(id);
+        }
+        evicted
+    }
+}
+
+/// 不加锁的 CachedStorage，调用者需要持有写锁。
+/// compare_and_swap、incr 等先读再写，在写锁里执行时是原子的
+struct Unlocked<'a, Cache: Storage, Backing: Storage>(&'a CachedStorage<Cache, Backing>);
+
+impl<Cache: Storage, Backing: Storage> Unlocked<'_, Cache, Backing> {
+    fn write_back(&self) -> bool {
+        self.0.config.mode == CacheMode::WriteBack
+    }
+
+    fn pending(&self, table: &str, key: &str) -> Option<Pending> {
+        let dirty = self.0.dirty.lock().unwrap();
+        dirty.get(&(table.to_owned(), key.to_owned())).cloned()
+    }
+
+    // value 为 None 表示删除
+    fn mark_dirty(&self, table: &str, key: &str, value: Option<Value>, ttl: Option<Duration>) {
+        // ttl 大到超出 Instant 的范围时相当于不过期
+        let pending = value.map(|v| (v, ttl.and_then(|ttl| Instant::now().checked_add(ttl))));
+        let mut dirty = self.0.dirty.lock().unwrap();
+        dirty.insert((table.to_owned(), key.to_owned()), pending);
+    }
+
+    // 缓存没有命中时从 Backing 读取并放入缓存
+    fn load(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let s = self.0;
+        if let Some(value) = s.cache.get(table, key)? {
+            s.lru.lock().unwrap().touch(table, key);
+            return Ok(Some(value));
+        }
+        // write-back 模式下还没有写入 Backing 的修改，可能已经被 Cache 淘汰了，重新放入缓存
+        if let Some(pending) = self.pending(table, key) {
+            return match change(pending) {
+                Some((value, ttl)) => {
+                    self.put(table, key.into(), value.clone(), ttl)?;
+                    Ok(Some(value))
+                }
+                None => Ok(None),
+            };
+        }
+
+        let value = match s.backing.get(table, key)? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let ttl = match s.backing.ttl(table, key) {
+            Ok(ttl) => ttl,
+            Err(KvError::NotFound(_, _)) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        self.put(table, key.into(), value.clone(), ttl)?;
+
+        Ok(Some(value))
+    }
+
+    // 写入缓存，超过容量时淘汰最久没有访问的 key，被淘汰的修改过的 key 先写入 Backing
+    fn put(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        ttl: Option<Duration>,
+    ) -> Result<(), KvError> {
+        let s = self.0;
+        let evicted = {
+            let mut lru = s.lru.lock().unwrap();
+            lru.touch(table, &key);
+            lru.pop_over(s.config.capacity)
+        };
+        match ttl {
+            Some(ttl) => s.cache.set_with_ttl(table, key, value, ttl)?,
+            None => s.cache.set(table, key, value)?,
+        };
+
+        for (table, key) in evicted {
+            let pending = s
+                .dirty
+                .lock()
+                .unwrap()
+                .remove(&(table.clone(), key.clone()));
+            if let Some(pending) = pending {
+                apply(&s.backing, &table, &key, change(pending))?;
+            }
+            s.cache.del(&table, &key)?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), KvError> {
+        let s = self.0;
+        let changes: Vec<_> = s
+            .dirty
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((table, key), pending)| (table.clone(), key.clone(), change(pending.clone())))
+            .collect();
+        if changes.is_empty() {
+            return Ok(());
+        }
+
+        let mut tables: Vec<_> = changes.iter().map(|(t, _, _)| t.as_str()).collect();
+        tables.sort_unstable();
+        tables.dedup();
+        s.backing.transaction(&tables, &mut |txn| {
+            for (table, key, change) in &changes {
+                apply(txn, table, key, change.clone())?;
+            }
+            Ok(())
+        })?;
+        s.dirty.lock().unwrap().clear();
+
+        Ok(())
+    }
+
+    // 遍历之类的操作访问 Backing，write-back 模式下先把修改写进去
+    fn backing(&self) -> Result<&Backing, KvError> {
+        self.flush()?;
+        Ok(&self.0.backing)
+    }
+}
+
+/// 一个 key 的数据和剩余的存活时间，None 表示删除
+type Change = Option<(Value, Option<Duration>)>;
+
+/// write-back 模式下 key 修改后的数据和过期时刻，None 表示删除
+type Pending = Option<(Value, Option<Instant>)>;
+
+// 已经过期的修改相当于删除
+fn change(pending: Pending) -> Change {
+    let (value, at) = pending?;
+    match at {
+        Some(at) => {
+            let ttl = at.checked_duration_since(Instant::now())?;
+            (!ttl.is_zero()).then_some((value, Some(ttl)))
+        }
+        None => Some((value, None)),
+    }
+}
+
+fn apply(
+    store: &(impl Storage + ?Sized),
+    table: &str,
+    key: &str,
+    change: Change,
+) -> Result<(), KvError> {
+    match change {
+        Some((value, Some(ttl))) => store.set_with_ttl(table, key.into(), value, ttl)?,
+        Some((value, None)) => store.set(table, key.into(), value)?,
+        None => store.del(table, key)?,
+    };
+
+    Ok(())
+}
+
+impl<Cache: Storage, Backing: Storage> Storage for Unlocked<'_, Cache, Backing> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.load(table, key)
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        let old = if self.write_back() {
+            let old = self.load(table, &key)?;
+            self.mark_dirty(table, &key, Some(value.clone()), None);
+            old
+        } else {
+            self.0.backing.set(table, key.clone(), value.clone())?
+        };
+        self.put(table, key, value, None)?;
+
+        Ok(old)
+    }
+
+    fn set_with_ttl(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> Result<Option<Value>, KvError> {
+        let old = if self.write_back() {
+            let old = self.load(table, &key)?;
+            self.mark_dirty(table, &key, Some(value.clone()), Some(ttl));
+            old
+        } else {
+            let backing = &self.0.backing;
+            backing.set_with_ttl(table, key.clone(), value.clone(), ttl)?
+        };
+        self.put(table, key, value, Some(ttl))?;
+
+        Ok(old)
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        Ok(self.load(table, key)?.is_some())
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let s = self.0;
+        let old = if self.write_back() {
+            let old = self.load(table, key)?;
+            self.mark_dirty(table, key, None, None);
+            old
+        } else {
+            s.backing.del(table, key)?
+        };
+        s.cache.del(table, key)?;
+        s.lru.lock().unwrap().remove(table, key);
+
+        Ok(old)
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.backing()?.get_all(table)
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        self.backing()?.get_iter(table)
+    }
+
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        self.backing()?.list_tables()
+    }
+
+    fn drop_table(&self, table: &str) -> Result<bool, KvError> {
+        let s = self.0;
+        let existed = self.backing()?.drop_table(table)?;
+        s.cache.drop_table(table)?;
+        s.lru.lock().unwrap().remove_table(table);
+
+        Ok(existed)
+    }
+
+    fn table_len(&self, table: &str) -> Result<usize, KvError> {
+        self.backing()?.table_len(table)
+    }
+
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        let s = self.0;
+        if self.write_back() {
+            match self.load(table, key)? {
+                Some(value) => self.mark_dirty(table, key, Some(value), Some(ttl)),
+                None => return Ok(false),
+            }
+        } else if !s.backing.expire(table, key, ttl)? {
+            return Ok(false);
+        }
+        s.cache.expire(table, key, ttl)?;
+
+        Ok(true)
+    }
+
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Duration>, KvError> {
+        if !self.write_back() {
+            return self.0.backing.ttl(table, key);
+        }
+        match self.load(table, key)? {
+            Some(_) => self.0.cache.ttl(table, key),
+            None => Err(KvError::NotFound(table.into(), key.into())),
+        }
+    }
+
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        let s = self.0;
+        if self.write_back() {
+            let value = self.load(table, key)?;
+            let persisted = s.cache.persist(table, key)?;
+            if persisted {
+                self.mark_dirty(table, key, value, None);
+            }
+            return Ok(persisted);
+        }
+        let persisted = s.backing.persist(table, key)?;
+        if persisted {
+            s.cache.persist(table, key)?;
+        }
+
+        Ok(persisted)
+    }
+
+    // 调用者持有写锁，先读再写是原子的
+    fn compare_and_swap(
+        &self,
+        table: &str,
+        key: &str,
+        expected: Option<&Value>,
+        value: Value,
+    ) -> Result<Result<(), Option<Value>>, KvError> {
+        serial_compare_and_swap(self, table, key, expected, value)
+    }
+
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        serial_incr(self, table, key, delta)
+    }
+
+    fn incr_float(&self, table: &str, key: &str, delta: f64) -> Result<f64, KvError> {
+        serial_incr_float(self, table, key, delta)
+    }
+
+    fn update_path(
+        &self,
+        table: &str,
+        key: &str,
+        path: &[PathSegment],
+        op: PathOp,
+    ) -> Result<Option<Value>, KvError> {
+        serial_update_path(self, table, key, path, op)
+    }
+
+    fn create_index(&self, table: &str) -> Result<bool, KvError> {
+        self.0.backing.create_index(table)
+    }
+
+    fn find(&self, table: &str, value: &Value) -> Result<Vec<Kvpair>, KvError> {
+        self.backing()?.find(table, value)
+    }
+
+    // 历史由 Backing 保留，写回模式下缓存里的修改写回之后才产生版本
+    fn history(&self, table: &str, key: &str) -> Result<Vec<Version>, KvError> {
+        self.backing()?.history(table, key)
+    }
+
+    fn get_version(&self, table: &str, key: &str, version: u64) -> Result<Option<Value>, KvError> {
+        self.backing()?.get_version(table, key, version)
+    }
+
+    fn get_at(&self, table: &str, key: &str, at: u64) -> Result<Option<Value>, KvError> {
+        self.backing()?.get_at(table, key, at)
+    }
+
+    fn gc_versions_in(
+        &self,
+        retention: Duration,
+        tables: &dyn Fn(&str) -> bool,
+    ) -> Result<usize, KvError> {
+        self.backing()?.gc_versions_in(retention, tables)
+    }
+
+    // list 不经过缓存，直接访问 Backing
+    fn list_push(
+        &self,
+        table: &str,
+        key: &str,
+        end: ListEnd,
+        values: Vec<Value>,
+    ) -> Result<usize, KvError> {
+        self.0.backing.list_push(table, key, end, values)
+    }
+
+    fn list_pop(
+        &self,
+        table: &str,
+        key: &str,
+        end: ListEnd,
+        count: usize,
+    ) -> Result<Vec<Value>, KvError> {
+        self.0.backing.list_pop(table, key, end, count)
+    }
+
+    fn list_range(
+        &self,
+        table: &str,
+        key: &str,
+        start: i64,
+        stop: i64,
+    ) -> Result<Vec<Value>, KvError> {
+        self.0.backing.list_range(table, key, start, stop)
+    }
+
+    fn list_trim(&self, table: &str, key: &str, start: i64, stop: i64) -> Result<usize, KvError> {
+        self.0.backing.list_trim(table, key, start, stop)
+    }
+
+    fn list_len(&self, table: &str, key: &str) -> Result<usize, KvError> {
+        self.0.backing.list_len(table, key)
+    }
+
+    fn list_index(&self, table: &str, key: &str, index: i64) -> Result<Option<Value>, KvError> {
+        self.0.backing.list_index(table, key, index)
+    }
+
+    fn list_keys(&self, table: &str) -> Result<Vec<String>, KvError> {
+        self.0.backing.list_keys(table)
+    }
+
+    fn set_add(&self, table: &str, key: &str, members: Vec<String>) -> Result<usize, KvError> {
+        self.0.backing.set_add(table, key, members)
+    }
+
+    fn set_remove(&self, table: &str, key: &str, members: Vec<String>) -> Result<usize, KvError> {
+        self.0.backing.set_remove(table, key, members)
+    }
+
+    fn set_members(&self, table: &str, key: &str) -> Result<Vec<String>, KvError> {
+        self.0.backing.set_members(table, key)
+    }
+
+    fn set_contains(&self, table: &str, key: &str, member: &str) -> Result<bool, KvError> {
+        self.0.backing.set_contains(table, key, member)
+    }
+
+    fn set_len(&self, table: &str, key: &str) -> Result<usize, KvError> {
+        self.0.backing.set_len(table, key)
+    }
+
+    fn set_keys(&self, table: &str) -> Result<Vec<String>, KvError> {
+        self.0.backing.set_keys(table)
+    }
+
+    fn zset_add(
+        &self,
+        table: &str,
+        key: &str,
+        members: Vec<(String, f64)>,
+    ) -> Result<usize, KvError> {
+        self.0.backing.zset_add(table, key, members)
+    }
+
+    fn zset_incr(&self, table: &str, key: &str, member: &str, delta: f64) -> Result<f64, KvError> {
+        self.0.backing.zset_incr(table, key, member, delta)
+    }
+
+    fn zset_remove(&self, table: &str, key: &str, members: Vec<String>) -> Result<usize, KvError> {
+        self.0.backing.zset_remove(table, key, members)
+    }
+
+    fn zset_range(
+        &self,
+        table: &str,
+        key: &str,
+        start: i64,
+        stop: i64,
+    ) -> Result<Vec<(String, f64)>, KvError> {
+        self.0.backing.zset_range(table, key, start, stop)
+    }
+
+    fn zset_range_by_score(
+        &self,
+        table: &str,
+        key: &str,
+        min: f64,
+        max: f64,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<(String, f64)>, KvError> {
+        self.0
+            .backing
+            .zset_range_by_score(table, key, min, max, offset, limit)
+    }
+
+    fn zset_score(&self, table: &str, key: &str, member: &str) -> Result<Option<f64>, KvError> {
+        self.0.backing.zset_score(table, key, member)
+    }
+
+    fn zset_rank(&self, table: &str, key: &str, member: &str) -> Result<Option<usize>, KvError> {
+        self.0.backing.zset_rank(table, key, member)
+    }
+
+    fn zset_len(&self, table: &str, key: &str) -> Result<usize, KvError> {
+        self.0.backing.zset_len(table, key)
+    }
+
+    fn zset_keys(&self, table: &str) -> Result<Vec<String>, KvError> {
+        self.0.backing.zset_keys(table)
+    }
+
+    fn transaction(
+        &self,
+        _tables: &[&str],
+        _f: &mut dyn FnMut(&dyn Storage) -> Result<(), KvError>,
+    ) -> Result<(), KvError> {
+        Err(KvError::InvalidCommand("Nested transaction".into()))
+    }
+}
+
+impl<Cache: Storage, Backing: Storage> Storage for CachedStorage<Cache, Backing> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        // 命中缓存时只需要读锁
+        {
+            let _guard = self.lock.read().unwrap();
+            if let Some(value) = self.cache.get(table, key)? {
+                self.lru.lock().unwrap().touch(table, key);
+                return Ok(Some(value));
+            }
+        }
+        self.write(|s| s.get(table, key))
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        self.write(|s| s.set(table, key, value))
+    }
+
+    fn set_with_ttl(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> Result<Option<Value>, KvError> {
+        self.write(|s| s.set_with_ttl(table, key, value, ttl))
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        Ok(self.get(table, key)?.is_some())
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.write(|s| s.del(table, key))
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.read_flushed(|s| s.get_all(table))
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        self.read_flushed(|s| s.get_iter(table))
+    }
+
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        self.read_flushed(|s| s.list_tables())
+    }
+
+    fn drop_table(&self, table: &str) -> Result<bool, KvError> {
+        self.write(|s| s.drop_table(table))
+    }
+
+    fn table_len(&self, table: &str) -> Result<usize, KvError> {
+        self.read_flushed(|s| s.table_len(table))
+    }
+
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        self.write(|s| s.expire(table, key, ttl))
+    }
+
+    // write-through 模式下直接读 Backing，write-back 模式下可能需要把 key 放入缓存
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Duration>, KvError> {
+        match self.config.mode {
+            CacheMode::WriteThrough => self.read(|s| s.ttl(table, key)),
+            CacheMode::WriteBack => self.write(|s| s.ttl(table, key)),
+        }
+    }
+
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.write(|s| s.persist(table, key))
+    }
+
+    fn compare_and_swap(
+        &self,
+        table: &str,
+        key: &str,
+        expected: Option<&Value>,
+        value: Value,
+    ) -> Result<Result<(), Option<Value>>, KvError> {
+        self.write(|s| s.compare_and_swap(table, key, expected, value))
+    }
+
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        self.write(|s| s.incr(table, key, delta))
+    }
+
+    fn incr_float(&self, table: &str, key: &str, delta: f64) -> Result<f64, KvError> {
+        self.write(|s| s.incr_float(table, key, delta))
+    }
+
+    fn update_path(
+        &self,
+        table: &str,
+        key: &str,
+        path: &[PathSegment],
+        op: PathOp,
+    ) -> Result<Option<Value>, KvError> {
+        self.write(|s| s.update_path(table, key, path, op))
+    }
+
+    fn create_index(&self, table: &str) -> Result<bool, KvError> {
+        self.write(|s| s.create_index(table))
+    }
+
+    fn find(&self, table: &str, value: &Value) -> Result<Vec<Kvpair>, KvError> {
+        self.read_flushed(|s| s.find(table, value))
+    }
+
+    fn history(&self, table: &str, key: &str) -> Result<Vec<Version>, KvError> {
+        self.read_flushed(|s| s.history(table, key))
+    }
+
+    fn get_version(&self, table: &str, key: &str, version: u64) -> Result<Option<Value>, KvError> {
+        self.read_flushed(|s| s.get_version(table, key, version))
+    }
+
+    fn get_at(&self, table: &str, key: &str, at: u64) -> Result<Option<Value>, KvError> {
+        self.read_flushed(|s| s.get_at(table, key, at))
+    }
+
+    fn gc_versions_in(
+        &self,
+        retention: Duration,
+        tables: &dyn Fn(&str) -> bool,
+    ) -> Result<usize, KvError> {
+        self.write(|s| s.gc_versions_in(retention, tables))
+    }
+
+    fn list_push(
+        &self,
+        table: &str,
+        key: &str,
+        end: ListEnd,
+        values: Vec<Value>,
+    ) -> Result<usize, KvError> {
+        self.write(|s| s.list_push(table, key, end, values))
+    }
+
+    fn list_pop(
+        &self,
+        table: &str,
+        key: &str,
+        end: ListEnd,
+        count: usize,
+    ) -> Result<Vec<Value>, KvError> {
+        self.write(|s| s.list_pop(table, key, end, count))
+    }
+
+    fn list_range(
+        &self,
+        table: &str,
+        key: &str,
+        start: i64,
+        stop: i64,
+    ) -> Result<Vec<Value>, KvError> {
+        self.read(|s| s.list_range(table, key, start, stop))
+    }
+
+    fn list_trim(&self, table: &str, key: &str, start: i64, stop: i64) -> Result<usize, KvError> {
+        self.write(|s| s.list_trim(table, key, start, stop))
+    }
+
+    fn list_len(&self, table: &str, key: &str) -> Result<usize, KvError> {
+        self.read(|s| s.list_len(table, key))
+    }
+
+    fn list_index(&self, table: &str, key: &str, index: i64) -> Result<Option<Value>, KvError> {
+        self.read(|s| s.list_index(table, key, index))
+    }
+
+    fn list_keys(&self, table: &str) -> Result<Vec<String>, KvError> {
+        self.read(|s| s.list_keys(table))
+    }
+
+    fn set_add(&self, table: &str, key: &str, members: Vec<String>) -> Result<usize, KvError> {
+        self.write(|s| s.set_add(table, key, members))
+    }
+
+    fn set_remove(&self, table: &str, key: &str, members: Vec<String>) -> Result<usize, KvError> {
+        self.write(|s| s.set_remove(table, key, members))
+    }
+
+    fn set_members(&self, table: &str, key: &str) -> Result<Vec<String>, KvError> {
+        self.read(|s| s.set_members(table, key))
+    }
+
+    fn set_contains(&self, table: &str, key: &str, member: &str) -> Result<bool, KvError> {
+        self.read(|s| s.set_contains(table, key, member))
+    }
+
+    fn set_len(&self, table: &str, key: &str) -> Result<usize, KvError> {
+        self.read(|s| s.set_len(table, key))
+    }
+
+    fn set_keys(&self, table: &str) -> Result<Vec<String>, KvError> {
+        self.read(|s| s.set_keys(table))
+    }
+
+    fn zset_add(
+        &self,
+        table: &str,
+        key: &str,
+        members: Vec<(String, f64)>,
+    ) -> Result<usize, KvError> {
+        self.write(|s| s.zset_add(table, key, members))
+    }
+
+    fn zset_incr(&self, table: &str, key: &str, member: &str, delta: f64) -> Result<f64, KvError> {
+        self.write(|s| s.zset_incr(table, key, member, delta))
+    }
+
+    fn zset_remove(&self, table: &str, key: &str, members: Vec<String>) -> Result<usize, KvError> {
+        self.write(|s| s.zset_remove(table, key, members))
+    }
+
+    fn zset_range(
+        &self,
+        table: &str,
+        key: &str,
+        start: i64,
+        stop: i64,
+    ) -> Result<Vec<(String, f64)>, KvError> {
+        self.read(|s| s.zset_range(table, key, start, stop))
+    }
+
+    fn zset_range_by_score(
+        &self,
+        table: &str,
+        key: &str,
+        min: f64,
+        max: f64,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<(String, f64)>, KvError> {
+        self.read(|s| s.zset_range_by_score(table, key, min, max, offset, limit))
+    }
+
+    fn zset_score(&self, table: &str, key: &str, member: &str) -> Result<Option<f64>, KvError> {
+        self.read(|s| s.zset_score(table, key, member))
+    }
+
+    fn zset_rank(&self, table: &str, key: &str, member: &str) -> Result<Option<usize>, KvError> {
+        self.read(|s| s.zset_rank(table, key, member))
+    }
+
+    fn zset_len(&self, table: &str, key: &str) -> Result<usize, KvError> {
+        self.read(|s| s.zset_len(table, key))
+    }
+
+    fn zset_keys(&self, table: &str) -> Result<Vec<String>, KvError> {
+        self.read(|s| s.zset_keys(table))
+    }
+
+    // 事务直接在 Backing 上执行，成功之后清掉缓存中涉及的 table
+    fn transaction(
+        &self,
+        tables: &[&str],
+        f: &mut dyn FnMut(&dyn Storage) -> Result<(), KvError>,
+    ) -> Result<(), KvError> {
+        self.write(|s| {
+            s.backing()?.transaction(tables, f)?;
+            for table in tables {
+                self.cache.drop_table(table)?;
+                self.lru.lock().unwrap().remove_table(table);
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{EvictionPolicy, MemTableConfig};
+    use tempfile::{tempdir, TempDir};
+
+    fn cached(mode: CacheMode, capacity: usize) -> (CachedStorage, TempDir) {
+        let dir = tempdir().unwrap();
+        let config = CacheConfig { mode, capacity };
+        let store = CachedStorage::new(MemTable::new(), SledDb::new(dir.path()), config);
+        (store, dir)
+    }
+
+    #[test]
+    fn write_through_should_keep_cache_consistent() {
+        let (store, _dir) = cached(CacheMode::WriteThrough, 100);
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        assert_eq!(store.backing.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.cache.get("t1", "k1").unwrap(), Some("v1".into()));
+
+        // 缓存没有命中时从 Backing 读取，过期时间也会放进缓存
+        store
+            .backing
+            .set_with_ttl("t1", "k2".into(), "v2".into(), Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(store.get("t1", "k2").unwrap(), Some("v2".into()));
+        assert!(store.cache.ttl("t1", "k2").unwrap().is_some());
+
+        assert_eq!(store.del("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.cache.get("t1", "k1").unwrap(), None);
+        assert_eq!(store.backing.get("t1", "k1").unwrap(), None);
+        assert_eq!(store.get("t1", "k1").unwrap(), None);
+    }
+
+    #[test]
+    fn write_back_should_defer_writes_until_flush() {
+        let (store, dir) = cached(CacheMode::WriteBack, 100);
+        store.backing.set("t1", "k0".into(), "v0".into()).unwrap();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        assert_eq!(store.del("t1", "k0").unwrap(), Some("v0".into()));
+        assert_eq!(store.backing.get("t1", "k1").unwrap(), None);
+
+        // 删除之后不会再从 Backing 读到旧的数据
+        assert_eq!(store.get("t1", "k0").unwrap(), None);
+        assert_eq!(store.backing.get("t1", "k0").unwrap(), Some("v0".into()));
+
+        store.flush().unwrap();
+        assert_eq!(store.backing.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.backing.get("t1", "k0").unwrap(), None);
+
+        // 释放时会把修改写入 Backing
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        drop(store);
+        let backing = SledDb::new(dir.path());
+        assert_eq!(backing.get("t1", "k2").unwrap(), Some("v2".into()));
+    }
+
+    #[test]
+    fn cache_should_be_bounded() {
+        let (store, _dir) = cached(CacheMode::WriteBack, 3);
+        for i in 0..10 {
+            store.set("t1", format!("k{}", i), i.into()).unwrap();
+            store.get("t1", "k0").unwrap();
+        }
+        assert_eq!(store.cache.table_len("t1").unwrap(), 3);
+        assert!(store.cache.contains("t1", "k0").unwrap());
+
+        // 被淘汰的 key 已经写入 Backing
+        assert_eq!(store.backing.get("t1", "k1").unwrap(), Some(1.into()));
+        assert_eq!(store.get("t1", "k1").unwrap(), Some(1.into()));
+        assert_eq!(store.table_len("t1").unwrap(), 10);
+    }
+
+    #[test]
+    fn write_back_should_keep_keys_evicted_by_cache() {
+        let dir = tempdir().unwrap();
+        let cache = MemTable::with_config(MemTableConfig {
+            max_memory: 4096,
+            eviction: EvictionPolicy::Lru,
+        });
+        let config = CacheConfig {
+            mode: CacheMode::WriteBack,
+            capacity: 1000,
+        };
+        let store = CachedStorage::new(cache, SledDb::new(dir.path()), config);
+        let value = Value::from("v".repeat(64));
+        for i in 0..100 {
+            store.set("t1", format!("k{}", i), value.clone()).unwrap();
+        }
+        store
+            .set_with_ttl("t1", "k100".into(), value.clone(), Duration::from_secs(60))
+            .unwrap();
+        // 内存有上限的 Cache 自己淘汰了还没有写入 Backing 的 key
+        assert!(store.cache.evicted_keys() > 0);
+
+        assert_eq!(store.get("t1", "k0").unwrap(), Some(value.clone()));
+        store.flush().unwrap();
+        for i in 0..100 {
+            let key = format!("k{}", i);
+            assert_eq!(store.backing.get("t1", &key).unwrap(), Some(value.clone()));
+        }
+        assert!(store.backing.ttl("t1", "k100").unwrap().is_some());
+    }
+
+    #[test]
+    fn pass_through_reads_should_not_take_write_lock() {
+        let (store, _dir) = cached(CacheMode::WriteThrough, 100);
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store
+            .list_push("t1", "l1", ListEnd::Right, vec!["v1".into()])
+            .unwrap();
+        store.set_add("t1", "s1", vec!["m1".into()]).unwrap();
+
+        // 持有读锁时，不改变缓存的读取依然可以执行
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::scope(|scope| {
+            let guard = store.lock.read().unwrap();
+            scope.spawn(|| {
+                store.ttl("t1", "k1").unwrap();
+                store.list_range("t1", "l1", 0, -1).unwrap();
+                store.set_members("t1", "s1").unwrap();
+                store.zset_len("t1", "z1").unwrap();
+                store.get_all("t1").unwrap();
+                store.table_len("t1").unwrap();
+                tx.send(()).unwrap();
+            });
+            let done = rx.recv_timeout(Duration::from_secs(5));
+            drop(guard);
+            assert!(done.is_ok());
+        });
+    }
+
+    #[test]
+    fn transaction_should_invalidate_cache() {
+        let (store, _dir) = cached(CacheMode::WriteThrough, 100);
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store
+            .transaction(&["t1"], &mut |txn| {
+                txn.set("t1", "k1".into(), "v2".into())?;
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(store.cache.get("t1", "k1").unwrap(), None);
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v2".into()));
+    }
+}
@@ -0,0 +1,388 @@
+use bytes::BytesMut;
+use crc32fast::Hasher;
+use std::{
+    convert::{TryFrom, TryInto},
+    io::{self, BufReader, Read, Seek, SeekFrom, Write},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    command_request::RequestData, network::frame::read_frame_blocking, CommandRequest, FrameCoder,
+    Hdrop, Hexpireat, Hset, KvError, Kvpair, ListEnd, Rpush, Sadd, ScoredMember, Storage, Zadd,
+};
+
+/// dump 文件开头的 magic
+const MAGIC: &[u8; 8] = b"KVDUMP\0\0";
+/// dump 文件格式的版本。版本 3 增加了 Hexpireat
+const VERSION: u32 = 3;
+/// header 依次是 magic、version 和之后所有内容的 CRC32
+const HEADER_LEN: usize = 16;
+
+/// 把 store 里的所有 table 导出到 writer，返回导出的 key 的数量（list、set 和 sorted set 也各算一个）。
+/// header 之后是一串 CommandRequest frame：每个 table 以一个 Hdrop 开始，后面跟着 table 里的数据，
+/// 和 AOF 的快照一样，每个 list、set 和 sorted set 分别是一个 Rpush、Sadd 和 Zadd，
+/// 每个普通的 key 是一个 Hset，设置了过期时间的 key 后面跟着一个 Hexpireat
+pub fn dump(
+    store: &(impl Storage + ?Sized),
+    mut writer: impl Write + Seek,
+) -> Result<usize, KvError> {
+    let start = writer.stream_position()?;
+    writer.write_all(&header(0))?;
+
+    let mut hasher = Hasher::new();
+    let mut count = 0;
+    for table in store.list_tables()? {
+        write_record(&mut writer, &mut hasher, &CommandRequest::new_hdrop(&table))?;
+        for key in store.list_keys(&table)? {
+            let values = store.list_range(&table, &key, 0, -1)?;
+            if values.is_empty() {
+                continue;
+            }
+            let cmd = CommandRequest::new_rpush(&table, key, values);
+            write_record(&mut writer, &mut hasher, &cmd)?;
+            count += 1;
+        }
+        for key in store.set_keys(&table)? {
+            let members = store.set_members(&table, &key)?;
+            if members.is_empty() {
+                continue;
+            }
+            let cmd = CommandRequest::new_sadd(&table, key, members);
+            write_record(&mut writer, &mut hasher, &cmd)?;
+            count += 1;
+        }
+        for key in store.zset_keys(&table)? {
+            let members: Vec<_> = store
+                .zset_range(&table, &key, 0, -1)?
+                .into_iter()
+                .map(|(member, score)| ScoredMember::new(member, score))
+                .collect();
+            if members.is_empty() {
+                continue;
+            }
+            let cmd = CommandRequest::new_zadd(&table, key, members);
+            write_record(&mut writer, &mut hasher, &cmd)?;
+            count += 1;
+        }
+        for pair in store.get_iter(&table)? {
+            let ttl = match store.ttl(&table, &pair.key) {
+                Ok(ttl) => ttl,
+                // 刚刚过期了
+                Err(KvError::NotFound(_, _)) => continue,
+                Err(e) => return Err(e),
+            };
+            let cmd = CommandRequest::new_hset(&table, &pair.key, pair.value.unwrap_or_default());
+            write_record(&mut writer, &mut hasher, &cmd)?;
+            // 记录过期的时刻，恢复时扣掉 dump 之后过去的时间
+            if let Some(ttl) = ttl {
+                let cmd = CommandRequest::new_hexpireat(&table, pair.key, deadline(ttl));
+                write_record(&mut writer, &mut hasher, &cmd)?;
+            }
+            count += 1;
+        }
+    }
+
+    // 数据都写完之后才知道校验和，回到开头重写 header
+    let end = writer.stream_position()?;
+    writer.seek(SeekFrom::Start(start))?;
+    writer.write_all(&header(hasher.finalize()))?;
+    writer.seek(SeekFrom::Start(end))?;
+    writer.flush()?;
+
+    Ok(count)
+}
+
+/// 把 dump 的数据恢复到 store 里，返回恢复的 key 的数量。
+/// 整个文件校验通过之后才会修改 store，dump 中出现的 table 会先被清空。
+/// 版本 1 的 dump 只有普通的 key，版本 2 的 dump 没有过期时间，仍然可以恢复
+pub fn restore(
+    mut reader: impl Read + Seek,
+    store: &(impl Storage + ?Sized),
+) -> Result<usize, KvError> {
+    let mut header = [0u8; HEADER_LEN];
+    read_header(&mut reader, &mut header)?;
+    if &header[..8] != MAGIC {
+        return Err(KvError::InvalidDump("bad magic".into()));
+    }
+    let version = u32::from_be_bytes(header[8..12].try_into().unwrap());
+    if !(1..=VERSION).contains(&version) {
+        return Err(KvError::InvalidDump(format!(
+            "unsupported version {}",
+            version
+        )));
+    }
+    let checksum = u32::from_be_bytes(header[12..].try_into().unwrap());
+
+    let body = reader.stream_position()?;
+    let mut hasher = Checksum(Hasher::new());
+    io::copy(&mut reader, &mut hasher)?;
+    if hasher.0.finalize() != checksum {
+        return Err(KvError::InvalidDump("checksum mismatch".into()));
+    }
+    reader.seek(SeekFrom::Start(body))?;
+
+    let mut reader = BufReader::new(reader);
+    if version == 1 {
+        return restore_pairs(&mut reader, store);
+    }
+
+    let mut count = 0;
+    while let Some(mut buf) = read_frame_blocking(&mut reader)? {
+        let cmd = CommandRequest::decode_frame(&mut buf)?;
+        // 只接受 dump 写出的几种命令，直接调用 store 的方法，不经过 dispatch
+        match cmd.request_data {
+            Some(RequestData::Hdrop(Hdrop { table })) => {
+                store.drop_table(&table)?;
+            }
+            Some(RequestData::Hset(Hset {
+                table,
+                pair: Some(pair),
+            })) => {
+                store.set(&table, pair.key, pair.value.unwrap_or_default())?;
+                count += 1;
+            }
+            // 已经过去的时刻会让 key 立即过期
+            Some(RequestData::Hexpireat(Hexpireat { table, key, at })) => {
+                store.expire(&table, &key, remaining(at))?;
+            }
+            Some(RequestData::Rpush(Rpush { table, key, values })) => {
+                store.list_push(&table, &key, ListEnd::Right, values)?;
+                count += 1;
+            }
+            Some(RequestData::Sadd(Sadd {
+                table,
+                key,
+                members,
+            })) => {
+                store.set_add(&table, &key, members)?;
+                count += 1;
+            }
+            Some(RequestData::Zadd(Zadd {
+                table,
+                key,
+                members,
+            })) => {
+                let members = members.into_iter().map(|m| (m.member, m.score)).collect();
+                store.zset_add(&table, &key, members)?;
+                count += 1;
+            }
+            _ => return Err(KvError::InvalidDump("unexpected record".into())),
+        }
+    }
+
+    Ok(count)
+}
+
+// 版本 1 的 dump 是一串 Kvpair frame，每个 table 以一个只有 key（table 名）、没有 value 的 Kvpair 开始
+fn restore_pairs(
+    reader: &mut impl Read,
+    store: &(impl Storage + ?Sized),
+) -> Result<usize, KvError> {
+    let mut table = None;
+    let mut count = 0;
+    while let Some(mut buf) = read_frame_blocking(reader)? {
+        let pair = Kvpair::decode_frame(&mut buf)?;
+        match (pair.value, &table) {
+            (None, _) => {
+                store.drop_table(&pair.key)?;
+                table = Some(pair.key);
+            }
+            (Some(value), Some(table)) => {
+                store.set(table, pair.key, value)?;
+                count += 1;
+            }
+            (Some(_), None) => {
+                return Err(KvError::InvalidDump("record without table".into()));
+            }
+        }
+    }
+
+    Ok(count)
+}
+
+// ttl 之后的时刻（unix 时间戳，毫秒）
+fn deadline(ttl: Duration) -> u64 {
+    u64::try_from(now().saturating_add(ttl).as_millis()).unwrap_or(u64::MAX)
+}
+
+// 距离 at 时刻的时间，已经过去的时刻为 0
+fn remaining(at: u64) -> Duration {
+    Duration::from_millis(at).saturating_sub(now())
+}
+
+fn now() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+fn header(checksum: u32) -> [u8; HEADER_LEN] {
+    let mut header = [0u8; HEADER_LEN];
+    header[..8].copy_from_slice(MAGIC);
+    header[8..12].copy_from_slice(&VERSION.to_be_bytes());
+    header[12..].copy_from_slice(&checksum.to_be_bytes());
+    header
+}
+
+fn read_header(reader: &mut impl Read, header: &mut [u8]) -> Result<(), KvError> {
+    reader.read_exact(header).map_err(|e| match e.kind() {
+        io::ErrorKind::UnexpectedEof => KvError::InvalidDump("missing header".into()),
+        _ => e.into(),
+    })
+}
+
+fn write_record(
+    writer: &mut impl Write,
+    hasher: &mut Hasher,
+    record: &impl FrameCoder,
+) -> Result<(), KvError> {
+    let mut buf = BytesMut::new();
+    record.encode_frame(&mut buf)?;
+    hasher.update(&buf);
+    writer.write_all(&buf)?;
+
+    Ok(())
+}
+
+// 写入的数据只用来计算校验和
+struct Checksum(Hasher);
+
+impl Write for Checksum {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemTable, SledDb};
+    use std::io::Cursor;
+    use tempfile::tempdir;
+
+    #[test]
+    fn dump_and_restore_should_work() {
+        let store = MemTable::new();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t1", "user:42".into(), 42.into()).unwrap();
+        store.set("t2", "k1".into(), b"data".into()).unwrap();
+        let values = vec!["a".into(), 1.into()];
+        store
+            .list_push("t1", "l1", ListEnd::Right, values.clone())
+            .unwrap();
+        store
+            .set_add("t2", "s1", vec!["x".into(), "y".into()])
+            .unwrap();
+        store
+            .zset_add("t2", "z1", vec![("m1".into(), 2.0), ("m2".into(), 1.0)])
+            .unwrap();
+
+        let mut buf = Cursor::new(Vec::new());
+        assert_eq!(dump(&store, &mut buf).unwrap(), 6);
+
+        // 恢复到另一种存储里，已有的 table 会被清空
+        let dir = tempdir().unwrap();
+        let target = SledDb::new(dir);
+        target.set("t1", "old".into(), "v0".into()).unwrap();
+        target.set("t3", "k1".into(), "v3".into()).unwrap();
+        buf.set_position(0);
+        target
+            .list_push("t1", "l1", ListEnd::Right, vec!["old".into()])
+            .unwrap();
+        target.set_add("t2", "s2", vec!["old".into()]).unwrap();
+        assert_eq!(restore(&mut buf, &target).unwrap(), 6);
+
+        assert_eq!(target.list_tables().unwrap(), ["t1", "t2", "t3"]);
+        assert_eq!(target.get("t1", "old").unwrap(), None);
+        assert_eq!(target.get("t1", "user:42").unwrap(), Some(42.into()));
+        assert_eq!(target.get("t2", "k1").unwrap(), Some(b"data".into()));
+        assert_eq!(target.list_range("t1", "l1", 0, -1).unwrap(), values);
+        assert_eq!(target.set_keys("t2").unwrap(), ["s1"]);
+        assert_eq!(target.set_members("t2", "s1").unwrap(), ["x", "y"]);
+        assert_eq!(
+            target.zset_range("t2", "z1", 0, -1).unwrap(),
+            [("m2".to_string(), 1.0), ("m1".to_string(), 2.0)]
+        );
+    }
+
+    #[test]
+    fn dump_and_restore_should_keep_ttl() {
+        let store = MemTable::new();
+        store
+            .set_with_ttl("t1", "k1".into(), "v1".into(), Duration::from_secs(60))
+            .unwrap();
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        store
+            .set_with_ttl("t1", "k3".into(), "v3".into(), Duration::from_millis(50))
+            .unwrap();
+
+        let mut buf = Cursor::new(Vec::new());
+        assert_eq!(dump(&store, &mut buf).unwrap(), 3);
+
+        // dump 之后过期的 key 恢复之后也是过期的
+        std::thread::sleep(Duration::from_millis(100));
+        let dir = tempdir().unwrap();
+        let target = SledDb::new(dir);
+        buf.set_position(0);
+        assert_eq!(restore(&mut buf, &target).unwrap(), 3);
+
+        let ttl = target.ttl("t1", "k1").unwrap().unwrap();
+        assert!(ttl > Duration::from_secs(50) && ttl <= Duration::from_secs(60));
+        assert_eq!(target.ttl("t1", "k2").unwrap(), None);
+        assert_eq!(target.get("t1", "k3").unwrap(), None);
+    }
+
+    #[test]
+    fn restore_should_accept_version_1_dump() {
+        // 版本 1 的 dump：table 标记之后跟着 Kvpair
+        let mut body = BytesMut::new();
+        let marker = Kvpair {
+            key: "t1".into(),
+            value: None,
+        };
+        for pair in [marker, Kvpair::new("k1", "v1".into())] {
+            let mut frame = BytesMut::new();
+            pair.encode_frame(&mut frame).unwrap();
+            body.unsplit(frame);
+        }
+
+        let mut data = header(crc32fast::hash(&body)).to_vec();
+        data[8..12].copy_from_slice(&1u32.to_be_bytes());
+        data.extend_from_slice(&body);
+
+        let target = MemTable::new();
+        target.set("t1", "old".into(), "v0".into()).unwrap();
+        assert_eq!(restore(Cursor::new(data), &target).unwrap(), 1);
+        assert_eq!(target.get("t1", "old").unwrap(), None);
+        assert_eq!(target.get("t1", "k1").unwrap(), Some("v1".into()));
+    }
+
+    #[test]
+    fn restore_should_reject_corrupted_dump() {
+        let store = MemTable::new();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        let mut buf = Cursor::new(Vec::new());
+        dump(&store, &mut buf).unwrap();
+        let data = buf.into_inner();
+
+        let target = MemTable::new();
+        let mut corrupted = data.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        let err = restore(Cursor::new(corrupted), &target).unwrap_err();
+        assert!(err.to_string().contains("checksum mismatch"));
+
+        let mut corrupted = data.clone();
+        corrupted[11] = 4;
+        let err = restore(Cursor::new(corrupted), &target).unwrap_err();
+        assert!(err.to_string().contains("unsupported version 4"));
+
+        let err = restore(Cursor::new(&data[..4]), &target).unwrap_err();
+        assert!(err.to_string().contains("missing header"));
+        assert!(target.list_tables().unwrap().is_empty());
+    }
+}
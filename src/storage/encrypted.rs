@@ -0,0 +1,565 @@
+use bytes::{BufMut, Bytes};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use std::{collections::HashMap, convert::TryFrom, convert::TryInto, time::Duration};
+
+use super::{
+    serial_compare_and_swap, serial_incr, serial_incr_float, serial_update_path, update_keep_ttl,
+};
+use crate::{value, KeyRange, KvError, Kvpair, PathOp, PathSegment, Storage, Value, Version};
+
+/// 密文格式的版本
+const VERSION: u8 = 1;
+/// header 依次是版本、key id 和 nonce
+const HEADER_LEN: usize = 1 + 4 + NONCE_LEN;
+const NONCE_LEN: usize = 12;
+
+/// 加密用的一组 key，用 id 区分。新数据总是用 current 加密，
+/// 旧的 key 留在里面用来解密轮换之前写入的数据
+#[derive(Clone)]
+pub struct Keyring {
+    current: u32,
+    keys: HashMap<u32, ChaCha20Poly1305>,
+}
+
+impl Keyring {
+    pub fn new(id: u32, key: [u8; 32]) -> Self {
+        let mut keyring = Self {
+            current: id,
+            keys: HashMap::new(),
+        };
+        keyring.add(id, key);
+        keyring
+    }
+
+    /// 随机生成一个 key
+    pub fn generate_key() -> [u8; 32] {
+        ChaCha20Poly1305::generate_key(&mut OsRng).into()
+    }
+
+    /// 添加一个只用来解密的 key
+    pub fn add(&mut self, id: u32, key: [u8; 32]) {
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&key));
+        self.keys.insert(id, cipher);
+    }
+
+    /// 添加一个 key 并用它加密之后写入的数据
+    pub fn rotate(&mut self, id: u32, key: [u8; 32]) {
+        self.add(id, key);
+        self.current = id;
+    }
+
+    pub fn current(&self) -> u32 {
+        self.current
+    }
+
+    // 加密序列化之后的 value。table 和 key 作为附加数据参与认证，密文不能被挪到别的 key 下面
+    fn seal(&self, table: &str, key: &str, value: &Value) -> Result<Value, KvError> {
+        let cipher = &self.keys[&self.current];
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let mut buf = Vec::with_capacity(HEADER_LEN);
+        buf.put_u8(VERSION);
+        buf.put_u32(self.current);
+        buf.extend_from_slice(&nonce);
+
+        let plain: Vec<u8> = value.clone().try_into()?;
+        let aad = aad(&buf[..5], table, key);
+        let sealed = cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: &plain,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| KvError::Internal("failed to encrypt value".into()))?;
+        buf.extend_from_slice(&sealed);
+
+        Ok(Bytes::from(buf).into())
+    }
+
+    // 解密 seal 生成的 value
+    fn open(&self, table: &str, key: &str, value: Value) -> Result<Value, KvError> {
+        let data =
+            Bytes::try_from(value).map_err(|_| corrupted(table, key, "value is not encrypted"))?;
+        let id = key_id(&data).ok_or_else(|| corrupted(table, key, "bad header"))?;
+        let cipher = self
+            .keys
+            .get(&id)
+            .ok_or_else(|| corrupted(table, key, &format!("unknown key id {}", id)))?;
+
+        let aad = aad(&data[..5], table, key);
+        let nonce = Nonce::from_slice(&data[5..HEADER_LEN]);
+        let payload = Payload {
+            msg: &data[HEADER_LEN..],
+            aad: &aad,
+        };
+        let plain = cipher
+            .decrypt(nonce, payload)
+            .map_err(|_| corrupted(table, key, "authentication failed"))?;
+
+        Value::try_from(&plain[..])
+    }
+
+    fn open_pair(&self, table: &str, pair: Kvpair) -> Result<Kvpair, KvError> {
+        let value = match pair.value {
+            Some(v) => Some(self.open(table, &pair.key, v)?),
+            None => None,
+        };
+
+        Ok(Kvpair {
+            key: pair.key,
+            value,
+        })
+    }
+}
+
+// 密文的 key id，格式不对时返回 None
+fn key_id(data: &[u8]) -> Option<u32> {
+    if data.len() < HEADER_LEN || data[0] != VERSION {
+        return None;
+    }
+    Some(u32::from_be_bytes(data[1..5].try_into().unwrap()))
+}
+
+// 附加数据：版本和 key id、table 的长度、table 和 key
+fn aad(header: &[u8], table: &str, key: &str) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(header.len() + 4 + table.len() + key.len());
+    buf.extend_from_slice(header);
+    buf.put_u32(table.len() as _);
+    buf.extend_from_slice(table.as_bytes());
+    buf.extend_from_slice(key.as_bytes());
+    buf
+}
+
+fn corrupted(table: &str, key: &str, reason: &str) -> KvError {
+    KvError::Corrupted(format!("table: {}, key: {}: {}", table, key, reason))
+}
+
+/// 加密 value 之后再交给内部的 Storage。table 名、key 和过期时间不加密。
+/// 同样的 value 每次加密的结果都不一样，所以不支持二级索引，find 会解密整个 table
+pub struct EncryptedStorage<S> {
+    inner: S,
+    keyring: Keyring,
+}
+
+impl<S: Storage> EncryptedStorage<S> {
+    pub fn new(inner: S, keyring: Keyring) -> Self {
+        Self { inner, keyring }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    /// 用当前的 key 重新加密所有用旧 key 加密的 value，过期时间保持不变，
+    /// 返回重新加密的 key 的数量。需要在没有其它读写的时候执行，完成之后就可以去掉旧的 key 了
+    pub fn reencrypt(&self) -> Result<usize, KvError> {
+        let mut count = 0;
+        for table in self.inner.list_tables()? {
+            for pair in self.inner.get_iter(&table)? {
+                let raw = match pair.value {
+                    Some(v) => v,
+                    None => continue,
+                };
+                let id = match &raw.value {
+                    Some(value::Value::Binary(data)) => key_id(data),
+                    _ => None,
+                };
+                if id == Some(self.keyring.current) {
+                    continue;
+                }
+
+                let value = self.keyring.open(&table, &pair.key, raw)?;
+                let sealed = self.keyring.seal(&table, &pair.key, &value)?;
+                update_keep_ttl(&self.inner, &table, &pair.key, sealed)?;
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+
+    fn sealed(&self) -> Sealed<'_> {
+        Sealed {
+            inner: &self.inner,
+            keyring: &self.keyring,
+        }
+    }
+}
+
+/// 加密存储的视图，内部可以是一个 Storage 也可以是事务中的 Storage。
+/// compare_and_swap 和 incr 先读再写，EncryptedStorage 在事务中调用它们
+struct Sealed<'a> {
+    inner: &'a dyn Storage,
+    keyring: &'a Keyring,
+}
+
+impl Sealed<'_> {
+    fn open(&self, table: &str, key: &str, value: Option<Value>) -> Result<Option<Value>, KvError> {
+        value.map(|v| self.keyring.open(table, key, v)).transpose()
+    }
+}
+
+impl Storage for Sealed<'_> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.open(table, key, self.inner.get(table, key)?)
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        let sealed = self.keyring.seal(table, &key, &value)?;
+        let old = self.inner.set(table, key.clone(), sealed)?;
+        self.open(table, &key, old)
+    }
+
+    fn set_with_ttl(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> Result<Option<Value>, KvError> {
+        let sealed = self.keyring.seal(table, &key, &value)?;
+        let old = self.inner.set_with_ttl(table, key.clone(), sealed, ttl)?;
+        self.open(table, &key, old)
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.inner.contains(table, key)
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.open(table, key, self.inner.del(table, key)?)
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.inner
+            .get_all(table)?
+            .into_iter()
+            .map(|pair| self.keyring.open_pair(table, pair))
+            .collect()
+    }
+
+    // 解密失败需要返回错误，所以一次解密整个 table
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        Ok(Box::new(self.get_all(table)?.into_iter()))
+    }
+
+    fn scan(&self, table: &str, range: &KeyRange, limit: usize) -> Result<Vec<Kvpair>, KvError> {
+        self.inner
+            .scan(table, range, limit)?
+            .into_iter()
+            .map(|pair| self.keyring.open_pair(table, pair))
+            .collect()
+    }
+
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        self.inner.list_tables()
+    }
+
+    fn drop_table(&self, table: &str) -> Result<bool, KvError> {
+        self.inner.drop_table(table)
+    }
+
+    fn table_len(&self, table: &str) -> Result<usize, KvError> {
+        self.inner.table_len(table)
+    }
+
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        self.inner.expire(table, key, ttl)
+    }
+
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Duration>, KvError> {
+        self.inner.ttl(table, key)
+    }
+
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.inner.persist(table, key)
+    }
+
+    // EncryptedStorage 只在事务中调用它们，读到的是解密后的 value
+    fn compare_and_swap(
+        &self,
+        table: &str,
+        key: &str,
+        expected: Option<&Value>,
+        value: Value,
+    ) -> Result<Result<(), Option<Value>>, KvError> {
+        serial_compare_and_swap(self, table, key, expected, value)
+    }
+
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        serial_incr(self, table, key, delta)
+    }
+
+    fn incr_float(&self, table: &str, key: &str, delta: f64) -> Result<f64, KvError> {
+        serial_incr_float(self, table, key, delta)
+    }
+
+    fn update_path(
+        &self,
+        table: &str,
+        key: &str,
+        path: &[PathSegment],
+        op: PathOp,
+    ) -> Result<Option<Value>, KvError> {
+        serial_update_path(self, table, key, path, op)
+    }
+
+    fn history(&self, table: &str, key: &str) -> Result<Vec<Version>, KvError> {
+        let mut history = self.inner.history(table, key)?;
+        for version in history.iter_mut() {
+            version.value = self.open(table, key, version.value.take())?;
+        }
+
+        Ok(history)
+    }
+
+    fn get_version(&self, table: &str, key: &str, version: u64) -> Result<Option<Value>, KvError> {
+        let value = self.inner.get_version(table, key, version)?;
+        self.open(table, key, value)
+    }
+
+    fn get_at(&self, table: &str, key: &str, at: u64) -> Result<Option<Value>, KvError> {
+        let value = self.inner.get_at(table, key, at)?;
+        self.open(table, key, value)
+    }
+
+    fn gc_versions_in(
+        &self,
+        retention: Duration,
+        tables: &dyn Fn(&str) -> bool,
+    ) -> Result<usize, KvError> {
+        self.inner.gc_versions_in(retention, tables)
+    }
+
+    fn transaction(
+        &self,
+        tables: &[&str],
+        f: &mut dyn FnMut(&dyn Storage) -> Result<(), KvError>,
+    ) -> Result<(), KvError> {
+        let keyring = self.keyring;
+        self.inner.transaction(tables, &mut |txn| {
+            f(&Sealed {
+                inner: txn,
+                keyring,
+            })
+        })
+    }
+}
+
+impl<S: Storage> Storage for EncryptedStorage<S> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.sealed().get(table, key)
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        self.sealed().set(table, key, value)
+    }
+
+    fn set_with_ttl(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> Result<Option<Value>, KvError> {
+        self.sealed().set_with_ttl(table, key, value, ttl)
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.sealed().contains(table, key)
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.sealed().del(table, key)
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.sealed().get_all(table)
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        self.sealed().get_iter(table)
+    }
+
+    fn scan(&self, table: &str, range: &KeyRange, limit: usize) -> Result<Vec<Kvpair>, KvError> {
+        self.sealed().scan(table, range, limit)
+    }
+
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        self.sealed().list_tables()
+    }
+
+    fn drop_table(&self, table: &str) -> Result<bool, KvError> {
+        self.sealed().drop_table(table)
+    }
+
+    fn table_len(&self, table: &str) -> Result<usize, KvError> {
+        self.sealed().table_len(table)
+    }
+
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        self.sealed().expire(table, key, ttl)
+    }
+
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Duration>, KvError> {
+        self.sealed().ttl(table, key)
+    }
+
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.sealed().persist(table, key)
+    }
+
+    fn history(&self, table: &str, key: &str) -> Result<Vec<Version>, KvError> {
+        self.sealed().history(table, key)
+    }
+
+    fn get_version(&self, table: &str, key: &str, version: u64) -> Result<Option<Value>, KvError> {
+        self.sealed().get_version(table, key, version)
+    }
+
+    fn get_at(&self, table: &str, key: &str, at: u64) -> Result<Option<Value>, KvError> {
+        self.sealed().get_at(table, key, at)
+    }
+
+    fn gc_versions_in(
+        &self,
+        retention: Duration,
+        tables: &dyn Fn(&str) -> bool,
+    ) -> Result<usize, KvError> {
+        self.sealed().gc_versions_in(retention, tables)
+    }
+
+    // 密文每次都不一样，不能直接用内部存储的原子操作，在事务里读出、比较再写入
+    fn compare_and_swap(
+        &self,
+        table: &str,
+        key: &str,
+        expected: Option<&Value>,
+        value: Value,
+    ) -> Result<Result<(), Option<Value>>, KvError> {
+        let mut result = Ok(());
+        self.transaction(&[table], &mut |txn| {
+            result = txn.compare_and_swap(table, key, expected, value.clone())?;
+            Ok(())
+        })?;
+
+        Ok(result)
+    }
+
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        let mut result = 0;
+        self.transaction(&[table], &mut |txn| {
+            result = txn.incr(table, key, delta)?;
+            Ok(())
+        })?;
+
+        Ok(result)
+    }
+
+    fn incr_float(&self, table: &str, key: &str, delta: f64) -> Result<f64, KvError> {
+        let mut result = 0.0;
+        self.transaction(&[table], &mut |txn| {
+            result = txn.incr_float(table, key, delta)?;
+            Ok(())
+        })?;
+
+        Ok(result)
+    }
+
+    fn update_path(
+        &self,
+        table: &str,
+        key: &str,
+        path: &[PathSegment],
+        op: PathOp,
+    ) -> Result<Option<Value>, KvError> {
+        let mut result = None;
+        self.transaction(&[table], &mut |txn| {
+            result = txn.update_path(table, key, path, op.clone())?;
+            Ok(())
+        })?;
+
+        Ok(result)
+    }
+
+    fn transaction(
+        &self,
+        tables: &[&str],
+        f: &mut dyn FnMut(&dyn Storage) -> Result<(), KvError>,
+    ) -> Result<(), KvError> {
+        self.sealed().transaction(tables, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SledDb;
+    use tempfile::tempdir;
+
+    #[test]
+    fn values_should_be_encrypted_in_inner_store() {
+        let dir = tempdir().unwrap();
+        let keyring = Keyring::new(1, Keyring::generate_key());
+        let store = EncryptedStorage::new(SledDb::new(dir.path()), keyring);
+        store.set("t1", "k1".into(), "secret".into()).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("secret".into()));
+
+        let raw = store.inner.get("t1", "k1").unwrap().unwrap();
+        let data = Bytes::try_from(raw).unwrap();
+        assert_eq!(key_id(&data), Some(1));
+        assert!(!data.windows(6).any(|w| w == b"secret"));
+    }
+
+    #[test]
+    fn tampered_or_moved_value_should_be_rejected() {
+        let keyring = Keyring::new(1, Keyring::generate_key());
+        let store = EncryptedStorage::new(crate::MemTable::new(), keyring);
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        let raw = store.inner.get("t1", "k1").unwrap().unwrap();
+
+        // 密文和 key 绑定在一起，复制到别的 key 下面无法解密
+        store.inner.set("t1", "k2".into(), raw.clone()).unwrap();
+        assert!(matches!(store.get("t1", "k2"), Err(KvError::Corrupted(_))));
+
+        let mut data = Bytes::try_from(raw).unwrap().to_vec();
+        *data.last_mut().unwrap() ^= 1;
+        store
+            .inner
+            .set("t1", "k1".into(), Bytes::from(data).into())
+            .unwrap();
+        assert!(matches!(store.get("t1", "k1"), Err(KvError::Corrupted(_))));
+    }
+
+    #[test]
+    fn reencrypt_should_rotate_keys() {
+        let dir = tempdir().unwrap();
+        let (old, new) = (Keyring::generate_key(), Keyring::generate_key());
+        let store = EncryptedStorage::new(SledDb::new(dir.path()), Keyring::new(1, old));
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store
+            .set_with_ttl("t2", "k2".into(), 2.into(), Duration::from_secs(60))
+            .unwrap();
+        let inner = store.into_inner();
+
+        // 轮换之后旧数据仍然可以读取，新数据用新的 key 加密
+        let mut keyring = Keyring::new(1, old);
+        keyring.rotate(2, new);
+        let store = EncryptedStorage::new(inner, keyring);
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        store.set("t1", "k3".into(), "v3".into()).unwrap();
+        assert_eq!(store.reencrypt().unwrap(), 2);
+        assert_eq!(store.reencrypt().unwrap(), 0);
+        let inner = store.into_inner();
+
+        // 重新加密之后去掉旧的 key
+        let store = EncryptedStorage::new(inner, Keyring::new(2, new));
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.get("t2", "k2").unwrap(), Some(2.into()));
+        assert!(store.ttl("t2", "k2").unwrap().is_some());
+    }
+}
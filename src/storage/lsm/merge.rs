@@ -0,0 +1,105 @@
+use std::iter::Peekable;
+
+use super::Entry;
+use crate::KvError;
+
+/// 一个按 key 排序、key 不重复的来源
+pub(super) type Source<'a> = Box<dyn Iterator<Item = Result<Entry, KvError>> + 'a>;
+
+/// 多路归并：按 key 的顺序合并多个有序的来源。sources 从新到旧排列，
+/// 同一个 key 只返回最新的来源中的记录。某个来源出错时返回错误并结束
+pub(super) struct MergeIter<'a> {
+    sources: Vec<Peekable<Source<'a>>>,
+    failed: bool,
+}
+
+impl<'a> MergeIter<'a> {
+    pub fn new(sources: Vec<Source<'a>>) -> Self {
+        Self {
+            sources: sources.into_iter().map(Iterator::peekable).collect(),
+            failed: false,
+        }
+    }
+}
+
+impl Iterator for MergeIter<'_> {
+    type Item = Result<Entry, KvError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+
+        // 来源的数量很少（memtable、imm、L0 的文件和每一层），直接逐个比较
+        let mut min: Option<(usize, &Vec<u8>)> = None;
+        let mut failed = None;
+        for (i, source) in self.sources.iter_mut().enumerate() {
+            match source.peek() {
+                Some(Ok((key, _))) if min.is_none_or(|(_, m)| key < m) => min = Some((i, key)),
+                Some(Err(_)) => {
+                    failed = Some(i);
+                    break;
+                }
+                _ => {}
+            }
+        }
+        if let Some(i) = failed {
+            self.failed = true;
+            return self.sources[i].next();
+        }
+
+        let (winner, key) = min.map(|(i, key)| (i, key.clone()))?;
+        let entry = self.sources[winner].next();
+        // 更旧的来源里同一个 key 的记录被覆盖了
+        for source in &mut self.sources {
+            if matches!(source.peek(), Some(Ok((k, _))) if *k == key) {
+                source.next();
+            }
+        }
+
+        entry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::lsm::Record;
+
+    fn source<'a>(keys: &[(&str, &str)]) -> Source<'a> {
+        let entries: Vec<_> = keys
+            .iter()
+            .map(|(k, v)| {
+                let record = Record::Value {
+                    data: v.as_bytes().to_vec(),
+                    expire_at: None,
+                };
+                Ok((k.as_bytes().to_vec(), record))
+            })
+            .collect();
+        Box::new(entries.into_iter())
+    }
+
+    #[test]
+    fn merge_should_keep_newest_record() {
+        let newer = source(&[("b", "new"), ("d", "new")]);
+        let older = source(&[("a", "old"), ("b", "old"), ("c", "old"), ("d", "old")]);
+        let merged: Vec<_> = MergeIter::new(vec![newer, older])
+            .map(|entry| {
+                let (key, record) = entry.unwrap();
+                let data = record.live().unwrap().0.to_vec();
+                (
+                    String::from_utf8(key).unwrap(),
+                    String::from_utf8(data).unwrap(),
+                )
+            })
+            .collect();
+
+        let expected = [("a", "old"), ("b", "new"), ("c", "old"), ("d", "new")];
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        assert_eq!(merged, expected);
+    }
+}
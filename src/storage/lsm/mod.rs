@@ -0,0 +1,1306 @@
+mod merge;
+mod sstable;
+mod wal;
+
+use bytes::{Buf, BufMut};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashSet},
+    convert::{TryFrom, TryInto},
+    fs,
+    io::Write,
+    mem,
+    ops::Bound,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::{info, warn};
+
+use self::{
+    merge::{MergeIter, Source},
+    sstable::{SsTable, SsTableBuilder},
+    wal::Wal,
+};
+use crate::{
+    storage::{
+        serial_compare_and_swap, serial_incr, serial_incr_float, serial_update_path, KeyRange,
+    },
+    KvError, Kvpair, PathOp, PathSegment, Storage, Value,
+};
+
+/// 写前日志的文件名
+const WAL_FILE: &str = "wal.log";
+/// 等待写入 L0 的 memtable 的写前日志的文件名
+const IMM_WAL_FILE: &str = "wal.imm.log";
+/// 记录每一层有哪些 SSTable 的文件名
+const MANIFEST_FILE: &str = "MANIFEST";
+
+/// 编码后的 key 和对应的记录
+type Entry = (Vec<u8>, Record);
+/// 内存中按 key 排序的记录
+type MemTable = BTreeMap<Vec<u8>, Record>;
+
+#[derive(Clone, Debug)]
+pub struct LsmConfig {
+    /// memtable 超过这个大小（字节）就写入 L0 的 SSTable
+    pub memtable_size: usize,
+    /// SSTable 中数据块的大小，每个块在稀疏索引里有一项
+    pub block_size: usize,
+    /// 合并时生成的 SSTable 的目标大小
+    pub sstable_size: u64,
+    /// L0 的文件数达到这个数量时合并到 L1
+    pub l0_compaction_trigger: usize,
+    /// L1 的总大小上限，之后每一层是上一层的 level_size_multiplier 倍
+    pub level_size_base: u64,
+    pub level_size_multiplier: u64,
+    /// 每次写入后是否 fsync 写前日志。不 fsync 时进程崩溃不会丢数据，但机器掉电可能会
+    pub sync_wal: bool,
+}
+
+impl Default for LsmConfig {
+    fn default() -> Self {
+        Self {
+            memtable_size: 4 * 1024 * 1024,
+            block_size: 4 * 1024,
+            sstable_size: 2 * 1024 * 1024,
+            l0_compaction_trigger: 4,
+            level_size_base: 10 * 1024 * 1024,
+            level_size_multiplier: 10,
+            sync_wal: false,
+        }
+    }
+}
+
+/// 基于 LSM tree 的存储引擎：修改先写入写前日志和内存中有序的 memtable，
+/// memtable 写满后变成不可修改的 imm，由后台线程生成 L0 的 SSTable，再逐层合并到更大、互不重叠的层里
+#[derive(Debug)]
+pub struct LsmStorage {
+    dir: PathBuf,
+    config: LsmConfig,
+    // 读操作持有读锁；写操作持有写锁。后台的 flush 和合并只在开始和结束时短暂地持有写锁
+    state: Arc<RwLock<State>>,
+    // 执行 flush 和合并的后台线程
+    worker: Mutex<Option<JoinHandle<()>>>,
+}
+
+#[derive(Debug)]
+struct State {
+    mem: MemTable,
+    mem_size: usize,
+    wal: Wal,
+    // 写满之后等待写入 L0 的 memtable，它的写前日志是 IMM_WAL_FILE
+    imm: Option<Arc<MemTable>>,
+    // levels[0] 中的文件可能互相重叠，按从旧到新的顺序排列；其它层按 key 排序，互不重叠。
+    // 后台线程不持有锁读取 SSTable，所以放在 Arc 里
+    levels: Vec<Vec<Arc<SsTable>>>,
+    next_id: u64,
+    // 每一层上次合并到的最大 key，下次从它之后的文件开始，轮流合并整层的文件
+    compact_pointer: Vec<Vec<u8>>,
+    // 后台线程是否在运行，只有它会修改 levels
+    working: bool,
+}
+
+/// 后台线程的一项工作
+enum Job {
+    Flush(Arc<MemTable>),
+    Compact(Compaction),
+}
+
+/// 把 level 层的 inputs 和下一层中与它们重叠的 overlapping 合并成下一层新的文件
+struct Compaction {
+    level: usize,
+    inputs: Vec<Arc<SsTable>>,
+    overlapping: Vec<Arc<SsTable>>,
+    // 目标层下面没有数据时，删除标记和过期的数据可以直接丢弃
+    bottom: bool,
+}
+
+/// 一个 key 的最新状态：value（带上可选的过期时刻）或者删除标记
+#[derive(Clone, Debug, PartialEq)]
+enum Record {
+    Value {
+        data: Vec<u8>,
+        expire_at: Option<u64>,
+    },
+    Tombstone,
+}
+
+impl LsmStorage {
+    /// 使用默认配置打开，失败时 panic，和 SledDb::new 一样
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self::open(path, LsmConfig::default()).unwrap()
+    }
+
+    /// 打开（或创建）目录 path 下的数据，重放写前日志恢复没有写入 SSTable 的修改
+    pub fn open(path: impl AsRef<Path>, config: LsmConfig) -> Result<Self, KvError> {
+        let dir = path.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let (mut next_id, files) = read_manifest(&dir)?;
+        let live: HashSet<_> = files.iter().map(|(_, id)| *id).collect();
+        let mut levels: Vec<Vec<Arc<SsTable>>> = vec![Vec::new()];
+        for (level, id) in files {
+            if levels.len() <= level {
+                levels.resize_with(level + 1, Vec::new);
+            }
+            levels[level].push(Arc::new(SsTable::open(sst_path(&dir, id), id)?));
+        }
+        for level in levels.iter_mut().skip(1) {
+            level.sort_by(|a, b| a.first_key.cmp(&b.first_key));
+        }
+
+        // flush 或合并中途退出时留下的文件不在 manifest 里，直接删除
+        for item in fs::read_dir(&dir)? {
+            let path = item?.path();
+            if let Some(id) = sst_id(&path) {
+                next_id = next_id.max(id + 1);
+                if !live.contains(&id) {
+                    fs::remove_file(&path)?;
+                }
+            }
+        }
+
+        // 上次退出时还没有写入 L0 的 imm，重新交给后台线程
+        let imm_path = dir.join(IMM_WAL_FILE);
+        let mut count = 0;
+        let imm = match imm_path.exists() {
+            true => {
+                let (_, batches) = Wal::open(&imm_path)?;
+                count += batches.len();
+                Some(Arc::new(batches.into_iter().flatten().collect()))
+            }
+            false => None,
+        };
+        let (wal, batches) = Wal::open(&dir.join(WAL_FILE))?;
+        let mut state = State {
+            mem: BTreeMap::new(),
+            mem_size: 0,
+            wal,
+            imm,
+            levels,
+            next_id,
+            compact_pointer: Vec::new(),
+            working: false,
+        };
+        count += batches.len();
+        for batch in batches {
+            state.apply(batch);
+        }
+        info!("Opened LSM storage {:?}, replayed {} batches", dir, count);
+
+        let store = Self {
+            dir,
+            config,
+            state: Arc::new(RwLock::new(state)),
+            worker: Mutex::new(None),
+        };
+        {
+            let mut state = store.state.write().unwrap();
+            if state.imm.is_some() {
+                store.start_worker(&mut state);
+            }
+        }
+
+        Ok(store)
+    }
+
+    // 在读锁的保护下读取数据
+    fn read<T>(&self, f: impl FnOnce(&LsmTxn) -> Result<T, KvError>) -> Result<T, KvError> {
+        let state = self.state.read().unwrap();
+        f(&LsmTxn::new(&state))
+    }
+
+    // 在写锁的保护下执行 f，f 成功后把它的修改作为一批写入
+    fn write<T>(&self, f: impl FnOnce(&LsmTxn) -> Result<T, KvError>) -> Result<T, KvError> {
+        let mut state = self.state.write().unwrap();
+        let txn = LsmTxn::new(&state);
+        let result = f(&txn)?;
+        let batch = txn.into_batch();
+        self.commit(&mut state, batch)?;
+
+        Ok(result)
+    }
+
+    fn commit(&self, state: &mut State, batch: Vec<Entry>) -> Result<(), KvError> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        state.wal.append(&batch, self.config.sync_wal)?;
+        state.apply(batch);
+
+        rotate(&self.dir, &self.config, state)?;
+        if state.imm.is_some() {
+            self.start_worker(state);
+        }
+
+        Ok(())
+    }
+
+    // 后台线程没有在运行时启动它。working 在写锁里修改，不会错过新的工作
+    fn start_worker(&self, state: &mut State) {
+        if state.working {
+            return;
+        }
+        state.working = true;
+
+        let dir = self.dir.clone();
+        let config = self.config.clone();
+        let shared = self.state.clone();
+        *self.worker.lock().unwrap() = Some(thread::spawn(move || {
+            if let Err(e) = run_worker(&dir, &config, &shared) {
+                warn!("LSM background work failed: {}", e);
+                shared.write().unwrap().working = false;
+            }
+        }));
+    }
+
+    // 等待后台线程完成所有的工作
+    fn join_worker(&self) {
+        if let Some(handle) = self.worker.lock().unwrap().take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for LsmStorage {
+    fn drop(&mut self) {
+        self.join_worker();
+    }
+}
+
+// 后台线程：先把 imm 写入 L0，再逐层合并，直到没有需要做的工作
+fn run_worker(dir: &Path, config: &LsmConfig, state: &RwLock<State>) -> Result<(), KvError> {
+    loop {
+        let job = {
+            let mut state = state.write().unwrap();
+            // 上一个 imm 写入 L0 期间 memtable 可能已经写满了
+            rotate(dir, config, &mut state)?;
+            match state.next_job(config) {
+                Some(job) => job,
+                None => {
+                    state.working = false;
+                    return Ok(());
+                }
+            }
+        };
+        match job {
+            Job::Flush(imm) => flush(dir, config, state, &imm)?,
+            Job::Compact(compaction) => compact(dir, config, state, compaction)?,
+        }
+    }
+}
+
+// memtable 写满时换成 imm 交给后台线程写入 L0。上一个 imm 还没有写完时继续写入 memtable
+fn rotate(dir: &Path, config: &LsmConfig, state: &mut State) -> Result<(), KvError> {
+    if state.mem_size >= config.memtable_size && state.imm.is_none() {
+        fs::rename(dir.join(WAL_FILE), dir.join(IMM_WAL_FILE))?;
+        state.wal = Wal::open(&dir.join(WAL_FILE))?.0;
+        state.imm = Some(Arc::new(mem::take(&mut state.mem)));
+        state.mem_size = 0;
+    }
+
+    Ok(())
+}
+
+// 把 imm 写成 L0 的 SSTable，然后删除它的写前日志
+fn flush(
+    dir: &Path,
+    config: &LsmConfig,
+    state: &RwLock<State>,
+    imm: &MemTable,
+) -> Result<(), KvError> {
+    let table = match imm.is_empty() {
+        true => None,
+        false => {
+            let mut builder = new_builder(dir, config, state)?;
+            for (key, record) in imm {
+                builder.add(key, record)?;
+            }
+            Some(builder.finish()?)
+        }
+    };
+
+    let mut state = state.write().unwrap();
+    if let Some(table) = table {
+        state.levels[0].push(Arc::new(table));
+        write_manifest(dir, &state)?;
+    }
+    fs::remove_file(dir.join(IMM_WAL_FILE))?;
+    state.imm = None;
+
+    Ok(())
+}
+
+// 不持有锁读取输入的文件并生成新的文件，最后在写锁里替换掉输入的文件
+fn compact(
+    dir: &Path,
+    config: &LsmConfig,
+    state: &RwLock<State>,
+    compaction: Compaction,
+) -> Result<(), KvError> {
+    let Compaction {
+        level,
+        inputs,
+        overlapping,
+        bottom,
+    } = compaction;
+    let target = level + 1;
+
+    // 边读边写，不把输入的文件全部读进内存。输入的文件从新到旧排列，最后是下一层互不重叠的文件
+    let mut sources: Vec<Source> = inputs
+        .iter()
+        .rev()
+        .map(|t| Box::new(t.iter(Vec::new())) as Source)
+        .collect();
+    sources.push(Box::new(
+        overlapping.iter().flat_map(|t| t.iter(Vec::new())),
+    ));
+    let obsolete: HashSet<_> = inputs.iter().chain(&overlapping).map(|t| t.id).collect();
+
+    let mut outputs = Vec::new();
+    let mut builder = None;
+    for entry in MergeIter::new(sources) {
+        let (key, record) = entry?;
+        if bottom && record.live().is_none() {
+            continue;
+        }
+        let b = match builder.as_mut() {
+            Some(b) => b,
+            None => builder.insert(new_builder(dir, config, state)?),
+        };
+        b.add(&key, &record)?;
+        if b.size() >= config.sstable_size {
+            outputs.push(Arc::new(builder.take().unwrap().finish()?));
+        }
+    }
+    if let Some(b) = builder {
+        outputs.push(Arc::new(b.finish()?));
+    }
+
+    // 只有后台线程会修改 levels，输入的文件都还在原来的位置
+    let mut state = state.write().unwrap();
+    let mut removed = Vec::new();
+    for i in [level, target] {
+        let (old, kept): (Vec<_>, Vec<_>) = state.levels[i]
+            .drain(..)
+            .partition(|t| obsolete.contains(&t.id));
+        removed.extend(old);
+        state.levels[i] = kept;
+    }
+    state.levels[target].extend(outputs);
+    state.levels[target].sort_by(|a, b| a.first_key.cmp(&b.first_key));
+    write_manifest(dir, &state)?;
+    drop(state);
+
+    info!(
+        "Compacted {} files from L{} into L{}",
+        removed.len(),
+        level,
+        target
+    );
+    for t in removed {
+        t.remove()?;
+    }
+
+    Ok(())
+}
+
+fn max_level_size(config: &LsmConfig, level: usize) -> u64 {
+    config.level_size_base
+        * config
+            .level_size_multiplier
+            .pow(level.saturating_sub(1) as u32)
+}
+
+// 在写锁里分配一个新的文件 id
+fn new_builder(
+    dir: &Path,
+    config: &LsmConfig,
+    state: &RwLock<State>,
+) -> Result<SsTableBuilder, KvError> {
+    let id = {
+        let mut state = state.write().unwrap();
+        state.next_id += 1;
+        state.next_id - 1
+    };
+    SsTableBuilder::new(sst_path(dir, id), id, config.block_size)
+}
+
+// 先写临时文件再改名，manifest 要么是旧的要么是新的
+fn write_manifest(dir: &Path, state: &State) -> Result<(), KvError> {
+    let mut content = format!("next_id {}\n", state.next_id);
+    for (level, tables) in state.levels.iter().enumerate() {
+        for t in tables {
+            content.push_str(&format!("{} {}\n", level, t.id));
+        }
+    }
+
+    let tmp = dir.join(format!("{}.tmp", MANIFEST_FILE));
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(content.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp, dir.join(MANIFEST_FILE))?;
+
+    Ok(())
+}
+
+impl State {
+    fn apply(&mut self, batch: Vec<Entry>) {
+        for (key, record) in batch {
+            self.mem_size += key.len() + record.size();
+            self.mem.insert(key, record);
+        }
+    }
+
+    // 有 imm 时先把它写入 L0；L0 文件太多时全部合并到 L1；其它层超过大小上限时，
+    // 把上次合并到的位置之后的一个文件合并到下一层，到末尾后再从头开始
+    fn next_job(&mut self, config: &LsmConfig) -> Option<Job> {
+        if let Some(imm) = &self.imm {
+            return Some(Job::Flush(imm.clone()));
+        }
+        let (level, range) = if self.levels[0].len() >= config.l0_compaction_trigger {
+            (0, 0..self.levels[0].len())
+        } else {
+            let level = (1..self.levels.len()).find(|&i| {
+                let size: u64 = self.levels[i].iter().map(|t| t.size).sum();
+                size > max_level_size(config, i)
+            })?;
+            if self.compact_pointer.len() <= level {
+                self.compact_pointer.resize_with(level + 1, Vec::new);
+            }
+            let files = &self.levels[level];
+            let pointer = &self.compact_pointer[level];
+            let pos = match files.partition_point(|t| t.first_key <= *pointer) {
+                pos if pos < files.len() => pos,
+                _ => 0,
+            };
+            self.compact_pointer[level] = self.levels[level][pos].last_key.clone();
+            (level, pos..pos + 1)
+        };
+
+        let target = level + 1;
+        if self.levels.len() <= target {
+            self.levels.push(Vec::new());
+        }
+        let inputs = self.levels[level][range].to_vec();
+        let first = inputs.iter().map(|t| t.first_key.clone()).min().unwrap();
+        let last = inputs.iter().map(|t| t.last_key.clone()).max().unwrap();
+        let overlapping = self.levels[target]
+            .iter()
+            .filter(|t| t.overlaps(&first, &last))
+            .cloned()
+            .collect();
+        let bottom = self.levels[target + 1..].iter().all(|l| l.is_empty());
+
+        Some(Job::Compact(Compaction {
+            level,
+            inputs,
+            overlapping,
+            bottom,
+        }))
+    }
+
+    // 依次查找 memtable、imm、L0（从新到旧）和之后的每一层，第一个找到的记录就是最新的
+    fn get(&self, key: &[u8]) -> Result<Option<Record>, KvError> {
+        if let Some(record) = self.mem.get(key) {
+            return Ok(Some(record.clone()));
+        }
+        if let Some(record) = self.imm.as_ref().and_then(|imm| imm.get(key)) {
+            return Ok(Some(record.clone()));
+        }
+        for t in self.levels[0].iter().rev() {
+            if let Some(record) = t.get(key)? {
+                return Ok(Some(record));
+            }
+        }
+        for level in &self.levels[1..] {
+            let pos = level.partition_point(|t| t.last_key.as_slice() < key);
+            if let Some(t) = level.get(pos) {
+                if let Some(record) = t.get(key)? {
+                    return Ok(Some(record));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    // 从第一个 >= start 的 key 开始按顺序遍历的来源，从新到旧排列：
+    // memtable、imm、L0（从新到旧）和之后的每一层
+    fn sources<'a>(&'a self, start: &[u8]) -> Vec<Source<'a>> {
+        let mut sources: Vec<Source<'a>> = Vec::new();
+        for mem in Some(&self.mem).into_iter().chain(self.imm.as_deref()) {
+            sources.push(Box::new(
+                mem.range(start.to_vec()..)
+                    .map(|(k, r)| Ok((k.clone(), r.clone()))),
+            ));
+        }
+        for t in self.levels[0].iter().rev() {
+            sources.push(Box::new(t.iter(start.to_vec())));
+        }
+        for level in &self.levels[1..] {
+            let pos = level.partition_point(|t| t.last_key.as_slice() < start);
+            let start = start.to_vec();
+            sources.push(Box::new(
+                level[pos..].iter().flat_map(move |t| t.iter(start.clone())),
+            ));
+        }
+
+        sources
+    }
+}
+
+impl Record {
+    fn new(value: Value, expire_at: Option<u64>) -> Result<Self, KvError> {
+        Ok(Record::Value {
+            data: value.try_into()?,
+            expire_at,
+        })
+    }
+
+    /// 没有被删除也没有过期时，返回 value 和过期时刻
+    fn live(&self) -> Option<(&[u8], Option<u64>)> {
+        match self {
+            Record::Value { data, expire_at } => match expire_at {
+                Some(at) if *at <= now() => None,
+                _ => Some((data, *expire_at)),
+            },
+            Record::Tombstone => None,
+        }
+    }
+
+    // 在 memtable 中大致占用的空间
+    fn size(&self) -> usize {
+        match self {
+            Record::Value { data, .. } => data.len() + 9,
+            Record::Tombstone => 1,
+        }
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Record::Tombstone => buf.put_u8(0),
+            Record::Value {
+                data,
+                expire_at: None,
+            } => {
+                buf.put_u8(1);
+                buf.extend_from_slice(data);
+            }
+            Record::Value {
+                data,
+                expire_at: Some(at),
+            } => {
+                buf.put_u8(2);
+                buf.put_u64(*at);
+                buf.extend_from_slice(data);
+            }
+        }
+    }
+
+    fn decode(mut buf: &[u8]) -> Option<Self> {
+        if buf.is_empty() {
+            return None;
+        }
+        match buf.get_u8() {
+            0 => Some(Record::Tombstone),
+            1 => Some(Record::Value {
+                data: buf.to_vec(),
+                expire_at: None,
+            }),
+            2 if buf.len() >= 8 => {
+                let at = buf.get_u64();
+                Some(Record::Value {
+                    data: buf.to_vec(),
+                    expire_at: Some(at),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// 写前日志和 SSTable 中 entry 的格式：key 的长度、key、记录的长度、记录
+fn encode_entry(buf: &mut Vec<u8>, key: &[u8], record: &Record) {
+    let mut data = Vec::with_capacity(record.size());
+    record.encode(&mut data);
+    buf.put_u32(key.len() as _);
+    buf.extend_from_slice(key);
+    buf.put_u32(data.len() as _);
+    buf.extend_from_slice(&data);
+}
+
+fn decode_entries(mut buf: &[u8]) -> Result<Vec<Entry>, KvError> {
+    let corrupted = || KvError::Corrupted("bad entry".into());
+    let mut entries = Vec::new();
+    while buf.has_remaining() {
+        let mut fields = [&[][..]; 2];
+        for field in fields.iter_mut() {
+            if buf.remaining() < 4 {
+                return Err(corrupted());
+            }
+            let len = buf.get_u32() as usize;
+            if buf.remaining() < len {
+                return Err(corrupted());
+            }
+            *field = &buf[..len];
+            buf.advance(len);
+        }
+        let record = Record::decode(fields[1]).ok_or_else(corrupted)?;
+        entries.push((fields[0].to_vec(), record));
+    }
+
+    Ok(entries)
+}
+
+/// 把 table 和 key 编码成一个 key：table 的长度、table、key。同一个 table 的 key 相邻且保持顺序
+fn encode_key(table: &str, key: &str) -> Vec<u8> {
+    let mut buf = table_prefix(table);
+    buf.extend_from_slice(key.as_bytes());
+    buf
+}
+
+fn table_prefix(table: &str) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(4 + table.len());
+    buf.put_u32(table.len() as _);
+    buf.extend_from_slice(table.as_bytes());
+    buf
+}
+
+// 比所有以 prefix 开头的 key 都大的最小的 key，prefix 全是 0xff 时没有
+fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
+}
+
+fn decode_key(mut buf: &[u8]) -> Option<(String, String)> {
+    if buf.len() < 4 {
+        return None;
+    }
+    let len = buf.get_u32() as usize;
+    if buf.len() < len {
+        return None;
+    }
+    let table = String::from_utf8_lossy(&buf[..len]).into_owned();
+    let key = String::from_utf8_lossy(&buf[len..]).into_owned();
+
+    Some((table, key))
+}
+
+fn read_manifest(dir: &Path) -> Result<(u64, Vec<(usize, u64)>), KvError> {
+    let content = match fs::read_to_string(dir.join(MANIFEST_FILE)) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((0, Vec::new())),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut next_id = 0;
+    let mut files = Vec::new();
+    for line in content.lines() {
+        let corrupted = || KvError::Corrupted(format!("bad manifest line: {}", line));
+        let (a, b) = line.split_once(' ').ok_or_else(corrupted)?;
+        let b = b.parse().map_err(|_| corrupted())?;
+        if a == "next_id" {
+            next_id = b;
+        } else {
+            files.push((a.parse().map_err(|_| corrupted())?, b));
+        }
+    }
+
+    Ok((next_id, files))
+}
+
+fn sst_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:06}.sst", id))
+}
+
+fn sst_id(path: &Path) -> Option<u64> {
+    match path.extension() {
+        Some(ext) if ext == "sst" => path.file_stem()?.to_str()?.parse().ok(),
+        _ => None,
+    }
+}
+
+// 当前时间戳（毫秒），过期时间需要在重启后依然有效
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+// ttl 之后的时间戳（毫秒），ttl 太大时取 u64::MAX，相当于不过期
+fn deadline(ttl: Duration) -> u64 {
+    now().saturating_add(ttl.as_millis().try_into().unwrap_or(u64::MAX))
+}
+
+/// LsmStorage 上的一组读写操作。修改先记在 writes 里，之后的读取能看到这些修改，
+/// 提交时作为一批写入写前日志和 memtable
+struct LsmTxn<'a> {
+    state: &'a State,
+    writes: RefCell<BTreeMap<Vec<u8>, Record>>,
+}
+
+impl<'a> LsmTxn<'a> {
+    fn new(state: &'a State) -> Self {
+        Self {
+            state,
+            writes: RefCell::new(BTreeMap::new()),
+        }
+    }
+
+    fn into_batch(self) -> Vec<Entry> {
+        self.writes.into_inner().into_iter().collect()
+    }
+
+    fn lookup(&self, key: &[u8]) -> Result<Option<Record>, KvError> {
+        match self.writes.borrow().get(key) {
+            Some(record) => Ok(Some(record.clone())),
+            None => self.state.get(key),
+        }
+    }
+
+    // 没有被删除也没有过期的 value 和过期时刻
+    fn live(&self, key: &[u8]) -> Result<Option<(Value, Option<u64>)>, KvError> {
+        match self.lookup(key)? {
+            Some(record) => match record.live() {
+                Some((data, expire_at)) => Ok(Some((Value::try_from(data)?, expire_at))),
+                None => Ok(None),
+            },
+            None => Ok(None),
+        }
+    }
+
+    // 从第一个 >= start 的 key 开始按顺序遍历，包括这组操作里的修改
+    fn iter(&self, start: &[u8]) -> MergeIter<'_> {
+        // 一组操作里的修改不多，复制出来，不用在遍历时一直借用 writes
+        let writes: Vec<_> = self
+            .writes
+            .borrow()
+            .range(start.to_vec()..)
+            .map(|(k, r)| Ok((k.clone(), r.clone())))
+            .collect();
+        let mut sources: Vec<Source> = vec![Box::new(writes.into_iter())];
+        sources.extend(self.state.sources(start));
+
+        MergeIter::new(sources)
+    }
+
+    // 以 prefix 开头、没有被删除也没有过期的 key 和记录，不解码 value
+    fn scan_live(&self, prefix: &[u8]) -> impl Iterator<Item = Result<Entry, KvError>> + '_ {
+        let prefix = prefix.to_vec();
+        self.iter(&prefix)
+            .take_while(move |entry| match entry {
+                Ok((k, _)) => k.starts_with(&prefix),
+                Err(_) => true,
+            })
+            .filter(|entry| match entry {
+                Ok((_, r)) => r.live().is_some(),
+                Err(_) => true,
+            })
+    }
+
+    fn put(&self, key: Vec<u8>, record: Record) {
+        self.writes.borrow_mut().insert(key, record);
+    }
+}
+
+impl Storage for LsmTxn<'_> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        Ok(self.live(&encode_key(table, key))?.map(|(v, _)| v))
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        let key = encode_key(table, &key);
+        let old = self.live(&key)?.map(|(v, _)| v);
+        self.put(key, Record::new(value, None)?);
+
+        Ok(old)
+    }
+
+    fn set_with_ttl(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> Result<Option<Value>, KvError> {
+        let key = encode_key(table, &key);
+        let old = self.live(&key)?.map(|(v, _)| v);
+        let at = deadline(ttl);
+        self.put(key, Record::new(value, Some(at))?);
+
+        Ok(old)
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        Ok(self.live(&encode_key(table, key))?.is_some())
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let key = encode_key(table, key);
+        let old = self.live(&key)?.map(|(v, _)| v);
+        if old.is_some() {
+            self.put(key, Record::Tombstone);
+        }
+
+        Ok(old)
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        let prefix = table_prefix(table);
+        self.scan_live(&prefix)
+            .map(|entry| {
+                let (k, r) = entry?;
+                let value = Value::try_from(r.live().unwrap().0)?;
+                Ok(Kvpair::new(
+                    String::from_utf8_lossy(&k[prefix.len()..]),
+                    value,
+                ))
+            })
+            .collect()
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        Ok(Box::new(self.get_all(table)?.into_iter()))
+    }
+
+    // 找到一个 table 里的 key 之后直接跳到下一个 table，不遍历它的其它数据
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        let mut tables = Vec::new();
+        let mut start = Vec::new();
+        loop {
+            let entry = self.iter(&start).find(|entry| match entry {
+                Ok((_, r)) => r.live().is_some(),
+                Err(_) => true,
+            });
+            let (key, _) = match entry {
+                Some(entry) => entry?,
+                None => break,
+            };
+            let next = match decode_key(&key) {
+                Some((table, _)) => {
+                    let prefix = table_prefix(&table);
+                    tables.push(table);
+                    prefix_end(&prefix)
+                }
+                None => Some([key.as_slice(), &[0]].concat()),
+            };
+            match next {
+                Some(next) => start = next,
+                None => break,
+            }
+        }
+
+        Ok(tables)
+    }
+
+    fn drop_table(&self, table: &str) -> Result<bool, KvError> {
+        let keys = self
+            .scan_live(&table_prefix(table))
+            .map(|entry| entry.map(|(k, _)| k))
+            .collect::<Result<Vec<_>, _>>()?;
+        let existed = !keys.is_empty();
+        for key in keys {
+            self.put(key, Record::Tombstone);
+        }
+
+        Ok(existed)
+    }
+
+    fn table_len(&self, table: &str) -> Result<usize, KvError> {
+        self.scan_live(&table_prefix(table))
+            .try_fold(0, |n, entry| entry.map(|_| n + 1))
+    }
+
+    // 从 range 的下界开始遍历，超出范围或者够了 limit 个就停止
+    fn scan(&self, table: &str, range: &KeyRange, limit: usize) -> Result<Vec<Kvpair>, KvError> {
+        let prefix = table_prefix(table);
+        let start = match range.lower_bound() {
+            Bound::Included(key) => encode_key(table, key),
+            Bound::Excluded(key) => [encode_key(table, key).as_slice(), &[0]].concat(),
+            Bound::Unbounded => prefix.clone(),
+        };
+
+        let mut pairs = Vec::new();
+        for entry in self.iter(&start) {
+            if pairs.len() >= limit {
+                break;
+            }
+            let (k, r) = entry?;
+            if !k.starts_with(&prefix) {
+                break;
+            }
+            let key = String::from_utf8_lossy(&k[prefix.len()..]);
+            if range.is_past(&key) {
+                break;
+            }
+            if let (true, Some((data, _))) = (range.contains(&key), r.live()) {
+                pairs.push(Kvpair::new(key, Value::try_from(data)?));
+            }
+        }
+
+        Ok(pairs)
+    }
+
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        let key = encode_key(table, key);
+        match self.live(&key)? {
+            Some((value, _)) => {
+                let at = deadline(ttl);
+                self.put(key, Record::new(value, Some(at))?);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Duration>, KvError> {
+        match self.live(&encode_key(table, key))? {
+            Some((_, at)) => Ok(at.map(|at| Duration::from_millis(at.saturating_sub(now())))),
+            None => Err(KvError::NotFound(table.into(), key.into())),
+        }
+    }
+
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        let key = encode_key(table, key);
+        match self.live(&key)? {
+            Some((value, Some(_))) => {
+                self.put(key, Record::new(value, None)?);
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    // 在写锁里执行，先读再写不会被其它修改打断
+    fn compare_and_swap(
+        &self,
+        table: &str,
+        key: &str,
+        expected: Option<&Value>,
+        value: Value,
+    ) -> Result<Result<(), Option<Value>>, KvError> {
+        serial_compare_and_swap(self, table, key, expected, value)
+    }
+
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        serial_incr(self, table, key, delta)
+    }
+
+    fn incr_float(&self, table: &str, key: &str, delta: f64) -> Result<f64, KvError> {
+        serial_incr_float(self, table, key, delta)
+    }
+
+    fn update_path(
+        &self,
+        table: &str,
+        key: &str,
+        path: &[PathSegment],
+        op: PathOp,
+    ) -> Result<Option<Value>, KvError> {
+        serial_update_path(self, table, key, path, op)
+    }
+
+    fn transaction(
+        &self,
+        _tables: &[&str],
+        _f: &mut dyn FnMut(&dyn Storage) -> Result<(), KvError>,
+    ) -> Result<(), KvError> {
+        Err(KvError::InvalidCommand("Nested transaction".into()))
+    }
+}
+
+impl Storage for LsmStorage {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.read(|t| t.get(table, key))
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        self.write(|t| t.set(table, key, value))
+    }
+
+    fn set_with_ttl(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> Result<Option<Value>, KvError> {
+        self.write(|t| t.set_with_ttl(table, key, value, ttl))
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.read(|t| t.contains(table, key))
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.write(|t| t.del(table, key))
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.read(|t| t.get_all(table))
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        self.read(|t| t.get_iter(table))
+    }
+
+    fn scan(&self, table: &str, range: &KeyRange, limit: usize) -> Result<Vec<Kvpair>, KvError> {
+        self.read(|t| t.scan(table, range, limit))
+    }
+
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        self.read(|t| t.list_tables())
+    }
+
+    fn drop_table(&self, table: &str) -> Result<bool, KvError> {
+        self.write(|t| t.drop_table(table))
+    }
+
+    fn table_len(&self, table: &str) -> Result<usize, KvError> {
+        self.read(|t| t.table_len(table))
+    }
+
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        self.write(|t| t.expire(table, key, ttl))
+    }
+
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Duration>, KvError> {
+        self.read(|t| t.ttl(table, key))
+    }
+
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.write(|t| t.persist(table, key))
+    }
+
+    // 下面这些在写锁里执行，读和写之间不会有其它修改
+    fn compare_and_swap(
+        &self,
+        table: &str,
+        key: &str,
+        expected: Option<&Value>,
+        value: Value,
+    ) -> Result<Result<(), Option<Value>>, KvError> {
+        self.write(|t| t.compare_and_swap(table, key, expected, value))
+    }
+
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        self.write(|t| t.incr(table, key, delta))
+    }
+
+    fn incr_float(&self, table: &str, key: &str, delta: f64) -> Result<f64, KvError> {
+        self.write(|t| t.incr_float(table, key, delta))
+    }
+
+    fn update_path(
+        &self,
+        table: &str,
+        key: &str,
+        path: &[PathSegment],
+        op: PathOp,
+    ) -> Result<Option<Value>, KvError> {
+        self.write(|t| t.update_path(table, key, path, op))
+    }
+
+    fn transaction(
+        &self,
+        _tables: &[&str],
+        f: &mut dyn FnMut(&dyn Storage) -> Result<(), KvError>,
+    ) -> Result<(), KvError> {
+        self.write(|t| f(t))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    // 很小的 memtable 和文件，写入少量数据就会触发 flush 和合并
+    fn small_config() -> LsmConfig {
+        LsmConfig {
+            memtable_size: 256,
+            block_size: 64,
+            sstable_size: 512,
+            l0_compaction_trigger: 2,
+            level_size_base: 1024,
+            level_size_multiplier: 2,
+            sync_wal: false,
+        }
+    }
+
+    fn level_count(store: &LsmStorage) -> Vec<usize> {
+        let state = store.state.read().unwrap();
+        state.levels.iter().map(|l| l.len()).collect()
+    }
+
+    #[test]
+    fn lsm_should_recover_from_wal() {
+        let dir = tempdir().unwrap();
+        let store = LsmStorage::new(dir.path());
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        store.del("t1", "k1").unwrap();
+        store
+            .set_with_ttl("t2", "k1".into(), "v1".into(), Duration::from_secs(60))
+            .unwrap();
+        drop(store);
+
+        let store = LsmStorage::new(dir.path());
+        assert_eq!(store.get("t1", "k1").unwrap(), None);
+        assert_eq!(store.get("t1", "k2").unwrap(), Some("v2".into()));
+        assert!(store.ttl("t2", "k1").unwrap().is_some());
+    }
+
+    #[test]
+    fn lsm_should_ignore_broken_wal_tail() {
+        let dir = tempdir().unwrap();
+        let store = LsmStorage::new(dir.path());
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        drop(store);
+
+        // 模拟写入一半时崩溃
+        let mut file = fs::OpenOptions::new()
+            .append(true)
+            .open(dir.path().join(WAL_FILE))
+            .unwrap();
+        file.write_all(&[0, 0, 0, 100, 1, 2]).unwrap();
+        drop(file);
+
+        let store = LsmStorage::new(dir.path());
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        drop(store);
+
+        let store = LsmStorage::new(dir.path());
+        assert_eq!(store.get("t1", "k2").unwrap(), Some("v2".into()));
+    }
+
+    #[test]
+    fn lsm_should_flush_and_compact() {
+        let dir = tempdir().unwrap();
+        let store = LsmStorage::open(dir.path(), small_config()).unwrap();
+        for i in 0..500 {
+            let key = format!("key{:04}", i % 200);
+            store.set("t1", key, i.into()).unwrap();
+        }
+        for i in 0..100 {
+            store.del("t1", &format!("key{:04}", i)).unwrap();
+        }
+        store
+            .set_with_ttl("t2", "k1".into(), "v1".into(), Duration::from_secs(60))
+            .unwrap();
+        // flush 和合并在后台执行
+        store.join_worker();
+
+        let levels = level_count(&store);
+        assert!(levels.len() > 2, "levels: {:?}", levels);
+        assert!(levels[0] < 2);
+
+        let check = |store: &LsmStorage| {
+            assert_eq!(store.table_len("t1").unwrap(), 100);
+            assert_eq!(store.get("t1", "key0050").unwrap(), None);
+            assert_eq!(store.get("t1", "key0150").unwrap(), Some(350.into()));
+            assert!(store.ttl("t2", "k1").unwrap().is_some());
+            let pairs = store.get_all("t1").unwrap();
+            assert_eq!(pairs[0], Kvpair::new("key0100", 300.into()));
+            assert_eq!(store.list_tables().unwrap(), ["t1", "t2"]);
+        };
+        check(&store);
+        drop(store);
+
+        // 重启后数据和层次结构保持不变
+        let store = LsmStorage::open(dir.path(), small_config()).unwrap();
+        check(&store);
+        assert_eq!(level_count(&store), levels);
+    }
+
+    #[test]
+    fn lsm_should_scan_range_across_levels() {
+        let dir = tempdir().unwrap();
+        let store = LsmStorage::open(dir.path(), small_config()).unwrap();
+        for i in 0..200 {
+            store.set("t1", format!("key{:04}", i), i.into()).unwrap();
+            store.set("t0", format!("key{:04}", i), i.into()).unwrap();
+        }
+        store.join_worker();
+        // 一部分新的修改还在 memtable 里，覆盖 SSTable 中的旧记录
+        store.set("t1", "key0010".into(), "new".into()).unwrap();
+        store.del("t1", "key0011").unwrap();
+        assert!(store.drop_table("t0").unwrap());
+        store.set("t2", "k1".into(), "v1".into()).unwrap();
+
+        let range = KeyRange {
+            start: "key0009".into(),
+            end: "key0100".into(),
+            ..Default::default()
+        };
+        let pairs = store.scan("t1", &range, 3).unwrap();
+        assert_eq!(
+            pairs,
+            [
+                Kvpair::new("key0009", 9.into()),
+                Kvpair::new("key0010", "new".into()),
+                Kvpair::new("key0012", 12.into()),
+            ]
+        );
+        let range = KeyRange {
+            after: "key0098".into(),
+            end: "key0100".into(),
+            ..Default::default()
+        };
+        let pairs = store.scan("t1", &range, 10).unwrap();
+        assert_eq!(pairs, [Kvpair::new("key0099", 99.into())]);
+
+        assert_eq!(store.table_len("t1").unwrap(), 199);
+        assert_eq!(store.table_len("t0").unwrap(), 0);
+        assert_eq!(store.list_tables().unwrap(), ["t1", "t2"]);
+    }
+
+    #[test]
+    fn lsm_should_recover_pending_immutable_memtable() {
+        let dir = tempdir().unwrap();
+        let store = LsmStorage::open(dir.path(), small_config()).unwrap();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        drop(store);
+
+        // 模拟 imm 写入 L0 之前退出
+        fs::rename(dir.path().join(WAL_FILE), dir.path().join(IMM_WAL_FILE)).unwrap();
+        let store = LsmStorage::open(dir.path(), small_config()).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        store.join_worker();
+
+        assert!(!dir.path().join(IMM_WAL_FILE).exists());
+        assert_eq!(level_count(&store)[0], 1);
+        drop(store);
+
+        let store = LsmStorage::open(dir.path(), small_config()).unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.get("t1", "k2").unwrap(), Some("v2".into()));
+    }
+
+    #[test]
+    fn lsm_should_remove_orphan_sstables() {
+        let dir = tempdir().unwrap();
+        let store = LsmStorage::open(dir.path(), small_config()).unwrap();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        drop(store);
+
+        // 合并中途退出时留下的文件
+        fs::write(sst_path(dir.path(), 999), b"garbage").unwrap();
+        let store = LsmStorage::open(dir.path(), small_config()).unwrap();
+        assert!(!sst_path(dir.path(), 999).exists());
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        assert_eq!(store.state.read().unwrap().next_id, 1000);
+    }
+}
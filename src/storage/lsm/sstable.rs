@@ -0,0 +1,298 @@
+use bytes::{Buf, BufMut};
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use super::{decode_entries, encode_entry, Entry, Record};
+use crate::KvError;
+
+/// SSTable 文件末尾的 magic
+const MAGIC: &[u8; 8] = b"KVLSMSST";
+/// footer 依次是 index 的位置、长度、CRC32 和 magic
+const FOOTER_LEN: usize = 8 + 4 + 4 + 8;
+
+/// 稀疏索引中的一项，指向一个数据块
+#[derive(Debug)]
+struct BlockHandle {
+    first_key: Vec<u8>,
+    offset: u64,
+    len: u32,
+    crc: u32,
+}
+
+/// 不可修改的有序文件。数据按 key 排序分成若干个块，内存里只保存每个块的第一个 key（稀疏索引），
+/// 查找时先在索引里定位到块，再读出整个块
+#[derive(Debug)]
+pub(super) struct SsTable {
+    pub id: u64,
+    pub first_key: Vec<u8>,
+    pub last_key: Vec<u8>,
+    pub size: u64,
+    path: PathBuf,
+    file: Mutex<File>,
+    index: Vec<BlockHandle>,
+}
+
+impl SsTable {
+    pub fn open(path: PathBuf, id: u64) -> Result<Self, KvError> {
+        let mut file = File::open(&path)?;
+        let size = file.metadata()?.len();
+        if size < FOOTER_LEN as u64 {
+            return Err(corrupted(&path, "file is too short"));
+        }
+
+        let mut footer = [0u8; FOOTER_LEN];
+        file.seek(SeekFrom::Start(size - FOOTER_LEN as u64))?;
+        file.read_exact(&mut footer)?;
+        if &footer[16..] != MAGIC {
+            return Err(corrupted(&path, "bad magic"));
+        }
+        let mut buf = &footer[..16];
+        let index_offset = buf.get_u64();
+        let index_len = buf.get_u32() as usize;
+        let index_crc = buf.get_u32();
+
+        let mut data = vec![0u8; index_len];
+        file.seek(SeekFrom::Start(index_offset))?;
+        file.read_exact(&mut data)?;
+        if crc32fast::hash(&data) != index_crc {
+            return Err(corrupted(&path, "index checksum mismatch"));
+        }
+        let (index, last_key) = decode_index(&data).ok_or_else(|| corrupted(&path, "bad index"))?;
+        let first_key = match index.first() {
+            Some(handle) => handle.first_key.clone(),
+            None => return Err(corrupted(&path, "empty table")),
+        };
+
+        Ok(Self {
+            id,
+            first_key,
+            last_key,
+            size,
+            path,
+            file: Mutex::new(file),
+            index,
+        })
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Record>, KvError> {
+        if key < self.first_key.as_slice() || key > self.last_key.as_slice() {
+            return Ok(None);
+        }
+        // 最后一个 first_key <= key 的块
+        let pos = self
+            .index
+            .partition_point(|h| h.first_key.as_slice() <= key);
+        let entries = self.read_block(&self.index[pos - 1])?;
+
+        Ok(entries
+            .into_iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, record)| record))
+    }
+
+    /// 从第一个 >= start 的 key 开始按顺序遍历，每次只读出一个块
+    pub fn iter(&self, start: Vec<u8>) -> SsTableIter<'_> {
+        // 最后一个 first_key <= start 的块
+        let block = self
+            .index
+            .partition_point(|h| h.first_key.as_slice() <= start.as_slice())
+            .saturating_sub(1);
+        SsTableIter {
+            table: self,
+            block,
+            entries: Vec::new().into_iter(),
+            start,
+        }
+    }
+
+    /// 和 [first, last] 范围是否有重叠
+    pub fn overlaps(&self, first: &[u8], last: &[u8]) -> bool {
+        self.first_key.as_slice() <= last && self.last_key.as_slice() >= first
+    }
+
+    pub fn remove(&self) -> Result<(), KvError> {
+        fs::remove_file(&self.path)?;
+        Ok(())
+    }
+
+    fn read_block(&self, handle: &BlockHandle) -> Result<Vec<Entry>, KvError> {
+        let mut data = vec![0u8; handle.len as usize];
+        {
+            let mut file = self.file.lock().unwrap();
+            file.seek(SeekFrom::Start(handle.offset))?;
+            file.read_exact(&mut data)?;
+        }
+        if crc32fast::hash(&data) != handle.crc {
+            return Err(corrupted(&self.path, "block checksum mismatch"));
+        }
+
+        decode_entries(&data)
+    }
+}
+
+/// SsTable::iter 返回的迭代器，读取出错后结束
+pub(super) struct SsTableIter<'a> {
+    table: &'a SsTable,
+    // 下一个要读的块
+    block: usize,
+    entries: std::vec::IntoIter<Entry>,
+    start: Vec<u8>,
+}
+
+impl Iterator for SsTableIter<'_> {
+    type Item = Result<Entry, KvError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.entries.next() {
+                // 只有第一个块里会有 < start 的 key
+                if entry.0 < self.start {
+                    continue;
+                }
+                return Some(Ok(entry));
+            }
+            let handle = self.table.index.get(self.block)?;
+            self.block += 1;
+            match self.table.read_block(handle) {
+                Ok(entries) => self.entries = entries.into_iter(),
+                Err(e) => {
+                    self.block = self.table.index.len();
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+/// 按 key 的顺序写入 entry，生成一个 SSTable 文件
+pub(super) struct SsTableBuilder {
+    id: u64,
+    path: PathBuf,
+    file: BufWriter<File>,
+    block_size: usize,
+    block: Vec<u8>,
+    block_first_key: Vec<u8>,
+    last_key: Vec<u8>,
+    index: Vec<BlockHandle>,
+    offset: u64,
+}
+
+impl SsTableBuilder {
+    pub fn new(path: PathBuf, id: u64, block_size: usize) -> Result<Self, KvError> {
+        Ok(Self {
+            id,
+            file: BufWriter::new(File::create(&path)?),
+            path,
+            block_size,
+            block: Vec::new(),
+            block_first_key: Vec::new(),
+            last_key: Vec::new(),
+            index: Vec::new(),
+            offset: 0,
+        })
+    }
+
+    pub fn add(&mut self, key: &[u8], record: &Record) -> Result<(), KvError> {
+        if self.block.is_empty() {
+            self.block_first_key = key.to_vec();
+        }
+        encode_entry(&mut self.block, key, record);
+        self.last_key = key.to_vec();
+        if self.block.len() >= self.block_size {
+            self.finish_block()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn size(&self) -> u64 {
+        self.offset + self.block.len() as u64
+    }
+
+    pub fn finish(mut self) -> Result<SsTable, KvError> {
+        self.finish_block()?;
+
+        let mut index = Vec::new();
+        for handle in &self.index {
+            index.put_u32(handle.first_key.len() as _);
+            index.extend_from_slice(&handle.first_key);
+            index.put_u64(handle.offset);
+            index.put_u32(handle.len);
+            index.put_u32(handle.crc);
+        }
+        index.put_u32(self.last_key.len() as _);
+        index.extend_from_slice(&self.last_key);
+
+        let mut footer = Vec::with_capacity(FOOTER_LEN);
+        footer.put_u64(self.offset);
+        footer.put_u32(index.len() as _);
+        footer.put_u32(crc32fast::hash(&index));
+        footer.extend_from_slice(MAGIC);
+
+        self.file.write_all(&index)?;
+        self.file.write_all(&footer)?;
+        self.file.flush()?;
+        self.file.get_ref().sync_all()?;
+
+        SsTable::open(self.path, self.id)
+    }
+
+    fn finish_block(&mut self) -> Result<(), KvError> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        self.file.write_all(&self.block)?;
+        self.index.push(BlockHandle {
+            first_key: std::mem::take(&mut self.block_first_key),
+            offset: self.offset,
+            len: self.block.len() as _,
+            crc: crc32fast::hash(&self.block),
+        });
+        self.offset += self.block.len() as u64;
+        self.block.clear();
+
+        Ok(())
+    }
+}
+
+fn decode_index(mut buf: &[u8]) -> Option<(Vec<BlockHandle>, Vec<u8>)> {
+    let mut index = Vec::new();
+    loop {
+        let first_key = read_bytes(&mut buf)?;
+        if buf.is_empty() {
+            // 最后一项是整个文件最大的 key
+            return Some((index, first_key));
+        }
+        if buf.remaining() < 16 {
+            return None;
+        }
+        index.push(BlockHandle {
+            first_key,
+            offset: buf.get_u64(),
+            len: buf.get_u32(),
+            crc: buf.get_u32(),
+        });
+    }
+}
+
+fn read_bytes(buf: &mut &[u8]) -> Option<Vec<u8>> {
+    if buf.remaining() < 4 {
+        return None;
+    }
+    let len = buf.get_u32() as usize;
+    if buf.remaining() < len {
+        return None;
+    }
+    let data = buf[..len].to_vec();
+    buf.advance(len);
+
+    Some(data)
+}
+
+fn corrupted(path: &Path, reason: &str) -> KvError {
+    KvError::Corrupted(format!("{:?}: {}", path, reason))
+}
@@ -0,0 +1,74 @@
+use bytes::{Buf, BufMut};
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Write},
+    path::Path,
+};
+use tracing::warn;
+
+use super::{decode_entries, encode_entry, Entry};
+use crate::KvError;
+
+/// 每条日志的 header：内容的长度和 CRC32
+const HEADER_LEN: usize = 8;
+
+/// 写前日志。每次提交的一批修改作为一条日志写入，恢复时要么整批生效，要么整批丢弃
+#[derive(Debug)]
+pub(super) struct Wal {
+    file: File,
+}
+
+impl Wal {
+    /// 打开日志并读出其中所有完整的批次，末尾不完整或校验失败的部分会被截掉
+    pub fn open(path: &Path) -> Result<(Self, Vec<Vec<Entry>>), KvError> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+
+        let mut batches = Vec::new();
+        let mut buf = &data[..];
+        while buf.len() >= HEADER_LEN {
+            let len = (&buf[..4]).get_u32() as usize;
+            let crc = (&buf[4..8]).get_u32();
+            let payload = match buf.get(HEADER_LEN..HEADER_LEN + len) {
+                Some(payload) if crc32fast::hash(payload) == crc => payload,
+                _ => break,
+            };
+            batches.push(decode_entries(payload)?);
+            buf = &buf[HEADER_LEN + len..];
+        }
+
+        if !buf.is_empty() {
+            warn!(
+                "WAL {:?} has {} bytes of broken data, truncated",
+                path,
+                buf.len()
+            );
+            file.set_len((data.len() - buf.len()) as u64)?;
+        }
+
+        Ok((Self { file }, batches))
+    }
+
+    pub fn append(&mut self, batch: &[Entry], sync: bool) -> Result<(), KvError> {
+        let mut payload = Vec::new();
+        for (key, record) in batch {
+            encode_entry(&mut payload, key, record);
+        }
+        let mut buf = Vec::with_capacity(HEADER_LEN + payload.len());
+        buf.put_u32(payload.len() as _);
+        buf.put_u32(crc32fast::hash(&payload));
+        buf.extend_from_slice(&payload);
+
+        self.file.write_all(&buf)?;
+        if sync {
+            self.file.sync_data()?;
+        }
+
+        Ok(())
+    }
+}
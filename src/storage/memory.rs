@@ -1,67 +1,1866 @@
-use crate::{KvError, Kvpair, StorageIter, Value};
-use dashmap::{mapref::one::Ref, DashMap};
+use crate::{
+    storage::{
+        add_float, add_integer, apply_path, check_score, list_bounds, serial_compare_and_swap,
+        serial_incr, serial_incr_float, serial_update_path,
+    },
+    value, KeyRange, KvError, Kvpair, ListEnd, PathOp, PathSegment, StorageIter, Value,
+};
+use dashmap::{
+    mapref::{entry::Entry as MapEntry, one::Ref},
+    DashMap,
+};
+use prost::Message;
+use std::{
+    cell::RefCell,
+    cmp::Ordering as CmpOrdering,
+    collections::{hash_map::RandomState, BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    hash::BuildHasher,
+    mem,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, RwLock, Weak,
+    },
+    thread,
+    time::{Duration, Instant},
+};
 
 use super::Storage;
 
-#[derive(Clone, Debug, Default)]
+/// 后台清理过期 key 的间隔
+const REAP_INTERVAL: Duration = Duration::from_secs(1);
+/// 每个 entry 除了 key 和 value 之外大致占用的内存（哈希表的槽位、过期时间、访问统计等）
+const ENTRY_OVERHEAD: usize = 64;
+/// 需要淘汰时多淘汰 1/EVICTION_SLACK 的内存，避免之后每次写入都要遍历所有的 key
+const EVICTION_SLACK: usize = 20;
+
+type Table = DashMap<String, Entry>;
+type ListTable = DashMap<String, VecDeque<Value>>;
+type SetTable = DashMap<String, HashSet<String>>;
+type ZsetTable = DashMap<String, SortedSet>;
+/// value 的二级索引，key 是编码后的 value，value 是所有等于它的 key
+type Index = DashMap<Vec<u8>, BTreeSet<String>>;
+
+/// 内存超过上限时选择淘汰哪些 key
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EvictionPolicy {
+    /// 最久没有访问的 key
+    Lru,
+    /// 访问次数最少的 key，每次淘汰之后访问次数减半，之前的热点数据会逐渐冷却
+    Lfu,
+    /// 随机选择
+    Random,
+    /// 只淘汰设置了过期时间的 key，最快过期的优先
+    VolatileTtl,
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = KvError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lru" => Ok(Self::Lru),
+            "lfu" => Ok(Self::Lfu),
+            "random" => Ok(Self::Random),
+            "volatile-ttl" => Ok(Self::VolatileTtl),
+            _ => Err(KvError::InvalidCommand(format!(
+                "unknown eviction policy: {}",
+                s
+            ))),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct MemTableConfig {
+    /// 所有 key 和 value 大致占用的内存上限（字节），0 表示不限制
+    pub max_memory: usize,
+    pub eviction: EvictionPolicy,
+}
+
+impl Default for MemTableConfig {
+    fn default() -> Self {
+        Self {
+            max_memory: 0,
+            eviction: EvictionPolicy::Lru,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct MemTable {
-    tables: DashMap<String, DashMap<String, Value>>,
+    tables: Arc<Tables>,
+    // 普通操作持有读锁，事务持有写锁，保证事务执行期间不会和其它操作交错
+    lock: Arc<RwLock<()>>,
+}
+
+/// MemTable 的实际数据，它上面的操作不加锁
+#[derive(Debug, Default)]
+struct Tables {
+    data: DashMap<String, Table>,
+    // 每个 table 中的 list、set 和 sorted set，和普通的 key 分开存放。它们没有过期时间，也不会被淘汰
+    lists: DashMap<String, ListTable>,
+    sets: DashMap<String, SetTable>,
+    zsets: DashMap<String, ZsetTable>,
+    // 建立了索引的 table 的索引。删除 table 时索引的定义保留下来
+    indexes: DashMap<String, Index>,
+    config: MemTableConfig,
+    // 所有 entry 大致占用的内存
+    used: AtomicUsize,
+    // 被淘汰的 key 的数量
+    evicted: AtomicU64,
+    // 逻辑时钟，每次访问加一，用于 LRU
+    clock: AtomicU64,
+    // 同一时间只有一个线程执行淘汰
+    evicting: Mutex<()>,
+    // 开始记录之后，被淘汰的 key 放在这里等待取走
+    evicted_keys: Mutex<Option<Vec<(String, String)>>>,
+}
+
+/// MemTable 中实际存储的数据，带上可选的过期时间
+#[derive(Debug)]
+struct Entry {
+    value: Value,
+    expire_at: Option<Instant>,
+    // key 和 value 大致占用的内存
+    size: usize,
+    // 最近一次访问的逻辑时刻和访问次数，用于 LRU 和 LFU
+    last_access: AtomicU64,
+    hits: AtomicU64,
+}
+
+impl Entry {
+    fn is_expired(&self) -> bool {
+        matches!(self.expire_at, Some(at) if at <= Instant::now())
+    }
+
+    fn touch(&self, now: u64) {
+        self.last_access.store(now, Ordering::Relaxed);
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Clone for Entry {
+    fn clone(&self) -> Self {
+        Self {
+            value: self.value.clone(),
+            expire_at: self.expire_at,
+            size: self.size,
+            last_access: AtomicU64::new(self.last_access.load(Ordering::Relaxed)),
+            hits: AtomicU64::new(self.hits.load(Ordering::Relaxed)),
+        }
+    }
 }
 
 impl MemTable {
     pub fn new() -> Self {
-        Self::default()
+        Self::with_config(MemTableConfig::default())
     }
 
-    fn get_or_create_table(&self, name: &str) -> Ref<String, DashMap<String, Value>> {
-        match self.tables.get(name) {
-            Some(table) => table,
-            None => {
-                let entry = self.tables.entry(name.into()).or_default();
-                entry.downgrade()
-            }
+    pub fn with_config(config: MemTableConfig) -> Self {
+        let tables = Arc::new(Tables {
+            config,
+            ..Default::default()
+        });
+        spawn_reaper(Arc::downgrade(&tables));
+
+        Self {
+            tables,
+            lock: Arc::new(RwLock::new(())),
         }
     }
+
+    /// 所有 key 和 value 大致占用的内存
+    pub fn used_memory(&self) -> usize {
+        self.tables.used.load(Ordering::Relaxed)
+    }
+
+    /// 因为内存超过上限被淘汰的 key 的数量
+    pub fn evicted_keys(&self) -> u64 {
+        self.tables.evicted.load(Ordering::Relaxed)
+    }
+
+    // 在读锁的保护下操作数据
+    fn read<T>(&self, f: impl FnOnce(&Tables) -> T) -> T {
+        let _guard = self.lock.read().unwrap();
+        f(&self.tables)
+    }
+}
+
+impl Default for MemTable {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Storage for MemTable {
     fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
-        let table = self.get_or_create_table(table);
-
-        Ok(table.get(key).map(|r| r.value().clone()))
+        self.read(|t| t.get(table, key))
     }
 
     fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
-        let table = self.get_or_create_table(table);
+        self.read(|t| t.set(table, key, value))
+    }
 
-        Ok(table.insert(key, value))
+    fn set_with_ttl(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> Result<Option<Value>, KvError> {
+        self.read(|t| t.set_with_ttl(table, key, value, ttl))
     }
 
     fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
-        let table = self.get_or_create_table(table);
-
-        Ok(table.contains_key(key))
+        self.read(|t| t.contains(table, key))
     }
 
     fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
-        let table = self.get_or_create_table(table);
+        self.read(|t| t.del(table, key))
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.read(|t| t.get_all(table))
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        self.read(|t| t.get_iter(table))
+    }
+
+    // 过期的 key 重放时也会过期，只需要记录还没过期就被淘汰的 key
+    fn track_evictions(&self) {
+        self.tables
+            .evicted_keys
+            .lock()
+            .unwrap()
+            .get_or_insert_with(Vec::new);
+    }
+
+    fn take_evicted(&self) -> Vec<(String, String)> {
+        match self.tables.evicted_keys.lock().unwrap().as_mut() {
+            Some(keys) => mem::take(keys),
+            None => Vec::new(),
+        }
+    }
+
+    fn scan(&self, table: &str, range: &KeyRange, limit: usize) -> Result<Vec<Kvpair>, KvError> {
+        self.read(|t| t.scan(table, range, limit))
+    }
+
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        self.read(|t| t.list_tables())
+    }
+
+    fn drop_table(&self, table: &str) -> Result<bool, KvError> {
+        self.read(|t| t.drop_table(table))
+    }
+
+    fn table_len(&self, table: &str) -> Result<usize, KvError> {
+        self.read(|t| t.table_len(table))
+    }
+
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        self.read(|t| t.expire(table, key, ttl))
+    }
+
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Duration>, KvError> {
+        self.read(|t| t.ttl(table, key))
+    }
+
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.read(|t| t.persist(table, key))
+    }
+
+    fn compare_and_swap(
+        &self,
+        table: &str,
+        key: &str,
+        expected: Option<&Value>,
+        value: Value,
+    ) -> Result<Result<(), Option<Value>>, KvError> {
+        self.read(|t| t.compare_and_swap(table, key, expected, value))
+    }
+
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        self.read(|t| t.update(table, key, |v| add_integer(v, delta)))
+    }
+
+    fn incr_float(&self, table: &str, key: &str, delta: f64) -> Result<f64, KvError> {
+        self.read(|t| t.update(table, key, |v| add_float(v, delta)))
+    }
+
+    fn update_path(
+        &self,
+        table: &str,
+        key: &str,
+        path: &[PathSegment],
+        op: PathOp,
+    ) -> Result<Option<Value>, KvError> {
+        self.read(|t| t.update_path(table, key, path, op))
+    }
+
+    // 持有写锁，建立索引时补上已有的数据，不会漏掉并发的写入
+    fn create_index(&self, table: &str) -> Result<bool, KvError> {
+        let _guard = self.lock.write().unwrap();
+        Ok(self.tables.create_index(table))
+    }
+
+    fn find(&self, table: &str, value: &Value) -> Result<Vec<Kvpair>, KvError> {
+        self.read(|t| t.find(table, value))
+    }
+
+    fn list_push(
+        &self,
+        table: &str,
+        key: &str,
+        end: ListEnd,
+        values: Vec<Value>,
+    ) -> Result<usize, KvError> {
+        self.read(|t| t.list_push(table, key, end, values))
+    }
+
+    fn list_pop(
+        &self,
+        table: &str,
+        key: &str,
+        end: ListEnd,
+        count: usize,
+    ) -> Result<Vec<Value>, KvError> {
+        self.read(|t| Ok(t.list_pop(table, key, end, count)))
+    }
+
+    fn list_range(
+        &self,
+        table: &str,
+        key: &str,
+        start: i64,
+        stop: i64,
+    ) -> Result<Vec<Value>, KvError> {
+        self.read(|t| Ok(t.list_range(table, key, start, stop)))
+    }
+
+    fn list_trim(&self, table: &str, key: &str, start: i64, stop: i64) -> Result<usize, KvError> {
+        self.read(|t| Ok(t.list_trim(table, key, start, stop)))
+    }
+
+    fn list_len(&self, table: &str, key: &str) -> Result<usize, KvError> {
+        self.read(|t| {
+            Ok(t.with_list(table, key, |list| list.len())
+                .unwrap_or_default())
+        })
+    }
+
+    fn list_index(&self, table: &str, key: &str, index: i64) -> Result<Option<Value>, KvError> {
+        self.read(|t| Ok(t.list_index(table, key, index)))
+    }
+
+    fn list_keys(&self, table: &str) -> Result<Vec<String>, KvError> {
+        self.read(|t| Ok(sorted_keys(&t.lists, table)))
+    }
+
+    fn set_add(&self, table: &str, key: &str, members: Vec<String>) -> Result<usize, KvError> {
+        self.read(|t| t.set_add(table, key, members))
+    }
+
+    fn set_remove(&self, table: &str, key: &str, members: Vec<String>) -> Result<usize, KvError> {
+        self.read(|t| Ok(t.set_remove(table, key, members)))
+    }
+
+    fn set_members(&self, table: &str, key: &str) -> Result<Vec<String>, KvError> {
+        self.read(|t| Ok(t.set_members(table, key)))
+    }
+
+    fn set_contains(&self, table: &str, key: &str, member: &str) -> Result<bool, KvError> {
+        self.read(|t| Ok(t.set_contains(table, key, member)))
+    }
+
+    fn set_len(&self, table: &str, key: &str) -> Result<usize, KvError> {
+        self.read(|t| Ok(t.set_len(table, key)))
+    }
+
+    fn set_keys(&self, table: &str) -> Result<Vec<String>, KvError> {
+        self.read(|t| Ok(sorted_keys(&t.sets, table)))
+    }
+
+    fn zset_add(
+        &self,
+        table: &str,
+        key: &str,
+        members: Vec<(String, f64)>,
+    ) -> Result<usize, KvError> {
+        self.read(|t| t.zset_add(table, key, members))
+    }
+
+    fn zset_incr(&self, table: &str, key: &str, member: &str, delta: f64) -> Result<f64, KvError> {
+        self.read(|t| t.zset_incr(table, key, member, delta))
+    }
+
+    fn zset_remove(&self, table: &str, key: &str, members: Vec<String>) -> Result<usize, KvError> {
+        self.read(|t| Ok(t.zset_remove(table, key, members)))
+    }
+
+    fn zset_range(
+        &self,
+        table: &str,
+        key: &str,
+        start: i64,
+        stop: i64,
+    ) -> Result<Vec<(String, f64)>, KvError> {
+        self.read(|t| {
+            Ok(t.with_zset(table, key, |zset| zset.range(start, stop))
+                .unwrap_or_default())
+        })
+    }
+
+    fn zset_range_by_score(
+        &self,
+        table: &str,
+        key: &str,
+        min: f64,
+        max: f64,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<(String, f64)>, KvError> {
+        self.read(|t| {
+            Ok(t.with_zset(table, key, |zset| {
+                zset.range_by_score(min, max, offset, limit)
+            })
+            .unwrap_or_default())
+        })
+    }
+
+    fn zset_score(&self, table: &str, key: &str, member: &str) -> Result<Option<f64>, KvError> {
+        self.read(|t| {
+            Ok(
+                t.with_zset(table, key, |zset| zset.scores.get(member).copied())
+                    .flatten(),
+            )
+        })
+    }
+
+    fn zset_rank(&self, table: &str, key: &str, member: &str) -> Result<Option<usize>, KvError> {
+        self.read(|t| Ok(t.with_zset(table, key, |zset| zset.rank(member)).flatten()))
+    }
 
-        Ok(table.remove(key).map(|r| r.1))
+    fn zset_len(&self, table: &str, key: &str) -> Result<usize, KvError> {
+        self.read(|t| {
+            Ok(t.with_zset(table, key, |zset| zset.scores.len())
+                .unwrap_or_default())
+        })
+    }
+
+    fn zset_keys(&self, table: &str) -> Result<Vec<String>, KvError> {
+        self.read(|t| Ok(sorted_keys(&t.zsets, table)))
+    }
+
+    fn transaction(
+        &self,
+        _tables: &[&str],
+        f: &mut dyn FnMut(&dyn Storage) -> Result<(), KvError>,
+    ) -> Result<(), KvError> {
+        let _guard = self.lock.write().unwrap();
+        let txn = MemTxn {
+            tables: &self.tables,
+            undo: RefCell::new(Vec::new()),
+            dropped: RefCell::new(Vec::new()),
+        };
+
+        let result = f(&txn);
+        if result.is_err() {
+            txn.rollback();
+        }
+
+        result
+    }
+}
+
+impl Tables {
+    fn get_or_create_table(&self, name: &str) -> Ref<'_, String, Table> {
+        get_or_create(&self.data, name)
+    }
+
+    fn new_entry(&self, key: &str, value: Value, ttl: Option<Duration>) -> Entry {
+        Entry {
+            size: entry_size(key, &value),
+            value,
+            // ttl 大到超出 Instant 的范围时相当于不过期
+            expire_at: ttl.and_then(|ttl| Instant::now().checked_add(ttl)),
+            last_access: AtomicU64::new(self.tick()),
+            hits: AtomicU64::new(1),
+        }
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed)
+    }
+
+    // 一个 entry 被 new 替换（None 表示删除）之后更新内存占用
+    fn account(&self, old: Option<&Entry>, new: Option<&Entry>) {
+        let old = old.map(|e| e.size).unwrap_or_default();
+        let new = new.map(|e| e.size).unwrap_or_default();
+        self.resize(old, new);
+    }
+
+    fn resize(&self, old: usize, new: usize) {
+        if new >= old {
+            self.used.fetch_add(new - old, Ordering::Relaxed);
+        } else {
+            self.used.fetch_sub(old - new, Ordering::Relaxed);
+        }
+    }
+
+    // 写入 size 字节之前确保内存足够，不够时按照淘汰策略删除一些 key。
+    // 调用时不能持有 table 的锁，否则淘汰时会死锁
+    fn reserve(&self, size: usize) -> Result<(), KvError> {
+        let max = self.config.max_memory;
+        let fits = || self.used.load(Ordering::Relaxed) + size <= max;
+        if max == 0 || fits() {
+            return Ok(());
+        }
+
+        {
+            let _guard = self.evicting.lock().unwrap();
+            if !fits() {
+                self.evict((max - max / EVICTION_SLACK).saturating_sub(size));
+            }
+        }
+        if !fits() {
+            return Err(KvError::OutOfMemory(format!(
+                "cannot free {} bytes with {:?} policy",
+                size, self.config.eviction
+            )));
+        }
+
+        Ok(())
+    }
+
+    // 按照淘汰策略删除 key，直到内存占用不超过 target。过期的 key 总是最先删除，不计入淘汰的数量
+    fn evict(&self, target: usize) {
+        let policy = self.config.eviction;
+        let random = RandomState::new();
+        let now = Instant::now();
+        let mut candidates = Vec::new();
+        for table in self.data.iter() {
+            for item in table.iter() {
+                let entry = item.value();
+                let score = match policy {
+                    EvictionPolicy::Lru => entry.last_access.load(Ordering::Relaxed),
+                    EvictionPolicy::Lfu => {
+                        let hits = entry.hits.load(Ordering::Relaxed);
+                        entry.hits.store(hits / 2, Ordering::Relaxed);
+                        hits
+                    }
+                    EvictionPolicy::Random => random.hash_one((table.key(), item.key())),
+                    EvictionPolicy::VolatileTtl => match entry.expire_at {
+                        Some(at) => at.saturating_duration_since(now).as_millis() as u64,
+                        None => continue,
+                    },
+                };
+                let expired = entry.is_expired();
+                candidates.push((!expired, score, table.key().clone(), item.key().clone()));
+            }
+        }
+        candidates.sort_unstable();
+
+        for (alive, _, table, key) in candidates {
+            if self.used.load(Ordering::Relaxed) <= target {
+                break;
+            }
+            if self.remove(&table, &key).is_some() && alive {
+                self.evicted.fetch_add(1, Ordering::Relaxed);
+                if let Some(keys) = self.evicted_keys.lock().unwrap().as_mut() {
+                    keys.push((table, key));
+                }
+            }
+        }
+    }
+
+    fn insert(&self, name: &str, key: String, entry: Entry) -> Result<Option<Value>, KvError> {
+        self.reserve(entry.size)?;
+        let table = self.get_or_create_table(name);
+        self.account(None, Some(&entry));
+        let old = match table.entry(key) {
+            MapEntry::Occupied(mut e) => {
+                self.reindex(name, e.key(), Some(&e.get().value), Some(&entry.value));
+                Some(e.insert(entry))
+            }
+            MapEntry::Vacant(e) => {
+                self.reindex(name, e.key(), None, Some(&entry.value));
+                e.insert(entry);
+                None
+            }
+        };
+        self.account(old.as_ref(), None);
+
+        Ok(old.filter(|old| !old.is_expired()).map(|old| old.value))
+    }
+
+    fn remove(&self, table: &str, key: &str) -> Option<Entry> {
+        let (_, old) = self.data.get(table)?.remove(key)?;
+        self.account(Some(&old), None);
+        self.reindex(table, key, Some(&old.value), None);
+        Some(old)
+    }
+
+    // 建立了索引的 table 中 key 的 value 从 old 变成 new 时更新索引
+    fn reindex(&self, table: &str, key: &str, old: Option<&Value>, new: Option<&Value>) {
+        let index = match self.indexes.get(table) {
+            Some(index) => index,
+            None => return,
+        };
+        if let Some(old) = old {
+            if let MapEntry::Occupied(mut keys) = index.entry(old.encode_to_vec()) {
+                keys.get_mut().remove(key);
+                if keys.get().is_empty() {
+                    keys.remove();
+                }
+            }
+        }
+        if let Some(new) = new {
+            index
+                .entry(new.encode_to_vec())
+                .or_default()
+                .insert(key.to_owned());
+        }
+    }
+
+    fn create_index(&self, table: &str) -> bool {
+        if self.indexes.contains_key(table) {
+            return false;
+        }
+        let index = Index::default();
+        if let Some(data) = self.data.get(table) {
+            for entry in data.iter() {
+                index
+                    .entry(entry.value().value.encode_to_vec())
+                    .or_default()
+                    .insert(entry.key().clone());
+            }
+        }
+        self.indexes.insert(table.into(), index);
+
+        true
+    }
+
+    // 有索引时从索引中找到候选的 key，再检查它们有没有过期
+    fn find(&self, table: &str, value: &Value) -> Result<Vec<Kvpair>, KvError> {
+        let keys = match self.indexes.get(table) {
+            Some(index) => index
+                .get(&value.encode_to_vec())
+                .map(|keys| keys.clone())
+                .unwrap_or_default(),
+            None => {
+                let mut pairs: Vec<_> = self
+                    .get_all(table)?
+                    .into_iter()
+                    .filter(|pair| pair.value.as_ref() == Some(value))
+                    .collect();
+                pairs.sort_by(|a, b| a.key.cmp(&b.key));
+                return Ok(pairs);
+            }
+        };
+
+        Ok(keys
+            .into_iter()
+            .filter_map(|key| match self.get_alive(table, &key) {
+                Some(entry) if &entry.value == value => Some(Kvpair::new(key, entry.value)),
+                _ => None,
+            })
+            .collect())
+    }
+
+    // 读取时顺便检查是否过期，过期的 key 直接删除。读取不会创建 table
+    fn get_alive(&self, name: &str, key: &str) -> Option<Entry> {
+        let table = self.data.get(name)?;
+        let entry = table.get(key).map(|r| {
+            r.value().touch(self.tick());
+            r.value().clone()
+        })?;
+        if entry.is_expired() {
+            if let Some((_, old)) = table.remove_if(key, |_, v| v.is_expired()) {
+                self.account(Some(&old), None);
+                self.reindex(name, key, Some(&old.value), None);
+            }
+            return None;
+        }
+
+        Some(entry)
+    }
+
+    // 取出 key 当前的原始数据，事务回滚时用它来恢复
+    fn snapshot(&self, table: &str, key: &str) -> Option<Entry> {
+        let table = self.data.get(table)?;
+        let entry = table.get(key);
+        entry.map(|r| r.value().clone())
+    }
+
+    fn restore(&self, name: &str, key: String, entry: Option<Entry>) {
+        let table = self.get_or_create_table(name);
+        self.account(None, entry.as_ref());
+        let new = entry.as_ref().map(|e| e.value.clone());
+        let old = match entry {
+            Some(entry) => table.insert(key.clone(), entry),
+            None => table.remove(&key).map(|(_, v)| v),
+        };
+        self.account(old.as_ref(), None);
+        self.reindex(name, &key, old.as_ref().map(|e| &e.value), new.as_ref());
+    }
+
+    // 下面的方法和 Storage 的接口一样，但不加锁，由 MemTable 和 MemTxn 调用
+
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        Ok(self.get_alive(table, key).map(|entry| entry.value))
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        let entry = self.new_entry(&key, value, None);
+        self.insert(table, key, entry)
+    }
+
+    fn set_with_ttl(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> Result<Option<Value>, KvError> {
+        let entry = self.new_entry(&key, value, Some(ttl));
+        self.insert(table, key, entry)
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        Ok(self.get_alive(table, key).is_some())
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        Ok(self
+            .remove(table, key)
+            .filter(|old| !old.is_expired())
+            .map(|old| old.value))
     }
 
     fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
-        let table = self.get_or_create_table(table);
+        let table = match self.data.get(table) {
+            Some(table) => table,
+            None => return Ok(Vec::new()),
+        };
 
         Ok(table
             .iter()
-            .map(|entry| Kvpair::new(entry.key(), entry.value().clone()))
+            .filter(|entry| !entry.value().is_expired())
+            .map(|entry| Kvpair::new(entry.key(), entry.value().value.clone()))
             .collect::<Vec<_>>())
     }
 
     fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
-        let table = self.get_or_create_table(table).clone();
-        let iter = StorageIter::new(table.into_iter());
+        let table = match self.data.get(table) {
+            Some(table) => table.clone(),
+            None => Table::default(),
+        };
+        let iter = StorageIter::new(
+            table
+                .into_iter()
+                .filter(|(_, entry)| !entry.is_expired())
+                .map(|(key, entry)| (key, entry.value)),
+        );
 
         Ok(Box::new(iter))
     }
+
+    // DashMap 里的 key 是无序的，遍历时只保留最小的 limit 个，不需要复制整个 table
+    fn scan(&self, table: &str, range: &KeyRange, limit: usize) -> Result<Vec<Kvpair>, KvError> {
+        let table = match self.data.get(table) {
+            Some(table) => table,
+            None => return Ok(Vec::new()),
+        };
+
+        let mut page = BTreeMap::new();
+        for entry in table.iter() {
+            if limit == 0 {
+                break;
+            }
+            let (key, e) = entry.pair();
+            if e.is_expired() || !range.contains(key) {
+                continue;
+            }
+            if page.len() == limit {
+                match page.last_key_value() {
+                    Some((last, _)) if key < last => {
+                        page.pop_last();
+                    }
+                    _ => continue,
+                }
+            }
+            page.insert(key.clone(), e.value.clone());
+        }
+
+        Ok(page
+            .into_iter()
+            .map(|(key, value)| Kvpair::new(key, value))
+            .collect())
+    }
+
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        let mut tables: Vec<_> = self
+            .data
+            .iter()
+            .filter(|table| table.value().iter().any(|entry| !entry.is_expired()))
+            .map(|table| table.key().clone())
+            .chain(non_empty_tables(&self.lists))
+            .chain(non_empty_tables(&self.sets))
+            .chain(non_empty_tables(&self.zsets))
+            .collect();
+        tables.sort();
+        tables.dedup();
+
+        Ok(tables)
+    }
+
+    fn drop_table(&self, table: &str) -> Result<bool, KvError> {
+        let collections = self.take_collections(table).existed();
+        if let Some(index) = self.indexes.get(table) {
+            index.clear();
+        }
+        let table = match self.data.remove(table) {
+            Some((_, table)) => table,
+            None => return Ok(collections),
+        };
+        let size: usize = table.iter().map(|entry| entry.size).sum();
+        self.used.fetch_sub(size, Ordering::Relaxed);
+
+        let existed = table.iter().any(|entry| !entry.is_expired());
+
+        Ok(existed || collections)
+    }
+
+    fn table_len(&self, table: &str) -> Result<usize, KvError> {
+        Ok(self
+            .data
+            .get(table)
+            .map(|table| table.iter().filter(|entry| !entry.is_expired()).count())
+            .unwrap_or_default())
+    }
+
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        let table = match self.data.get(table) {
+            Some(table) => table,
+            None => return Ok(false),
+        };
+        let result = match table.get_mut(key) {
+            Some(mut entry) if !entry.is_expired() => {
+                entry.expire_at = Instant::now().checked_add(ttl);
+                true
+            }
+            _ => false,
+        };
+
+        Ok(result)
+    }
+
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Duration>, KvError> {
+        match self.get_alive(table, key) {
+            Some(entry) => Ok(entry
+                .expire_at
+                .map(|at| at.saturating_duration_since(Instant::now()))),
+            None => Err(KvError::NotFound(table.into(), key.into())),
+        }
+    }
+
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        let table = match self.data.get(table) {
+            Some(table) => table,
+            None => return Ok(false),
+        };
+        let result = match table.get_mut(key) {
+            Some(mut entry) if !entry.is_expired() => entry.expire_at.take().is_some(),
+            _ => false,
+        };
+
+        Ok(result)
+    }
+
+    // 通过 entry 持有 key 所在分片的锁，比较和写入之间不会被其它操作打断
+    fn compare_and_swap(
+        &self,
+        table: &str,
+        key: &str,
+        expected: Option<&Value>,
+        value: Value,
+    ) -> Result<Result<(), Option<Value>>, KvError> {
+        let new = self.new_entry(key, value, None);
+        self.reserve(new.size)?;
+        let name = table;
+        let table = self.get_or_create_table(name);
+        let result = match table.entry(key.into()) {
+            MapEntry::Occupied(mut entry) => {
+                let current = Some(entry.get())
+                    .filter(|e| !e.is_expired())
+                    .map(|e| e.value.clone());
+                if current.as_ref() == expected {
+                    self.account(None, Some(&new));
+                    self.reindex(name, key, Some(&entry.get().value), Some(&new.value));
+                    let old = entry.insert(new);
+                    self.account(Some(&old), None);
+                    Ok(())
+                } else {
+                    Err(current)
+                }
+            }
+            MapEntry::Vacant(entry) => match expected {
+                None => {
+                    self.account(None, Some(&new));
+                    self.reindex(name, key, None, Some(&new.value));
+                    entry.insert(new);
+                    Ok(())
+                }
+                Some(_) => Err(None),
+            },
+        };
+
+        Ok(result)
+    }
+
+    // 在 entry 的锁里读出旧值、计算并写入新值，过期时间保持不变
+    fn update<T>(
+        &self,
+        table: &str,
+        key: &str,
+        f: impl FnOnce(Option<Value>) -> Result<T, KvError>,
+    ) -> Result<T, KvError>
+    where
+        T: Into<Value> + Clone,
+    {
+        // 数字的大小是固定的
+        self.reserve(entry_size(key, &Value::default()))?;
+        let name = table;
+        let table = self.get_or_create_table(name);
+        let result = match table.entry(key.into()) {
+            MapEntry::Occupied(mut entry) if !entry.get().is_expired() => {
+                let result = f(Some(entry.get().value.clone()))?;
+                let entry = entry.get_mut();
+                let old = entry.size;
+                let value = result.clone().into();
+                self.reindex(name, key, Some(&entry.value), Some(&value));
+                entry.value = value;
+                entry.size = entry_size(key, &entry.value);
+                entry.touch(self.tick());
+                self.resize(old, entry.size);
+                Ok(result)
+            }
+            MapEntry::Occupied(mut entry) => {
+                let result = f(None)?;
+                let new = self.new_entry(key, result.clone().into(), None);
+                self.account(None, Some(&new));
+                self.reindex(name, key, Some(&entry.get().value), Some(&new.value));
+                let old = entry.insert(new);
+                self.account(Some(&old), None);
+                Ok(result)
+            }
+            MapEntry::Vacant(entry) => {
+                let result = f(None)?;
+                let new = self.new_entry(key, result.clone().into(), None);
+                self.account(None, Some(&new));
+                self.reindex(name, key, None, Some(&new.value));
+                entry.insert(new);
+                Ok(result)
+            }
+        };
+
+        result
+    }
+
+    // 持有 entry 的锁直接修改 value 中的元素，不用复制整个 value
+    fn update_path(
+        &self,
+        table: &str,
+        key: &str,
+        path: &[PathSegment],
+        op: PathOp,
+    ) -> Result<Option<Value>, KvError> {
+        let size = match &op {
+            PathOp::Set(value) => entry_size(key, value),
+            PathOp::Delete => 0,
+            PathOp::Append(values) => values.iter().map(value_size).sum(),
+        };
+        self.reserve(size)?;
+        let data = self.get_or_create_table(table);
+        let result = match data.entry(key.into()) {
+            MapEntry::Occupied(mut entry) if !entry.get().is_expired() => {
+                let entry = entry.get_mut();
+                // 只有建立了索引时才需要复制原来的 value
+                let indexed = self.indexes.contains_key(table);
+                let before = indexed.then(|| entry.value.clone());
+                let result = entry.value.update_path(path, op)?;
+                if indexed {
+                    self.reindex(table, key, before.as_ref(), Some(&entry.value));
+                }
+                let old = entry.size;
+                entry.size = entry_size(key, &entry.value);
+                entry.touch(self.tick());
+                self.resize(old, entry.size);
+                Ok(result)
+            }
+            MapEntry::Occupied(mut entry) => {
+                let (value, result) = apply_path(table, key, None, path, op)?;
+                let new = self.new_entry(key, value, None);
+                self.account(None, Some(&new));
+                self.reindex(table, key, Some(&entry.get().value), Some(&new.value));
+                let old = entry.insert(new);
+                self.account(Some(&old), None);
+                Ok(result)
+            }
+            MapEntry::Vacant(entry) => {
+                let (value, result) = apply_path(table, key, None, path, op)?;
+                let new = self.new_entry(key, value, None);
+                self.account(None, Some(&new));
+                self.reindex(table, key, None, Some(&new.value));
+                entry.insert(new);
+                Ok(result)
+            }
+        };
+
+        result
+    }
+
+    // 持有 list 的锁在两端放入元素，整个 push 不会和其它操作交错
+    fn list_push(
+        &self,
+        table: &str,
+        key: &str,
+        end: ListEnd,
+        values: Vec<Value>,
+    ) -> Result<usize, KvError> {
+        if values.is_empty() {
+            return Ok(self
+                .with_list(table, key, |list| list.len())
+                .unwrap_or_default());
+        }
+        let size: usize = values.iter().map(value_size).sum();
+        self.reserve(collection_overhead(key) + size)?;
+
+        let lists = get_or_create(&self.lists, table);
+        let mut list = lists.entry(key.into()).or_insert_with(|| {
+            self.resize(0, collection_overhead(key));
+            VecDeque::new()
+        });
+        self.resize(0, size);
+        for value in values {
+            match end {
+                ListEnd::Left => list.push_front(value),
+                ListEnd::Right => list.push_back(value),
+            }
+        }
+
+        Ok(list.len())
+    }
+
+    fn list_pop(&self, table: &str, key: &str, end: ListEnd, count: usize) -> Vec<Value> {
+        self.update_list(table, key, |list| {
+            let n = count.min(list.len());
+            (0..n)
+                .filter_map(|_| match end {
+                    ListEnd::Left => list.pop_front(),
+                    ListEnd::Right => list.pop_back(),
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+    }
+
+    fn list_range(&self, table: &str, key: &str, start: i64, stop: i64) -> Vec<Value> {
+        self.with_list(table, key, |list| {
+            match list_bounds(list.len(), start, stop) {
+                Some((from, to)) => list.range(from..to).cloned().collect(),
+                None => Vec::new(),
+            }
+        })
+        .unwrap_or_default()
+    }
+
+    fn list_trim(&self, table: &str, key: &str, start: i64, stop: i64) -> usize {
+        self.update_list(table, key, |list| {
+            let (from, to) = list_bounds(list.len(), start, stop).unwrap_or((0, 0));
+            let mut removed: Vec<_> = list.drain(to..).collect();
+            removed.extend(list.drain(..from));
+            removed
+        })
+        .map(|removed: Vec<_>| removed.len())
+        .unwrap_or_default()
+    }
+
+    fn list_index(&self, table: &str, key: &str, index: i64) -> Option<Value> {
+        self.with_list(table, key, |list| {
+            let (from, _) = list_bounds(list.len(), index, index)?;
+            list.get(from).cloned()
+        })
+        .flatten()
+    }
+
+    // 读取 key 对应的 list，list 不存在时返回 None
+    fn with_list<T>(
+        &self,
+        table: &str,
+        key: &str,
+        f: impl FnOnce(&VecDeque<Value>) -> T,
+    ) -> Option<T> {
+        let lists = self.lists.get(table)?;
+        let list = lists.get(key)?;
+        Some(f(list.value()))
+    }
+
+    // 从 list 中删除元素，f 返回删除的元素。list 变空之后被删除
+    fn update_list(
+        &self,
+        table: &str,
+        key: &str,
+        f: impl FnOnce(&mut VecDeque<Value>) -> Vec<Value>,
+    ) -> Option<Vec<Value>> {
+        let lists = self.lists.get(table)?;
+        let removed = {
+            let mut list = lists.get_mut(key)?;
+            f(list.value_mut())
+        };
+        self.resize(removed.iter().map(value_size).sum(), 0);
+        if lists.remove_if(key, |_, list| list.is_empty()).is_some() {
+            self.resize(collection_overhead(key), 0);
+        }
+
+        Some(removed)
+    }
+
+    fn set_add(&self, table: &str, key: &str, members: Vec<String>) -> Result<usize, KvError> {
+        if members.is_empty() {
+            return Ok(0);
+        }
+        let size: usize = members.iter().map(|m| member_size(m)).sum();
+        self.reserve(collection_overhead(key) + size)?;
+
+        let sets = get_or_create(&self.sets, table);
+        let mut set = sets.entry(key.into()).or_insert_with(|| {
+            self.resize(0, collection_overhead(key));
+            HashSet::new()
+        });
+        let mut added = 0;
+        for member in members {
+            let size = member_size(&member);
+            if set.insert(member) {
+                self.resize(0, size);
+                added += 1;
+            }
+        }
+
+        Ok(added)
+    }
+
+    fn set_remove(&self, table: &str, key: &str, members: Vec<String>) -> usize {
+        let sets = match self.sets.get(table) {
+            Some(sets) => sets,
+            None => return 0,
+        };
+        let (removed, freed) = match sets.get_mut(key) {
+            Some(mut set) => members
+                .iter()
+                .filter(|m| set.remove(m.as_str()))
+                .fold((0, 0), |(n, size), m| (n + 1, size + member_size(m))),
+            None => return 0,
+        };
+        self.resize(freed, 0);
+        if sets.remove_if(key, |_, set| set.is_empty()).is_some() {
+            self.resize(collection_overhead(key), 0);
+        }
+
+        removed
+    }
+
+    fn set_members(&self, table: &str, key: &str) -> Vec<String> {
+        let mut members: Vec<_> = self
+            .sets
+            .get(table)
+            .and_then(|sets| Some(sets.get(key)?.iter().cloned().collect()))
+            .unwrap_or_default();
+        members.sort();
+        members
+    }
+
+    fn set_contains(&self, table: &str, key: &str, member: &str) -> bool {
+        self.sets
+            .get(table)
+            .and_then(|sets| Some(sets.get(key)?.contains(member)))
+            .unwrap_or_default()
+    }
+
+    fn set_len(&self, table: &str, key: &str) -> usize {
+        self.sets
+            .get(table)
+            .and_then(|sets| Some(sets.get(key)?.len()))
+            .unwrap_or_default()
+    }
+
+    fn zset_add(
+        &self,
+        table: &str,
+        key: &str,
+        members: Vec<(String, f64)>,
+    ) -> Result<usize, KvError> {
+        let members = members
+            .into_iter()
+            .map(|(member, score)| Ok((member, check_score(score)?)))
+            .collect::<Result<Vec<_>, KvError>>()?;
+        if members.is_empty() {
+            return Ok(0);
+        }
+        let size: usize = members.iter().map(|(m, _)| scored_member_size(m)).sum();
+        self.reserve(collection_overhead(key) + size)?;
+
+        let zsets = get_or_create(&self.zsets, table);
+        let mut zset = zsets.entry(key.into()).or_insert_with(|| {
+            self.resize(0, collection_overhead(key));
+            SortedSet::default()
+        });
+        let mut added = 0;
+        for (member, score) in members {
+            let size = scored_member_size(&member);
+            if zset.insert(member, score).is_none() {
+                self.resize(0, size);
+                added += 1;
+            }
+        }
+
+        Ok(added)
+    }
+
+    // 持有 sorted set 的锁读出旧的 score 再写入新的 score
+    fn zset_incr(&self, table: &str, key: &str, member: &str, delta: f64) -> Result<f64, KvError> {
+        let size = scored_member_size(member);
+        self.reserve(collection_overhead(key) + size)?;
+
+        let zsets = get_or_create(&self.zsets, table);
+        let score = match zsets.entry(key.into()) {
+            MapEntry::Occupied(mut entry) => {
+                let zset = entry.get_mut();
+                let score = add_float(zset.scores.get(member).map(|&s| s.into()), delta)?;
+                if zset.insert(member.into(), score).is_none() {
+                    self.resize(0, size);
+                }
+                score
+            }
+            MapEntry::Vacant(entry) => {
+                let score = add_float(None, delta)?;
+                let mut zset = SortedSet::default();
+                zset.insert(member.into(), score);
+                self.resize(0, collection_overhead(key) + size);
+                entry.insert(zset);
+                score
+            }
+        };
+
+        Ok(score)
+    }
+
+    fn zset_remove(&self, table: &str, key: &str, members: Vec<String>) -> usize {
+        let zsets = match self.zsets.get(table) {
+            Some(zsets) => zsets,
+            None => return 0,
+        };
+        let (removed, freed) = match zsets.get_mut(key) {
+            Some(mut zset) => members
+                .iter()
+                .filter(|m| zset.remove(m).is_some())
+                .fold((0, 0), |(n, size), m| (n + 1, size + scored_member_size(m))),
+            None => return 0,
+        };
+        self.resize(freed, 0);
+        if zsets.remove_if(key, |_, zset| zset.is_empty()).is_some() {
+            self.resize(collection_overhead(key), 0);
+        }
+
+        removed
+    }
+
+    // 读取 key 对应的 sorted set，不存在时返回 None
+    fn with_zset<T>(&self, table: &str, key: &str, f: impl FnOnce(&SortedSet) -> T) -> Option<T> {
+        let zsets = self.zsets.get(table)?;
+        let zset = zsets.get(key)?;
+        Some(f(zset.value()))
+    }
+
+    // 删除 table 中所有的 list、set 和 sorted set
+    fn take_collections(&self, table: &str) -> Dropped {
+        Dropped {
+            lists: self.take(&self.lists, table),
+            sets: self.take(&self.sets, table),
+            zsets: self.take(&self.zsets, table),
+        }
+    }
+
+    fn restore_collections(&self, table: &str, dropped: Dropped) {
+        if let Some(lists) = dropped.lists {
+            self.put(&self.lists, table, lists);
+        }
+        if let Some(sets) = dropped.sets {
+            self.put(&self.sets, table, sets);
+        }
+        if let Some(zsets) = dropped.zsets {
+            self.put(&self.zsets, table, zsets);
+        }
+    }
+
+    fn take<C: Collection>(
+        &self,
+        map: &DashMap<String, DashMap<String, C>>,
+        table: &str,
+    ) -> Option<DashMap<String, C>> {
+        let (_, collections) = map.remove(table)?;
+        self.resize(collections_size(&collections), 0);
+        Some(collections)
+    }
+
+    fn put<C: Collection>(
+        &self,
+        map: &DashMap<String, DashMap<String, C>>,
+        table: &str,
+        collections: DashMap<String, C>,
+    ) {
+        self.resize(0, collections_size(&collections));
+        if let Some(old) = map.insert(table.into(), collections) {
+            self.resize(collections_size(&old), 0);
+        }
+    }
+}
+
+/// 删除 table 时一起删除的 list、set 和 sorted set，事务回滚时用来恢复
+struct Dropped {
+    lists: Option<ListTable>,
+    sets: Option<SetTable>,
+    zsets: Option<ZsetTable>,
+}
+
+impl Dropped {
+    // 删除之前是否有数据
+    fn existed(&self) -> bool {
+        matches!(&self.lists, Some(lists) if !lists.is_empty())
+            || matches!(&self.sets, Some(sets) if !sets.is_empty())
+            || matches!(&self.zsets, Some(zsets) if !zsets.is_empty())
+    }
+}
+
+/// list、set 等集合类型的 value
+trait Collection {
+    // 所有元素大致占用的内存
+    fn size(&self) -> usize;
+}
+
+impl Collection for VecDeque<Value> {
+    fn size(&self) -> usize {
+        self.iter().map(value_size).sum()
+    }
+}
+
+impl Collection for HashSet<String> {
+    fn size(&self) -> usize {
+        self.iter().map(|m| member_size(m)).sum()
+    }
+}
+
+impl Collection for SortedSet {
+    fn size(&self) -> usize {
+        self.scores.keys().map(|m| scored_member_size(m)).sum()
+    }
+}
+
+/// sorted set：member 到 score 的映射，加上按 (score, member) 排序的索引
+#[derive(Debug, Default)]
+struct SortedSet {
+    scores: HashMap<String, f64>,
+    index: BTreeSet<(Score, String)>,
+}
+
+impl SortedSet {
+    // 写入 member 的 score，返回原来的 score
+    fn insert(&mut self, member: String, score: f64) -> Option<f64> {
+        let old = self.scores.insert(member.clone(), score);
+        if let Some(old) = old {
+            self.index.remove(&(Score(old), member.clone()));
+        }
+        self.index.insert((Score(score), member));
+        old
+    }
+
+    fn remove(&mut self, member: &str) -> Option<f64> {
+        let score = self.scores.remove(member)?;
+        self.index.remove(&(Score(score), member.to_owned()));
+        Some(score)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    fn rank(&self, member: &str) -> Option<usize> {
+        let score = *self.scores.get(member)?;
+        Some(
+            self.index
+                .range(..(Score(score), member.to_owned()))
+                .count(),
+        )
+    }
+
+    fn range(&self, start: i64, stop: i64) -> Vec<(String, f64)> {
+        match list_bounds(self.index.len(), start, stop) {
+            Some((from, to)) => self
+                .index
+                .iter()
+                .skip(from)
+                .take(to - from)
+                .map(|(score, member)| (member.clone(), score.0))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    fn range_by_score(
+        &self,
+        min: f64,
+        max: f64,
+        offset: usize,
+        limit: usize,
+    ) -> Vec<(String, f64)> {
+        let limit = if limit == 0 { usize::MAX } else { limit };
+        self.index
+            .range((Score(min), String::new())..)
+            .take_while(|(score, _)| score.0 <= max)
+            .skip(offset)
+            .take(limit)
+            .map(|(score, member)| (member.clone(), score.0))
+            .collect()
+    }
+}
+
+/// 可以排序的 score，写入的 score 都是有限的数字
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Score(f64);
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// MemTable 的事务视图。执行时已经持有 MemTable 的写锁，
+/// 每次修改前记录 key 原来的数据，失败时按相反的顺序恢复
+struct MemTxn<'a> {
+    tables: &'a Tables,
+    undo: RefCell<Vec<(String, String, Option<Entry>)>>,
+    // 事务中删除的 table 里的 list、set 和 sorted set
+    dropped: RefCell<Vec<(String, Dropped)>>,
+}
+
+impl MemTxn<'_> {
+    fn record(&self, table: &str, key: &str) {
+        let entry = self.tables.snapshot(table, key);
+        self.undo
+            .borrow_mut()
+            .push((table.to_owned(), key.to_owned(), entry));
+    }
+
+    fn rollback(self) {
+        for (table, key, entry) in self.undo.into_inner().into_iter().rev() {
+            self.tables.restore(&table, key, entry);
+        }
+        for (table, dropped) in self.dropped.into_inner() {
+            self.tables.restore_collections(&table, dropped);
+        }
+    }
+}
+
+impl Storage for MemTxn<'_> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.tables.get(table, key)
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        self.record(table, &key);
+        self.tables.set(table, key, value)
+    }
+
+    fn set_with_ttl(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> Result<Option<Value>, KvError> {
+        self.record(table, &key);
+        self.tables.set_with_ttl(table, key, value, ttl)
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.tables.contains(table, key)
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.record(table, key);
+        self.tables.del(table, key)
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.tables.get_all(table)
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        self.tables.get_iter(table)
+    }
+
+    fn scan(&self, table: &str, range: &KeyRange, limit: usize) -> Result<Vec<Kvpair>, KvError> {
+        self.tables.scan(table, range, limit)
+    }
+
+    fn find(&self, table: &str, value: &Value) -> Result<Vec<Kvpair>, KvError> {
+        self.tables.find(table, value)
+    }
+
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        self.tables.list_tables()
+    }
+
+    fn drop_table(&self, table: &str) -> Result<bool, KvError> {
+        let keys: Vec<_> = match self.tables.data.get(table) {
+            Some(t) => t.iter().map(|entry| entry.key().clone()).collect(),
+            None => Vec::new(),
+        };
+        for key in keys {
+            self.record(table, &key);
+        }
+        let dropped = self.tables.take_collections(table);
+        let collections = dropped.existed();
+        self.dropped.borrow_mut().push((table.to_owned(), dropped));
+
+        Ok(self.tables.drop_table(table)? || collections)
+    }
+
+    fn table_len(&self, table: &str) -> Result<usize, KvError> {
+        self.tables.table_len(table)
+    }
+
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        self.record(table, key);
+        self.tables.expire(table, key, ttl)
+    }
+
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Duration>, KvError> {
+        self.tables.ttl(table, key)
+    }
+
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.record(table, key);
+        self.tables.persist(table, key)
+    }
+
+    // 事务持有写锁，写入通过 set 记录在 undo log 里
+    fn compare_and_swap(
+        &self,
+        table: &str,
+        key: &str,
+        expected: Option<&Value>,
+        value: Value,
+    ) -> Result<Result<(), Option<Value>>, KvError> {
+        serial_compare_and_swap(self, table, key, expected, value)
+    }
+
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        serial_incr(self, table, key, delta)
+    }
+
+    fn incr_float(&self, table: &str, key: &str, delta: f64) -> Result<f64, KvError> {
+        serial_incr_float(self, table, key, delta)
+    }
+
+    fn update_path(
+        &self,
+        table: &str,
+        key: &str,
+        path: &[PathSegment],
+        op: PathOp,
+    ) -> Result<Option<Value>, KvError> {
+        serial_update_path(self, table, key, path, op)
+    }
+
+    fn transaction(
+        &self,
+        _tables: &[&str],
+        _f: &mut dyn FnMut(&dyn Storage) -> Result<(), KvError>,
+    ) -> Result<(), KvError> {
+        Err(KvError::InvalidCommand("Nested transaction".into()))
+    }
+}
+
+// 后台线程定期清理过期的 key，MemTable 被释放后线程自动退出
+fn spawn_reaper(tables: Weak<Tables>) {
+    thread::spawn(move || loop {
+        thread::sleep(REAP_INTERVAL);
+        match tables.upgrade() {
+            Some(tables) => tables.data.iter().for_each(|table| {
+                table.retain(|key, entry| {
+                    if entry.is_expired() {
+                        tables.account(Some(entry), None);
+                        tables.reindex(table.key(), key, Some(&entry.value), None);
+                        return false;
+                    }
+                    true
+                })
+            }),
+            None => break,
+        }
+    });
+}
+
+fn get_or_create<'a, V: Default>(map: &'a DashMap<String, V>, name: &str) -> Ref<'a, String, V> {
+    match map.get(name) {
+        Some(v) => v,
+        None => {
+            let entry = map.entry(name.into()).or_default();
+            entry.downgrade()
+        }
+    }
+}
+
+// key 和 value 大致占用的内存
+fn entry_size(key: &str, value: &Value) -> usize {
+    ENTRY_OVERHEAD + key.len() + value_size(value)
+}
+
+fn value_size(value: &Value) -> usize {
+    let data = match &value.value {
+        Some(value::Value::String(s)) => s.len(),
+        Some(value::Value::Binary(b)) => b.len(),
+        Some(value::Value::Array(a)) => a.values.iter().map(value_size).sum(),
+        Some(value::Value::Map(m)) => m
+            .entries
+            .iter()
+            .map(|(k, v)| member_size(k) + value_size(v))
+            .sum(),
+        _ => 0,
+    };
+
+    mem::size_of::<Value>() + data
+}
+
+fn member_size(member: &str) -> usize {
+    mem::size_of::<String>() + member.len()
+}
+
+// sorted set 中的 member 在 score 的映射和索引中各存了一份
+fn scored_member_size(member: &str) -> usize {
+    2 * (member_size(member) + mem::size_of::<f64>())
+}
+
+// 一个 list、set 或 sorted set 除了元素之外大致占用的内存
+fn collection_overhead(key: &str) -> usize {
+    ENTRY_OVERHEAD + key.len()
+}
+
+fn collections_size<C: Collection>(collections: &DashMap<String, C>) -> usize {
+    collections
+        .iter()
+        .map(|c| collection_overhead(c.key()) + c.value().size())
+        .sum()
+}
+
+// 有 list、set 或 sorted set 的 table
+fn non_empty_tables<C>(map: &DashMap<String, DashMap<String, C>>) -> Vec<String> {
+    map.iter()
+        .filter(|t| !t.value().is_empty())
+        .map(|t| t.key().clone())
+        .collect()
+}
+
+fn sorted_keys<C>(map: &DashMap<String, DashMap<String, C>>, table: &str) -> Vec<String> {
+    let mut keys: Vec<_> = match map.get(table) {
+        Some(t) => t.iter().map(|c| c.key().clone()).collect(),
+        None => Vec::new(),
+    };
+    keys.sort();
+    keys
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 大约能放下 10 个 key 的内存上限
+    fn limited(eviction: EvictionPolicy) -> MemTable {
+        MemTable::with_config(MemTableConfig {
+            max_memory: entry_size("key0", &"value".into()) * 10,
+            eviction,
+        })
+    }
+
+    fn fill(store: &MemTable, range: std::ops::Range<usize>) {
+        for i in range {
+            store
+                .set("t1", format!("key{}", i), "value".into())
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn used_memory_should_be_accounted() {
+        let store = MemTable::new();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        let size = store.used_memory();
+        assert_eq!(size, entry_size("k1", &"v1".into()));
+
+        store.set("t1", "k1".into(), "value".into()).unwrap();
+        assert_eq!(store.used_memory(), size + 3);
+        store.incr("t1", "k2", 1).unwrap();
+        store
+            .set_with_ttl("t2", "k1".into(), "v1".into(), Duration::from_secs(60))
+            .unwrap();
+        store.del("t1", "k1").unwrap();
+        store.drop_table("t2").unwrap();
+        assert_eq!(store.used_memory(), entry_size("k2", &1.into()));
+
+        // 回滚也会恢复内存占用
+        let used = store.used_memory();
+        let _ = store.transaction(&["t1"], &mut |txn| {
+            txn.set("t1", "k3".into(), "v3".into())?;
+            txn.del("t1", "k2")?;
+            Err(KvError::Internal("abort".into()))
+        });
+        assert_eq!(store.used_memory(), used);
+        assert_eq!(store.evicted_keys(), 0);
+    }
+
+    #[test]
+    fn list_memory_should_be_accounted() {
+        let store = MemTable::new();
+        let values: Vec<Value> = vec!["a".into(), "bc".into()];
+        store
+            .list_push("t1", "l1", ListEnd::Right, values.clone())
+            .unwrap();
+        let size = collection_overhead("l1") + values.iter().map(value_size).sum::<usize>();
+        assert_eq!(store.used_memory(), size);
+
+        store.list_pop("t1", "l1", ListEnd::Left, 1).unwrap();
+        assert_eq!(store.used_memory(), size - value_size(&values[0]));
+        store.list_trim("t1", "l1", 1, 0).unwrap();
+        assert_eq!(store.used_memory(), 0);
+
+        // 事务中删除的 list 在回滚时恢复
+        store.list_push("t1", "l1", ListEnd::Left, values).unwrap();
+        let _ = store.transaction(&["t1"], &mut |txn| {
+            txn.drop_table("t1")?;
+            Err(KvError::Internal("abort".into()))
+        });
+        assert_eq!(store.list_len("t1", "l1").unwrap(), 2);
+        assert_eq!(store.used_memory(), size);
+        store.drop_table("t1").unwrap();
+        assert_eq!(store.used_memory(), 0);
+    }
+
+    #[test]
+    fn zset_memory_should_be_accounted() {
+        let store = MemTable::new();
+        let members = vec![("a".to_string(), 1.0), ("bc".to_string(), 2.0)];
+        store.zset_add("t1", "z1", members).unwrap();
+        let size = collection_overhead("z1") + scored_member_size("a") + scored_member_size("bc");
+        assert_eq!(store.used_memory(), size);
+
+        // 只修改 score 不改变内存占用
+        store.zset_incr("t1", "z1", "a", 1.0).unwrap();
+        assert_eq!(store.used_memory(), size);
+        store.zset_incr("t1", "z1", "d", 1.0).unwrap();
+        assert_eq!(store.used_memory(), size + scored_member_size("d"));
+
+        let members = vec!["a".into(), "bc".into(), "d".into()];
+        store.zset_remove("t1", "z1", members).unwrap();
+        assert_eq!(store.used_memory(), 0);
+
+        // 失败的 zset_incr 不会留下空的 sorted set
+        assert!(store.zset_incr("t1", "z1", "a", f64::NAN).is_err());
+        assert!(store.zset_keys("t1").unwrap().is_empty());
+        assert_eq!(store.used_memory(), 0);
+    }
+
+    #[test]
+    fn lru_should_evict_least_recently_used() {
+        let store = limited(EvictionPolicy::Lru);
+        fill(&store, 0..10);
+        store.get("t1", "key0").unwrap();
+        fill(&store, 10..11);
+
+        assert!(store.evicted_keys() > 0);
+        assert!(store.used_memory() <= store.tables.config.max_memory);
+        assert!(store.contains("t1", "key0").unwrap());
+        assert!(!store.contains("t1", "key1").unwrap());
+        assert!(store.contains("t1", "key10").unwrap());
+    }
+
+    #[test]
+    fn lfu_should_evict_least_frequently_used() {
+        let store = limited(EvictionPolicy::Lfu);
+        fill(&store, 0..10);
+        for i in 0..8 {
+            for _ in 0..3 {
+                store.get("t1", &format!("key{}", i)).unwrap();
+            }
+        }
+        // 会多淘汰一些，给之后的写入留出空间
+        fill(&store, 10..11);
+
+        assert_eq!(store.evicted_keys(), 2);
+        assert!(!store.contains("t1", "key8").unwrap());
+        assert!(!store.contains("t1", "key9").unwrap());
+        assert_eq!(store.table_len("t1").unwrap(), 9);
+    }
+
+    #[test]
+    fn random_should_evict_some_keys() {
+        let store = limited(EvictionPolicy::Random);
+        fill(&store, 0..100);
+
+        assert_eq!(
+            store.table_len("t1").unwrap() as u64 + store.evicted_keys(),
+            100
+        );
+        assert!(store.used_memory() <= store.tables.config.max_memory);
+        assert!(store.contains("t1", "key99").unwrap());
+    }
+
+    #[test]
+    fn volatile_ttl_should_only_evict_keys_with_ttl() {
+        let store = limited(EvictionPolicy::VolatileTtl);
+        fill(&store, 0..7);
+        for (key, secs) in &[("ttl1", 60), ("ttl2", 30), ("ttl3", 90)] {
+            let ttl = Duration::from_secs(*secs);
+            store
+                .set_with_ttl("t1", key.to_string(), "value".into(), ttl)
+                .unwrap();
+        }
+        fill(&store, 7..8);
+
+        // 最快过期的 key 先被淘汰
+        assert_eq!(store.evicted_keys(), 2);
+        assert!(!store.contains("t1", "ttl1").unwrap());
+        assert!(!store.contains("t1", "ttl2").unwrap());
+        assert!(store.contains("t1", "ttl3").unwrap());
+
+        fill(&store, 8..10);
+        assert_eq!(store.evicted_keys(), 3);
+
+        // 没有可以淘汰的 key 时写入失败
+        let err = store.set("t1", "key10".into(), "value".into()).unwrap_err();
+        assert!(matches!(err, KvError::OutOfMemory(_)));
+        assert!(!store.contains("t1", "key10").unwrap());
+        assert_eq!(store.table_len("t1").unwrap(), 10);
+    }
 }
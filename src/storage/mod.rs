@@ -1,16 +1,46 @@
-use crate::{KvError, Kvpair, Value};
+use std::{collections::BTreeMap, convert::TryFrom, ops::Bound, time::Duration};
 
+use crate::{KvError, Kvpair, PathSegment, Value, Version};
+
+pub mod aof;
+pub mod async_storage;
+pub mod bitcask;
+pub mod cached;
+pub mod dump;
+pub mod encrypted;
+pub mod lsm;
 pub mod memory;
+pub mod namespace;
 pub mod sleddb;
+pub mod versioned;
 
+pub use aof::{AofConfig, AofStorage, FsyncPolicy};
+pub use async_storage::AsyncStorage;
+pub use bitcask::{BitcaskConfig, BitcaskStorage};
+pub use cached::{CacheConfig, CacheMode, CachedStorage};
+pub use dump::{dump, restore};
+pub use encrypted::{EncryptedStorage, Keyring};
+pub use lsm::{LsmConfig, LsmStorage};
+pub use namespace::{
+    create_database, database_exists, flush_database, list_databases, Namespace, DEFAULT_DB,
+};
 pub use sleddb::SledDb;
+pub use versioned::VersionedStorage;
 
 /// 对存储的抽象，我们不关心数据存在哪儿，但需要定义外界如何和存储打交道
 pub trait Storage {
     /// 从一个 HashTable 里获取一个 key 的 value
     fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError>;
-    /// 从一个 HashTable 里设置一个 key 的 value，返回旧的 value
+    /// 从一个 HashTable 里设置一个 key 的 value，返回旧的 value。原有的过期时间会被清除
     fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError>;
+    /// 设置一个 key 的 value，并在 ttl 之后过期，返回旧的 value
+    fn set_with_ttl(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> Result<Option<Value>, KvError>;
     /// 查看 HashTable 中是否有 key
     fn contains(&self, table: &str, key: &str) -> Result<bool, KvError>;
     /// 从 HashTable 中删除一个 key
@@ -19,6 +49,515 @@ pub trait Storage {
     fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError>;
     /// 遍历 HashTable，返回 kv pair 的 Iterator
     fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError>;
+    /// 按 key 的顺序返回 table 中在 range 内的前 limit 个 kv pair。
+    /// 默认实现基于 get_iter 遍历整个 table，只保留最小的 limit 个 key
+    fn scan(&self, table: &str, range: &KeyRange, limit: usize) -> Result<Vec<Kvpair>, KvError> {
+        let mut page = BTreeMap::new();
+        for pair in self
+            .get_iter(table)?
+            .filter(|pair| range.contains(&pair.key))
+        {
+            page.insert(pair.key, pair.value);
+            if page.len() > limit {
+                page.pop_last();
+            }
+        }
+
+        Ok(page
+            .into_iter()
+            .map(|(key, value)| Kvpair { key, value })
+            .collect())
+    }
+    /// 返回所有非空 table 的名字，按名字排序
+    fn list_tables(&self) -> Result<Vec<String>, KvError>;
+    /// 删除整个 table，返回 table 之前是否有数据
+    fn drop_table(&self, table: &str) -> Result<bool, KvError>;
+    /// 返回 table 中 key 的数量
+    fn table_len(&self, table: &str) -> Result<usize, KvError>;
+    /// 为一个已存在的 key 设置过期时间，key 不存在时返回 false
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError>;
+    /// 获取 key 剩余的存活时间，没有设置过期时间返回 None，key 不存在返回 NotFound
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Duration>, KvError>;
+    /// 清除 key 的过期时间，返回之前是否设置过过期时间
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError>;
+    /// 比较并交换：key 当前的值（不存在为 None）等于 expected 时写入 value 并清除过期时间，
+    /// 否则返回当前的值。比较和写入必须是原子的
+    fn compare_and_swap(
+        &self,
+        table: &str,
+        key: &str,
+        expected: Option<&Value>,
+        value: Value,
+    ) -> Result<Result<(), Option<Value>>, KvError>;
+    /// 给整数 value 加上 delta 并返回新的值，key 不存在时从 0 开始，过期时间保持不变。
+    /// 读和写必须是原子的
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError>;
+    /// 给浮点数 value 加上 delta 并返回新的值，其它和 incr 一样
+    fn incr_float(&self, table: &str, key: &str, delta: f64) -> Result<f64, KvError>;
+    /// 返回 key 的 value 中 path 指向的元素，key 或者元素不存在时返回 None
+    fn get_path(
+        &self,
+        table: &str,
+        key: &str,
+        path: &[PathSegment],
+    ) -> Result<Option<Value>, KvError> {
+        Ok(self
+            .get(table, key)?
+            .and_then(|value| value.get_path(path).cloned()))
+    }
+    /// 在 key 的 value 中 path 指向的位置执行 op，返回原来的元素（Append 返回追加之后数组的长度），
+    /// 过期时间保持不变。
+    /// key 不存在时只能用空的 path 写入整个 value。读和写必须是原子的
+    fn update_path(
+        &self,
+        table: &str,
+        key: &str,
+        path: &[PathSegment],
+        op: PathOp,
+    ) -> Result<Option<Value>, KvError>;
+    /// 为 table 建立 value 的二级索引，返回索引之前是否不存在。默认不支持索引
+    fn create_index(&self, table: &str) -> Result<bool, KvError> {
+        Err(KvError::InvalidCommand(format!(
+            "Cannot create index on table {}: not supported by this storage",
+            table
+        )))
+    }
+    /// 返回 table 中 value 等于 value 的所有 kv pair，按 key 排序。
+    /// 默认实现遍历整个 table，有索引的存储引擎通过索引查找
+    fn find(&self, table: &str, value: &Value) -> Result<Vec<Kvpair>, KvError> {
+        let mut pairs: Vec<_> = self
+            .get_iter(table)?
+            .filter(|pair| pair.value.as_ref() == Some(value))
+            .collect();
+        pairs.sort_by(|a, b| a.key.cmp(&b.key));
+
+        Ok(pairs)
+    }
+    /// 返回 key 保留下来的所有版本，按版本号从旧到新。默认不支持多版本
+    fn history(&self, table: &str, key: &str) -> Result<Vec<Version>, KvError> {
+        Err(KvError::InvalidCommand(format!(
+            "Cannot read history of table: {}, key: {}: not supported by this storage",
+            table, key
+        )))
+    }
+    /// 返回 key 的某个版本的 value，版本不存在或者是删除产生的版本时返回 None
+    fn get_version(&self, table: &str, key: &str, version: u64) -> Result<Option<Value>, KvError> {
+        Ok(self
+            .history(table, key)?
+            .into_iter()
+            .find(|v| v.version == version)
+            .and_then(|v| v.value))
+    }
+    /// 返回 key 在 at 时刻（unix 时间戳，毫秒）的 value，那时 key 不存在返回 None
+    fn get_at(&self, table: &str, key: &str, at: u64) -> Result<Option<Value>, KvError> {
+        Ok(self
+            .history(table, key)?
+            .into_iter()
+            .rev()
+            .find(|v| v.timestamp <= at)
+            .and_then(|v| v.value))
+    }
+    /// 清理 retention 之前的旧版本，每个 key 保留在那个时刻可见的版本，返回清理掉的版本数量
+    fn gc_versions(&self, retention: Duration) -> Result<usize, KvError> {
+        self.gc_versions_in(retention, &|_| true)
+    }
+    /// 和 gc_versions 一样，只清理 tables 返回 true 的 table。默认不支持多版本
+    fn gc_versions_in(
+        &self,
+        _retention: Duration,
+        _tables: &dyn Fn(&str) -> bool,
+    ) -> Result<usize, KvError> {
+        Err(KvError::InvalidCommand(
+            "Cannot collect versions: not supported by this storage".into(),
+        ))
+    }
+    /// 开始记录因为内存不足被淘汰的 key，之后用 take_evicted 取走，AOF 用它把淘汰写入日志。
+    /// 默认不会淘汰 key
+    fn track_evictions(&self) {}
+    /// 取走记录下来的被淘汰的 key（table 和 key），按淘汰的顺序
+    fn take_evicted(&self) -> Vec<(String, String)> {
+        Vec::new()
+    }
+    /// 把 values 依次放入 list 的 end 一端，list 不存在时创建，返回放入之后 list 的长度。
+    /// list 和普通的 value 是同一个 table 中相互独立的两组 key。默认不支持 list
+    fn list_push(
+        &self,
+        table: &str,
+        key: &str,
+        _end: ListEnd,
+        _values: Vec<Value>,
+    ) -> Result<usize, KvError> {
+        Err(list_not_supported(table, key))
+    }
+    /// 从 list 的 end 一端取出最多 count 个元素，list 取空之后被删除
+    fn list_pop(
+        &self,
+        table: &str,
+        key: &str,
+        _end: ListEnd,
+        _count: usize,
+    ) -> Result<Vec<Value>, KvError> {
+        Err(list_not_supported(table, key))
+    }
+    /// 返回 list 中下标从 start 到 stop（包含 stop）的元素，负数表示从末尾倒数，-1 是最后一个
+    fn list_range(
+        &self,
+        table: &str,
+        key: &str,
+        _start: i64,
+        _stop: i64,
+    ) -> Result<Vec<Value>, KvError> {
+        Err(list_not_supported(table, key))
+    }
+    /// 只保留 list 中下标从 start 到 stop 的元素，返回删除的元素数量
+    fn list_trim(&self, table: &str, key: &str, _start: i64, _stop: i64) -> Result<usize, KvError> {
+        Err(list_not_supported(table, key))
+    }
+    /// list 的长度，list 不存在时为 0
+    fn list_len(&self, table: &str, key: &str) -> Result<usize, KvError> {
+        Ok(self.list_range(table, key, 0, -1)?.len())
+    }
+    /// list 中下标为 index 的元素，负数表示从末尾倒数
+    fn list_index(&self, table: &str, key: &str, index: i64) -> Result<Option<Value>, KvError> {
+        Ok(self.list_range(table, key, index, index)?.pop())
+    }
+    /// table 中所有 list 的 key，按 key 排序。不支持 list 的存储里没有 list
+    fn list_keys(&self, _table: &str) -> Result<Vec<String>, KvError> {
+        Ok(Vec::new())
+    }
+    /// 把 members 加入 set，set 不存在时创建，返回新加入的 member 数量。
+    /// set 和 list、普通的 value 一样是 table 中独立的一组 key。默认不支持 set
+    fn set_add(&self, table: &str, key: &str, _members: Vec<String>) -> Result<usize, KvError> {
+        Err(set_not_supported(table, key))
+    }
+    /// 从 set 中删除 members，返回实际删除的数量，set 变空之后被删除
+    fn set_remove(&self, table: &str, key: &str, _members: Vec<String>) -> Result<usize, KvError> {
+        Err(set_not_supported(table, key))
+    }
+    /// set 中所有的 member，按字典序排序，set 不存在时为空
+    fn set_members(&self, table: &str, key: &str) -> Result<Vec<String>, KvError> {
+        Err(set_not_supported(table, key))
+    }
+    /// member 是否在 set 中
+    fn set_contains(&self, table: &str, key: &str, member: &str) -> Result<bool, KvError> {
+        Ok(self.set_members(table, key)?.iter().any(|m| m == member))
+    }
+    /// set 中 member 的数量
+    fn set_len(&self, table: &str, key: &str) -> Result<usize, KvError> {
+        Ok(self.set_members(table, key)?.len())
+    }
+    /// table 中所有 set 的 key，按 key 排序。不支持 set 的存储里没有 set
+    fn set_keys(&self, _table: &str) -> Result<Vec<String>, KvError> {
+        Ok(Vec::new())
+    }
+    /// 把 members 加入 sorted set，已经存在的 member 更新 score，返回新加入的 member 数量。
+    /// sorted set 按 score 从小到大排序，score 相同时按 member 的字典序，
+    /// 它也是 table 中独立的一组 key。默认不支持 sorted set
+    fn zset_add(
+        &self,
+        table: &str,
+        key: &str,
+        _members: Vec<(String, f64)>,
+    ) -> Result<usize, KvError> {
+        Err(zset_not_supported(table, key))
+    }
+    /// 给 member 的 score 加上 delta，member 不存在时从 0 开始，返回新的 score
+    fn zset_incr(
+        &self,
+        table: &str,
+        key: &str,
+        _member: &str,
+        _delta: f64,
+    ) -> Result<f64, KvError> {
+        Err(zset_not_supported(table, key))
+    }
+    /// 从 sorted set 中删除 members，返回实际删除的数量，sorted set 变空之后被删除
+    fn zset_remove(&self, table: &str, key: &str, _members: Vec<String>) -> Result<usize, KvError> {
+        Err(zset_not_supported(table, key))
+    }
+    /// 按排名返回从 start 到 stop（包含 stop）的 member 和 score，负数表示从末尾倒数
+    fn zset_range(
+        &self,
+        table: &str,
+        key: &str,
+        _start: i64,
+        _stop: i64,
+    ) -> Result<Vec<(String, f64)>, KvError> {
+        Err(zset_not_supported(table, key))
+    }
+    /// 按排名返回 score 在 min 和 max 之间（包含两端）的 member 和 score，
+    /// 跳过前 offset 个，最多返回 limit 个，limit 为 0 表示不限制
+    fn zset_range_by_score(
+        &self,
+        table: &str,
+        key: &str,
+        _min: f64,
+        _max: f64,
+        _offset: usize,
+        _limit: usize,
+    ) -> Result<Vec<(String, f64)>, KvError> {
+        Err(zset_not_supported(table, key))
+    }
+    /// member 的 score，member 不存在时返回 None
+    fn zset_score(&self, table: &str, key: &str, member: &str) -> Result<Option<f64>, KvError> {
+        Ok(self
+            .zset_range(table, key, 0, -1)?
+            .into_iter()
+            .find(|(m, _)| m == member)
+            .map(|(_, score)| score))
+    }
+    /// member 的排名，score 最小的是 0，member 不存在时返回 None
+    fn zset_rank(&self, table: &str, key: &str, member: &str) -> Result<Option<usize>, KvError> {
+        Ok(self
+            .zset_range(table, key, 0, -1)?
+            .iter()
+            .position(|(m, _)| m == member))
+    }
+    /// sorted set 中 member 的数量
+    fn zset_len(&self, table: &str, key: &str) -> Result<usize, KvError> {
+        Ok(self.zset_range(table, key, 0, -1)?.len())
+    }
+    /// table 中所有 sorted set 的 key，按 key 排序。不支持 sorted set 的存储里没有 sorted set
+    fn zset_keys(&self, _table: &str) -> Result<Vec<String>, KvError> {
+        Ok(Vec::new())
+    }
+    /// 在一个事务中执行 f，f 看到的 Storage 和其它操作相互隔离。f 返回错误时，
+    /// 事务中的所有修改都会被回滚。f 可能会被执行多次（比如 sled 遇到冲突时重试）。
+    /// tables 是 f 会访问的所有 table，有的存储引擎需要在事务开始前准备好它们
+    fn transaction(
+        &self,
+        tables: &[&str],
+        f: &mut dyn FnMut(&dyn Storage) -> Result<(), KvError>,
+    ) -> Result<(), KvError>;
+}
+
+/// 遍历 table 时 key 需要满足的条件，空字符串表示不限制
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct KeyRange {
+    /// key 需要以 prefix 开头
+    pub prefix: String,
+    /// start <= key
+    pub start: String,
+    /// key < end
+    pub end: String,
+    /// key > after，用于从上一页的最后一个 key 继续遍历
+    pub after: String,
+}
+
+impl KeyRange {
+    pub fn contains(&self, key: &str) -> bool {
+        key.starts_with(&self.prefix)
+            && key >= self.start.as_str()
+            && (self.after.is_empty() || key > self.after.as_str())
+            && !self.is_past(key)
+    }
+
+    /// 有序遍历时，第一个可能满足条件的 key
+    pub fn lower_bound(&self) -> Bound<&str> {
+        let start = self.start.as_str().max(self.prefix.as_str());
+        if !self.after.is_empty() && self.after.as_str() >= start {
+            Bound::Excluded(&self.after)
+        } else {
+            Bound::Included(start)
+        }
+    }
+
+    /// 有序遍历时，key 之后的所有 key 都不会满足条件
+    pub fn is_past(&self, key: &str) -> bool {
+        (!self.end.is_empty() && key >= self.end.as_str())
+            || (key > self.prefix.as_str() && !key.starts_with(&self.prefix))
+    }
+}
+
+/// list 的两端
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ListEnd {
+    Left,
+    Right,
+}
+
+/// 对嵌套的 value 中某个元素的修改
+#[derive(Clone, Debug, PartialEq)]
+pub enum PathOp {
+    /// 替换元素，元素不存在时加入
+    Set(Value),
+    /// 删除元素
+    Delete,
+    /// 在数组的末尾追加元素
+    Append(Vec<Value>),
+}
+
+fn list_not_supported(table: &str, key: &str) -> KvError {
+    KvError::InvalidCommand(format!(
+        "Cannot access list in table: {}, key: {}: not supported by this storage",
+        table, key
+    ))
+}
+
+fn set_not_supported(table: &str, key: &str) -> KvError {
+    KvError::InvalidCommand(format!(
+        "Cannot access set in table: {}, key: {}: not supported by this storage",
+        table, key
+    ))
+}
+
+fn zset_not_supported(table: &str, key: &str) -> KvError {
+    KvError::InvalidCommand(format!(
+        "Cannot access sorted set in table: {}, key: {}: not supported by this storage",
+        table, key
+    ))
+}
+
+/// sorted set 的 score 必须是有限的数字，-0 和 0 当作同一个 score
+pub(crate) fn check_score(score: f64) -> Result<f64, KvError> {
+    if !score.is_finite() {
+        return Err(KvError::InvalidCommand(format!(
+            "score {} is not a finite number",
+            score
+        )));
+    }
+
+    Ok(score + 0.0)
+}
+
+/// 把 start 和 stop（包含，负数从末尾倒数）转换成长度为 len 的 list 中的下标范围 [from, to)，
+/// 范围为空时返回 None
+pub(crate) fn list_bounds(len: usize, start: i64, stop: i64) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let normalize = |i: i64| if i < 0 { len + i } else { i };
+    let from = normalize(start).max(0);
+    let to = normalize(stop).min(len - 1);
+    if from > to {
+        return None;
+    }
+
+    Some((from as usize, to as usize + 1))
+}
+
+/// 先读再写的 compare_and_swap，读和写之间没有加锁。
+/// 只能在已经和其它修改隔离开的视图（事务视图、持有写锁执行的视图）中使用
+pub(crate) fn serial_compare_and_swap<S: Storage + ?Sized>(
+    store: &S,
+    table: &str,
+    key: &str,
+    expected: Option<&Value>,
+    value: Value,
+) -> Result<Result<(), Option<Value>>, KvError> {
+    let current = store.get(table, key)?;
+    if current.as_ref() != expected {
+        return Ok(Err(current));
+    }
+    store.set(table, key.into(), value)?;
+
+    Ok(Ok(()))
+}
+
+/// 先读再写的 incr，和 serial_compare_and_swap 一样只能在隔离的视图中使用
+pub(crate) fn serial_incr<S: Storage + ?Sized>(
+    store: &S,
+    table: &str,
+    key: &str,
+    delta: i64,
+) -> Result<i64, KvError> {
+    let result = add_integer(store.get(table, key)?, delta)?;
+    update_keep_ttl(store, table, key, result.into())?;
+
+    Ok(result)
+}
+
+/// 先读再写的 incr_float，和 serial_compare_and_swap 一样只能在隔离的视图中使用
+pub(crate) fn serial_incr_float<S: Storage + ?Sized>(
+    store: &S,
+    table: &str,
+    key: &str,
+    delta: f64,
+) -> Result<f64, KvError> {
+    let result = add_float(store.get(table, key)?, delta)?;
+    update_keep_ttl(store, table, key, result.into())?;
+
+    Ok(result)
+}
+
+/// 先读再写的 update_path，和 serial_compare_and_swap 一样只能在隔离的视图中使用
+pub(crate) fn serial_update_path<S: Storage + ?Sized>(
+    store: &S,
+    table: &str,
+    key: &str,
+    path: &[PathSegment],
+    op: PathOp,
+) -> Result<Option<Value>, KvError> {
+    let (value, old) = apply_path(table, key, store.get(table, key)?, path, op)?;
+    update_keep_ttl(store, table, key, value)?;
+
+    Ok(old)
+}
+
+// 写入新的 value，保留 key 原有的过期时间
+fn update_keep_ttl<S: Storage + ?Sized>(
+    store: &S,
+    table: &str,
+    key: &str,
+    value: Value,
+) -> Result<(), KvError> {
+    let ttl = match store.ttl(table, key) {
+        Ok(ttl) => ttl,
+        Err(KvError::NotFound(_, _)) => None,
+        Err(e) => return Err(e),
+    };
+    match ttl {
+        Some(ttl) => store.set_with_ttl(table, key.into(), value, ttl)?,
+        None => store.set(table, key.into(), value)?,
+    };
+
+    Ok(())
+}
+
+/// 在 key 当前的 value 上执行 path 的修改，返回修改之后的 value 和原来的元素
+pub(crate) fn apply_path(
+    table: &str,
+    key: &str,
+    current: Option<Value>,
+    path: &[PathSegment],
+    op: PathOp,
+) -> Result<(Value, Option<Value>), KvError> {
+    match (current, op) {
+        (Some(mut value), op) => {
+            let old = value.update_path(path, op)?;
+            Ok((value, old))
+        }
+        (None, PathOp::Set(value)) if path.is_empty() => Ok((value, None)),
+        (None, _) => Err(KvError::NotFound(table.into(), key.into())),
+    }
+}
+
+/// 在整数 value 上加上 delta，value 不存在时从 0 开始
+pub(crate) fn add_integer(current: Option<Value>, delta: i64) -> Result<i64, KvError> {
+    let current = match current {
+        Some(v) => i64::try_from(v)?,
+        None => 0,
+    };
+
+    current
+        .checked_add(delta)
+        .ok_or_else(|| KvError::InvalidCommand(format!("{} + {} overflows", current, delta)))
+}
+
+/// 在浮点数 value 上加上 delta，value 不存在时从 0 开始
+pub(crate) fn add_float(current: Option<Value>, delta: f64) -> Result<f64, KvError> {
+    let current = match current {
+        Some(v) => f64::try_from(v)?,
+        None => 0.0,
+    };
+
+    let result = current + delta;
+    if !result.is_finite() {
+        return Err(KvError::InvalidCommand(format!(
+            "{} + {} is not a finite number",
+            current, delta
+        )));
+    }
+
+    Ok(result)
 }
 
 pub struct StorageIter<T> {
@@ -46,6 +585,11 @@ where
 #[cfg(test)]
 mod tests {
     use super::{memory::MemTable, *};
+    use std::{
+        convert::TryInto,
+        thread,
+        time::{SystemTime, UNIX_EPOCH},
+    };
     use tempfile::tempdir;
 
     #[test]
@@ -69,14 +613,12 @@ mod tests {
         let v = store.get("t1", "hello");
         assert_eq!(v.unwrap(), Some("world1".into()));
 
-        assert_eq!(None, store.get("t1", "hello1".into()).unwrap());
+        assert_eq!(None, store.get("t1", "hello1").unwrap());
         assert!(store.get("t2", "hello1").unwrap().is_none());
 
         assert!(store.contains("t1", "hello").unwrap());
-        assert!(store.contains("t1", "hello1").unwrap());
-        assert!(store.contains("t2", "hello").unwrap());
-
-        assert!(store.contains("t2", "hello").unwrap());
+        assert!(!store.contains("t1", "hello1").unwrap());
+        assert!(!store.contains("t2", "hello").unwrap());
 
         assert_eq!(None, store.del("t1", "hello1").unwrap());
         assert_eq!(None, store.del("t2", "hello").unwrap());
@@ -118,21 +660,1541 @@ mod tests {
     }
 
     #[test]
-    fn sleddb_basic_interface_should_work() {
-        let dir = tempdir().unwrap();
-        let store = SledDb::new(dir);
-        test_basi_interface(store);
+    fn memtable_ttl_should_work() {
+        let store = MemTable::new();
+        test_ttl(store);
+    }
+
+    fn test_ttl(store: impl Storage) {
+        store
+            .set_with_ttl("t1", "k1".into(), "v1".into(), Duration::from_millis(10))
+            .unwrap();
+        store
+            .set_with_ttl("t1", "k2".into(), "v2".into(), Duration::from_secs(60))
+            .unwrap();
+        store.set("t1", "k3".into(), "v3".into()).unwrap();
+
+        let ttl = store.ttl("t1", "k2").unwrap().unwrap();
+        assert!(ttl > Duration::from_secs(59));
+        assert_eq!(store.ttl("t1", "k3").unwrap(), None);
+        assert!(store.ttl("t1", "k4").is_err());
+
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(store.get("t1", "k1").unwrap(), None);
+        assert!(!store.contains("t1", "k1").unwrap());
+        assert!(store.ttl("t1", "k1").is_err());
+        assert_eq!(store.get_iter("t1").unwrap().count(), 2);
+
+        // set 会清除过期时间
+        assert_eq!(
+            store.set("t1", "k2".into(), "v2".into()).unwrap(),
+            Some("v2".into())
+        );
+        assert_eq!(store.ttl("t1", "k2").unwrap(), None);
+
+        assert!(!store.expire("t1", "k1", Duration::from_secs(1)).unwrap());
+        assert!(store.expire("t1", "k3", Duration::from_millis(10)).unwrap());
+        assert!(store.persist("t1", "k3").unwrap());
+        assert!(!store.persist("t1", "k3").unwrap());
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(store.get("t1", "k3").unwrap(), Some("v3".into()));
     }
+
     #[test]
-    fn sleddb_get_all_should_work() {
-        let dir = tempdir().unwrap();
-        let store = SledDb::new(dir);
-        test_get_all(store);
+    fn memtable_huge_ttl_should_work() {
+        let store = MemTable::new();
+        test_huge_ttl(store);
+    }
+
+    // 过期时间超出范围时不能溢出，相当于不过期
+    fn test_huge_ttl(store: impl Storage) {
+        let ttl = Duration::from_millis(u64::MAX);
+        store
+            .set_with_ttl("t1", "k1".into(), "v1".into(), ttl)
+            .unwrap();
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        assert!(store.expire("t1", "k2", Duration::MAX).unwrap());
+
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.get("t1", "k2").unwrap(), Some("v2".into()));
+        for key in ["k1", "k2"] {
+            if let Some(ttl) = store.ttl("t1", key).unwrap() {
+                assert!(ttl > Duration::from_secs(3600 * 24 * 365));
+            }
+        }
+    }
+
+    #[test]
+    fn memtable_transaction_should_work() {
+        let store = MemTable::new();
+        test_transaction(store);
+    }
+
+    fn test_transaction(store: impl Storage) {
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+
+        store
+            .transaction(&["t1"], &mut |txn| {
+                assert_eq!(txn.set("t1", "k1".into(), "v2".into())?, Some("v1".into()));
+                txn.set("t1", "k2".into(), "v2".into())?;
+                assert_eq!(txn.get("t1", "k2")?, Some("v2".into()));
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v2".into()));
+        assert_eq!(store.get("t1", "k2").unwrap(), Some("v2".into()));
+
+        let result = store.transaction(&["t1"], &mut |txn| {
+            txn.set("t1", "k1".into(), "v3".into())?;
+            txn.del("t1", "k2")?;
+            txn.set_with_ttl("t1", "k3".into(), "v3".into(), Duration::from_secs(1))?;
+            txn.set("t1", "k1".into(), "v4".into())?;
+            Err(KvError::Internal("abort".into()))
+        });
+        assert!(result.is_err());
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v2".into()));
+        assert_eq!(store.get("t1", "k2").unwrap(), Some("v2".into()));
+        assert!(!store.contains("t1", "k3").unwrap());
     }
+
     #[test]
-    fn sleddb_iter_should_work() {
-        let dir = tempdir().unwrap();
-        let store = SledDb::new(dir);
-        test_get_iter(store);
+    fn memtable_compare_and_swap_should_work() {
+        let store = MemTable::new();
+        test_compare_and_swap(store);
+    }
+
+    fn test_compare_and_swap(store: impl Storage) {
+        let v1: Value = "v1".into();
+        let v2: Value = "v2".into();
+        assert_eq!(
+            store
+                .compare_and_swap("t1", "k1", Some(&v1), v2.clone())
+                .unwrap(),
+            Err(None)
+        );
+        assert_eq!(
+            store
+                .compare_and_swap("t1", "k1", None, v1.clone())
+                .unwrap(),
+            Ok(())
+        );
+        assert_eq!(
+            store
+                .compare_and_swap("t1", "k1", None, v2.clone())
+                .unwrap(),
+            Err(Some(v1.clone()))
+        );
+
+        store.expire("t1", "k1", Duration::from_secs(60)).unwrap();
+        assert_eq!(
+            store
+                .compare_and_swap("t1", "k1", Some(&v1), v2.clone())
+                .unwrap(),
+            Ok(())
+        );
+        assert_eq!(store.get("t1", "k1").unwrap(), Some(v2.clone()));
+        assert_eq!(store.ttl("t1", "k1").unwrap(), None);
+
+        // 过期的 key 当作不存在
+        store
+            .set_with_ttl("t1", "k2".into(), v1.clone(), Duration::from_millis(10))
+            .unwrap();
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(
+            store.compare_and_swap("t1", "k2", None, v2).unwrap(),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn memtable_incr_should_work() {
+        let store = MemTable::new();
+        test_incr(store);
+    }
+
+    fn test_incr(store: impl Storage) {
+        assert_eq!(store.incr("t1", "k1", 10).unwrap(), 10);
+        assert_eq!(store.incr("t1", "k1", -3).unwrap(), 7);
+        assert_eq!(store.get("t1", "k1").unwrap(), Some(7.into()));
+        assert!(store.incr("t1", "k1", i64::MAX).is_err());
+
+        assert_eq!(store.incr_float("t1", "k2", 1.5).unwrap(), 1.5);
+        assert_eq!(store.incr_float("t1", "k2", 1.0).unwrap(), 2.5);
+        assert!(matches!(
+            store.incr_float("t1", "k1", 1.0),
+            Err(KvError::ConvertError(_, "Float"))
+        ));
+        assert!(matches!(
+            store.incr("t1", "k2", 1),
+            Err(KvError::ConvertError(_, "Integer"))
+        ));
+
+        // incr 保留过期时间
+        store.expire("t1", "k1", Duration::from_secs(60)).unwrap();
+        assert_eq!(store.incr("t1", "k1", 1).unwrap(), 8);
+        assert!(store.ttl("t1", "k1").unwrap().is_some());
+
+        // 在事务中也可以使用
+        store
+            .transaction(&["t1"], &mut |txn| {
+                assert_eq!(txn.incr("t1", "k1", 1)?, 9);
+                Ok(())
+            })
+            .unwrap();
+        assert!(store.ttl("t1", "k1").unwrap().is_some());
+    }
+
+    #[test]
+    fn memtable_scan_should_work() {
+        let store = MemTable::new();
+        test_scan(store);
+    }
+
+    fn test_scan(store: impl Storage) {
+        for key in &["a1", "a2", "a3", "b1", "b2", "c1"] {
+            store.set("t1", key.to_string(), (*key).into()).unwrap();
+        }
+        store.set("t2", "a0".into(), "a0".into()).unwrap();
+        let keys = |pairs: Vec<Kvpair>| pairs.into_iter().map(|p| p.key).collect::<Vec<_>>();
+
+        let range = KeyRange::default();
+        assert_eq!(keys(store.scan("t1", &range, 2).unwrap()), ["a1", "a2"]);
+
+        let range = KeyRange {
+            after: "a2".into(),
+            ..Default::default()
+        };
+        assert_eq!(keys(store.scan("t1", &range, 2).unwrap()), ["a3", "b1"]);
+
+        let range = KeyRange {
+            prefix: "b".into(),
+            ..Default::default()
+        };
+        assert_eq!(keys(store.scan("t1", &range, 10).unwrap()), ["b1", "b2"]);
+
+        let range = KeyRange {
+            start: "a3".into(),
+            end: "b2".into(),
+            ..Default::default()
+        };
+        assert_eq!(keys(store.scan("t1", &range, 10).unwrap()), ["a3", "b1"]);
+
+        let range = KeyRange {
+            after: "c1".into(),
+            ..Default::default()
+        };
+        assert!(store.scan("t1", &range, 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn memtable_tables_should_work() {
+        let store = MemTable::new();
+        test_tables(store);
+    }
+
+    fn test_tables(store: impl Storage) {
+        // 读取不会创建 table
+        store.get("t0", "k1").unwrap();
+        store.contains("t0", "k1").unwrap();
+        assert!(store.list_tables().unwrap().is_empty());
+
+        store.set("t2", "k1".into(), "v1".into()).unwrap();
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        store
+            .set_with_ttl("t3", "k1".into(), "v1".into(), Duration::from_millis(10))
+            .unwrap();
+        thread::sleep(Duration::from_millis(20));
+
+        assert_eq!(store.list_tables().unwrap(), ["t1", "t2"]);
+        assert_eq!(store.table_len("t1").unwrap(), 2);
+        assert_eq!(store.table_len("t3").unwrap(), 0);
+        assert_eq!(store.table_len("t4").unwrap(), 0);
+
+        assert!(store.drop_table("t1").unwrap());
+        assert!(!store.drop_table("t1").unwrap());
+        assert_eq!(store.get("t1", "k1").unwrap(), None);
+        assert_eq!(store.list_tables().unwrap(), ["t2"]);
+    }
+
+    #[test]
+    fn memtable_colon_in_names_should_work() {
+        let store = MemTable::new();
+        test_colon_in_names(store);
+    }
+
+    fn test_colon_in_names(store: impl Storage) {
+        store.set("a", "b:c".into(), "v1".into()).unwrap();
+        store.set("a:b", "c".into(), "v2".into()).unwrap();
+        store.set("a", "user:42".into(), "v3".into()).unwrap();
+
+        assert_eq!(store.get("a", "b:c").unwrap(), Some("v1".into()));
+        assert_eq!(store.get("a:b", "c").unwrap(), Some("v2".into()));
+        assert_eq!(store.get("a", "c").unwrap(), None);
+        assert_eq!(store.table_len("a").unwrap(), 2);
+        assert_eq!(store.list_tables().unwrap(), ["a", "a:b"]);
+
+        let mut data = store.get_all("a").unwrap();
+        data.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(
+            data,
+            vec![
+                Kvpair::new("b:c", "v1".into()),
+                Kvpair::new("user:42", "v3".into())
+            ]
+        );
+
+        assert!(store.drop_table("a").unwrap());
+        assert_eq!(store.get("a:b", "c").unwrap(), Some("v2".into()));
+    }
+
+    #[test]
+    fn memtable_find_should_work() {
+        let store = MemTable::new();
+        test_find(store);
+    }
+
+    #[test]
+    fn memtable_find_with_index_should_work() {
+        let store = MemTable::new();
+        assert!(store.create_index("t1").unwrap());
+        assert!(!store.create_index("t1").unwrap());
+        test_find(store);
+    }
+
+    #[test]
+    fn memtable_index_should_cover_existing_data() {
+        let store = MemTable::new();
+        store.set("t1", "u1".into(), "a".into()).unwrap();
+        assert!(store.create_index("t1").unwrap());
+        store.set("t1", "u2".into(), "a".into()).unwrap();
+        let doc: Value = vec!["a".into()].into();
+        store.set("t1", "k1".into(), doc).unwrap();
+        store
+            .update_path("t1", "k1", &path(&[1.into()]), PathOp::Set("b".into()))
+            .unwrap();
+        let keys: Vec<_> = store
+            .find("t1", &"a".into())
+            .unwrap()
+            .into_iter()
+            .map(|p| p.key)
+            .collect();
+        assert_eq!(keys, ["u1", "u2"]);
+        let doc: Value = vec!["a".into(), "b".into()].into();
+        assert_eq!(store.find("t1", &doc).unwrap(), [Kvpair::new("k1", doc)]);
+
+        // 删除 table 之后索引的定义还在
+        assert!(store.drop_table("t1").unwrap());
+        assert!(store.find("t1", &"a".into()).unwrap().is_empty());
+        assert!(!store.create_index("t1").unwrap());
+    }
+
+    fn test_find(store: impl Storage) {
+        let find = |value: Value| -> Vec<String> {
+            let pairs = store.find("t1", &value).unwrap();
+            pairs.into_iter().map(|p| p.key).collect()
+        };
+        store.set("t1", "u1".into(), "a".into()).unwrap();
+        store.set("t1", "u2".into(), "b".into()).unwrap();
+        store.set("t1", "u3".into(), "a".into()).unwrap();
+        store.set("t2", "u4".into(), "a".into()).unwrap();
+        assert_eq!(find("a".into()), ["u1", "u3"]);
+        assert!(find("c".into()).is_empty());
+
+        store.set("t1", "u3".into(), "b".into()).unwrap();
+        store.del("t1", "u1").unwrap();
+        assert!(find("a".into()).is_empty());
+        assert_eq!(find("b".into()), ["u2", "u3"]);
+
+        store.set("t1", "n1".into(), 1.into()).unwrap();
+        store.incr("t1", "n1", 1).unwrap();
+        assert!(find(1.into()).is_empty());
+        assert_eq!(find(2.into()), ["n1"]);
+
+        store
+            .set_with_ttl("t1", "u5".into(), "c".into(), Duration::from_millis(10))
+            .unwrap();
+        assert_eq!(find("c".into()), ["u5"]);
+        thread::sleep(Duration::from_millis(20));
+        assert!(find("c".into()).is_empty());
+
+        store
+            .transaction(&["t1"], &mut |txn| {
+                txn.set("t1", "u6".into(), "d".into())?;
+                txn.del("t1", "u2")?;
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(find("d".into()), ["u6"]);
+        assert_eq!(find("b".into()), ["u3"]);
+    }
+
+    #[test]
+    fn memtable_versions_should_work() {
+        assert!(MemTable::new().history("t1", "k1").is_err());
+        let store = VersionedStorage::new(MemTable::new());
+        test_versions(store);
+    }
+
+    fn test_versions(store: impl Storage) {
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store
+            .set_with_ttl("t1", "k1".into(), "v2".into(), Duration::from_secs(3600))
+            .unwrap();
+        store.del("t1", "k1").unwrap();
+        // 删除不存在的 key 和 cas 失败不产生新的版本
+        store.del("t1", "k1").unwrap();
+        store.incr("t1", "k1", 5).unwrap();
+        let result = store.compare_and_swap("t1", "k1", None, "v4".into());
+        assert!(result.unwrap().is_err());
+        let result = store.compare_and_swap("t1", "k1", Some(&5.into()), "v3".into());
+        assert!(result.unwrap().is_ok());
+        let result = store.transaction(&["t1"], &mut |txn| {
+            txn.set("t1", "k1".into(), "v5".into())?;
+            Err(KvError::Internal("abort".into()))
+        });
+        assert!(result.is_err());
+        store
+            .transaction(&["t1"], &mut |txn| {
+                txn.set("t1", "k2".into(), "v1".into())?;
+                Ok(())
+            })
+            .unwrap();
+
+        let history = store.history("t1", "k1").unwrap();
+        let values: Vec<_> = history.iter().map(|v| v.value.clone()).collect();
+        assert_eq!(
+            values,
+            [
+                Some("v1".into()),
+                Some("v2".into()),
+                None,
+                Some(5.into()),
+                Some("v3".into())
+            ]
+        );
+        assert!(history
+            .iter()
+            .enumerate()
+            .all(|(i, v)| v.version == i as u64 + 1));
+        assert!(history.windows(2).all(|w| w[0].timestamp <= w[1].timestamp));
+
+        assert_eq!(store.get_version("t1", "k1", 2).unwrap(), Some("v2".into()));
+        assert_eq!(store.get_version("t1", "k1", 3).unwrap(), None);
+        assert_eq!(store.get_version("t1", "k1", 9).unwrap(), None);
+        let first = history[0].timestamp;
+        assert_eq!(store.get_at("t1", "k1", first - 1).unwrap(), None);
+        let last = history[4].timestamp;
+        assert_eq!(store.get_at("t1", "k1", last).unwrap(), Some("v3".into()));
+        assert_eq!(store.history("t1", "k2").unwrap().len(), 1);
+        assert!(store.history("t1", "k3").unwrap().is_empty());
+        assert_eq!(store.list_tables().unwrap(), ["t1"]);
+    }
+
+    #[test]
+    fn memtable_lists_should_work() {
+        test_lists(MemTable::new());
+    }
+
+    fn test_lists(store: impl Storage) {
+        let values = |items: &[&str]| -> Vec<Value> { items.iter().map(|&v| v.into()).collect() };
+        let range = |start, stop| store.list_range("t1", "l1", start, stop).unwrap();
+
+        let pushed = store.list_push("t1", "l1", ListEnd::Right, values(&["b", "c"]));
+        assert_eq!(pushed.unwrap(), 2);
+        let pushed = store.list_push("t1", "l1", ListEnd::Left, values(&["a", "z"]));
+        assert_eq!(pushed.unwrap(), 4);
+        assert_eq!(range(0, -1), values(&["z", "a", "b", "c"]));
+        assert_eq!(range(1, 2), values(&["a", "b"]));
+        assert_eq!(range(-2, -1), values(&["b", "c"]));
+        assert_eq!(range(-100, 100), values(&["z", "a", "b", "c"]));
+        assert!(range(3, 1).is_empty());
+        assert!(range(4, 10).is_empty());
+        assert_eq!(store.list_len("t1", "l1").unwrap(), 4);
+        assert_eq!(store.list_index("t1", "l1", -1).unwrap(), Some("c".into()));
+        assert_eq!(store.list_index("t1", "l1", 0).unwrap(), Some("z".into()));
+        assert_eq!(store.list_index("t1", "l1", 4).unwrap(), None);
+
+        // list 和普通的 key 互不影响
+        assert_eq!(store.get("t1", "l1").unwrap(), None);
+        store.set("t1", "l1".into(), "v1".into()).unwrap();
+        store.del("t1", "l1").unwrap();
+        assert_eq!(store.list_len("t1", "l1").unwrap(), 4);
+
+        let popped = store.list_pop("t1", "l1", ListEnd::Left, 1).unwrap();
+        assert_eq!(popped, values(&["z"]));
+        let popped = store.list_pop("t1", "l1", ListEnd::Right, 2).unwrap();
+        assert_eq!(popped, values(&["c", "b"]));
+        let pushed = store.list_push("t1", "l2", ListEnd::Right, values(&["x"]));
+        assert_eq!(pushed.unwrap(), 1);
+        assert_eq!(store.list_keys("t1").unwrap(), ["l1", "l2"]);
+
+        // 取空的 list 被删除
+        let popped = store.list_pop("t1", "l1", ListEnd::Right, 10).unwrap();
+        assert_eq!(popped, values(&["a"]));
+        assert_eq!(store.list_len("t1", "l1").unwrap(), 0);
+        assert!(store
+            .list_pop("t1", "l1", ListEnd::Left, 1)
+            .unwrap()
+            .is_empty());
+        assert_eq!(store.list_keys("t1").unwrap(), ["l2"]);
+
+        let items: Vec<Value> = (0..10).map(|i: i64| i.into()).collect();
+        store.list_push("t1", "l3", ListEnd::Right, items).unwrap();
+        assert_eq!(store.list_trim("t1", "l3", 2, 4).unwrap(), 7);
+        store
+            .list_push("t1", "l3", ListEnd::Left, vec![1.into()])
+            .unwrap();
+        let expected: Vec<Value> = vec![1.into(), 2.into(), 3.into(), 4.into()];
+        assert_eq!(store.list_range("t1", "l3", 0, -1).unwrap(), expected);
+        assert_eq!(store.list_trim("t1", "l3", 5, 10).unwrap(), 4);
+        assert_eq!(store.list_len("t1", "l3").unwrap(), 0);
+
+        // 只有 list 的 table 也会被列出和删除
+        store
+            .list_push("t2", "l1", ListEnd::Right, values(&["a"]))
+            .unwrap();
+        assert_eq!(store.list_tables().unwrap(), ["t1", "t2"]);
+        assert!(store.drop_table("t2").unwrap());
+        assert_eq!(store.list_len("t2", "l1").unwrap(), 0);
+        assert!(store.drop_table("t1").unwrap());
+        assert!(store.list_keys("t1").unwrap().is_empty());
+        assert!(store.list_tables().unwrap().is_empty());
+    }
+
+    #[test]
+    fn memtable_zsets_should_work() {
+        test_zsets(MemTable::new());
+    }
+
+    fn test_zsets(store: impl Storage) {
+        let scored = |items: &[(&str, f64)]| -> Vec<(String, f64)> {
+            items.iter().map(|&(m, s)| (m.into(), s)).collect()
+        };
+        let all = |key| store.zset_range("t1", key, 0, -1).unwrap();
+
+        let members = scored(&[("a", 3.0), ("b", -1.5), ("c", 3.0), ("d", 0.0)]);
+        assert_eq!(store.zset_add("t1", "z1", members).unwrap(), 4);
+        // 已经存在的 member 只更新 score
+        let members = scored(&[("d", 10.0), ("e", -100.0)]);
+        assert_eq!(store.zset_add("t1", "z1", members).unwrap(), 1);
+        let expected = scored(&[
+            ("e", -100.0),
+            ("b", -1.5),
+            ("a", 3.0),
+            ("c", 3.0),
+            ("d", 10.0),
+        ]);
+        assert_eq!(all("z1"), expected);
+        assert_eq!(store.zset_len("t1", "z1").unwrap(), 5);
+
+        assert_eq!(store.zset_score("t1", "z1", "b").unwrap(), Some(-1.5));
+        assert_eq!(store.zset_score("t1", "z1", "x").unwrap(), None);
+        assert_eq!(store.zset_rank("t1", "z1", "e").unwrap(), Some(0));
+        assert_eq!(store.zset_rank("t1", "z1", "c").unwrap(), Some(3));
+        assert_eq!(store.zset_rank("t1", "z1", "x").unwrap(), None);
+
+        let range = store.zset_range("t1", "z1", 1, 2).unwrap();
+        assert_eq!(range, scored(&[("b", -1.5), ("a", 3.0)]));
+        let range = store.zset_range("t1", "z1", -2, -1).unwrap();
+        assert_eq!(range, scored(&[("c", 3.0), ("d", 10.0)]));
+        assert!(store.zset_range("t1", "z1", 5, 10).unwrap().is_empty());
+
+        let range = store.zset_range_by_score("t1", "z1", -2.0, 3.0, 0, 0);
+        assert_eq!(
+            range.unwrap(),
+            scored(&[("b", -1.5), ("a", 3.0), ("c", 3.0)])
+        );
+        let range = store.zset_range_by_score("t1", "z1", -2.0, 3.0, 1, 1);
+        assert_eq!(range.unwrap(), scored(&[("a", 3.0)]));
+        let range = store.zset_range_by_score("t1", "z1", f64::NEG_INFINITY, 0.0, 0, 0);
+        assert_eq!(range.unwrap(), scored(&[("e", -100.0), ("b", -1.5)]));
+        let range = store.zset_range_by_score("t1", "z1", 4.0, 3.0, 0, 0);
+        assert!(range.unwrap().is_empty());
+
+        // 加上 delta 之后排名跟着变化
+        assert_eq!(store.zset_incr("t1", "z1", "e", 200.5).unwrap(), 100.5);
+        assert_eq!(store.zset_rank("t1", "z1", "e").unwrap(), Some(4));
+        assert_eq!(store.zset_incr("t1", "z2", "x", -2.0).unwrap(), -2.0);
+        assert!(store.zset_incr("t1", "z2", "x", f64::NAN).is_err());
+        assert!(store
+            .zset_add("t1", "z2", scored(&[("y", f64::INFINITY)]))
+            .is_err());
+        assert_eq!(all("z2"), scored(&[("x", -2.0)]));
+
+        // 前缀相同的 key 的 member 不会混在一起
+        store.zset_add("t1", "z", scored(&[("a", 1.0)])).unwrap();
+        assert_eq!(all("z"), scored(&[("a", 1.0)]));
+        assert_eq!(store.zset_keys("t1").unwrap(), ["z", "z1", "z2"]);
+
+        // sorted set 和普通的 key、set 互不影响
+        assert_eq!(store.get("t1", "z1").unwrap(), None);
+        assert!(store.set_members("t1", "z1").unwrap().is_empty());
+
+        let members = vec!["a".to_string(), "x".to_string(), "c".to_string()];
+        assert_eq!(store.zset_remove("t1", "z1", members).unwrap(), 2);
+        assert_eq!(all("z1"), scored(&[("b", -1.5), ("d", 10.0), ("e", 100.5)]));
+        assert_eq!(store.zset_rank("t1", "z1", "d").unwrap(), Some(1));
+
+        // 删空的 sorted set 被删除
+        assert_eq!(store.zset_remove("t1", "z2", vec!["x".into()]).unwrap(), 1);
+        assert_eq!(store.zset_len("t1", "z2").unwrap(), 0);
+        assert_eq!(store.zset_keys("t1").unwrap(), ["z", "z1"]);
+
+        // 只有 sorted set 的 table 也会被列出和删除
+        store.zset_add("t2", "z1", scored(&[("a", 1.0)])).unwrap();
+        assert_eq!(store.list_tables().unwrap(), ["t1", "t2"]);
+        assert!(store.drop_table("t2").unwrap());
+        assert_eq!(store.zset_len("t2", "z1").unwrap(), 0);
+        assert!(store.drop_table("t1").unwrap());
+        assert!(store.zset_keys("t1").unwrap().is_empty());
+        assert!(store.list_tables().unwrap().is_empty());
+    }
+
+    #[test]
+    fn memtable_paths_should_work() {
+        test_paths(MemTable::new());
+    }
+
+    fn path(segments: &[PathSegment]) -> Vec<PathSegment> {
+        segments.to_vec()
+    }
+
+    fn test_paths(store: impl Storage) {
+        let set = |p: Vec<PathSegment>, v: Value| store.update_path("t1", "k1", &p, PathOp::Set(v));
+        let del = |p: Vec<PathSegment>| store.update_path("t1", "k1", &p, PathOp::Delete);
+        let get = |p: Vec<PathSegment>| store.get_path("t1", "k1", &p).unwrap();
+
+        // key 不存在时只能写入整个 value
+        let err = set(path(&["name".into()]), "kv".into()).unwrap_err();
+        assert!(matches!(err, KvError::NotFound(_, _)));
+        let doc: Value = BTreeMap::from([
+            ("name".to_string(), "tyr".into()),
+            ("tags".to_string(), vec!["a".into(), "b".into()].into()),
+        ])
+        .into();
+        assert_eq!(set(vec![], doc.clone()).unwrap(), None);
+        assert_eq!(get(vec![]), Some(doc));
+
+        assert_eq!(get(path(&["name".into()])), Some("tyr".into()));
+        assert_eq!(get(path(&["tags".into(), (-1).into()])), Some("b".into()));
+        assert_eq!(get(path(&["tags".into(), 2.into()])), None);
+        assert_eq!(get(path(&["name".into(), 0.into()])), None);
+        assert_eq!(get(path(&["x".into(), "y".into()])), None);
+        assert_eq!(store.get_path("t1", "k2", &[]).unwrap(), None);
+
+        // 替换已有的元素返回原来的值，加入新的 key 或者追加到数组末尾返回 None
+        let old = set(path(&["name".into()]), "kv".into()).unwrap();
+        assert_eq!(old, Some("tyr".into()));
+        assert_eq!(set(path(&["age".into()]), 10.into()).unwrap(), None);
+        assert_eq!(
+            set(path(&["tags".into(), 2.into()]), "c".into()).unwrap(),
+            None
+        );
+        let old = set(path(&["tags".into(), (-3).into()]), "z".into()).unwrap();
+        assert_eq!(old, Some("a".into()));
+        assert!(set(path(&["tags".into(), 5.into()]), "x".into()).is_err());
+        assert!(set(path(&["x".into(), "y".into()]), "x".into()).is_err());
+        assert!(set(path(&["name".into(), "y".into()]), "x".into()).is_err());
+
+        assert_eq!(
+            del(path(&["tags".into(), 0.into()])).unwrap(),
+            Some("z".into())
+        );
+        assert_eq!(del(path(&["tags".into(), 9.into()])).unwrap(), None);
+        assert_eq!(del(path(&["missing".into()])).unwrap(), None);
+        assert!(del(vec![]).is_err());
+
+        let expected: Value = BTreeMap::from([
+            ("age".to_string(), 10.into()),
+            ("name".to_string(), "kv".into()),
+            ("tags".to_string(), vec!["b".into(), "c".into()].into()),
+        ])
+        .into();
+        assert_eq!(store.get("t1", "k1").unwrap(), Some(expected));
+
+        // 过期时间保持不变
+        let ttl = Duration::from_secs(100);
+        store
+            .set_with_ttl("t1", "k2".into(), Vec::<Value>::new().into(), ttl)
+            .unwrap();
+        let p = path(&[0.into()]);
+        store
+            .update_path("t1", "k2", &p, PathOp::Set(1.into()))
+            .unwrap();
+        assert_eq!(store.get_path("t1", "k2", &p).unwrap(), Some(1.into()));
+        assert!(store.ttl("t1", "k2").unwrap().is_some());
+
+        // 追加之后返回数组的长度
+        let op = PathOp::Append(vec![2.into(), 3.into()]);
+        assert_eq!(
+            store.update_path("t1", "k2", &[], op).unwrap(),
+            Some(3.into())
+        );
+        let op = PathOp::Append(vec![2.into()]);
+        assert!(store.update_path("t1", "k1", &[], op).is_err());
+        let op = PathOp::Append(vec![2.into()]);
+        assert!(store.update_path("t1", "k3", &[], op).is_err());
+    }
+
+    #[test]
+    fn memtable_sets_should_work() {
+        test_sets(MemTable::new());
+    }
+
+    fn test_sets(store: impl Storage) {
+        let members = |items: &[&str]| -> Vec<String> { items.iter().map(|&m| m.into()).collect() };
+
+        assert_eq!(
+            store
+                .set_add("t1", "s1", members(&["b", "a", "b"]))
+                .unwrap(),
+            2
+        );
+        assert_eq!(store.set_add("t1", "s1", members(&["c", "a"])).unwrap(), 1);
+        assert_eq!(store.set_members("t1", "s1").unwrap(), ["a", "b", "c"]);
+        assert_eq!(store.set_len("t1", "s1").unwrap(), 3);
+        assert!(store.set_contains("t1", "s1", "b").unwrap());
+        assert!(!store.set_contains("t1", "s1", "d").unwrap());
+
+        // 前缀相同的 key 的 member 不会混在一起
+        store.set_add("t1", "s", members(&["x"])).unwrap();
+        store.set_add("t1", "s10", members(&["y"])).unwrap();
+        assert_eq!(store.set_members("t1", "s").unwrap(), ["x"]);
+        assert_eq!(store.set_members("t1", "s1").unwrap(), ["a", "b", "c"]);
+        assert_eq!(store.set_keys("t1").unwrap(), ["s", "s1", "s10"]);
+
+        // set 和普通的 key、list 互不影响
+        assert_eq!(store.get("t1", "s1").unwrap(), None);
+        store.set("t1", "s1".into(), "v1".into()).unwrap();
+        store.del("t1", "s1").unwrap();
+        assert_eq!(store.set_len("t1", "s1").unwrap(), 3);
+
+        assert_eq!(
+            store.set_remove("t1", "s1", members(&["a", "d"])).unwrap(),
+            1
+        );
+        assert_eq!(store.set_len("t1", "s1").unwrap(), 2);
+        assert_eq!(store.set_remove("t1", "none", members(&["a"])).unwrap(), 0);
+
+        // 删空的 set 被删除
+        assert_eq!(
+            store.set_remove("t1", "s1", members(&["b", "c"])).unwrap(),
+            2
+        );
+        assert!(store.set_members("t1", "s1").unwrap().is_empty());
+        assert_eq!(store.set_keys("t1").unwrap(), ["s", "s10"]);
+
+        // 只有 set 的 table 也会被列出和删除
+        store.set_add("t2", "s1", members(&["a"])).unwrap();
+        assert_eq!(store.list_tables().unwrap(), ["t1", "t2"]);
+        assert!(store.drop_table("t2").unwrap());
+        assert_eq!(store.set_len("t2", "s1").unwrap(), 0);
+        assert!(store.drop_table("t1").unwrap());
+        assert!(store.set_keys("t1").unwrap().is_empty());
+        assert!(store.list_tables().unwrap().is_empty());
+    }
+
+    #[test]
+    fn sleddb_basic_interface_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_basi_interface(store);
+    }
+    #[test]
+    fn sleddb_get_all_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_get_all(store);
+    }
+    #[test]
+    fn sleddb_iter_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_get_iter(store);
+    }
+
+    #[test]
+    fn sleddb_ttl_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_ttl(store);
+    }
+
+    #[test]
+    fn sleddb_transaction_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_transaction(store);
+    }
+
+    #[test]
+    fn sleddb_compare_and_swap_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_compare_and_swap(store);
+    }
+
+    #[test]
+    fn sleddb_compare_and_swap_should_be_atomic() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        // 并发地用 compare_and_swap 做自增，一次更新都不能丢
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..50 {
+                        loop {
+                            let current = store.get("t1", "n").unwrap();
+                            let next = match &current {
+                                Some(v) => i64::try_from(v.clone()).unwrap() + 1,
+                                None => 1,
+                            };
+                            let swapped = store
+                                .compare_and_swap("t1", "n", current.as_ref(), next.into())
+                                .unwrap();
+                            if swapped.is_ok() {
+                                break;
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(store.get("t1", "n").unwrap(), Some(200.into()));
+    }
+
+    #[test]
+    fn sleddb_compare_and_swap_should_not_race_with_ttl() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        // compare_and_swap 写入的 value 不能带上并发的 set_with_ttl 设置的过期时间。
+        // 每次用新的 table，开始时 table 里没有设置过过期时间的 key
+        let v1: Value = "v1".into();
+        for i in 0..200 {
+            let table = format!("t{}", i);
+            store.set(&table, "k1".into(), v1.clone()).unwrap();
+            thread::scope(|s| {
+                s.spawn(|| {
+                    let ttl = Duration::from_secs(60);
+                    store.set_with_ttl(&table, "k1".into(), v1.clone(), ttl)
+                });
+                s.spawn(|| store.compare_and_swap(&table, "k1", Some(&v1), "v2".into()));
+            });
+            let value = store.get(&table, "k1").unwrap().unwrap();
+            let ttl = store.ttl(&table, "k1").unwrap();
+            assert_eq!(value == v1, ttl.is_some(), "table: {}", table);
+        }
+    }
+
+    #[test]
+    fn sleddb_incr_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_incr(store);
+    }
+
+    #[test]
+    fn sleddb_scan_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_scan(store);
+    }
+
+    #[test]
+    fn sleddb_tables_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_tables(store);
+    }
+
+    #[test]
+    fn sleddb_huge_ttl_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_huge_ttl(store);
+    }
+
+    #[test]
+    fn sleddb_ttl_should_survive_restart() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir.path());
+        store
+            .set_with_ttl("t1", "k1".into(), "v1".into(), Duration::from_millis(100))
+            .unwrap();
+        store
+            .set_with_ttl("t1", "k2".into(), "v2".into(), Duration::from_secs(60))
+            .unwrap();
+        drop(store);
+
+        let store = SledDb::new(dir.path());
+        assert!(store.ttl("t1", "k2").unwrap().is_some());
+        thread::sleep(Duration::from_millis(150));
+        assert_eq!(store.get("t1", "k1").unwrap(), None);
+        assert_eq!(store.get("t1", "k2").unwrap(), Some("v2".into()));
+    }
+
+    #[test]
+    fn sleddb_colon_in_names_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_colon_in_names(store);
+    }
+
+    #[test]
+    fn sleddb_find_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_find(store);
+    }
+
+    #[test]
+    fn sleddb_find_with_index_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir.path());
+        assert!(store.create_index("t1").unwrap());
+        assert!(!store.create_index("t1").unwrap());
+        test_find(store);
+    }
+
+    #[test]
+    fn sleddb_lists_should_work() {
+        let dir = tempdir().unwrap();
+        test_lists(SledDb::new(dir.path()));
+    }
+
+    #[test]
+    fn sleddb_lists_should_persist() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir.path());
+        let values: Vec<Value> = vec!["a".into(), "b".into()];
+        store
+            .list_push("t1", "l1", ListEnd::Right, values.clone())
+            .unwrap();
+        drop(store);
+
+        let store = SledDb::new(dir.path());
+        assert_eq!(store.list_range("t1", "l1", 0, -1).unwrap(), values);
+        assert_eq!(store.list_tables().unwrap(), ["t1"]);
+    }
+
+    #[test]
+    fn sleddb_zsets_should_work() {
+        let dir = tempdir().unwrap();
+        test_zsets(SledDb::new(dir.path()));
+    }
+
+    #[test]
+    fn sleddb_zsets_should_persist() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir.path());
+        let members = vec![("a".to_string(), 2.5), ("b".to_string(), -1.0)];
+        store.zset_add("t1", "z1", members).unwrap();
+        drop(store);
+
+        let store = SledDb::new(dir.path());
+        let expected = vec![("b".to_string(), -1.0), ("a".to_string(), 2.5)];
+        assert_eq!(store.zset_range("t1", "z1", 0, -1).unwrap(), expected);
+        assert_eq!(store.zset_len("t1", "z1").unwrap(), 2);
+        assert_eq!(store.list_tables().unwrap(), ["t1"]);
+    }
+
+    #[test]
+    fn sleddb_paths_should_work() {
+        let dir = tempdir().unwrap();
+        test_paths(SledDb::new(dir.path()));
+    }
+
+    #[test]
+    fn sleddb_paths_should_update_index() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir.path());
+        store.create_index("t1").unwrap();
+        let doc: Value = vec!["a".into()].into();
+        store.set("t1", "k1".into(), doc.clone()).unwrap();
+
+        let p = path(&[1.into()]);
+        store
+            .update_path("t1", "k1", &p, PathOp::Set("b".into()))
+            .unwrap();
+        assert!(store.find("t1", &doc).unwrap().is_empty());
+        let doc: Value = vec!["a".into(), "b".into()].into();
+        assert_eq!(store.find("t1", &doc).unwrap(), [Kvpair::new("k1", doc)]);
+    }
+
+    #[test]
+    fn sleddb_sets_should_work() {
+        let dir = tempdir().unwrap();
+        test_sets(SledDb::new(dir.path()));
+    }
+
+    #[test]
+    fn sleddb_sets_should_persist() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir.path());
+        store
+            .set_add("t1", "s1", vec!["a".into(), "b".into()])
+            .unwrap();
+        drop(store);
+
+        let store = SledDb::new(dir.path());
+        assert_eq!(store.set_members("t1", "s1").unwrap(), ["a", "b"]);
+        assert_eq!(store.set_len("t1", "s1").unwrap(), 2);
+        assert_eq!(store.list_tables().unwrap(), ["t1"]);
+    }
+
+    #[test]
+    fn sleddb_index_should_persist() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir.path());
+        store.set("t1", "u1".into(), "a".into()).unwrap();
+        // 建立索引时补上已有数据的索引项
+        assert!(store.create_index("t1").unwrap());
+        assert_eq!(
+            store.find("t1", &"a".into()).unwrap(),
+            [Kvpair::new("u1", "a".into())]
+        );
+        drop(store);
+
+        let store = SledDb::new(dir.path());
+        assert!(!store.create_index("t1").unwrap());
+        store.set("t1", "u2".into(), "a".into()).unwrap();
+        let keys: Vec<_> = store
+            .find("t1", &"a".into())
+            .unwrap()
+            .into_iter()
+            .map(|p| p.key)
+            .collect();
+        assert_eq!(keys, ["u1", "u2"]);
+
+        // 删除 table 之后索引的定义还在
+        assert!(store.drop_table("t1").unwrap());
+        assert!(store.find("t1", &"a".into()).unwrap().is_empty());
+        assert!(!store.create_index("t1").unwrap());
+    }
+
+    #[test]
+    fn sleddb_should_migrate_legacy_layout() {
+        let dir = tempdir().unwrap();
+        {
+            // 旧版本的格式：默认 tree 里存 {table}:{key}，__expiry__ 里存过期时间
+            let db = sled::open(dir.path()).unwrap();
+            let expiry = db.open_tree("__expiry__").unwrap();
+            let v1: Vec<u8> = Value::from("v1").try_into().unwrap();
+            let v2: Vec<u8> = Value::from("v2").try_into().unwrap();
+            let at = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as u64
+                + 60_000;
+            db.insert("t1:k1", v1).unwrap();
+            db.insert("t2:user:42", v2).unwrap();
+            expiry.insert("t2:user:42", &at.to_be_bytes()).unwrap();
+            db.flush().unwrap();
+        }
+
+        let store = SledDb::new(dir.path());
+        assert_eq!(store.list_tables().unwrap(), ["t1", "t2"]);
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(store.get("t2", "user:42").unwrap(), Some("v2".into()));
+        assert_eq!(store.ttl("t1", "k1").unwrap(), None);
+        assert!(store.ttl("t2", "user:42").unwrap().is_some());
+        drop(store);
+
+        // 再次打开不会重复迁移
+        let store = SledDb::new(dir.path());
+        assert_eq!(store.table_len("t2").unwrap(), 1);
+    }
+
+    #[test]
+    fn aof_basic_interface_should_work() {
+        let dir = tempdir().unwrap();
+        let store = AofStorage::open(dir.path().join("kv.aof"), AofConfig::default()).unwrap();
+        test_basi_interface(store);
+    }
+
+    #[test]
+    fn aof_transaction_should_work() {
+        let dir = tempdir().unwrap();
+        let store = AofStorage::open(dir.path().join("kv.aof"), AofConfig::default()).unwrap();
+        test_transaction(store);
+    }
+
+    #[test]
+    fn aof_incr_should_work() {
+        let dir = tempdir().unwrap();
+        let store = AofStorage::open(dir.path().join("kv.aof"), AofConfig::default()).unwrap();
+        test_incr(store);
+    }
+
+    #[test]
+    fn lsm_basic_interface_should_work() {
+        let dir = tempdir().unwrap();
+        let store = LsmStorage::new(dir);
+        test_basi_interface(store);
+    }
+
+    #[test]
+    fn lsm_get_all_should_work() {
+        let dir = tempdir().unwrap();
+        let store = LsmStorage::new(dir);
+        test_get_all(store);
+    }
+
+    #[test]
+    fn lsm_iter_should_work() {
+        let dir = tempdir().unwrap();
+        let store = LsmStorage::new(dir);
+        test_get_iter(store);
+    }
+
+    #[test]
+    fn lsm_ttl_should_work() {
+        let dir = tempdir().unwrap();
+        let store = LsmStorage::new(dir);
+        test_ttl(store);
+    }
+
+    #[test]
+    fn lsm_huge_ttl_should_work() {
+        let dir = tempdir().unwrap();
+        let store = LsmStorage::new(dir);
+        test_huge_ttl(store);
+    }
+
+    #[test]
+    fn lsm_transaction_should_work() {
+        let dir = tempdir().unwrap();
+        let store = LsmStorage::new(dir);
+        test_transaction(store);
+    }
+
+    #[test]
+    fn lsm_compare_and_swap_should_work() {
+        let dir = tempdir().unwrap();
+        let store = LsmStorage::new(dir);
+        test_compare_and_swap(store);
+    }
+
+    #[test]
+    fn lsm_incr_should_work() {
+        let dir = tempdir().unwrap();
+        let store = LsmStorage::new(dir);
+        test_incr(store);
+    }
+
+    #[test]
+    fn lsm_scan_should_work() {
+        let dir = tempdir().unwrap();
+        let store = LsmStorage::new(dir);
+        test_scan(store);
+    }
+
+    #[test]
+    fn lsm_tables_should_work() {
+        let dir = tempdir().unwrap();
+        let store = LsmStorage::new(dir);
+        test_tables(store);
+    }
+
+    #[test]
+    fn lsm_colon_in_names_should_work() {
+        let dir = tempdir().unwrap();
+        let store = LsmStorage::new(dir);
+        test_colon_in_names(store);
+    }
+
+    #[test]
+    fn bitcask_basic_interface_should_work() {
+        let dir = tempdir().unwrap();
+        let store = BitcaskStorage::new(dir);
+        test_basi_interface(store);
+    }
+
+    #[test]
+    fn bitcask_get_all_should_work() {
+        let dir = tempdir().unwrap();
+        let store = BitcaskStorage::new(dir);
+        test_get_all(store);
+    }
+
+    #[test]
+    fn bitcask_iter_should_work() {
+        let dir = tempdir().unwrap();
+        let store = BitcaskStorage::new(dir);
+        test_get_iter(store);
+    }
+
+    #[test]
+    fn bitcask_ttl_should_work() {
+        let dir = tempdir().unwrap();
+        let store = BitcaskStorage::new(dir);
+        test_ttl(store);
+    }
+
+    #[test]
+    fn bitcask_huge_ttl_should_work() {
+        let dir = tempdir().unwrap();
+        let store = BitcaskStorage::new(dir);
+        test_huge_ttl(store);
+    }
+
+    #[test]
+    fn bitcask_transaction_should_work() {
+        let dir = tempdir().unwrap();
+        let store = BitcaskStorage::new(dir);
+        test_transaction(store);
+    }
+
+    #[test]
+    fn bitcask_compare_and_swap_should_work() {
+        let dir = tempdir().unwrap();
+        let store = BitcaskStorage::new(dir);
+        test_compare_and_swap(store);
+    }
+
+    #[test]
+    fn bitcask_incr_should_work() {
+        let dir = tempdir().unwrap();
+        let store = BitcaskStorage::new(dir);
+        test_incr(store);
+    }
+
+    #[test]
+    fn bitcask_scan_should_work() {
+        let dir = tempdir().unwrap();
+        let store = BitcaskStorage::new(dir);
+        test_scan(store);
+    }
+
+    #[test]
+    fn bitcask_tables_should_work() {
+        let dir = tempdir().unwrap();
+        let store = BitcaskStorage::new(dir);
+        test_tables(store);
+    }
+
+    #[test]
+    fn bitcask_colon_in_names_should_work() {
+        let dir = tempdir().unwrap();
+        let store = BitcaskStorage::new(dir);
+        test_colon_in_names(store);
+    }
+
+    #[test]
+    fn cached_basic_interface_should_work() {
+        let dir = tempdir().unwrap();
+        let store = cached_store(dir.path(), CacheMode::WriteThrough);
+        test_basi_interface(store);
+    }
+
+    #[test]
+    fn cached_get_all_should_work() {
+        let dir = tempdir().unwrap();
+        let store = cached_store(dir.path(), CacheMode::WriteThrough);
+        test_get_all(store);
+    }
+
+    #[test]
+    fn cached_iter_should_work() {
+        let dir = tempdir().unwrap();
+        let store = cached_store(dir.path(), CacheMode::WriteThrough);
+        test_get_iter(store);
+    }
+
+    #[test]
+    fn cached_ttl_should_work() {
+        let dir = tempdir().unwrap();
+        let store = cached_store(dir.path(), CacheMode::WriteThrough);
+        test_ttl(store);
+    }
+
+    #[test]
+    fn cached_transaction_should_work() {
+        let dir = tempdir().unwrap();
+        let store = cached_store(dir.path(), CacheMode::WriteThrough);
+        test_transaction(store);
+    }
+
+    #[test]
+    fn cached_compare_and_swap_should_work() {
+        let dir = tempdir().unwrap();
+        let store = cached_store(dir.path(), CacheMode::WriteThrough);
+        test_compare_and_swap(store);
+    }
+
+    #[test]
+    fn cached_incr_should_work() {
+        let dir = tempdir().unwrap();
+        let store = cached_store(dir.path(), CacheMode::WriteThrough);
+        test_incr(store);
+    }
+
+    #[test]
+    fn cached_scan_should_work() {
+        let dir = tempdir().unwrap();
+        let store = cached_store(dir.path(), CacheMode::WriteThrough);
+        test_scan(store);
+    }
+
+    #[test]
+    fn cached_tables_should_work() {
+        let dir = tempdir().unwrap();
+        let store = cached_store(dir.path(), CacheMode::WriteThrough);
+        test_tables(store);
+    }
+
+    #[test]
+    fn cached_colon_in_names_should_work() {
+        let dir = tempdir().unwrap();
+        let store = cached_store(dir.path(), CacheMode::WriteThrough);
+        test_colon_in_names(store);
+    }
+
+    #[test]
+    fn cached_write_back_basic_interface_should_work() {
+        let dir = tempdir().unwrap();
+        let store = cached_store(dir.path(), CacheMode::WriteBack);
+        test_basi_interface(store);
+    }
+
+    #[test]
+    fn cached_write_back_get_all_should_work() {
+        let dir = tempdir().unwrap();
+        let store = cached_store(dir.path(), CacheMode::WriteBack);
+        test_get_all(store);
+    }
+
+    #[test]
+    fn cached_write_back_iter_should_work() {
+        let dir = tempdir().unwrap();
+        let store = cached_store(dir.path(), CacheMode::WriteBack);
+        test_get_iter(store);
+    }
+
+    #[test]
+    fn cached_write_back_ttl_should_work() {
+        let dir = tempdir().unwrap();
+        let store = cached_store(dir.path(), CacheMode::WriteBack);
+        test_ttl(store);
+    }
+
+    #[test]
+    fn cached_write_back_transaction_should_work() {
+        let dir = tempdir().unwrap();
+        let store = cached_store(dir.path(), CacheMode::WriteBack);
+        test_transaction(store);
+    }
+
+    #[test]
+    fn cached_write_back_compare_and_swap_should_work() {
+        let dir = tempdir().unwrap();
+        let store = cached_store(dir.path(), CacheMode::WriteBack);
+        test_compare_and_swap(store);
+    }
+
+    #[test]
+    fn cached_write_back_incr_should_work() {
+        let dir = tempdir().unwrap();
+        let store = cached_store(dir.path(), CacheMode::WriteBack);
+        test_incr(store);
+    }
+
+    #[test]
+    fn cached_write_back_scan_should_work() {
+        let dir = tempdir().unwrap();
+        let store = cached_store(dir.path(), CacheMode::WriteBack);
+        test_scan(store);
+    }
+
+    #[test]
+    fn cached_write_back_tables_should_work() {
+        let dir = tempdir().unwrap();
+        let store = cached_store(dir.path(), CacheMode::WriteBack);
+        test_tables(store);
+    }
+
+    #[test]
+    fn cached_write_back_colon_in_names_should_work() {
+        let dir = tempdir().unwrap();
+        let store = cached_store(dir.path(), CacheMode::WriteBack);
+        test_colon_in_names(store);
+    }
+
+    #[test]
+    fn encrypted_basic_interface_should_work() {
+        let dir = tempdir().unwrap();
+        let keyring = Keyring::new(1, Keyring::generate_key());
+        let store = EncryptedStorage::new(SledDb::new(dir), keyring);
+        test_basi_interface(store);
+    }
+
+    #[test]
+    fn encrypted_get_all_should_work() {
+        let dir = tempdir().unwrap();
+        let keyring = Keyring::new(1, Keyring::generate_key());
+        let store = EncryptedStorage::new(SledDb::new(dir), keyring);
+        test_get_all(store);
+    }
+
+    #[test]
+    fn encrypted_iter_should_work() {
+        let dir = tempdir().unwrap();
+        let keyring = Keyring::new(1, Keyring::generate_key());
+        let store = EncryptedStorage::new(SledDb::new(dir), keyring);
+        test_get_iter(store);
+    }
+
+    #[test]
+    fn encrypted_ttl_should_work() {
+        let dir = tempdir().unwrap();
+        let keyring = Keyring::new(1, Keyring::generate_key());
+        let store = EncryptedStorage::new(SledDb::new(dir), keyring);
+        test_ttl(store);
+    }
+
+    #[test]
+    fn encrypted_transaction_should_work() {
+        let dir = tempdir().unwrap();
+        let keyring = Keyring::new(1, Keyring::generate_key());
+        let store = EncryptedStorage::new(SledDb::new(dir), keyring);
+        test_transaction(store);
+    }
+
+    #[test]
+    fn encrypted_compare_and_swap_should_work() {
+        let dir = tempdir().unwrap();
+        let keyring = Keyring::new(1, Keyring::generate_key());
+        let store = EncryptedStorage::new(SledDb::new(dir), keyring);
+        test_compare_and_swap(store);
+    }
+
+    #[test]
+    fn encrypted_incr_should_work() {
+        let dir = tempdir().unwrap();
+        let keyring = Keyring::new(1, Keyring::generate_key());
+        let store = EncryptedStorage::new(SledDb::new(dir), keyring);
+        test_incr(store);
+    }
+
+    #[test]
+    fn encrypted_scan_should_work() {
+        let dir = tempdir().unwrap();
+        let keyring = Keyring::new(1, Keyring::generate_key());
+        let store = EncryptedStorage::new(SledDb::new(dir), keyring);
+        test_scan(store);
+    }
+
+    #[test]
+    fn encrypted_tables_should_work() {
+        let dir = tempdir().unwrap();
+        let keyring = Keyring::new(1, Keyring::generate_key());
+        let store = EncryptedStorage::new(SledDb::new(dir), keyring);
+        test_tables(store);
+    }
+
+    #[test]
+    fn encrypted_colon_in_names_should_work() {
+        let dir = tempdir().unwrap();
+        let keyring = Keyring::new(1, Keyring::generate_key());
+        let store = EncryptedStorage::new(SledDb::new(dir), keyring);
+        test_colon_in_names(store);
+    }
+
+    #[test]
+    fn versioned_basic_interface_should_work() {
+        let dir = tempdir().unwrap();
+        let store = VersionedStorage::new(SledDb::new(dir));
+        test_basi_interface(store);
+    }
+
+    #[test]
+    fn versioned_get_all_should_work() {
+        let dir = tempdir().unwrap();
+        let store = VersionedStorage::new(SledDb::new(dir));
+        test_get_all(store);
+    }
+
+    #[test]
+    fn versioned_iter_should_work() {
+        let dir = tempdir().unwrap();
+        let store = VersionedStorage::new(SledDb::new(dir));
+        test_get_iter(store);
+    }
+
+    #[test]
+    fn versioned_ttl_should_work() {
+        let dir = tempdir().unwrap();
+        let store = VersionedStorage::new(SledDb::new(dir));
+        test_ttl(store);
+    }
+
+    #[test]
+    fn versioned_transaction_should_work() {
+        let dir = tempdir().unwrap();
+        let store = VersionedStorage::new(SledDb::new(dir));
+        test_transaction(store);
+    }
+
+    #[test]
+    fn versioned_compare_and_swap_should_work() {
+        let dir = tempdir().unwrap();
+        let store = VersionedStorage::new(SledDb::new(dir));
+        test_compare_and_swap(store);
+    }
+
+    #[test]
+    fn versioned_incr_should_work() {
+        let dir = tempdir().unwrap();
+        let store = VersionedStorage::new(SledDb::new(dir));
+        test_incr(store);
+    }
+
+    #[test]
+    fn versioned_scan_should_work() {
+        let dir = tempdir().unwrap();
+        let store = VersionedStorage::new(SledDb::new(dir));
+        test_scan(store);
+    }
+
+    #[test]
+    fn versioned_tables_should_work() {
+        let dir = tempdir().unwrap();
+        let store = VersionedStorage::new(SledDb::new(dir));
+        test_tables(store);
+    }
+
+    #[test]
+    fn versioned_colon_in_names_should_work() {
+        let dir = tempdir().unwrap();
+        let store = VersionedStorage::new(SledDb::new(dir));
+        test_colon_in_names(store);
+    }
+
+    #[test]
+    fn versioned_find_should_work() {
+        let dir = tempdir().unwrap();
+        let store = VersionedStorage::new(SledDb::new(dir));
+        test_find(store);
+    }
+
+    #[test]
+    fn versioned_versions_should_work() {
+        let dir = tempdir().unwrap();
+        let store = VersionedStorage::new(SledDb::new(dir));
+        test_versions(store);
+    }
+
+    #[test]
+    fn encrypted_versions_should_work() {
+        let dir = tempdir().unwrap();
+        let keyring = Keyring::new(1, Keyring::generate_key());
+        let store = EncryptedStorage::new(VersionedStorage::new(SledDb::new(dir)), keyring);
+        test_versions(store);
+    }
+
+    fn cached_store(path: &std::path::Path, mode: CacheMode) -> CachedStorage {
+        let config = CacheConfig { mode, capacity: 4 };
+        CachedStorage::new(MemTable::new(), SledDb::new(path), config)
     }
 }
@@ -0,0 +1,482 @@
+use std::{borrow::Cow, ops::Deref, time::Duration};
+
+use crate::{KeyRange, KvError, Kvpair, ListEnd, PathOp, PathSegment, Storage, Value, Version};
+
+/// 默认的数据库，它的 table 名不加前缀，和使用数据库之前写入的数据兼容。
+/// 其它数据库中以 __db 开头的 table 名和 __databases__ 是保留的；默认数据库不保留，
+/// 之前就存在的这些 table 依然可以访问
+pub const DEFAULT_DB: u32 = 0;
+/// 其它数据库的 table 在内部存储中的名字是 __db{n}__/{table}
+const DB_PREFIX: &str = "__db";
+const DB_SUFFIX: &str = "__/";
+/// 记录创建了哪些数据库，key 是数据库的编号
+const DATABASES_TABLE: &str = "__databases__";
+
+/// 内部存储中的一个逻辑数据库。每个数据库的 table 是内部存储中不同的 table，
+/// 所以 MemTable、SledDb 等存储引擎中不同数据库的 key 互不影响
+pub struct Namespace<S> {
+    inner: S,
+    db: u32,
+}
+
+impl<S> Namespace<S>
+where
+    S: Deref,
+    S::Target: Storage,
+{
+    pub fn new(inner: S, db: u32) -> Self {
+        Self { inner, db }
+    }
+
+    // table 在内部存储中的名字。默认数据库的 table 名原样使用，和使用数据库之前兼容
+    fn table<'a>(&self, table: &'a str) -> Result<Cow<'a, str>, KvError> {
+        let reserved = table.starts_with(DB_PREFIX) || table == DATABASES_TABLE;
+        if reserved && self.db != DEFAULT_DB {
+            return Err(KvError::InvalidCommand(format!(
+                "Table name {} is reserved",
+                table
+            )));
+        }
+
+        Ok(qualify(self.db, table))
+    }
+}
+
+impl<S> Storage for Namespace<S>
+where
+    S: Deref,
+    S::Target: Storage,
+{
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.inner.get(&self.table(table)?, key)
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        self.inner.set(&self.table(table)?, key, value)
+    }
+
+    fn set_with_ttl(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> Result<Option<Value>, KvError> {
+        self.inner
+            .set_with_ttl(&self.table(table)?, key, value, ttl)
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.inner.contains(&self.table(table)?, key)
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.inner.del(&self.table(table)?, key)
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.inner.get_all(&self.table(table)?)
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        self.inner.get_iter(&self.table(table)?)
+    }
+
+    fn scan(&self, table: &str, range: &KeyRange, limit: usize) -> Result<Vec<Kvpair>, KvError> {
+        self.inner.scan(&self.table(table)?, range, limit)
+    }
+
+    // 只列出这个数据库的 table，去掉相同的前缀之后顺序不变
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        Ok(self
+            .inner
+            .list_tables()?
+            .into_iter()
+            .filter(|t| t != DATABASES_TABLE)
+            .filter_map(|t| match split(&t) {
+                (db, table) if db == self.db => Some(table.to_string()),
+                _ => None,
+            })
+            .collect())
+    }
+
+    fn drop_table(&self, table: &str) -> Result<bool, KvError> {
+        self.inner.drop_table(&self.table(table)?)
+    }
+
+    fn table_len(&self, table: &str) -> Result<usize, KvError> {
+        self.inner.table_len(&self.table(table)?)
+    }
+
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        self.inner.expire(&self.table(table)?, key, ttl)
+    }
+
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Duration>, KvError> {
+        self.inner.ttl(&self.table(table)?, key)
+    }
+
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.inner.persist(&self.table(table)?, key)
+    }
+
+    fn compare_and_swap(
+        &self,
+        table: &str,
+        key: &str,
+        expected: Option<&Value>,
+        value: Value,
+    ) -> Result<Result<(), Option<Value>>, KvError> {
+        self.inner
+            .compare_and_swap(&self.table(table)?, key, expected, value)
+    }
+
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        self.inner.incr(&self.table(table)?, key, delta)
+    }
+
+    fn incr_float(&self, table: &str, key: &str, delta: f64) -> Result<f64, KvError> {
+        self.inner.incr_float(&self.table(table)?, key, delta)
+    }
+
+    fn update_path(
+        &self,
+        table: &str,
+        key: &str,
+        path: &[PathSegment],
+        op: PathOp,
+    ) -> Result<Option<Value>, KvError> {
+        self.inner.update_path(&self.table(table)?, key, path, op)
+    }
+
+    fn create_index(&self, table: &str) -> Result<bool, KvError> {
+        self.inner.create_index(&self.table(table)?)
+    }
+
+    fn find(&self, table: &str, value: &Value) -> Result<Vec<Kvpair>, KvError> {
+        self.inner.find(&self.table(table)?, value)
+    }
+
+    fn history(&self, table: &str, key: &str) -> Result<Vec<Version>, KvError> {
+        self.inner.history(&self.table(table)?, key)
+    }
+
+    fn get_version(&self, table: &str, key: &str, version: u64) -> Result<Option<Value>, KvError> {
+        self.inner.get_version(&self.table(table)?, key, version)
+    }
+
+    fn get_at(&self, table: &str, key: &str, at: u64) -> Result<Option<Value>, KvError> {
+        self.inner.get_at(&self.table(table)?, key, at)
+    }
+
+    // 只清理这个数据库的 table 的旧版本
+    fn gc_versions_in(
+        &self,
+        retention: Duration,
+        tables: &dyn Fn(&str) -> bool,
+    ) -> Result<usize, KvError> {
+        let db = self.db;
+        self.inner.gc_versions_in(retention, &|name| {
+            let (n, table) = split(name);
+            n == db && tables(table)
+        })
+    }
+
+    fn list_push(
+        &self,
+        table: &str,
+        key: &str,
+        end: ListEnd,
+        values: Vec<Value>,
+    ) -> Result<usize, KvError> {
+        self.inner.list_push(&self.table(table)?, key, end, values)
+    }
+
+    fn list_pop(
+        &self,
+        table: &str,
+        key: &str,
+        end: ListEnd,
+        count: usize,
+    ) -> Result<Vec<Value>, KvError> {
+        self.inner.list_pop(&self.table(table)?, key, end, count)
+    }
+
+    fn list_range(
+        &self,
+        table: &str,
+        key: &str,
+        start: i64,
+        stop: i64,
+    ) -> Result<Vec<Value>, KvError> {
+        self.inner.list_range(&self.table(table)?, key, start, stop)
+    }
+
+    fn list_trim(&self, table: &str, key: &str, start: i64, stop: i64) -> Result<usize, KvError> {
+        self.inner.list_trim(&self.table(table)?, key, start, stop)
+    }
+
+    fn list_len(&self, table: &str, key: &str) -> Result<usize, KvError> {
+        self.inner.list_len(&self.table(table)?, key)
+    }
+
+    fn list_index(&self, table: &str, key: &str, index: i64) -> Result<Option<Value>, KvError> {
+        self.inner.list_index(&self.table(table)?, key, index)
+    }
+
+    fn list_keys(&self, table: &str) -> Result<Vec<String>, KvError> {
+        self.inner.list_keys(&self.table(table)?)
+    }
+
+    fn set_add(&self, table: &str, key: &str, members: Vec<String>) -> Result<usize, KvError> {
+        self.inner.set_add(&self.table(table)?, key, members)
+    }
+
+    fn set_remove(&self, table: &str, key: &str, members: Vec<String>) -> Result<usize, KvError> {
+        self.inner.set_remove(&self.table(table)?, key, members)
+    }
+
+    fn set_members(&self, table: &str, key: &str) -> Result<Vec<String>, KvError> {
+        self.inner.set_members(&self.table(table)?, key)
+    }
+
+    fn set_contains(&self, table: &str, key: &str, member: &str) -> Result<bool, KvError> {
+        self.inner.set_contains(&self.table(table)?, key, member)
+    }
+
+    fn set_len(&self, table: &str, key: &str) -> Result<usize, KvError> {
+        self.inner.set_len(&self.table(table)?, key)
+    }
+
+    fn set_keys(&self, table: &str) -> Result<Vec<String>, KvError> {
+        self.inner.set_keys(&self.table(table)?)
+    }
+
+    fn zset_add(
+        &self,
+        table: &str,
+        key: &str,
+        members: Vec<(String, f64)>,
+    ) -> Result<usize, KvError> {
+        self.inner.zset_add(&self.table(table)?, key, members)
+    }
+
+    fn zset_incr(&self, table: &str, key: &str, member: &str, delta: f64) -> Result<f64, KvError> {
+        self.inner
+            .zset_incr(&self.table(table)?, key, member, delta)
+    }
+
+    fn zset_remove(&self, table: &str, key: &str, members: Vec<String>) -> Result<usize, KvError> {
+        self.inner.zset_remove(&self.table(table)?, key, members)
+    }
+
+    fn zset_range(
+        &self,
+        table: &str,
+        key: &str,
+        start: i64,
+        stop: i64,
+    ) -> Result<Vec<(String, f64)>, KvError> {
+        self.inner.zset_range(&self.table(table)?, key, start, stop)
+    }
+
+    fn zset_range_by_score(
+        &self,
+        table: &str,
+        key: &str,
+        min: f64,
+        max: f64,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<(String, f64)>, KvError> {
+        self.inner
+            .zset_range_by_score(&self.table(table)?, key, min, max, offset, limit)
+    }
+
+    fn zset_score(&self, table: &str, key: &str, member: &str) -> Result<Option<f64>, KvError> {
+        self.inner.zset_score(&self.table(table)?, key, member)
+    }
+
+    fn zset_rank(&self, table: &str, key: &str, member: &str) -> Result<Option<usize>, KvError> {
+        self.inner.zset_rank(&self.table(table)?, key, member)
+    }
+
+    fn zset_len(&self, table: &str, key: &str) -> Result<usize, KvError> {
+        self.inner.zset_len(&self.table(table)?, key)
+    }
+
+    fn zset_keys(&self, table: &str) -> Result<Vec<String>, KvError> {
+        self.inner.zset_keys(&self.table(table)?)
+    }
+
+    fn transaction(
+        &self,
+        tables: &[&str],
+        f: &mut dyn FnMut(&dyn Storage) -> Result<(), KvError>,
+    ) -> Result<(), KvError> {
+        let names = tables
+            .iter()
+            .map(|t| self.table(t))
+            .collect::<Result<Vec<_>, _>>()?;
+        let names: Vec<_> = names.iter().map(|t| t.as_ref()).collect();
+        let db = self.db;
+        self.inner
+            .transaction(&names, &mut |txn| f(&Namespace { inner: txn, db }))
+    }
+}
+
+/// 列出所有的数据库，按编号排序。默认的数据库总是存在
+pub fn list_databases(store: &(impl Storage + ?Sized)) -> Result<Vec<u32>, KvError> {
+    let mut dbs: Vec<u32> = store
+        .get_iter(DATABASES_TABLE)?
+        .filter_map(|pair| pair.key.parse().ok())
+        .collect();
+    dbs.push(DEFAULT_DB);
+    dbs.sort_unstable();
+    dbs.dedup();
+
+    Ok(dbs)
+}
+
+/// 创建一个数据库，返回它之前是否不存在
+pub fn create_database(store: &(impl Storage + ?Sized), db: u32) -> Result<bool, KvError> {
+    if db == DEFAULT_DB {
+        return Ok(false);
+    }
+    let old = store.set(DATABASES_TABLE, db.to_string(), true.into())?;
+
+    Ok(old.is_none())
+}
+
+/// 数据库是否存在，Select 只能选择存在的数据库
+pub fn database_exists(store: &(impl Storage + ?Sized), db: u32) -> Result<bool, KvError> {
+    Ok(db == DEFAULT_DB || store.contains(DATABASES_TABLE, &db.to_string())?)
+}
+
+/// 删除数据库中所有的 table，数据库本身保留，返回删除的 table 的数量
+pub fn flush_database(store: &(impl Storage + ?Sized), db: u32) -> Result<usize, KvError> {
+    let mut count = 0;
+    for table in Namespace::new(store, db).list_tables()? {
+        if store.drop_table(&qualify(db, &table))? {
+            count += 1;
+        }
+    }
+
+    Ok(count)
+}
+
+fn qualify(db: u32, table: &str) -> Cow<'_, str> {
+    match db {
+        DEFAULT_DB => Cow::Borrowed(table),
+        _ => Cow::Owned(format!("{}{}{}{}", DB_PREFIX, db, DB_SUFFIX, table)),
+    }
+}
+
+/// 把内部存储中的 table 名拆成数据库的编号和数据库中的 table 名
+pub(crate) fn split(name: &str) -> (u32, &str) {
+    let parsed = name.strip_prefix(DB_PREFIX).and_then(|rest| {
+        let (db, table) = rest.split_once(DB_SUFFIX)?;
+        let n: u32 = db.parse().ok()?;
+        // 只接受 qualify 生成的写法，__db0__、__db01__ 开头的是默认数据库里普通的 table
+        (n != DEFAULT_DB && n.to_string() == db).then_some((n, table))
+    });
+
+    parsed.unwrap_or((DEFAULT_DB, name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemTable, SledDb, VersionedStorage};
+    use tempfile::tempdir;
+
+    #[test]
+    fn split_should_reverse_qualify() {
+        for (db, table) in [(0, "t1"), (1, "t1"), (42, "a/b"), (7, "")] {
+            assert_eq!(split(&qualify(db, table)), (db, table));
+        }
+        assert_eq!(split("__db01__/t1"), (0, "__db01__/t1"));
+        assert_eq!(split("__db0__/t1"), (0, "__db0__/t1"));
+        assert_eq!(split("__dbx__/t1"), (0, "__dbx__/t1"));
+    }
+
+    #[test]
+    fn memtable_databases_should_be_isolated() {
+        test_databases(MemTable::new());
+    }
+
+    #[test]
+    fn sleddb_databases_should_be_isolated() {
+        let dir = tempdir().unwrap();
+        test_databases(SledDb::new(dir));
+    }
+
+    fn test_databases(store: impl Storage) {
+        let db0 = Namespace::new(&store, DEFAULT_DB);
+        let db1 = Namespace::new(&store, 1);
+        assert!(create_database(&store, 1).unwrap());
+        assert!(!create_database(&store, 1).unwrap());
+        assert_eq!(list_databases(&store).unwrap(), [0, 1]);
+        assert!(database_exists(&store, 1).unwrap());
+        assert!(!database_exists(&store, 2).unwrap());
+
+        db0.set("t1", "k1".into(), "v0".into()).unwrap();
+        db1.set("t1", "k1".into(), "v1".into()).unwrap();
+        db1.set("t2", "k1".into(), "v1".into()).unwrap();
+        assert_eq!(db0.get("t1", "k1").unwrap(), Some("v0".into()));
+        assert_eq!(db1.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert_eq!(db0.list_tables().unwrap(), ["t1"]);
+        assert_eq!(db1.list_tables().unwrap(), ["t1", "t2"]);
+
+        db1.transaction(&["t1"], &mut |txn| {
+            txn.set("t1", "k2".into(), "v2".into())?;
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(db1.get("t1", "k2").unwrap(), Some("v2".into()));
+        assert!(!db0.contains("t1", "k2").unwrap());
+
+        assert_eq!(flush_database(&store, 1).unwrap(), 2);
+        assert!(db1.list_tables().unwrap().is_empty());
+        assert_eq!(db0.get("t1", "k1").unwrap(), Some("v0".into()));
+        assert_eq!(list_databases(&store).unwrap(), [0, 1]);
+
+        // 其它数据库不能通过内部的 table 名访问别的数据库和数据库列表
+        db1.set("t1", "k1".into(), "v1".into()).unwrap();
+        for table in ["__db1__/t1", "__databases__", "__db"] {
+            assert!(db1.get(table, "k1").is_err());
+            assert!(db1.set(table, "1".into(), "v".into()).is_err());
+            assert!(db1.drop_table(table).is_err());
+            assert!(db1.transaction(&[table], &mut |_| Ok(())).is_err());
+        }
+        assert_eq!(db1.get("t1", "k1").unwrap(), Some("v1".into()));
+        assert!(!database_exists(&store, 2).unwrap());
+
+        // 默认数据库中之前就存在的以 __db 开头的 table 依然可以访问
+        store.set("__dbstats", "k1".into(), "v".into()).unwrap();
+        assert_eq!(db0.get("__dbstats", "k1").unwrap(), Some("v".into()));
+        db0.set("__dbstats", "k2".into(), "v".into()).unwrap();
+        assert_eq!(store.table_len("__dbstats").unwrap(), 2);
+        assert!(db0
+            .list_tables()
+            .unwrap()
+            .contains(&"__dbstats".to_string()));
+    }
+
+    #[test]
+    fn gc_versions_should_only_clean_own_database() {
+        let store = VersionedStorage::new(MemTable::new());
+        let db0 = Namespace::new(&store, DEFAULT_DB);
+        let db1 = Namespace::new(&store, 1);
+        for db in [&db0, &db1] {
+            db.set("t1", "k1".into(), "v1".into()).unwrap();
+            db.set("t1", "k1".into(), "v2".into()).unwrap();
+        }
+
+        assert_eq!(db1.gc_versions(Duration::ZERO).unwrap(), 1);
+        assert_eq!(db1.history("t1", "k1").unwrap().len(), 1);
+        assert_eq!(db0.history("t1", "k1").unwrap().len(), 2);
+        assert_eq!(db0.gc_versions(Duration::ZERO).unwrap(), 1);
+        assert_eq!(db0.history("t1", "k1").unwrap().len(), 1);
+    }
+}
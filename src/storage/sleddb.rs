@@ -1,69 +1,1379 @@
-use sled::{Db, IVec};
-use std::{convert::TryInto, path::Path, str};
+use dashmap::DashMap;
+use sled::{
+    transaction::{
+        ConflictableTransactionError, TransactionError, TransactionalTree,
+        UnabortableTransactionError,
+    },
+    Db, IVec, Transactional, Tree,
+};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    convert::{TryFrom, TryInto},
+    ops::Bound,
+    path::Path,
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, RwLock, RwLockReadGuard,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::info;
 
-use crate::{KvError, Kvpair, Storage, StorageIter, Value};
+use crate::{
+    storage::{
+        add_float, add_integer, apply_path, check_score, list_bounds, serial_compare_and_swap,
+        serial_incr, serial_incr_float, serial_update_path,
+    },
+    KeyRange, KvError, Kvpair, ListEnd, PathOp, PathSegment, Storage, StorageIter, Value,
+};
+
+/// 每个 table 的数据放在名为 table/{table} 的 tree 里，key 就是原始的 key
+const TABLE_TREE_PREFIX: &str = "table/";
+/// 每个 table 的过期时间放在名为 expiry/{table} 的 tree 里，value 是过期的时间戳（毫秒）
+const EXPIRY_TREE_PREFIX: &str = "expiry/";
+/// 建立了索引的 table 的索引放在名为 index/{table} 的 tree 里，key 是 value 的长度、value 和原始的 key
+const INDEX_TREE_PREFIX: &str = "index/";
+/// 每个 table 的 list 放在名为 list/{table} 的 tree 里。每个 list 有一条元数据，
+/// key 是 META 和 list 的 key，value 是第一个元素和最后一个元素之后的下标；
+/// 每个元素是单独的一条，key 是 ITEM、list 的 key 的长度、key 和元素的下标
+const LIST_TREE_PREFIX: &str = "list/";
+/// 每个 table 的 set 放在名为 set/{table} 的 tree 里。元数据的 value 是 member 的数量，
+/// 每个 member 是单独的一条，key 是 ITEM、set 的 key 的长度、key 和 member，value 为空
+const SET_TREE_PREFIX: &str = "set/";
+/// 每个 table 的 sorted set 放在名为 zset/{table} 的 tree 里。元数据和 set 一样，
+/// ITEM 开头的一条记录 member 的 score；SCORE 开头的一条是按 score 排序的索引，
+/// key 是 SCORE、sorted set 的 key 的长度、key、编码后的 score 和 member，value 为空
+const ZSET_TREE_PREFIX: &str = "zset/";
+const META: u8 = 0;
+const ITEM: u8 = 1;
+const SCORE: u8 = 2;
+/// 记录哪些 table 建立了索引
+const INDEXES_TREE: &str = "__indexes__";
+/// 旧版本把所有 table 放在默认 tree 里，key 是 {table}:{key}，过期时间放在这个 tree 里
+const LEGACY_EXPIRY_TREE: &str = "__expiry__";
+/// 后台清理过期 key 的间隔
+const REAP_INTERVAL: Duration = Duration::from_secs(1);
+/// 打开 db 时等待文件锁的次数和间隔
+const LOCK_RETRIES: usize = 50;
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(20);
+
+type Tables = Arc<DashMap<String, TableTrees>>;
 
 #[derive(Debug)]
-pub struct SledDb(Db);
+pub struct SledDb {
+    db: Db,
+    // 已经存在的 table。读操作只在这里查找，不会创建新的 table
+    tables: Tables,
+    // 写入数据时持有读锁，建立索引和删除 table 时持有写锁，保证索引建立之后的写入都会更新索引，
+    // 也不会写入已经被删除的 tree
+    schema: RwLock<()>,
+    // 后台清理线程，SledDb 释放时通知它退出并等待结束
+    reaper: Option<(Sender<()>, JoinHandle<()>)>,
+}
+
+/// 一个 table 对应的数据、过期时间、list、set 和 sorted set 五个 tree，建立了索引的 table 还有一个索引的 tree
+#[derive(Clone, Debug)]
+struct TableTrees {
+    data: Tree,
+    expiry: Tree,
+    list: Tree,
+    set: Tree,
+    zset: Tree,
+    index: Option<Tree>,
+}
 
 impl SledDb {
     pub fn new(path: impl AsRef<Path>) -> Self {
-        Self(sled::open(path).unwrap())
+        let db = open_db(path.as_ref()).unwrap();
+        migrate_legacy_layout(&db).unwrap();
+
+        let tables: Tables = Arc::new(DashMap::new());
+        for name in db.tree_names() {
+            if let Some(table) = name.strip_prefix(TABLE_TREE_PREFIX.as_bytes()) {
+                let table = String::from_utf8_lossy(table).into_owned();
+                let trees = TableTrees::open(&db, &table).unwrap();
+                tables.insert(table, trees);
+            }
+        }
+
+        let (tx, rx) = mpsc::channel();
+        let handle = spawn_reaper(tables.clone(), rx);
+
+        Self {
+            db,
+            tables,
+            schema: RwLock::new(()),
+            reaper: Some((tx, handle)),
+        }
     }
 
-    // 在 sleddb 里，因为它可以 scan_prefix，我们用 prefix
-    // 来模拟一个 table。当然，还可以用其它方案。
-    fn get_full_key(table: &str, key: &str) -> String {
-        format!("{}:{}", table, key)
+    fn get_table(&self, table: &str) -> Option<TableTrees> {
+        self.tables.get(table).map(|t| t.value().clone())
     }
 
-    // 遍历 table 的 key 时，我们直接把 prefix: 当成 table
-    fn get_table_prefix(table: &str) -> String {
-        format!("{}:", table)
+    // 写操作用到的 table，不存在就创建
+    fn open_table(&self, table: &str) -> Result<TableTrees, KvError> {
+        if let Some(trees) = self.get_table(table) {
+            return Ok(trees);
+        }
+        let trees = TableTrees::open(&self.db, table)?;
+        let entry = self.tables.entry(table.into()).or_insert(trees);
+
+        Ok(entry.value().clone())
+    }
+
+    // 写操作用到的 table，以及 schema 的读锁
+    fn write_table(&self, table: &str) -> Result<(RwLockReadGuard<'_, ()>, TableTrees), KvError> {
+        let schema = self.schema.read().unwrap();
+        Ok((schema, self.open_table(table)?))
+    }
+
+    // 只修改已有数据的写操作用到的 table（不存在时不创建），以及 schema 的读锁
+    fn existing_table(&self, table: &str) -> (RwLockReadGuard<'_, ()>, Option<TableTrees>) {
+        let schema = self.schema.read().unwrap();
+        (schema, self.get_table(table))
+    }
+
+    // 有索引的 table 不能用 update_and_fetch，在事务里读出、计算再写入
+    fn update_in_txn<T: Default>(
+        &self,
+        table: &str,
+        mut f: impl FnMut(&dyn Storage) -> Result<T, KvError>,
+    ) -> Result<T, KvError> {
+        let mut result = T::default();
+        self.transaction(&[table], &mut |txn| {
+            result = f(txn)?;
+            Ok(())
+        })?;
+
+        Ok(result)
     }
 }
 
-impl Storage for SledDb {
-    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
-        let name = SledDb::get_full_key(table, key);
-        let result = self.0.get(name.as_bytes())?.map(|v| v.as_ref().try_into());
+impl TableTrees {
+    fn open(db: &Db, table: &str) -> Result<Self, KvError> {
+        let index = match db.open_tree(INDEXES_TREE)?.contains_key(table)? {
+            true => Some(open_index(db, table)?),
+            false => None,
+        };
+
+        Ok(Self {
+            data: db.open_tree(format!("{}{}", TABLE_TREE_PREFIX, table))?,
+            expiry: db.open_tree(format!("{}{}", EXPIRY_TREE_PREFIX, table))?,
+            list: db.open_tree(format!("{}{}", LIST_TREE_PREFIX, table))?,
+            set: db.open_tree(format!("{}{}", SET_TREE_PREFIX, table))?,
+            zset: db.open_tree(format!("{}{}", ZSET_TREE_PREFIX, table))?,
+            index,
+        })
+    }
+
+    // 事务里用到的 tree：数据、过期时间和索引（如果有）
+    fn trees(&self) -> Vec<Tree> {
+        let mut trees = vec![self.data.clone(), self.expiry.clone()];
+        trees.extend(self.index.clone());
+        trees
+    }
+
+    fn is_expired(&self, key: &[u8]) -> Result<bool, KvError> {
+        Ok(matches!(self.expiry.get(key)?, Some(at) if is_past(&at)))
+    }
+
+    // 如果 key 已过期就删除它，返回是否过期
+    fn purge_if_expired(&self, key: &[u8]) -> Result<bool, KvError> {
+        match self.expiry.get(key)? {
+            Some(at) if is_past(&at) => {
+                self.remove_expired(key, &at)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    // 删除一个过期的 key，只有过期时间没有被修改过才删除
+    fn remove_expired(&self, key: &[u8], at: &IVec) -> Result<(), KvError> {
+        self.trees()
+            .as_slice()
+            .transaction(|t| {
+                if t[1].get(key)?.as_ref() == Some(at) {
+                    t[1].remove(key)?;
+                    let old = t[0].remove(key)?;
+                    reindex(t.get(2), key, old.as_deref(), None)?;
+                }
+                Ok(())
+            })
+            .map_err(to_kv_error)
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Value>, KvError> {
+        if self.purge_if_expired(key.as_bytes())? {
+            return Ok(None);
+        }
+        let result = self.data.get(key)?.map(|v| v.as_ref().try_into());
 
         result.transpose()
     }
 
-    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
-        let name = SledDb::get_full_key(table, &key);
+    // 写入数据，同时设置（或清除）过期时间
+    fn insert(
+        &self,
+        key: &str,
+        value: Value,
+        expire_at: Option<u64>,
+    ) -> Result<Option<Value>, KvError> {
+        self.purge_if_expired(key.as_bytes())?;
         let data: Vec<u8> = value.try_into()?;
-        let result = self.0.insert(name, data)?.map(|v| v.as_ref().try_into());
+        let old = self
+            .trees()
+            .as_slice()
+            .transaction(|t| {
+                match expire_at {
+                    Some(at) => t[1].insert(key, &at.to_be_bytes())?,
+                    None => t[1].remove(key)?,
+                };
+                let old = t[0].insert(key, data.as_slice())?;
+                reindex(t.get(2), key.as_bytes(), old.as_deref(), Some(&data))?;
+                Ok(old)
+            })
+            .map_err(to_kv_error)?;
+
+        old.map(|v| v.as_ref().try_into()).transpose()
+    }
+
+    // 用 sled 的 update_and_fetch 原子地读出旧值、计算并写入新值。过期时间不受影响，
+    // 不会更新索引，只能用在没有索引的 table 上
+    fn update<T>(
+        &self,
+        key: &str,
+        f: impl Fn(Option<Value>) -> Result<T, KvError>,
+    ) -> Result<T, KvError>
+    where
+        T: Into<Value> + Clone,
+    {
+        self.update_with(key, |old| {
+            let v = f(old)?;
+            Ok((v.clone().into(), v))
+        })
+    }
+
+    // 和 update 一样，但 f 分别返回写入的新值和调用者需要的结果
+    fn update_with<T>(
+        &self,
+        key: &str,
+        f: impl Fn(Option<Value>) -> Result<(Value, T), KvError>,
+    ) -> Result<T, KvError> {
+        self.purge_if_expired(key.as_bytes())?;
+        // update_and_fetch 遇到并发修改时会重新调用闭包，只保留最后一次的结果
+        let mut result = Err(KvError::Internal("update is not executed".into()));
+        self.data.update_and_fetch(key, |old| {
+            let computed =
+                old.map(Value::try_from)
+                    .transpose()
+                    .and_then(&f)
+                    .and_then(|(value, v)| {
+                        let data: Vec<u8> = value.try_into()?;
+                        Ok((v, data))
+                    });
+            let next = match &computed {
+                Ok((_, data)) => Some(data.clone()),
+                // 出错时保持原来的值不变
+                Err(_) => old.map(|v| v.to_vec()),
+            };
+            result = computed;
+            next
+        })?;
+
+        result.map(|(v, _)| v)
+    }
+
+    // 通过索引查找 value 的编码等于 data 的 key，顺便清理过期或者旧 value 留下的索引项
+    fn find(&self, index: &Tree, data: &[u8], value: &Value) -> Result<Vec<Kvpair>, KvError> {
+        let prefix = index_key(data, b"");
+        let mut result = Vec::new();
+        for item in index.scan_prefix(&prefix) {
+            let (entry, _) = item?;
+            let key = &entry[prefix.len()..];
+            if self.purge_if_expired(key)? {
+                continue;
+            }
+            if self.data.get(key)?.as_deref() == Some(data) {
+                result.push(Kvpair::new(String::from_utf8_lossy(key), value.clone()));
+                continue;
+            }
+            (&self.data, index)
+                .transaction(|(tree, index)| {
+                    if tree.get(key)?.as_deref() != Some(data) {
+                        index.remove(&entry)?;
+                    }
+                    Ok(())
+                })
+                .map_err(to_kv_error)?;
+        }
+
+        Ok(result)
+    }
+
+    // 遍历 table 中没有过期的 kv pair
+    fn iter(&self) -> impl Iterator<Item = Kvpair> {
+        let expiry = self.expiry.clone();
+        StorageIter::new(self.data.iter().filter(move |item| match item {
+            Ok((k, _)) => !matches!(expiry.get(k), Ok(Some(at)) if is_past(&at)),
+            Err(_) => true,
+        }))
+    }
+
+    // table 中有没有数据、list、set 或者 sorted set
+    fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+            && self.list.is_empty()
+            && self.set.is_empty()
+            && self.zset.is_empty()
+    }
+
+    // 每个元素单独写入一条，只需要修改元数据和新的元素
+    fn list_push(&self, key: &str, end: ListEnd, values: &[Vec<u8>]) -> Result<usize, KvError> {
+        self.list
+            .transaction(|t| {
+                let meta = meta_key(key);
+                let (mut head, mut tail) = decode_list_meta(t.get(&meta)?);
+                for data in values {
+                    let index = match end {
+                        ListEnd::Left => {
+                            head -= 1;
+                            head
+                        }
+                        ListEnd::Right => {
+                            tail += 1;
+                            tail - 1
+                        }
+                    };
+                    t.insert(list_item_key(key, index), data.as_slice())?;
+                }
+                if head < tail {
+                    t.insert(meta, encode_list_meta(head, tail))?;
+                }
+                Ok((tail - head) as usize)
+            })
+            .map_err(to_kv_error)
+    }
+
+    fn list_pop(&self, key: &str, end: ListEnd, count: usize) -> Result<Vec<Value>, KvError> {
+        let popped = self
+            .list
+            .transaction(|t| {
+                let meta = meta_key(key);
+                let (mut head, mut tail) = decode_list_meta(t.get(&meta)?);
+                let mut popped = Vec::new();
+                while popped.len() < count && head < tail {
+                    let index = match end {
+                        ListEnd::Left => {
+                            head += 1;
+                            head - 1
+                        }
+                        ListEnd::Right => {
+                            tail -= 1;
+                            tail
+                        }
+                    };
+                    popped.extend(t.remove(list_item_key(key, index))?);
+                }
+                set_list_meta(t, meta, head, tail)?;
+                Ok(popped)
+            })
+            .map_err(to_kv_error)?;
+
+        popped.iter().map(|v| v.as_ref().try_into()).collect()
+    }
+
+    // 元素的下标是连续的，直接按 key 的顺序读出范围内的元素
+    fn list_range(&self, key: &str, start: i64, stop: i64) -> Result<Vec<Value>, KvError> {
+        let (head, tail) = decode_list_meta(self.list.get(meta_key(key))?);
+        let (from, to) = match list_bounds((tail - head) as usize, start, stop) {
+            Some(bounds) => bounds,
+            None => return Ok(Vec::new()),
+        };
+        let lower = list_item_key(key, head + from as i64);
+        let upper = list_item_key(key, head + to as i64);
+
+        self.list
+            .range(lower..upper)
+            .map(|item| {
+                let (_, v) = item?;
+                v.as_ref().try_into()
+            })
+            .collect()
+    }
+
+    fn list_trim(&self, key: &str, start: i64, stop: i64) -> Result<usize, KvError> {
+        self.list
+            .transaction(|t| {
+                let meta = meta_key(key);
+                let (head, tail) = decode_list_meta(t.get(&meta)?);
+                let (from, to) = match list_bounds((tail - head) as usize, start, stop) {
+                    Some((from, to)) => (head + from as i64, head + to as i64),
+                    None => (tail, tail),
+                };
+                for index in (head..from).chain(to..tail) {
+                    t.remove(list_item_key(key, index))?;
+                }
+                set_list_meta(t, meta, from, to)?;
+                Ok((tail - head - (to - from)) as usize)
+            })
+            .map_err(to_kv_error)
+    }
+
+    fn list_len(&self, key: &str) -> Result<usize, KvError> {
+        let (head, tail) = decode_list_meta(self.list.get(meta_key(key))?);
+        Ok((tail - head) as usize)
+    }
+
+    fn list_index(&self, key: &str, index: i64) -> Result<Option<Value>, KvError> {
+        let (head, tail) = decode_list_meta(self.list.get(meta_key(key))?);
+        let (from, _) = match list_bounds((tail - head) as usize, index, index) {
+            Some(bounds) => bounds,
+            None => return Ok(None),
+        };
+        let result = self
+            .list
+            .get(list_item_key(key, head + from as i64))?
+            .map(|v| v.as_ref().try_into());
 
         result.transpose()
     }
 
-    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
-        let name = SledDb::get_full_key(table, &key);
+    fn set_add(&self, key: &str, members: &[String]) -> Result<usize, KvError> {
+        self.set
+            .transaction(|t| {
+                let meta = meta_key(key);
+                let len = decode_count(t.get(&meta)?);
+                let mut added = 0;
+                for member in members {
+                    if t.insert(set_member_key(key, member), IVec::default())?
+                        .is_none()
+                    {
+                        added += 1;
+                    }
+                }
+                set_count(t, meta, len + added as u64)?;
+                Ok(added)
+            })
+            .map_err(to_kv_error)
+    }
+
+    fn set_remove(&self, key: &str, members: &[String]) -> Result<usize, KvError> {
+        self.set
+            .transaction(|t| {
+                let meta = meta_key(key);
+                let len = decode_count(t.get(&meta)?);
+                let mut removed = 0;
+                for member in members {
+                    if t.remove(set_member_key(key, member))?.is_some() {
+                        removed += 1;
+                    }
+                }
+                set_count(t, meta, len.saturating_sub(removed as u64))?;
+                Ok(removed)
+            })
+            .map_err(to_kv_error)
+    }
 
-        Ok(self.0.contains_key(name)?)
+    // member 按字节序存放，utf8 字符串的字节序就是字典序
+    fn set_members(&self, key: &str) -> Result<Vec<String>, KvError> {
+        let prefix = key_prefix(ITEM, key);
+        self.set
+            .scan_prefix(&prefix)
+            .map(|item| {
+                let (k, _) = item?;
+                Ok(String::from_utf8_lossy(&k[prefix.len()..]).into_owned())
+            })
+            .collect()
+    }
+
+    fn set_contains(&self, key: &str, member: &str) -> Result<bool, KvError> {
+        Ok(self.set.contains_key(set_member_key(key, member))?)
+    }
+
+    fn set_len(&self, key: &str) -> Result<usize, KvError> {
+        Ok(decode_count(self.set.get(meta_key(key))?) as usize)
+    }
+
+    fn zset_add(&self, key: &str, members: &[(String, f64)]) -> Result<usize, KvError> {
+        self.zset
+            .transaction(|t| {
+                let meta = meta_key(key);
+                let len = decode_count(t.get(&meta)?);
+                let mut added = 0;
+                for (member, score) in members {
+                    if zset_put(t, key, member, *score)? {
+                        added += 1;
+                    }
+                }
+                set_count(t, meta, len + added as u64)?;
+                Ok(added)
+            })
+            .map_err(to_kv_error)
+    }
+
+    fn zset_incr(&self, key: &str, member: &str, delta: f64) -> Result<f64, KvError> {
+        self.zset
+            .transaction(|t| {
+                let current = t.get(set_member_key(key, member))?;
+                let current = current.map(|s| decode_score(&s).into());
+                let score =
+                    add_float(current, delta).map_err(ConflictableTransactionError::Abort)?;
+                if zset_put(t, key, member, score)? {
+                    let meta = meta_key(key);
+                    let len = decode_count(t.get(&meta)?);
+                    set_count(t, meta, len + 1)?;
+                }
+                Ok(score)
+            })
+            .map_err(to_kv_error)
+    }
+
+    fn zset_remove(&self, key: &str, members: &[String]) -> Result<usize, KvError> {
+        self.zset
+            .transaction(|t| {
+                let meta = meta_key(key);
+                let len = decode_count(t.get(&meta)?);
+                let mut removed = 0;
+                for member in members {
+                    if let Some(score) = t.remove(set_member_key(key, member))? {
+                        t.remove(zset_score_key(key, decode_score(&score), member))?;
+                        removed += 1;
+                    }
+                }
+                set_count(t, meta, len.saturating_sub(removed as u64))?;
+                Ok(removed)
+            })
+            .map_err(to_kv_error)
+    }
+
+    // 索引按 score 和 member 排序，跳过前面的排名就能找到要的范围
+    fn zset_range(&self, key: &str, start: i64, stop: i64) -> Result<Vec<(String, f64)>, KvError> {
+        let (from, to) = match list_bounds(self.zset_len(key)?, start, stop) {
+            Some(bounds) => bounds,
+            None => return Ok(Vec::new()),
+        };
+        let prefix = key_prefix(SCORE, key);
+        self.zset
+            .scan_prefix(&prefix)
+            .skip(from)
+            .take(to - from)
+            .map(|item| Ok(decode_score_key(prefix.len(), &item?.0)))
+            .collect()
+    }
+
+    fn zset_range_by_score(
+        &self,
+        key: &str,
+        min: f64,
+        max: f64,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<(String, f64)>, KvError> {
+        let prefix = key_prefix(SCORE, key);
+        let limit = if limit == 0 { usize::MAX } else { limit };
+        let (mut skipped, mut members) = (0, Vec::new());
+        for item in self.zset.range(zset_score_key(key, min, "")..) {
+            let (k, _) = item?;
+            if !k.starts_with(&prefix) || members.len() == limit {
+                break;
+            }
+            let (member, score) = decode_score_key(prefix.len(), &k);
+            if score > max {
+                break;
+            }
+            if skipped < offset {
+                skipped += 1;
+                continue;
+            }
+            members.push((member, score));
+        }
+
+        Ok(members)
+    }
+
+    fn zset_score(&self, key: &str, member: &str) -> Result<Option<f64>, KvError> {
+        let score = self.zset.get(set_member_key(key, member))?;
+        Ok(score.map(|s| decode_score(&s)))
+    }
+
+    // 排名就是索引中排在 member 前面的数量
+    fn zset_rank(&self, key: &str, member: &str) -> Result<Option<usize>, KvError> {
+        let score = match self.zset_score(key, member)? {
+            Some(score) => score,
+            None => return Ok(None),
+        };
+        let rank = self
+            .zset
+            .range(key_prefix(SCORE, key)..zset_score_key(key, score, member))
+            .count();
+
+        Ok(Some(rank))
+    }
+
+    fn zset_len(&self, key: &str) -> Result<usize, KvError> {
+        Ok(decode_count(self.zset.get(meta_key(key))?) as usize)
+    }
+}
+
+impl Storage for SledDb {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        match self.get_table(table) {
+            Some(t) => t.get(key),
+            None => Ok(None),
+        }
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        let (_schema, t) = self.write_table(table)?;
+        t.insert(&key, value, None)
+    }
+
+    fn set_with_ttl(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> Result<Option<Value>, KvError> {
+        let at = deadline(ttl);
+        let (_schema, t) = self.write_table(table)?;
+        t.insert(&key, value, Some(at))
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        match self.get_table(table) {
+            Some(t) => Ok(!t.purge_if_expired(key.as_bytes())? && t.data.contains_key(key)?),
+            None => Ok(false),
+        }
     }
 
     fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
-        let name = SledDb::get_full_key(table, &key);
-        let result = self.0.remove(name)?.map(|v| v.as_ref().try_into());
+        let (_schema, t) = self.existing_table(table);
+        let t = match t {
+            Some(t) => t,
+            None => return Ok(None),
+        };
+        if t.purge_if_expired(key.as_bytes())? {
+            return Ok(None);
+        }
+        let old = t
+            .trees()
+            .as_slice()
+            .transaction(|t| {
+                t[1].remove(key)?;
+                let old = t[0].remove(key)?;
+                reindex(t.get(2), key.as_bytes(), old.as_deref(), None)?;
+                Ok(old)
+            })
+            .map_err(to_kv_error)?;
 
-        result.transpose()
+        old.map(|v| v.as_ref().try_into()).transpose()
     }
 
     fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
-        let prefix = SledDb::get_table_prefix(table);
-        let result = self.0.scan_prefix(prefix).map(|v| v.into()).collect();
+        Ok(self.get_iter(table)?.collect())
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        match self.get_table(table) {
+            Some(t) => Ok(Box::new(t.iter())),
+            None => Ok(Box::new(std::iter::empty())),
+        }
+    }
+
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        let mut tables: Vec<_> = self
+            .tables
+            .iter()
+            .filter(|t| !t.value().is_empty())
+            .map(|t| t.key().clone())
+            .collect();
+        tables.sort();
+
+        Ok(tables)
+    }
+
+    // 持有 schema 的写锁，正在写入这个 table 的操作完成之后才删除它的 tree
+    fn drop_table(&self, table: &str) -> Result<bool, KvError> {
+        let _schema = self.schema.write().unwrap();
+        let t = match self.tables.remove(table) {
+            Some((_, t)) => t,
+            None => return Ok(false),
+        };
+        let existed = !t.is_empty();
+        self.db.drop_tree(t.data.name())?;
+        self.db.drop_tree(t.expiry.name())?;
+        self.db.drop_tree(t.list.name())?;
+        self.db.drop_tree(t.set.name())?;
+        self.db.drop_tree(t.zset.name())?;
+        // 索引的定义保留下来，table 重新写入数据时继续使用
+        if let Some(index) = t.index {
+            self.db.drop_tree(index.name())?;
+        }
+
+        Ok(existed)
+    }
+
+    fn table_len(&self, table: &str) -> Result<usize, KvError> {
+        Ok(self
+            .get_table(table)
+            .map(|t| t.iter().count())
+            .unwrap_or_default())
+    }
+
+    // sled 里的 key 是有序的，直接从 range 的下界开始遍历，超出范围就停止
+    fn scan(&self, table: &str, range: &KeyRange, limit: usize) -> Result<Vec<Kvpair>, KvError> {
+        let t = match self.get_table(table) {
+            Some(t) => t,
+            None => return Ok(Vec::new()),
+        };
+
+        let mut result = Vec::new();
+        for item in t
+            .data
+            .range::<&str, _>((range.lower_bound(), Bound::Unbounded))
+        {
+            if result.len() >= limit {
+                break;
+            }
+            let (k, v) = item?;
+            if t.is_expired(&k)? {
+                continue;
+            }
+            let pair = Kvpair::from(Ok((k, v)));
+            if range.is_past(&pair.key) {
+                break;
+            }
+            if range.contains(&pair.key) {
+                result.push(pair);
+            }
+        }
 
         Ok(result)
     }
 
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        let (_schema, t) = self.existing_table(table);
+        let t = match t {
+            Some(t) => t,
+            None => return Ok(false),
+        };
+        if t.purge_if_expired(key.as_bytes())? {
+            return Ok(false);
+        }
+        let at = deadline(ttl);
+        let result = (&t.data, &t.expiry)
+            .transaction(|(data, expiry)| {
+                if data.get(key)?.is_none() {
+                    return Ok(false);
+                }
+                expiry.insert(key, &at.to_be_bytes())?;
+                Ok(true)
+            })
+            .map_err(to_kv_error)?;
+
+        Ok(result)
+    }
+
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Duration>, KvError> {
+        let t = match self.get_table(table) {
+            Some(t) if !t.purge_if_expired(key.as_bytes())? && t.data.contains_key(key)? => t,
+            _ => return Err(KvError::NotFound(table.into(), key.into())),
+        };
+
+        Ok(t.expiry
+            .get(key)?
+            .map(|at| Duration::from_millis(ivec_to_timestamp(&at).saturating_sub(now()))))
+    }
+
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        let (_schema, t) = self.existing_table(table);
+        match t {
+            Some(t) if !t.purge_if_expired(key.as_bytes())? => Ok(t.expiry.remove(key)?.is_some()),
+            _ => Ok(false),
+        }
+    }
+
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        {
+            let (_schema, t) = self.write_table(table)?;
+            if t.index.is_none() {
+                return t.update(key, |v| add_integer(v, delta));
+            }
+        }
+        self.update_in_txn(table, |txn| txn.incr(table, key, delta))
+    }
+
+    fn incr_float(&self, table: &str, key: &str, delta: f64) -> Result<f64, KvError> {
+        {
+            let (_schema, t) = self.write_table(table)?;
+            if t.index.is_none() {
+                return t.update(key, |v| add_float(v, delta));
+            }
+        }
+        self.update_in_txn(table, |txn| txn.incr_float(table, key, delta))
+    }
+
+    fn update_path(
+        &self,
+        table: &str,
+        key: &str,
+        path: &[PathSegment],
+        op: PathOp,
+    ) -> Result<Option<Value>, KvError> {
+        {
+            let (_schema, t) = self.write_table(table)?;
+            if t.index.is_none() {
+                return t.update_with(key, |v| apply_path(table, key, v, path, op.clone()));
+            }
+        }
+        self.update_in_txn(table, |txn| txn.update_path(table, key, path, op.clone()))
+    }
+
+    // 没有索引的 table 在 data 和 expiry 两个 tree 的 sled 事务里比较、写入并清除过期时间，
+    // 和并发的 set_with_ttl 不会交错；有索引时和索引一起在事务里完成。冲突时由 sled 重试
+    fn compare_and_swap(
+        &self,
+        table: &str,
+        key: &str,
+        expected: Option<&Value>,
+        value: Value,
+    ) -> Result<Result<(), Option<Value>>, KvError> {
+        {
+            let (_schema, t) = self.write_table(table)?;
+            if t.index.is_none() {
+                let old: Option<Vec<u8>> = expected.cloned().map(Vec::try_from).transpose()?;
+                let new: Vec<u8> = value.try_into()?;
+                let result = [t.data.clone(), t.expiry.clone()]
+                    .as_slice()
+                    .transaction(|t| {
+                        // 过期的 key 当作不存在
+                        let current = match t[1].get(key)? {
+                            Some(at) if is_past(&at) => None,
+                            _ => t[0].get(key)?,
+                        };
+                        if current.as_deref() != old.as_deref() {
+                            return Ok(Err(current));
+                        }
+                        t[1].remove(key)?;
+                        t[0].insert(key, new.as_slice())?;
+                        Ok(Ok(()))
+                    })
+                    .map_err(to_kv_error)?;
+                return match result {
+                    Ok(()) => Ok(Ok(())),
+                    Err(current) => Ok(Err(current
+                        .map(|v| Value::try_from(v.as_ref()))
+                        .transpose()?)),
+                };
+            }
+        }
+        let mut result = Ok(());
+        self.transaction(&[table], &mut |txn| {
+            result = txn.compare_and_swap(table, key, expected, value.clone())?;
+            Ok(())
+        })?;
+
+        Ok(result)
+    }
+
+    // 索引建立之前写入的数据在这里补上索引项。和并发的写入交错时可能留下旧 value 的索引项，
+    // 查找时会检查并清理
+    fn create_index(&self, table: &str) -> Result<bool, KvError> {
+        let t = {
+            let _schema = self.schema.write().unwrap();
+            let mut t = self.open_table(table)?;
+            if t.index.is_some() {
+                return Ok(false);
+            }
+            t.index = Some(open_index(&self.db, table)?);
+            self.tables.insert(table.into(), t.clone());
+            t
+        };
+
+        let index = t.index.as_ref().unwrap();
+        for item in t.data.iter() {
+            let (key, value) = item?;
+            index.insert(index_key(&value, &key), IVec::default())?;
+        }
+        // 补完索引项之后才记录下来，中途退出时重新打开的 table 没有索引
+        self.db
+            .open_tree(INDEXES_TREE)?
+            .insert(table, IVec::default())?;
+
+        Ok(true)
+    }
+
+    fn find(&self, table: &str, value: &Value) -> Result<Vec<Kvpair>, KvError> {
+        let t = match self.get_table(table) {
+            Some(t) => t,
+            None => return Ok(Vec::new()),
+        };
+        match &t.index {
+            Some(index) => {
+                let data: Vec<u8> = value.clone().try_into()?;
+                t.find(index, &data, value)
+            }
+            None => Ok(t
+                .iter()
+                .filter(|pair| pair.value.as_ref() == Some(value))
+                .collect()),
+        }
+    }
+
+    fn list_push(
+        &self,
+        table: &str,
+        key: &str,
+        end: ListEnd,
+        values: Vec<Value>,
+    ) -> Result<usize, KvError> {
+        let values = values
+            .into_iter()
+            .map(Vec::<u8>::try_from)
+            .collect::<Result<Vec<_>, _>>()?;
+        let (_schema, t) = self.write_table(table)?;
+        t.list_push(key, end, &values)
+    }
+
+    fn list_pop(
+        &self,
+        table: &str,
+        key: &str,
+        end: ListEnd,
+        count: usize,
+    ) -> Result<Vec<Value>, KvError> {
+        let (_schema, t) = self.existing_table(table);
+        match t {
+            Some(t) => t.list_pop(key, end, count),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn list_range(
+        &self,
+        table: &str,
+        key: &str,
+        start: i64,
+        stop: i64,
+    ) -> Result<Vec<Value>, KvError> {
+        match self.get_table(table) {
+            Some(t) => t.list_range(key, start, stop),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn list_trim(&self, table: &str, key: &str, start: i64, stop: i64) -> Result<usize, KvError> {
+        let (_schema, t) = self.existing_table(table);
+        match t {
+            Some(t) => t.list_trim(key, start, stop),
+            None => Ok(0),
+        }
+    }
+
+    fn list_len(&self, table: &str, key: &str) -> Result<usize, KvError> {
+        match self.get_table(table) {
+            Some(t) => t.list_len(key),
+            None => Ok(0),
+        }
+    }
+
+    fn list_index(&self, table: &str, key: &str, index: i64) -> Result<Option<Value>, KvError> {
+        match self.get_table(table) {
+            Some(t) => t.list_index(key, index),
+            None => Ok(None),
+        }
+    }
+
+    fn list_keys(&self, table: &str) -> Result<Vec<String>, KvError> {
+        match self.get_table(table) {
+            Some(t) => collection_keys(&t.list),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn set_add(&self, table: &str, key: &str, members: Vec<String>) -> Result<usize, KvError> {
+        let (_schema, t) = self.write_table(table)?;
+        t.set_add(key, &members)
+    }
+
+    fn set_remove(&self, table: &str, key: &str, members: Vec<String>) -> Result<usize, KvError> {
+        let (_schema, t) = self.existing_table(table);
+        match t {
+            Some(t) => t.set_remove(key, &members),
+            None => Ok(0),
+        }
+    }
+
+    fn set_members(&self, table: &str, key: &str) -> Result<Vec<String>, KvError> {
+        match self.get_table(table) {
+            Some(t) => t.set_members(key),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn set_contains(&self, table: &str, key: &str, member: &str) -> Result<bool, KvError> {
+        match self.get_table(table) {
+            Some(t) => t.set_contains(key, member),
+            None => Ok(false),
+        }
+    }
+
+    fn set_len(&self, table: &str, key: &str) -> Result<usize, KvError> {
+        match self.get_table(table) {
+            Some(t) => t.set_len(key),
+            None => Ok(0),
+        }
+    }
+
+    fn set_keys(&self, table: &str) -> Result<Vec<String>, KvError> {
+        match self.get_table(table) {
+            Some(t) => collection_keys(&t.set),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn zset_add(
+        &self,
+        table: &str,
+        key: &str,
+        members: Vec<(String, f64)>,
+    ) -> Result<usize, KvError> {
+        let members = members
+            .into_iter()
+            .map(|(member, score)| Ok((member, check_score(score)?)))
+            .collect::<Result<Vec<_>, KvError>>()?;
+        let (_schema, t) = self.write_table(table)?;
+        t.zset_add(key, &members)
+    }
+
+    fn zset_incr(&self, table: &str, key: &str, member: &str, delta: f64) -> Result<f64, KvError> {
+        let (_schema, t) = self.write_table(table)?;
+        t.zset_incr(key, member, delta)
+    }
+
+    fn zset_remove(&self, table: &str, key: &str, members: Vec<String>) -> Result<usize, KvError> {
+        let (_schema, t) = self.existing_table(table);
+        match t {
+            Some(t) => t.zset_remove(key, &members),
+            None => Ok(0),
+        }
+    }
+
+    fn zset_range(
+        &self,
+        table: &str,
+        key: &str,
+        start: i64,
+        stop: i64,
+    ) -> Result<Vec<(String, f64)>, KvError> {
+        match self.get_table(table) {
+            Some(t) => t.zset_range(key, start, stop),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn zset_range_by_score(
+        &self,
+        table: &str,
+        key: &str,
+        min: f64,
+        max: f64,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<(String, f64)>, KvError> {
+        match self.get_table(table) {
+            Some(t) => t.zset_range_by_score(key, min, max, offset, limit),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn zset_score(&self, table: &str, key: &str, member: &str) -> Result<Option<f64>, KvError> {
+        match self.get_table(table) {
+            Some(t) => t.zset_score(key, member),
+            None => Ok(None),
+        }
+    }
+
+    fn zset_rank(&self, table: &str, key: &str, member: &str) -> Result<Option<usize>, KvError> {
+        match self.get_table(table) {
+            Some(t) => t.zset_rank(key, member),
+            None => Ok(None),
+        }
+    }
+
+    fn zset_len(&self, table: &str, key: &str) -> Result<usize, KvError> {
+        match self.get_table(table) {
+            Some(t) => t.zset_len(key),
+            None => Ok(0),
+        }
+    }
+
+    fn zset_keys(&self, table: &str) -> Result<Vec<String>, KvError> {
+        match self.get_table(table) {
+            Some(t) => collection_keys(&t.zset),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn transaction(
+        &self,
+        tables: &[&str],
+        f: &mut dyn FnMut(&dyn Storage) -> Result<(), KvError>,
+    ) -> Result<(), KvError> {
+        // sled 的事务需要事先打开所有用到的 tree，每个 table 依次放入数据、过期时间和索引的 tree
+        let _schema = self.schema.read().unwrap();
+        let mut names = tables.to_vec();
+        names.sort_unstable();
+        names.dedup();
+        let mut trees = Vec::with_capacity(names.len() * 3);
+        let mut counts = Vec::with_capacity(names.len());
+        for name in &names {
+            let t = self.open_table(name)?.trees();
+            counts.push(t.len());
+            trees.extend(t);
+        }
+
+        let f = RefCell::new(f);
+        trees
+            .as_slice()
+            .transaction(|trees| {
+                let mut tables = HashMap::with_capacity(names.len());
+                let mut rest = &trees[..];
+                for (name, n) in names.iter().zip(&counts) {
+                    let (t, next) = rest.split_at(*n);
+                    tables.insert(*name, t);
+                    rest = next;
+                }
+                let txn = SledTxn {
+                    tables,
+                    error: Cell::new(None),
+                };
+                match (f.borrow_mut())(&txn) {
+                    Ok(()) => Ok(()),
+                    // sled 内部的冲突或存储错误要交还给 sled，冲突时它会重试整个事务
+                    Err(e) => match txn.error.take() {
+                        Some(error) => Err(error.into()),
+                        None => Err(ConflictableTransactionError::Abort(e)),
+                    },
+                }
+            })
+            .map_err(to_kv_error)
+    }
+}
+
+/// SledDb 的事务视图，所有操作都在同一个 sled 事务里执行，只能访问事务开始时声明的 table
+struct SledTxn<'a> {
+    // 每个 table 的数据、过期时间和索引（如果有）
+    tables: HashMap<&'a str, &'a [TransactionalTree]>,
+    // 记录 sled 返回的冲突或存储错误
+    error: Cell<Option<UnabortableTransactionError>>,
+}
+
+impl SledTxn<'_> {
+    fn check<T>(&self, result: Result<T, UnabortableTransactionError>) -> Result<T, KvError> {
+        result.map_err(|e| {
+            let err = KvError::Internal(e.to_string());
+            self.error.set(Some(e));
+            err
+        })
+    }
+
+    fn table(&self, table: &str) -> Result<(&TransactionalTree, &TransactionalTree), KvError> {
+        match self.tables.get(table) {
+            Some(t) => Ok((&t[0], &t[1])),
+            None => Err(KvError::InvalidCommand(format!(
+                "Table {} is not declared in transaction",
+                table
+            ))),
+        }
+    }
+
+    fn reindex(
+        &self,
+        table: &str,
+        key: &str,
+        old: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<(), KvError> {
+        let index = self.tables.get(table).and_then(|t| t.get(2));
+        self.check(reindex(index, key.as_bytes(), old, new))
+    }
+
+    fn purge_if_expired(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        let (data, expiry) = self.table(table)?;
+        match self.check(expiry.get(key))? {
+            Some(at) if is_past(&at) => {
+                self.check(expiry.remove(key))?;
+                let old = self.check(data.remove(key))?;
+                self.reindex(table, key, old.as_deref(), None)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn insert(
+        &self,
+        table: &str,
+        key: &str,
+        value: Value,
+        expire_at: Option<u64>,
+    ) -> Result<Option<Value>, KvError> {
+        self.purge_if_expired(table, key)?;
+        let (data, expiry) = self.table(table)?;
+        let value: Vec<u8> = value.try_into()?;
+        match expire_at {
+            Some(at) => self.check(expiry.insert(key, &at.to_be_bytes()))?,
+            None => self.check(expiry.remove(key))?,
+        };
+        let old = self.check(data.insert(key, value.as_slice()))?;
+        self.reindex(table, key, old.as_deref(), Some(&value))?;
+
+        old.map(|v| v.as_ref().try_into()).transpose()
+    }
+}
+
+impl Storage for SledTxn<'_> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        if self.purge_if_expired(table, key)? {
+            return Ok(None);
+        }
+        let (data, _) = self.table(table)?;
+        let result = self.check(data.get(key))?;
+
+        result.map(|v| v.as_ref().try_into()).transpose()
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        self.insert(table, &key, value, None)
+    }
+
+    fn set_with_ttl(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> Result<Option<Value>, KvError> {
+        let at = deadline(ttl);
+        self.insert(table, &key, value, Some(at))
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        Ok(self.get(table, key)?.is_some())
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        if self.purge_if_expired(table, key)? {
+            return Ok(None);
+        }
+        let (data, expiry) = self.table(table)?;
+        self.check(expiry.remove(key))?;
+        let old = self.check(data.remove(key))?;
+        self.reindex(table, key, old.as_deref(), None)?;
+
+        old.map(|v| v.as_ref().try_into()).transpose()
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        Ok(self.get_iter(table)?.collect())
+    }
+
     fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
-        let prefix = SledDb::get_table_prefix(table);
-        let iter = StorageIter::new(self.0.scan_prefix(prefix));
+        Err(cannot_iterate(table))
+    }
 
-        Ok(Box::new(iter))
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        Err(cannot_iterate("*"))
+    }
+
+    fn drop_table(&self, table: &str) -> Result<bool, KvError> {
+        Err(cannot_iterate(table))
+    }
+
+    fn table_len(&self, table: &str) -> Result<usize, KvError> {
+        Err(cannot_iterate(table))
+    }
+
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        if !self.contains(table, key)? {
+            return Ok(false);
+        }
+        let (_, expiry) = self.table(table)?;
+        let at = deadline(ttl);
+        self.check(expiry.insert(key, &at.to_be_bytes()))?;
+
+        Ok(true)
+    }
+
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Duration>, KvError> {
+        if !self.contains(table, key)? {
+            return Err(KvError::NotFound(table.into(), key.into()));
+        }
+        let (_, expiry) = self.table(table)?;
+        let at = self.check(expiry.get(key))?;
+
+        Ok(at.map(|at| Duration::from_millis(ivec_to_timestamp(&at).saturating_sub(now()))))
+    }
+
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        if !self.contains(table, key)? {
+            return Ok(false);
+        }
+        let (_, expiry) = self.table(table)?;
+
+        Ok(self.check(expiry.remove(key))?.is_some())
+    }
+
+    // sled 事务中读写冲突时整个事务会重试
+    fn compare_and_swap(
+        &self,
+        table: &str,
+        key: &str,
+        expected: Option<&Value>,
+        value: Value,
+    ) -> Result<Result<(), Option<Value>>, KvError> {
+        serial_compare_and_swap(self, table, key, expected, value)
+    }
+
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        serial_incr(self, table, key, delta)
+    }
+
+    fn incr_float(&self, table: &str, key: &str, delta: f64) -> Result<f64, KvError> {
+        serial_incr_float(self, table, key, delta)
+    }
+
+    fn update_path(
+        &self,
+        table: &str,
+        key: &str,
+        path: &[PathSegment],
+        op: PathOp,
+    ) -> Result<Option<Value>, KvError> {
+        serial_update_path(self, table, key, path, op)
+    }
+
+    fn transaction(
+        &self,
+        _tables: &[&str],
+        _f: &mut dyn FnMut(&dyn Storage) -> Result<(), KvError>,
+    ) -> Result<(), KvError> {
+        Err(KvError::InvalidCommand("Nested transaction".into()))
+    }
+}
+
+impl Drop for SledDb {
+    fn drop(&mut self) {
+        if let Some((tx, handle)) = self.reaper.take() {
+            drop(tx);
+            let _ = handle.join();
+        }
     }
 }
 
@@ -71,7 +1381,7 @@ impl From<Result<(IVec, IVec), sled::Error>> for Kvpair {
     fn from(v: Result<(IVec, IVec), sled::Error>) -> Self {
         match v {
             Ok((k, v)) => match v.as_ref().try_into() {
-                Ok(v) => Kvpair::new(ivec_to_key(k.as_ref()), v),
+                Ok(v) => Kvpair::new(String::from_utf8_lossy(&k), v),
                 Err(_) => Kvpair::default(),
             },
             _ => Kvpair::default(),
@@ -79,9 +1389,283 @@ impl From<Result<(IVec, IVec), sled::Error>> for Kvpair {
     }
 }
 
-fn ivec_to_key(ivec: &[u8]) -> &str {
-    let s = str::from_utf8(ivec).unwrap();
-    let mut iter = s.split(":");
-    iter.next();
-    iter.next().unwrap()
+fn is_past(at: &IVec) -> bool {
+    ivec_to_timestamp(at) <= now()
+}
+
+fn ivec_to_timestamp(ivec: &IVec) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&ivec[..8]);
+    u64::from_be_bytes(buf)
+}
+
+// 同一个进程里刚释放的 db，sled 后台的 io 线程可能还没有释放文件锁，等一会儿再重试
+fn open_db(path: &Path) -> sled::Result<Db> {
+    let mut retries = 0;
+    loop {
+        match sled::open(path) {
+            Err(sled::Error::Io(e))
+                if retries < LOCK_RETRIES && e.to_string().contains("could not acquire lock") =>
+            {
+                retries += 1;
+                thread::sleep(LOCK_RETRY_INTERVAL);
+            }
+            result => return result,
+        }
+    }
+}
+
+// 当前时间戳（毫秒），过期时间需要在重启后依然有效，所以不能用 Instant
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+// ttl 之后的时间戳（毫秒），ttl 太大时取 u64::MAX，相当于不过期
+fn deadline(ttl: Duration) -> u64 {
+    now().saturating_add(ttl.as_millis().try_into().unwrap_or(u64::MAX))
+}
+
+// sled 的事务不支持遍历
+fn cannot_iterate(table: &str) -> KvError {
+    KvError::InvalidCommand(format!("Cannot iterate table {} in transaction", table))
+}
+
+fn open_index(db: &Db, table: &str) -> Result<Tree, KvError> {
+    Ok(db.open_tree(format!("{}{}", INDEX_TREE_PREFIX, table))?)
+}
+
+// 索引项的 key：value 编码的长度、value 的编码和原始的 key。key 为空时就是查找 value 用的前缀
+fn index_key(value: &[u8], key: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(4 + value.len() + key.len());
+    buf.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    buf.extend_from_slice(value);
+    buf.extend_from_slice(key);
+    buf
+}
+
+// 在事务里更新 key 的索引项：去掉旧 value 的，加上新 value 的。没有索引时什么都不做
+fn reindex(
+    index: Option<&TransactionalTree>,
+    key: &[u8],
+    old: Option<&[u8]>,
+    new: Option<&[u8]>,
+) -> Result<(), UnabortableTransactionError> {
+    let index = match index {
+        Some(index) => index,
+        None => return Ok(()),
+    };
+    if let Some(old) = old {
+        index.remove(index_key(old, key))?;
+    }
+    if let Some(new) = new {
+        index.insert(index_key(new, key), IVec::default())?;
+    }
+
+    Ok(())
+}
+
+// list、set 或 sorted set 的元数据的 key
+fn meta_key(key: &str) -> Vec<u8> {
+    let mut meta = Vec::with_capacity(1 + key.len());
+    meta.push(META);
+    meta.extend_from_slice(key.as_bytes());
+    meta
+}
+
+// 一个 list、set 或 sorted set 的所有元素共同的前缀。带上 key 的长度，
+// 一个 key 的元素不会和更长的 key 的元素交错
+fn key_prefix(tag: u8, key: &str) -> Vec<u8> {
+    let mut prefix = Vec::with_capacity(1 + 4 + key.len() + 8);
+    prefix.push(tag);
+    prefix.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    prefix.extend_from_slice(key.as_bytes());
+    prefix
+}
+
+// 下标的符号位取反之后按大端序编码，负数的下标排在前面
+fn list_item_key(key: &str, index: i64) -> Vec<u8> {
+    let mut item = key_prefix(ITEM, key);
+    item.extend_from_slice(&((index as u64) ^ (1 << 63)).to_be_bytes());
+    item
+}
+
+fn set_member_key(key: &str, member: &str) -> Vec<u8> {
+    let mut item = key_prefix(ITEM, key);
+    item.extend_from_slice(member.as_bytes());
+    item
+}
+
+fn zset_score_key(key: &str, score: f64, member: &str) -> Vec<u8> {
+    let mut item = key_prefix(SCORE, key);
+    item.extend_from_slice(&encode_score(score));
+    item.extend_from_slice(member.as_bytes());
+    item
+}
+
+// 按字节序比较编码之后的 score 和比较 score 本身的顺序一样：
+// 正数把符号位置为 1，负数把所有位取反
+fn encode_score(score: f64) -> [u8; 8] {
+    let bits = score.to_bits();
+    let bits = if bits >> 63 == 1 {
+        !bits
+    } else {
+        bits | 1 << 63
+    };
+    bits.to_be_bytes()
+}
+
+fn decode_score(data: &[u8]) -> f64 {
+    let bits = u64::from_be_bytes(data.try_into().unwrap());
+    let bits = if bits >> 63 == 1 {
+        bits & !(1 << 63)
+    } else {
+        !bits
+    };
+    f64::from_bits(bits)
+}
+
+// 从索引的 key 中取出 member 和 score
+fn decode_score_key(prefix_len: usize, k: &[u8]) -> (String, f64) {
+    let (score, member) = k[prefix_len..].split_at(8);
+    (
+        String::from_utf8_lossy(member).into_owned(),
+        decode_score(score),
+    )
+}
+
+// 写入 member 的 score 并更新索引，返回 member 之前是否不存在
+fn zset_put(
+    t: &TransactionalTree,
+    key: &str,
+    member: &str,
+    score: f64,
+) -> Result<bool, UnabortableTransactionError> {
+    let old = t.insert(set_member_key(key, member), &encode_score(score))?;
+    if let Some(old) = &old {
+        t.remove(zset_score_key(key, decode_score(old), member))?;
+    }
+    t.insert(zset_score_key(key, score, member), IVec::default())?;
+
+    Ok(old.is_none())
+}
+
+// 更新 set 或 sorted set 的元数据，变空时删除
+fn set_count(
+    t: &TransactionalTree,
+    meta: Vec<u8>,
+    len: u64,
+) -> Result<(), UnabortableTransactionError> {
+    if len > 0 {
+        t.insert(meta, &len.to_be_bytes())?;
+    } else {
+        t.remove(meta)?;
+    }
+
+    Ok(())
+}
+
+// 所有 META 开头的 key 去掉 META 就是 list、set 或 sorted set 的 key
+fn collection_keys(tree: &Tree) -> Result<Vec<String>, KvError> {
+    tree.scan_prefix([META])
+        .map(|item| {
+            let (k, _) = item?;
+            Ok(String::from_utf8_lossy(&k[1..]).into_owned())
+        })
+        .collect()
+}
+
+fn encode_list_meta(head: i64, tail: i64) -> Vec<u8> {
+    let mut meta = head.to_be_bytes().to_vec();
+    meta.extend_from_slice(&tail.to_be_bytes());
+    meta
+}
+
+// 不存在的 list 相当于空的 list
+fn decode_list_meta(meta: Option<IVec>) -> (i64, i64) {
+    match meta {
+        Some(meta) if meta.len() == 16 => (
+            i64::from_be_bytes(meta[..8].try_into().unwrap()),
+            i64::from_be_bytes(meta[8..].try_into().unwrap()),
+        ),
+        _ => (0, 0),
+    }
+}
+
+// set 或 sorted set 中 member 的数量，不存在的相当于空的
+fn decode_count(meta: Option<IVec>) -> u64 {
+    match meta {
+        Some(meta) if meta.len() == 8 => u64::from_be_bytes(meta[..].try_into().unwrap()),
+        _ => 0,
+    }
+}
+
+// 更新 list 的元数据，list 变空时删除
+fn set_list_meta(
+    t: &TransactionalTree,
+    meta: Vec<u8>,
+    head: i64,
+    tail: i64,
+) -> Result<(), UnabortableTransactionError> {
+    if head < tail {
+        t.insert(meta, encode_list_meta(head, tail))?;
+    } else {
+        t.remove(meta)?;
+    }
+
+    Ok(())
+}
+
+fn to_kv_error(e: TransactionError<KvError>) -> KvError {
+    match e {
+        TransactionError::Abort(e) => e,
+        TransactionError::Storage(e) => e.into(),
+    }
+}
+
+// 把旧版本放在默认 tree 里的 {table}:{key} 迁移到每个 table 自己的 tree 里。
+// 每个 key 先写入新的 tree 再从默认 tree 删除，迁移中途退出后再次打开可以继续
+fn migrate_legacy_layout(db: &Db) -> Result<(), KvError> {
+    if db.is_empty() {
+        return Ok(());
+    }
+    info!("Migrating {} keys to one tree per table", db.len());
+
+    let legacy_expiry = db.open_tree(LEGACY_EXPIRY_TREE)?;
+    for item in db.iter() {
+        let (name, value) = item?;
+        // 旧版本的 table 名里没有 ':'，第一个 ':' 之后的都是 key
+        let (table, key) = match name.iter().position(|c| *c == b':') {
+            Some(pos) => (&name[..pos], &name[pos + 1..]),
+            None => continue,
+        };
+        let trees = TableTrees::open(db, &String::from_utf8_lossy(table))?;
+        trees.data.insert(key, value)?;
+        if let Some(at) = legacy_expiry.get(&name)? {
+            trees.expiry.insert(key, at)?;
+        }
+        legacy_expiry.remove(&name)?;
+        db.remove(&name)?;
+    }
+    db.drop_tree(LEGACY_EXPIRY_TREE)?;
+
+    Ok(())
+}
+
+// 后台线程定期扫描每个 table 的 expiry tree，清理过期的 key。stop 的发送端被释放后线程退出
+fn spawn_reaper(tables: Tables, stop: Receiver<()>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        while let Err(RecvTimeoutError::Timeout) = stop.recv_timeout(REAP_INTERVAL) {
+            let trees: Vec<_> = tables.iter().map(|t| t.value().clone()).collect();
+            for t in trees {
+                for (key, at) in t.expiry.iter().flatten() {
+                    if is_past(&at) {
+                        let _ = t.remove_expired(&key, &at);
+                    }
+                }
+            }
+        }
+    })
 }
@@ -0,0 +1,872 @@
+use bytes::Bytes;
+use prost::Message;
+use std::{
+    convert::{TryFrom, TryInto},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use super::{serial_compare_and_swap, serial_incr, serial_incr_float, serial_update_path};
+use crate::{KeyRange, KvError, Kvpair, ListEnd, PathOp, PathSegment, Storage, Value, Version};
+
+/// key 的每个版本是内部存储的这个前缀加上 table 名的 table 里单独的一条记录，
+/// key 是原来的 key 加上版本号
+const HISTORY_PREFIX: &str = "__history__/";
+/// key 保留下来的第一个和最后一个版本号放在这个前缀加上 table 名的 table 里
+const HEAD_PREFIX: &str = "__history_head__/";
+
+/// 为每个 key 保留修改的历史：每次写入和删除都在同一个事务里写入一个带时间戳的新版本，
+/// 可以读取 key 的某个版本或者某个时刻的 value。历史和数据存在同一个内部存储里，
+/// 所以内部存储持久化时历史也会持久化。过期被清理的 key 不会产生新的版本。
+///
+/// 历史被 gc_versions 全部清理之后，key 的版本号会从 1 重新开始
+pub struct VersionedStorage<S> {
+    inner: S,
+}
+
+impl<S: Storage> VersionedStorage<S> {
+    pub fn new(inner: S) -> Self {
+        Self { inner }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn versioned(&self) -> Versioned<'_> {
+        Versioned { inner: &self.inner }
+    }
+}
+
+/// 多版本存储的视图，内部可以是一个 Storage 也可以是事务中的 Storage。
+/// 修改操作需要在包含历史 table 的事务中执行，compare_and_swap 和 incr 先读再写，
+/// 通过 set 产生新的版本
+struct Versioned<'a> {
+    inner: &'a dyn Storage,
+}
+
+impl Versioned<'_> {
+    // 给 key 写入一个新版本，value 为 None 表示删除。只读写新版本、最后一个版本和 head，
+    // 不会随着历史变长而变慢
+    fn record(&self, table: &str, key: &str, value: Option<Value>) -> Result<(), KvError> {
+        let (first, version, timestamp) = match load_head(self.inner, table, key)? {
+            Some(head) => {
+                let last = load_version(self.inner, table, key, head.last)?;
+                // 系统时间回拨时时间戳保持不变，history 总是按时间排序的
+                (head.first, head.last + 1, now().max(last.timestamp))
+            }
+            None => (1, 1, now()),
+        };
+        let version = Version {
+            version,
+            timestamp,
+            value,
+        };
+        self.inner.set(
+            &history_table(table),
+            version_key(key, version.version),
+            Bytes::from(version.encode_to_vec()).into(),
+        )?;
+
+        save_head(
+            self.inner,
+            table,
+            key,
+            Some(Head {
+                first,
+                last: version.version,
+            }),
+        )
+    }
+}
+
+impl Storage for Versioned<'_> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.inner.get(table, key)
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        let old = self.inner.set(table, key.clone(), value.clone())?;
+        self.record(table, &key, Some(value))?;
+
+        Ok(old)
+    }
+
+    fn set_with_ttl(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> Result<Option<Value>, KvError> {
+        let old = self
+            .inner
+            .set_with_ttl(table, key.clone(), value.clone(), ttl)?;
+        self.record(table, &key, Some(value))?;
+
+        Ok(old)
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.inner.contains(table, key)
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let old = self.inner.del(table, key)?;
+        if old.is_some() {
+            self.record(table, key, None)?;
+        }
+
+        Ok(old)
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.inner.get_all(table)
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        self.inner.get_iter(table)
+    }
+
+    fn scan(&self, table: &str, range: &KeyRange, limit: usize) -> Result<Vec<Kvpair>, KvError> {
+        self.inner.scan(table, range, limit)
+    }
+
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        list_tables(self.inner)
+    }
+
+    // 为 table 中的每个 key 留下删除的版本
+    fn drop_table(&self, table: &str) -> Result<bool, KvError> {
+        let keys: Vec<_> = self.inner.get_iter(table)?.map(|pair| pair.key).collect();
+        let existed = self.inner.drop_table(table)?;
+        for key in keys {
+            self.record(table, &key, None)?;
+        }
+
+        Ok(existed)
+    }
+
+    fn table_len(&self, table: &str) -> Result<usize, KvError> {
+        self.inner.table_len(table)
+    }
+
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        self.inner.expire(table, key, ttl)
+    }
+
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Duration>, KvError> {
+        self.inner.ttl(table, key)
+    }
+
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.inner.persist(table, key)
+    }
+
+    // 在事务中执行，通过 set 产生新的版本
+    fn compare_and_swap(
+        &self,
+        table: &str,
+        key: &str,
+        expected: Option<&Value>,
+        value: Value,
+    ) -> Result<Result<(), Option<Value>>, KvError> {
+        serial_compare_and_swap(self, table, key, expected, value)
+    }
+
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        serial_incr(self, table, key, delta)
+    }
+
+    fn incr_float(&self, table: &str, key: &str, delta: f64) -> Result<f64, KvError> {
+        serial_incr_float(self, table, key, delta)
+    }
+
+    fn update_path(
+        &self,
+        table: &str,
+        key: &str,
+        path: &[PathSegment],
+        op: PathOp,
+    ) -> Result<Option<Value>, KvError> {
+        serial_update_path(self, table, key, path, op)
+    }
+
+    fn create_index(&self, table: &str) -> Result<bool, KvError> {
+        self.inner.create_index(table)
+    }
+
+    fn find(&self, table: &str, value: &Value) -> Result<Vec<Kvpair>, KvError> {
+        self.inner.find(table, value)
+    }
+
+    fn history(&self, table: &str, key: &str) -> Result<Vec<Version>, KvError> {
+        load(self.inner, table, key)
+    }
+
+    // 只读取 head 和这一个版本
+    fn get_version(&self, table: &str, key: &str, version: u64) -> Result<Option<Value>, KvError> {
+        match load_head(self.inner, table, key)? {
+            Some(head) if (head.first..=head.last).contains(&version) => {
+                Ok(load_version(self.inner, table, key, version)?.value)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    // 版本的时间戳随版本号递增，在 head 的范围里二分查找最后一个不晚于 at 的版本
+    fn get_at(&self, table: &str, key: &str, at: u64) -> Result<Option<Value>, KvError> {
+        let head = match load_head(self.inner, table, key)? {
+            Some(head) => head,
+            None => return Ok(None),
+        };
+        let (mut lo, mut hi) = (head.first, head.last + 1);
+        let mut found = None;
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let version = load_version(self.inner, table, key, mid)?;
+            if version.timestamp <= at {
+                lo = mid + 1;
+                found = Some(version);
+            } else {
+                hi = mid;
+            }
+        }
+
+        Ok(found.and_then(|v| v.value))
+    }
+
+    // 事务中用到的 table 的历史也放进事务里
+    fn transaction(
+        &self,
+        tables: &[&str],
+        f: &mut dyn FnMut(&dyn Storage) -> Result<(), KvError>,
+    ) -> Result<(), KvError> {
+        let histories: Vec<_> = tables
+            .iter()
+            .flat_map(|t| [history_table(t), head_table(t)])
+            .collect();
+        let mut all = tables.to_vec();
+        all.extend(histories.iter().map(String::as_str));
+
+        self.inner
+            .transaction(&all, &mut |txn| f(&Versioned { inner: txn }))
+    }
+}
+
+impl<S: Storage> Storage for VersionedStorage<S> {
+    fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        self.inner.get(table, key)
+    }
+
+    fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+        let mut old = None;
+        self.transaction(&[table], &mut |txn| {
+            old = txn.set(table, key.clone(), value.clone())?;
+            Ok(())
+        })?;
+
+        Ok(old)
+    }
+
+    fn set_with_ttl(
+        &self,
+        table: &str,
+        key: String,
+        value: Value,
+        ttl: Duration,
+    ) -> Result<Option<Value>, KvError> {
+        let mut old = None;
+        self.transaction(&[table], &mut |txn| {
+            old = txn.set_with_ttl(table, key.clone(), value.clone(), ttl)?;
+            Ok(())
+        })?;
+
+        Ok(old)
+    }
+
+    fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.inner.contains(table, key)
+    }
+
+    fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+        let mut old = None;
+        self.transaction(&[table], &mut |txn| {
+            old = txn.del(table, key)?;
+            Ok(())
+        })?;
+
+        Ok(old)
+    }
+
+    fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+        self.inner.get_all(table)
+    }
+
+    fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+        self.inner.get_iter(table)
+    }
+
+    fn scan(&self, table: &str, range: &KeyRange, limit: usize) -> Result<Vec<Kvpair>, KvError> {
+        self.inner.scan(table, range, limit)
+    }
+
+    fn list_tables(&self) -> Result<Vec<String>, KvError> {
+        list_tables(&self.inner)
+    }
+
+    // 有的存储引擎不能在事务里遍历 table，所以先在一个事务里逐个删除 key，再删除整个 table。
+    // 两步之间写入的 key 被删除时不会留下版本
+    fn drop_table(&self, table: &str) -> Result<bool, KvError> {
+        let keys: Vec<_> = self.inner.get_iter(table)?.map(|pair| pair.key).collect();
+        if !keys.is_empty() {
+            self.transaction(&[table], &mut |txn| {
+                for key in &keys {
+                    txn.del(table, key)?;
+                }
+                Ok(())
+            })?;
+        }
+        let existed = self.inner.drop_table(table)?;
+
+        Ok(existed || !keys.is_empty())
+    }
+
+    fn table_len(&self, table: &str) -> Result<usize, KvError> {
+        self.inner.table_len(table)
+    }
+
+    fn expire(&self, table: &str, key: &str, ttl: Duration) -> Result<bool, KvError> {
+        self.inner.expire(table, key, ttl)
+    }
+
+    fn ttl(&self, table: &str, key: &str) -> Result<Option<Duration>, KvError> {
+        self.inner.ttl(table, key)
+    }
+
+    fn persist(&self, table: &str, key: &str) -> Result<bool, KvError> {
+        self.inner.persist(table, key)
+    }
+
+    // 写入和追加版本需要是原子的，不能直接用内部存储的原子操作
+    fn compare_and_swap(
+        &self,
+        table: &str,
+        key: &str,
+        expected: Option<&Value>,
+        value: Value,
+    ) -> Result<Result<(), Option<Value>>, KvError> {
+        let mut result = Ok(());
+        self.transaction(&[table], &mut |txn| {
+            result = txn.compare_and_swap(table, key, expected, value.clone())?;
+            Ok(())
+        })?;
+
+        Ok(result)
+    }
+
+    fn incr(&self, table: &str, key: &str, delta: i64) -> Result<i64, KvError> {
+        let mut result = 0;
+        self.transaction(&[table], &mut |txn| {
+            result = txn.incr(table, key, delta)?;
+            Ok(())
+        })?;
+
+        Ok(result)
+    }
+
+    fn incr_float(&self, table: &str, key: &str, delta: f64) -> Result<f64, KvError> {
+        let mut result = 0.0;
+        self.transaction(&[table], &mut |txn| {
+            result = txn.incr_float(table, key, delta)?;
+            Ok(())
+        })?;
+
+        Ok(result)
+    }
+
+    fn update_path(
+        &self,
+        table: &str,
+        key: &str,
+        path: &[PathSegment],
+        op: PathOp,
+    ) -> Result<Option<Value>, KvError> {
+        let mut result = None;
+        self.transaction(&[table], &mut |txn| {
+            result = txn.update_path(table, key, path, op.clone())?;
+            Ok(())
+        })?;
+
+        Ok(result)
+    }
+
+    fn create_index(&self, table: &str) -> Result<bool, KvError> {
+        self.inner.create_index(table)
+    }
+
+    fn find(&self, table: &str, value: &Value) -> Result<Vec<Kvpair>, KvError> {
+        self.inner.find(table, value)
+    }
+
+    fn history(&self, table: &str, key: &str) -> Result<Vec<Version>, KvError> {
+        self.versioned().history(table, key)
+    }
+
+    fn get_version(&self, table: &str, key: &str, version: u64) -> Result<Option<Value>, KvError> {
+        self.versioned().get_version(table, key, version)
+    }
+
+    fn get_at(&self, table: &str, key: &str, at: u64) -> Result<Option<Value>, KvError> {
+        self.versioned().get_at(table, key, at)
+    }
+
+    fn gc_versions_in(
+        &self,
+        retention: Duration,
+        tables: &dyn Fn(&str) -> bool,
+    ) -> Result<usize, KvError> {
+        let cutoff = now().saturating_sub(retention.as_millis() as u64);
+        let mut count = 0;
+        for heads in self.inner.list_tables()? {
+            let table = match heads.strip_prefix(HEAD_PREFIX) {
+                Some(table) if tables(table) => table,
+                _ => continue,
+            };
+            let history = history_table(table);
+            for pair in self.inner.get_iter(&heads)? {
+                let key = pair.key;
+                let mut versions = load(&self.inner, table, &key)?;
+                if prune(&mut versions, cutoff) == 0 {
+                    continue;
+                }
+                // 在事务里重新读取，不会丢掉刚刚写入的版本
+                let mut removed = 0;
+                self.inner.transaction(&[&heads, &history], &mut |txn| {
+                    removed = remove_before(txn, table, &key, cutoff)?;
+                    Ok(())
+                })?;
+                count += removed;
+            }
+        }
+
+        Ok(count)
+    }
+
+    // list 不保留历史版本
+    fn list_push(
+        &self,
+        table: &str,
+        key: &str,
+        end: ListEnd,
+        values: Vec<Value>,
+    ) -> Result<usize, KvError> {
+        self.inner.list_push(table, key, end, values)
+    }
+
+    fn list_pop(
+        &self,
+        table: &str,
+        key: &str,
+        end: ListEnd,
+        count: usize,
+    ) -> Result<Vec<Value>, KvError> {
+        self.inner.list_pop(table, key, end, count)
+    }
+
+    fn list_range(
+        &self,
+        table: &str,
+        key: &str,
+        start: i64,
+        stop: i64,
+    ) -> Result<Vec<Value>, KvError> {
+        self.inner.list_range(table, key, start, stop)
+    }
+
+    fn list_trim(&self, table: &str, key: &str, start: i64, stop: i64) -> Result<usize, KvError> {
+        self.inner.list_trim(table, key, start, stop)
+    }
+
+    fn list_len(&self, table: &str, key: &str) -> Result<usize, KvError> {
+        self.inner.list_len(table, key)
+    }
+
+    fn list_index(&self, table: &str, key: &str, index: i64) -> Result<Option<Value>, KvError> {
+        self.inner.list_index(table, key, index)
+    }
+
+    fn list_keys(&self, table: &str) -> Result<Vec<String>, KvError> {
+        self.inner.list_keys(table)
+    }
+
+    fn set_add(&self, table: &str, key: &str, members: Vec<String>) -> Result<usize, KvError> {
+        self.inner.set_add(table, key, members)
+    }
+
+    fn set_remove(&self, table: &str, key: &str, members: Vec<String>) -> Result<usize, KvError> {
+        self.inner.set_remove(table, key, members)
+    }
+
+    fn set_members(&self, table: &str, key: &str) -> Result<Vec<String>, KvError> {
+        self.inner.set_members(table, key)
+    }
+
+    fn set_contains(&self, table: &str, key: &str, member: &str) -> Result<bool, KvError> {
+        self.inner.set_contains(table, key, member)
+    }
+
+    fn set_len(&self, table: &str, key: &str) -> Result<usize, KvError> {
+        self.inner.set_len(table, key)
+    }
+
+    fn set_keys(&self, table: &str) -> Result<Vec<String>, KvError> {
+        self.inner.set_keys(table)
+    }
+
+    fn zset_add(
+        &self,
+        table: &str,
+        key: &str,
+        members: Vec<(String, f64)>,
+    ) -> Result<usize, KvError> {
+        self.inner.zset_add(table, key, members)
+    }
+
+    fn zset_incr(&self, table: &str, key: &str, member: &str, delta: f64) -> Result<f64, KvError> {
+        self.inner.zset_incr(table, key, member, delta)
+    }
+
+    fn zset_remove(&self, table: &str, key: &str, members: Vec<String>) -> Result<usize, KvError> {
+        self.inner.zset_remove(table, key, members)
+    }
+
+    fn zset_range(
+        &self,
+        table: &str,
+        key: &str,
+        start: i64,
+        stop: i64,
+    ) -> Result<Vec<(String, f64)>, KvError> {
+        self.inner.zset_range(table, key, start, stop)
+    }
+
+    fn zset_range_by_score(
+        &self,
+        table: &str,
+        key: &str,
+        min: f64,
+        max: f64,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<(String, f64)>, KvError> {
+        self.inner
+            .zset_range_by_score(table, key, min, max, offset, limit)
+    }
+
+    fn zset_score(&self, table: &str, key: &str, member: &str) -> Result<Option<f64>, KvError> {
+        self.inner.zset_score(table, key, member)
+    }
+
+    fn zset_rank(&self, table: &str, key: &str, member: &str) -> Result<Option<usize>, KvError> {
+        self.inner.zset_rank(table, key, member)
+    }
+
+    fn zset_len(&self, table: &str, key: &str) -> Result<usize, KvError> {
+        self.inner.zset_len(table, key)
+    }
+
+    fn zset_keys(&self, table: &str) -> Result<Vec<String>, KvError> {
+        self.inner.zset_keys(table)
+    }
+
+    fn transaction(
+        &self,
+        tables: &[&str],
+        f: &mut dyn FnMut(&dyn Storage) -> Result<(), KvError>,
+    ) -> Result<(), KvError> {
+        self.versioned().transaction(tables, f)
+    }
+}
+
+fn history_table(table: &str) -> String {
+    format!("{}{}", HISTORY_PREFIX, table)
+}
+
+fn head_table(table: &str) -> String {
+    format!("{}{}", HEAD_PREFIX, table)
+}
+
+// 版本号固定 20 位放在最后，key 里有 \0 时也不会和其它 key 的版本冲突
+fn version_key(key: &str, version: u64) -> String {
+    format!("{}\0{:020}", key, version)
+}
+
+// 存放历史的 table 不出现在 list_tables 中
+fn list_tables(store: &dyn Storage) -> Result<Vec<String>, KvError> {
+    let mut tables = store.list_tables()?;
+    tables.retain(|t| !t.starts_with(HISTORY_PREFIX) && !t.starts_with(HEAD_PREFIX));
+
+    Ok(tables)
+}
+
+// 去掉 cutoff 之前的版本，只保留那个时刻可见的版本，返回去掉的数量。
+// 可见的版本是删除产生的时候，读到的结果和没有这个版本一样，也可以去掉
+fn prune(versions: &mut Vec<Version>, cutoff: u64) -> usize {
+    let visible = match versions.iter().rposition(|v| v.timestamp <= cutoff) {
+        Some(i) => i,
+        None => return 0,
+    };
+    let removed = match versions[visible].value {
+        Some(_) => visible,
+        None => visible + 1,
+    };
+    versions.drain(..removed);
+
+    removed
+}
+
+// 删除 key 在 cutoff 之前的版本，只修改被删除的版本和 head
+fn remove_before(
+    store: &dyn Storage,
+    table: &str,
+    key: &str,
+    cutoff: u64,
+) -> Result<usize, KvError> {
+    let head = match load_head(store, table, key)? {
+        Some(head) => head,
+        None => return Ok(0),
+    };
+    let mut versions = load(store, table, key)?;
+    let removed = prune(&mut versions, cutoff);
+    if removed == 0 {
+        return Ok(0);
+    }
+
+    let history = history_table(table);
+    for version in head.first..head.first + removed as u64 {
+        store.del(&history, &version_key(key, version))?;
+    }
+    let head = match versions.is_empty() {
+        true => None,
+        false => Some(Head {
+            first: head.first + removed as u64,
+            last: head.last,
+        }),
+    };
+    save_head(store, table, key, head)?;
+
+    Ok(removed)
+}
+
+/// key 保留下来的版本号范围
+struct Head {
+    first: u64,
+    last: u64,
+}
+
+fn load_head(store: &dyn Storage, table: &str, key: &str) -> Result<Option<Head>, KvError> {
+    let heads = head_table(table);
+    let value = match store.get(&heads, key)? {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+    let buf = Bytes::try_from(value)
+        .ok()
+        .filter(|buf| buf.len() == 16)
+        .ok_or_else(|| {
+            KvError::Corrupted(format!("table: {}, key: {}: bad history head", heads, key))
+        })?;
+    let (first, last) = buf.split_at(8);
+
+    Ok(Some(Head {
+        first: u64::from_be_bytes(first.try_into().unwrap()),
+        last: u64::from_be_bytes(last.try_into().unwrap()),
+    }))
+}
+
+// head 为 None 时删除 head，key 之后的版本号从 1 重新开始
+fn save_head(
+    store: &dyn Storage,
+    table: &str,
+    key: &str,
+    head: Option<Head>,
+) -> Result<(), KvError> {
+    let heads = head_table(table);
+    match head {
+        Some(head) => {
+            let mut buf = Vec::with_capacity(16);
+            buf.extend_from_slice(&head.first.to_be_bytes());
+            buf.extend_from_slice(&head.last.to_be_bytes());
+            store.set(&heads, key.into(), Bytes::from(buf).into())?;
+        }
+        None => {
+            store.del(&heads, key)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn load_version(
+    store: &dyn Storage,
+    table: &str,
+    key: &str,
+    version: u64,
+) -> Result<Version, KvError> {
+    let history = history_table(table);
+    let corrupted = || {
+        KvError::Corrupted(format!(
+            "table: {}, key: {}: bad version {}",
+            history, key, version
+        ))
+    };
+    let value = store
+        .get(&history, &version_key(key, version))?
+        .ok_or_else(corrupted)?;
+    let buf = Bytes::try_from(value).map_err(|_| corrupted())?;
+
+    Ok(Version::decode(buf)?)
+}
+
+// 按 head 逐个读取版本，事务中不能遍历 table 的存储引擎也可以读取历史
+fn load(store: &dyn Storage, table: &str, key: &str) -> Result<Vec<Version>, KvError> {
+    match load_head(store, table, key)? {
+        Some(head) => (head.first..=head.last)
+            .map(|version| load_version(store, table, key, version))
+            .collect(),
+        None => Ok(Vec::new()),
+    }
+}
+
+// 当前时间戳（毫秒），版本的时间戳需要在重启后依然有效
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemTable, SledDb};
+    use tempfile::tempdir;
+
+    fn version(version: u64, timestamp: u64, value: Option<Value>) -> Version {
+        Version {
+            version,
+            timestamp,
+            value,
+        }
+    }
+
+    #[test]
+    fn prune_should_keep_visible_version() {
+        let mut versions = vec![
+            version(1, 10, Some("v1".into())),
+            version(2, 20, Some("v2".into())),
+            version(3, 30, None),
+            version(4, 40, Some("v4".into())),
+        ];
+        assert_eq!(prune(&mut versions, 5), 0);
+        assert_eq!(prune(&mut versions, 25), 1);
+        assert_eq!(versions[0].version, 2);
+        // 删除产生的版本也会被去掉
+        assert_eq!(prune(&mut versions, 35), 2);
+        assert_eq!(versions, vec![version(4, 40, Some("v4".into()))]);
+        assert_eq!(prune(&mut versions, 45), 0);
+    }
+
+    #[test]
+    fn gc_should_remove_old_versions() {
+        let store = VersionedStorage::new(MemTable::new());
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t1", "k1".into(), "v2".into()).unwrap();
+        store.set("t1", "k2".into(), "v1".into()).unwrap();
+        store.del("t1", "k2").unwrap();
+
+        assert_eq!(store.gc_versions(Duration::from_secs(60)).unwrap(), 0);
+        assert_eq!(store.gc_versions(Duration::ZERO).unwrap(), 3);
+        let history = store.history("t1", "k1").unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].version, 2);
+        assert!(store.history("t1", "k2").unwrap().is_empty());
+        assert_eq!(store.list_tables().unwrap(), vec!["t1".to_string()]);
+    }
+
+    #[test]
+    fn history_should_store_one_entry_per_version() {
+        let store = VersionedStorage::new(MemTable::new());
+        for i in 0..10 {
+            store.set("t1", "k1".into(), i.into()).unwrap();
+        }
+        let inner = store.into_inner();
+        assert_eq!(inner.table_len(&history_table("t1")).unwrap(), 10);
+        assert!(inner
+            .contains(&history_table("t1"), &version_key("k1", 10))
+            .unwrap());
+
+        // gc 只删除旧的版本，之后的版本号继续增长
+        let store = VersionedStorage::new(inner);
+        assert_eq!(store.gc_versions(Duration::ZERO).unwrap(), 9);
+        store.set("t1", "k1".into(), 10.into()).unwrap();
+        let history = store.history("t1", "k1").unwrap();
+        let versions: Vec<_> = history.iter().map(|v| v.version).collect();
+        assert_eq!(versions, [10, 11]);
+        assert_eq!(store.get_version("t1", "k1", 11).unwrap(), Some(10.into()));
+        let inner = store.into_inner();
+        assert_eq!(inner.table_len(&history_table("t1")).unwrap(), 2);
+    }
+
+    #[test]
+    fn versions_should_be_read_without_loading_history() {
+        let store = VersionedStorage::new(MemTable::new());
+        // 时间戳是 10、20、...、90 的 9 个版本，第 5 个是删除
+        let inner = &store.inner;
+        for i in 1..=9u64 {
+            let value = (i != 5).then(|| Value::from(i as i64));
+            let version = version(i, i * 10, value);
+            inner
+                .set(
+                    &history_table("t1"),
+                    version_key("k1", i),
+                    Bytes::from(version.encode_to_vec()).into(),
+                )
+                .unwrap();
+        }
+        save_head(inner, "t1", "k1", Some(Head { first: 1, last: 9 })).unwrap();
+        // 第 1 个版本损坏，读取完整的历史会失败，单个版本的读取不受影响
+        inner
+            .del(&history_table("t1"), &version_key("k1", 1))
+            .unwrap();
+        assert!(store.history("t1", "k1").is_err());
+
+        assert_eq!(store.get_version("t1", "k1", 7).unwrap(), Some(7.into()));
+        assert_eq!(store.get_version("t1", "k1", 5).unwrap(), None);
+        assert_eq!(store.get_version("t1", "k1", 10).unwrap(), None);
+        assert_eq!(store.get_at("t1", "k1", 75).unwrap(), Some(7.into()));
+        assert_eq!(store.get_at("t1", "k1", 90).unwrap(), Some(9.into()));
+        assert_eq!(store.get_at("t1", "k1", 1000).unwrap(), Some(9.into()));
+        assert_eq!(store.get_at("t1", "k1", 55).unwrap(), None);
+        assert_eq!(store.get_at("t1", "k2", 55).unwrap(), None);
+    }
+
+    #[test]
+    fn history_should_persist() {
+        let dir = tempdir().unwrap();
+        {
+            let store = VersionedStorage::new(SledDb::new(dir.path()));
+            store.set("t1", "k1".into(), "v1".into()).unwrap();
+            store.incr("t1", "k2", 1).unwrap();
+            store.drop_table("t1").unwrap();
+        }
+
+        let store = VersionedStorage::new(SledDb::new(dir.path()));
+        let history = store.history("t1", "k1").unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[1].value, None);
+        assert_eq!(store.get_version("t1", "k2", 1).unwrap(), Some(1.into()));
+        assert_eq!(store.get_version("t1", "k2", 2).unwrap(), None);
+        assert!(store.list_tables().unwrap().is_empty());
+    }
+}